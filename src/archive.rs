@@ -0,0 +1,545 @@
+use bytes::Bytes;
+use futures::{Async, Future, Poll, Stream, future, stream};
+use futures::future::Loop;
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use bottle::{BottleType, DecodeLimits, ReadOptions, make_bottle, peek_bottle_type, read_bottle_with_options};
+use bottle_header::{Header};
+use compressed_bottle::{CompressOptions, CompressionType, compress_bottle_with_options, decompress_bottle_with_limits};
+use dedup_bottle::{dedup_bottle, reassemble_bottle_with_limits};
+use error::BottleError;
+use encrypted_bottle::{EncryptionInfo, EncryptionType, KeySource, decrypt_bottle_with_limits, encrypt_bottle_with_cipher};
+use file_bottle::{
+  Deterministic, ExtractOptions, FileMetadata, WriteSettings, content_children, extract_bottle, safe_filename, tracked_directory,
+  tracked_file_bottle
+};
+use hash_bottle::{
+  HashInfo, hash_bottle, hash_bottle_signed, hash_info, verify_hash_bottle_signed_with_limits, verify_hash_bottle_with_limits
+};
+use hashing::HashAlgorithm;
+use incremental::Manifest;
+use parity_bottle::{repair_bottle_with_limits, with_parity};
+use sparse::{dense_stream, sparse_data};
+use stream_helpers::concat_limited;
+
+// folder name used when more than one path is archived.
+const DEFAULT_FOLDER_NAME: &str = "archive";
+
+pub type BottleStream = Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error>>;
+pub type ByteStream = Box<dyn Stream<Item = Bytes, Error = io::Error>>;
+type Signer = Box<dyn FnOnce(Bytes) -> Box<dyn Future<Item = Bytes, Error = io::Error>>>;
+type KeyResolver = Rc<dyn Fn(&EncryptionInfo) -> io::Result<KeySource>>;
+type Verifier = Rc<dyn Fn(&str, Bytes) -> Box<dyn Future<Item = Bytes, Error = io::Error>>>;
+
+/*
+ * Builds an archive of files and folders, wrapped in whichever layers are
+ * requested. From the inside out, the layers are always: files, hash (or
+ * signature), dedup, compression, encryption, parity. The hash covers the
+ * files themselves, compression has to happen before encryption to do any
+ * good, and parity protects the bytes that are actually stored.
+ */
+#[derive(Default)]
+pub struct ArchiveWriter {
+  paths: Vec<PathBuf>,
+  folder_name: Option<String>,
+  hash: Option<HashAlgorithm>,
+  crc32c: bool,
+  deterministic: Option<Deterministic>,
+  large_reads: bool,
+  parent: Option<Rc<Manifest>>,
+  signer: Option<( String, Signer )>,
+  dedup: bool,
+  compression: Option<CompressOptions>,
+  encryption: Option<( KeySource, Vec<String>, EncryptionType )>,
+  parity: Option<( usize, usize )>
+}
+
+impl ArchiveWriter {
+  pub fn new() -> ArchiveWriter {
+    ArchiveWriter::default()
+  }
+
+  /// Add a file or folder (recursively). If more than one path is added,
+  /// they're collected into a folder bottle named by `folder_name`.
+  pub fn add_path<P: Into<PathBuf>>(mut self, path: P) -> ArchiveWriter {
+    self.paths.push(path.into());
+    self
+  }
+
+  pub fn folder_name<S: Into<String>>(mut self, name: S) -> ArchiveWriter {
+    self.folder_name = Some(name.into());
+    self
+  }
+
+  pub fn hash(mut self, algorithm: HashAlgorithm) -> ArchiveWriter {
+    self.hash = Some(algorithm);
+    self
+  }
+
+  /// Follow each file's contents with a CRC32C: much cheaper than `hash`,
+  /// and checked per file as it's read.
+  pub fn crc32c(mut self) -> ArchiveWriter {
+    self.crc32c = true;
+    self
+  }
+
+  /// Make the same files always archive to the same bytes (see
+  /// `Deterministic`). Encryption still picks a new random key each time.
+  pub fn deterministic(mut self, deterministic: Deterministic) -> ArchiveWriter {
+    self.deterministic = Some(deterministic);
+    self
+  }
+
+  /// Read files of `LARGE_FILE_SIZE` bytes or more in bigger blocks (see
+  /// `large_file_stream`).
+  pub fn large_reads(mut self) -> ArchiveWriter {
+    self.large_reads = true;
+    self
+  }
+
+  /// Store the files in folders that `parent` already has as references
+  /// to it (see `incremental`).
+  pub fn incremental(mut self, parent: Manifest) -> ArchiveWriter {
+    self.parent = Some(Rc::new(parent));
+    self
+  }
+
+  /// Sign the hash (SHA-512, unless `hash` picks another).
+  pub fn sign<S, F, Fut>(mut self, signed_by: S, signer: F) -> ArchiveWriter
+    where
+      S: Into<String>,
+      F: FnOnce(Bytes) -> Fut + 'static,
+      Fut: Future<Item = Bytes, Error = io::Error> + 'static
+  {
+    self.signer = Some(( signed_by.into(), Box::new(move |digest| Box::new(signer(digest))) ));
+    self
+  }
+
+  /// Store repeated data only once (see `dedup_bottle`).
+  pub fn dedup(mut self) -> ArchiveWriter {
+    self.dedup = true;
+    self
+  }
+
+  pub fn compress(self, compression_type: CompressionType) -> ArchiveWriter {
+    self.compress_with(CompressOptions::new(compression_type))
+  }
+
+  pub fn compress_with(mut self, options: CompressOptions) -> ArchiveWriter {
+    self.compression = Some(options);
+    self
+  }
+
+  pub fn encrypt(self, key: KeySource, recipients: Vec<String>) -> ArchiveWriter {
+    self.encrypt_with(key, recipients, EncryptionType::Aes256Gcm)
+  }
+
+  pub fn encrypt_with(mut self, key: KeySource, recipients: Vec<String>, encryption_type: EncryptionType) -> ArchiveWriter {
+    self.encryption = Some(( key, recipients, encryption_type ));
+    self
+  }
+
+  /// Add Reed-Solomon parity, to repair damage (see `with_parity`).
+  pub fn parity(mut self, data_shards: usize, parity_shards: usize) -> ArchiveWriter {
+    self.parity = Some(( data_shards, parity_shards ));
+    self
+  }
+
+  /// Build the archive. Paths are checked now, but files aren't read until
+  /// the stream is.
+  pub fn into_stream(self) -> io::Result<BottleStream> {
+    let settings = WriteSettings {
+      crc32c: self.crc32c,
+      deterministic: self.deterministic.map(Rc::new),
+      large_reads: self.large_reads
+    };
+    let parent = self.parent;
+    let mut bottles = self.paths.into_iter().map(|path| path_bottle(path, settings.clone(), parent.clone())).collect::<io::Result<Vec<_>>>()?;
+    let mut s = match bottles.len() {
+      0 => return Err(nothing_to_archive_error()),
+      1 => bottles.remove(0),
+      _ => {
+        let metadata = FileMetadata {
+          filename: self.folder_name.unwrap_or_else(|| DEFAULT_FOLDER_NAME.to_string()),
+          folder: true,
+          ..FileMetadata::default()
+        };
+        Box::new(make_bottle(BottleType::File, &metadata.try_to_header()?, bottles))
+      }
+    };
+
+    let algorithm = self.hash.unwrap_or(HashAlgorithm::Sha512);
+    s = match ( self.signer, self.hash ) {
+      ( Some(( signed_by, signer )), _ ) => Box::new(hash_bottle_signed(s, algorithm, signed_by, signer)?),
+      ( None, Some(_) ) => Box::new(hash_bottle(s, algorithm)?),
+      ( None, None ) => s
+    };
+    if self.dedup {
+      s = Box::new(dedup_bottle(s));
+    }
+    if let Some(options) = self.compression {
+      s = Box::new(compress_bottle_with_options(s, &options)?);
+    }
+    if let Some(( key, recipients, encryption_type )) = self.encryption {
+      s = Box::new(encrypt_bottle_with_cipher(s, key, recipients, encryption_type)?);
+    }
+    if let Some(( data_shards, parity_shards )) = self.parity {
+      s = Box::new(with_parity(s, data_shards, parity_shards)?);
+    }
+    Ok(s)
+  }
+}
+
+/*
+ * Reads an archive made by `ArchiveWriter` (or anything else made of the
+ * standard bottle types): decrypts, decompresses, and verifies its way down
+ * to the file bottles, and then lists each file and folder, depth first.
+ */
+#[derive(Default)]
+pub struct ArchiveReader {
+  key_resolver: Option<KeyResolver>,
+  verifier: Option<Verifier>,
+  limits: DecodeLimits
+}
+
+impl ArchiveReader {
+  pub fn new() -> ArchiveReader {
+    ArchiveReader::default()
+  }
+
+  /// Called for each encrypted layer, to find the key.
+  pub fn with_key<F>(mut self, resolver: F) -> ArchiveReader
+    where F: Fn(&EncryptionInfo) -> io::Result<KeySource> + 'static
+  {
+    self.key_resolver = Some(Rc::new(resolver));
+    self
+  }
+
+  /// Called for each signed hash layer, like the `verifier` passed to
+  /// `verify_hash_bottle_signed`. Without one, signed bottles are refused.
+  pub fn with_verifier<F, Fut>(mut self, verifier: F) -> ArchiveReader
+    where
+      F: Fn(&str, Bytes) -> Fut + 'static,
+      Fut: Future<Item = Bytes, Error = io::Error> + 'static
+  {
+    self.verifier = Some(Rc::new(move |signed_by: &str, blob| Box::new(verifier(signed_by, blob))));
+    self
+  }
+
+  /// Refuse archives that would make the reader allocate (or nest) more
+  /// than this. Layers and folders both count toward the nesting depth.
+  pub fn with_limits(mut self, limits: DecodeLimits) -> ArchiveReader {
+    self.limits = limits;
+    self
+  }
+
+  /// Stream the entries of an archive. Like the child streams of
+  /// `read_bottle`, each entry's content has to be read before asking for
+  /// the next entry, or it's skipped. A bad hash is reported as an error
+  /// after the last entry.
+  pub fn entries<S>(self, s: S) -> ArchiveEntries where S: Stream<Item = Bytes, Error = io::Error> + 'static {
+    let files: Rc<RefCell<Option<ByteStream>>> = Rc::new(RefCell::new(None));
+    let shared = files.clone();
+    let layers = Rc::new(Cell::new(0));
+    let shared_layers = layers.clone();
+    let limits = self.limits;
+    let root = self.unwrap_layers(Box::new(s)).and_then(move |( s, depth )| {
+      *shared.borrow_mut() = Some(s);
+      shared_layers.set(depth);
+      read_boxed_bottle(Box::new(SharedStream(shared)), limits)
+    });
+    ArchiveEntries { files, layers, limits, stack: Vec::new(), pending: Some(( PathBuf::new(), Box::new(root) )), done: false }
+  }
+
+  /// List the files and folders in an archive, depth first, without
+  /// reading their contents. Hashes are reported, not checked. Entries
+  /// aren't known to be complete until the end of the archive, so none are
+  /// emitted until then.
+  pub fn list<S>(self, s: S) -> impl Stream<Item = EntryInfo, Error = io::Error>
+    where S: Stream<Item = Bytes, Error = io::Error> + 'static
+  {
+    list_layers(self.key_resolver, self.limits, Box::new(s), PathBuf::new(), 1).map(stream::iter_ok).flatten_stream()
+  }
+
+  /// Extract an archive into `target_dir`, like `extract_bottle` on the
+  /// file bottles inside its layers. The layers are read to the end after,
+  /// so a bad hash is still reported.
+  pub fn extract<S, P>(self, s: S, target_dir: P, options: ExtractOptions) -> impl Future<Item = Vec<PathBuf>, Error = io::Error>
+    where
+      S: Stream<Item = Bytes, Error = io::Error> + 'static,
+      P: AsRef<Path>
+  {
+    let target = target_dir.as_ref().to_path_buf();
+    self.unwrap_layers(Box::new(s)).and_then(move |( s, _ )| {
+      // drain what's left of the layers, so they can check themselves.
+      let inner: Rc<RefCell<Option<ByteStream>>> = Rc::new(RefCell::new(Some(s)));
+      let rest = SharedStream(inner.clone());
+      extract_bottle(SharedStream(inner), target, options).and_then(move |paths| rest.for_each(|_| Ok(())).map(move |_| paths))
+    })
+  }
+
+  // peel off layers until we reach a file bottle, counting them.
+  pub(crate) fn unwrap_layers(self, s: ByteStream) -> impl Future<Item = ( ByteStream, usize ), Error = io::Error> {
+    let limits = self.limits;
+    future::loop_fn(( s, 0 ), move |( s, depth )| {
+      let key_resolver = self.key_resolver.clone();
+      let verifier = self.verifier.clone();
+      peek_bottle_type(s).and_then(move |( btype, s )| -> UnwrapFuture {
+        if let Err(e) = limits.check_depth(depth + 1) { return Box::new(future::err(e)) }
+        let next = move |s| Loop::Continue(( s, depth + 1 ));
+        match btype {
+          BottleType::File => Box::new(future::ok(Loop::Break(( Box::new(s) as ByteStream, depth )))),
+          BottleType::Compressed => Box::new(decompress_bottle_with_limits(s, limits).map(move |( _, s )| next(Box::new(s) as ByteStream))),
+          BottleType::Dedup => Box::new(reassemble_bottle_with_limits(s, limits).map(move |( _, s )| next(Box::new(s) as ByteStream))),
+          BottleType::Parity => Box::new(repair_bottle_with_limits(s, limits).map(move |( _, s )| next(Box::new(s) as ByteStream))),
+          BottleType::Hashed => match verifier {
+            Some(verifier) => {
+              let verified = verify_hash_bottle_signed_with_limits(s, move |signed_by, blob| verifier(signed_by, blob), limits);
+              Box::new(verified.map(move |( _, s )| next(Box::new(s) as ByteStream)))
+            }
+            None => Box::new(verify_hash_bottle_with_limits(s, limits).map(move |( _, s )| next(Box::new(s) as ByteStream)))
+          },
+          BottleType::Encrypted => match key_resolver {
+            Some(resolver) => Box::new(decrypt_bottle_with_limits(s, move |info| resolver(info), limits).map(move |( _, s )| {
+              next(Box::new(s) as ByteStream)
+            })),
+            None => Box::new(future::err(no_key_error()))
+          },
+          _ => Box::new(future::err(unexpected_bottle_error(btype)))
+        }
+      })
+    })
+  }
+}
+
+/// A file or folder in an archive. `path` is relative to the archive root,
+/// and a folder's `content` is empty.
+pub struct ArchiveEntry {
+  pub path: PathBuf,
+  pub metadata: FileMetadata,
+  pub content: ByteStream
+}
+
+/// A file or folder in an archive, from `list_bottle`. `layers` is the
+/// chain of bottle types wrapping it, outermost first and ending with
+/// `File`, and `hashes` has one entry for each hashed layer, in the same
+/// order.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct EntryInfo {
+  pub path: PathBuf,
+  pub metadata: FileMetadata,
+  pub layers: Vec<BottleType>,
+  pub hashes: Vec<HashInfo>
+}
+
+/// List an archive that isn't encrypted. (Use `ArchiveReader::list` to
+/// supply a key.)
+pub fn list_bottle<S>(s: S) -> impl Stream<Item = EntryInfo, Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error> + 'static
+{
+  ArchiveReader::new().list(s)
+}
+
+// a layer's stream and depth: done, or another layer to peel off.
+type UnwrapFuture = Box<dyn Future<Item = Loop<( ByteStream, usize ), ( ByteStream, usize )>, Error = io::Error>>;
+type ChildStreamStream = Box<dyn Stream<Item = ByteStream, Error = io::Error>>;
+type BottleFuture = Box<dyn Future<Item = ( BottleType, Header, ChildStreamStream ), Error = io::Error>>;
+
+/// Stream of entries, from `ArchiveReader::entries`.
+#[must_use = "streams do nothing unless polled"]
+pub struct ArchiveEntries {
+  // the unwrapped file bottles, so they can be read to the end (which
+  // checks the hash of any layer wrapping them).
+  files: Rc<RefCell<Option<ByteStream>>>,
+  // how many layers were around the file bottles.
+  layers: Rc<Cell<usize>>,
+  limits: DecodeLimits,
+  // the children of each folder we're inside, and its path.
+  stack: Vec<( PathBuf, ChildStreamStream )>,
+  // the next entry's bottle, and the path of the folder it's in.
+  pending: Option<( PathBuf, BottleFuture )>,
+  done: bool
+}
+
+impl Stream for ArchiveEntries {
+  type Item = ArchiveEntry;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    loop {
+      if self.done { return Ok(Async::Ready(None)) }
+
+      if let Some(( folder, mut f )) = self.pending.take() {
+        let ( btype, header, children ) = match f.poll()? {
+          Async::NotReady => {
+            self.pending = Some(( folder, f ));
+            return Ok(Async::NotReady);
+          }
+          Async::Ready(bottle) => bottle
+        };
+        if btype != BottleType::File { return Err(unexpected_bottle_error(btype)) }
+        let metadata = FileMetadata::from_header(&header)?;
+        let path = folder.join(safe_filename(&metadata.filename)?);
+        let content: ByteStream = if metadata.folder {
+          self.stack.push(( path.clone(), children ));
+          Box::new(stream::empty())
+        } else if metadata.sparse {
+          let size = metadata.size.unwrap_or(0);
+          Box::new(sparse_data(content_children(children, &metadata), size, self.limits).map(move |data| dense_stream(data, size)).flatten_stream())
+        } else {
+          Box::new(content_children(children, &metadata).flatten())
+        };
+        return Ok(Async::Ready(Some(ArchiveEntry { path, metadata, content })));
+      }
+
+      let depth = self.layers.get() + self.stack.len() + 1;
+      match self.stack.last_mut() {
+        Some(&mut ( ref folder, ref mut children )) => {
+          if let Some(child) = try_ready!(children.poll()) {
+            self.limits.check_depth(depth)?;
+            self.pending = Some(( folder.clone(), Box::new(read_boxed_bottle(child, self.limits)) ));
+            continue;
+          }
+        }
+        None => {
+          while try_ready!(SharedStream(self.files.clone()).poll()).is_some() {}
+          self.done = true;
+          continue;
+        }
+      }
+      self.stack.pop();
+    }
+  }
+}
+
+type EntriesFuture = Box<dyn Future<Item = Vec<EntryInfo>, Error = io::Error>>;
+
+// list the bottle in `s` and everything inside it. the first entry is the
+// one for this bottle, so each layer adds itself to that one on the way out.
+// `depth` counts this bottle and everything around it.
+fn list_layers(key_resolver: Option<KeyResolver>, limits: DecodeLimits, s: ByteStream, folder: PathBuf, depth: usize) -> EntriesFuture {
+  if let Err(e) = limits.check_depth(depth) { return Box::new(future::err(e)) }
+  Box::new(peek_bottle_type(s).and_then(move |( btype, s )| -> EntriesFuture {
+    let inner: EntriesFuture = match btype {
+      BottleType::File => list_file(key_resolver, limits, Box::new(s), folder, depth),
+      BottleType::Hashed => list_hashed(key_resolver, limits, Box::new(s), folder, depth),
+      BottleType::Compressed => Box::new(decompress_bottle_with_limits(s, limits).and_then(move |( _, s )| {
+        list_layers(key_resolver, limits, Box::new(s), folder, depth + 1)
+      })),
+      BottleType::Dedup => Box::new(reassemble_bottle_with_limits(s, limits).and_then(move |( _, s )| {
+        list_layers(key_resolver, limits, Box::new(s), folder, depth + 1)
+      })),
+      BottleType::Parity => Box::new(repair_bottle_with_limits(s, limits).and_then(move |( _, s )| {
+        list_layers(key_resolver, limits, Box::new(s), folder, depth + 1)
+      })),
+      BottleType::Encrypted => match key_resolver.clone() {
+        Some(resolver) => Box::new(decrypt_bottle_with_limits(s, move |info| resolver(info), limits).and_then(move |( _, s )| {
+          list_layers(key_resolver, limits, Box::new(s), folder, depth + 1)
+        })),
+        None => Box::new(future::err(no_key_error()))
+      },
+      _ => Box::new(future::err(unexpected_bottle_error(btype)))
+    };
+    if btype == BottleType::File || btype == BottleType::Hashed { return inner }
+    Box::new(inner.map(move |entries| add_layer(entries, btype, None)))
+  }))
+}
+
+// the digest comes after the inner bottle, so the inner bottle has to be
+// listed before the hash can be added to it.
+fn list_hashed(key_resolver: Option<KeyResolver>, limits: DecodeLimits, s: ByteStream, folder: PathBuf, depth: usize) -> EntriesFuture {
+  Box::new(read_boxed_bottle(s, limits).and_then(move |( _, header, children )| {
+    children.into_future().map_err(|( e, _ )| e).and_then(move |( inner, children )| {
+      let inner = inner.ok_or_else(|| unexpected_bottle_error(BottleType::Hashed))?;
+      Ok(list_layers(key_resolver, limits, inner, folder, depth + 1).and_then(move |entries| {
+        children.into_future().map_err(|( e, _ )| e).and_then(|( digest, _ )| {
+          digest.ok_or_else(|| unexpected_bottle_error(BottleType::Hashed))
+        }).and_then(move |digest| concat_limited(digest, limits)).map(|digest| ( entries, digest ))
+      }))
+    }).flatten().and_then(move |( entries, digest )| {
+      Ok(add_layer(entries, BottleType::Hashed, Some(hash_info(&header, digest)?)))
+    })
+  }))
+}
+
+// a file's contents are skipped, and a folder's children listed in order.
+fn list_file(key_resolver: Option<KeyResolver>, limits: DecodeLimits, s: ByteStream, folder: PathBuf, depth: usize) -> EntriesFuture {
+  Box::new(read_boxed_bottle(s, limits).and_then(move |( _, header, children )| -> EntriesFuture {
+    let metadata = match FileMetadata::from_header(&header) {
+      Ok(metadata) => metadata,
+      Err(e) => return Box::new(future::err(e))
+    };
+    let path = match safe_filename(&metadata.filename) {
+      Ok(filename) => folder.join(filename),
+      Err(e) => return Box::new(future::err(e))
+    };
+    let is_folder = metadata.folder;
+    let entry = EntryInfo { path: path.clone(), metadata, layers: vec![ BottleType::File ], hashes: Vec::new() };
+    if !is_folder { return Box::new(future::ok(vec![ entry ])) }
+    Box::new(children.fold(vec![ entry ], move |mut entries, child| {
+      list_layers(key_resolver.clone(), limits, child, path.clone(), depth + 1).map(move |more| {
+        entries.extend(more);
+        entries
+      })
+    }))
+  }))
+}
+
+fn add_layer(mut entries: Vec<EntryInfo>, btype: BottleType, hash: Option<HashInfo>) -> Vec<EntryInfo> {
+  if let Some(entry) = entries.first_mut() {
+    entry.layers.insert(0, btype);
+    if let Some(hash) = hash { entry.hashes.insert(0, hash) }
+  }
+  entries
+}
+
+fn read_boxed_bottle(s: ByteStream, limits: DecodeLimits) -> BottleFuture {
+  Box::new(read_bottle_with_options(s, ReadOptions { limits, ..ReadOptions::default() }).map(|( btype, header, children )| {
+    let children: ChildStreamStream = Box::new(children.map(|child| Box::new(child) as ByteStream));
+    ( btype, header, children )
+  }))
+}
+
+// lets the entries stream (or a walker) keep a handle on a stream while the
+// bottle reader reads from it, so whatever's left can be drained after.
+pub(crate) struct SharedStream(pub(crate) Rc<RefCell<Option<ByteStream>>>);
+
+impl Stream for SharedStream {
+  type Item = Bytes;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    match *self.0.borrow_mut() {
+      Some(ref mut s) => s.poll(),
+      None => Ok(Async::Ready(None))
+    }
+  }
+}
+
+pub(crate) fn path_bottle(path: PathBuf, settings: WriteSettings, parent: Option<Rc<Manifest>>) -> io::Result<BottleStream> {
+  if fs::metadata(&path)?.is_dir() {
+    tracked_directory(&path, None, settings, parent)
+  } else {
+    Ok(Box::new(tracked_file_bottle(&path, None, &settings)?))
+  }
+}
+
+
+// ----- errors
+
+fn no_key_error() -> io::Error {
+  BottleError::NoKey.into()
+}
+
+fn unexpected_bottle_error(btype: BottleType) -> io::Error {
+  BottleError::UnexpectedType(btype).into()
+}
+
+fn nothing_to_archive_error() -> io::Error {
+  BottleError::NothingToArchive.into()
+}
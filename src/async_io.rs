@@ -0,0 +1,244 @@
+use bytes::Bytes;
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream, stream};
+use futures::stream::Fuse;
+use std::collections::VecDeque;
+use std::io::{self, IoSlice};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::{flush, write_all};
+
+use bottle::{BottleOptions, BottleType, END_OF_ALL_STREAMS_BYTES, encode_bottle_header, frame_child, make_bottle};
+use bottle_header::{Header};
+
+// how much to ask for on each read.
+const READ_SIZE: usize = 64 * 1024;
+
+// most buffers handed to one vectored write. (the OS limit is usually 1024.)
+const MAX_IO_SLICES: usize = 64;
+
+/// Turn anything readable (a tokio file or socket) into the byte stream
+/// the bottle readers expect. The stream ends at EOF.
+pub fn bottle_from_async_read<R>(reader: R) -> impl Stream<Item = Bytes, Error = io::Error>
+  where R: AsyncRead
+{
+  let mut reader = reader;
+  let mut done = false;
+  stream::poll_fn(move || -> Poll<Option<Bytes>, io::Error> {
+    if done { return Ok(Async::Ready(None)) }
+    let mut buffer = vec![ 0u8; READ_SIZE ];
+    let n = try_ready!(reader.poll_read(&mut buffer));
+    if n == 0 {
+      done = true;
+      return Ok(Async::Ready(None));
+    }
+    buffer.truncate(n);
+    Ok(Async::Ready(Some(Bytes::from(buffer))))
+  })
+}
+
+/// Write a bottle (or any stream of buffers) to a tokio writer, flushing
+/// at the end, and hand the writer back.
+pub fn write_bottle_to<S, W>(s: S, writer: W) -> impl Future<Item = W, Error = io::Error>
+  where
+    S: Stream<Item = Vec<Bytes>, Error = io::Error>,
+    W: AsyncWrite
+{
+  s.map(stream::iter_ok).flatten().fold(writer, |writer, b| {
+    write_all(writer, b).map(|( writer, _ )| writer)
+  }).and_then(flush)
+}
+
+/// Like `write_bottle_to`, but queues up whatever buffers are ready and
+/// hands them to the writer as one vectored write, so nothing is copied
+/// or written a buffer at a time.
+pub fn write_vectored_to<S, W>(s: S, writer: W) -> WriteVectored<S, W>
+  where
+    S: Stream<Item = Vec<Bytes>, Error = io::Error>,
+    W: AsyncWrite
+{
+  WriteVectored { stream: s.fuse(), writer: Some(writer), pending: VecDeque::new(), done: false }
+}
+
+#[must_use = "futures do nothing unless polled"]
+pub struct WriteVectored<S, W> where S: Stream<Item = Vec<Bytes>, Error = io::Error>, W: AsyncWrite {
+  stream: Fuse<S>,
+  writer: Option<W>,
+  // buffers waiting to be written, the first one possibly partly written
+  pending: VecDeque<Bytes>,
+  done: bool
+}
+
+impl<S, W> Future for WriteVectored<S, W> where S: Stream<Item = Vec<Bytes>, Error = io::Error>, W: AsyncWrite {
+  type Item = W;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<W, io::Error> {
+    loop {
+      while !self.done && self.pending.len() < MAX_IO_SLICES {
+        match self.stream.poll()? {
+          Async::Ready(Some(buffers)) => self.pending.extend(buffers.into_iter().filter(|b| !b.is_empty())),
+          Async::Ready(None) => self.done = true,
+          Async::NotReady => break
+        }
+      }
+
+      let writer = self.writer.as_mut().expect("polled after completion");
+      if self.pending.is_empty() {
+        if !self.done { return Ok(Async::NotReady) }
+        try_ready!(writer.poll_flush());
+        return Ok(Async::Ready(self.writer.take().unwrap()));
+      }
+
+      try_ready!(write_pending(writer, &mut self.pending));
+    }
+  }
+}
+
+// hand as many queued buffers as possible to one vectored write, and drop
+// whatever was written from the front of the queue.
+fn write_pending<W: AsyncWrite>(writer: &mut W, pending: &mut VecDeque<Bytes>) -> Poll<(), io::Error> {
+  let mut slices = [ IoSlice::new(&[]); MAX_IO_SLICES ];
+  let count = pending.len().min(MAX_IO_SLICES);
+  for (slice, b) in slices.iter_mut().zip(pending.iter()) { *slice = IoSlice::new(b) }
+  let mut n = match writer.write_vectored(&slices[0 .. count]) {
+    Ok(0) => return Err(write_zero_error()),
+    Ok(n) => n,
+    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => 0,
+    Err(e) => return Err(e)
+  };
+  while n > 0 {
+    let mut b = pending.pop_front().expect("wrote more than was queued");
+    if b.len() > n {
+      b.advance(n);
+      pending.push_front(b);
+      break;
+    }
+    n -= b.len();
+  }
+  Ok(Async::Ready(()))
+}
+
+
+// ----- sink
+
+/// One child stream for a `BottleSink`.
+pub struct BottleEntry {
+  stream: Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error>>
+}
+
+impl BottleEntry {
+  /// A child stream of plain data.
+  pub fn data<S>(s: S) -> BottleEntry where S: Stream<Item = Vec<Bytes>, Error = io::Error> + 'static {
+    BottleEntry { stream: Box::new(s) }
+  }
+
+  /// A child stream holding a whole nested bottle, like `make_bottle`.
+  pub fn bottle<I, A>(btype: BottleType, header: &Header, streams: I) -> BottleEntry
+    where
+      I: IntoIterator<Item = A> + 'static,
+      A: Stream<Item = Vec<Bytes>, Error = io::Error> + 'static
+  {
+    BottleEntry::data(make_bottle(btype, header, streams))
+  }
+}
+
+/// Write a bottle to a tokio writer by pushing child streams into it, for
+/// producers that can't hand `make_bottle` a stream to pull from. The
+/// header goes out first; each entry becomes the next child stream, framed
+/// the same way `make_bottle` would; and closing the sink ends the bottle
+/// and flushes the writer (but doesn't shut it down).
+///
+/// Only one entry is written at a time: while one is still being drained,
+/// `start_send` refuses the next.
+#[must_use = "sinks do nothing unless polled"]
+pub struct BottleSink<W> where W: AsyncWrite {
+  writer: W,
+  options: BottleOptions,
+  // the framed child stream being written
+  current: Option<Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error>>>,
+  // buffers waiting to be written, the first one possibly partly written
+  pending: VecDeque<Bytes>,
+  closed: bool
+}
+
+impl<W> BottleSink<W> where W: AsyncWrite {
+  pub fn new(writer: W, btype: BottleType, header: &Header) -> io::Result<BottleSink<W>> {
+    BottleSink::with_options(writer, btype, header, &BottleOptions::default())
+  }
+
+  /// Like `new`, with control over the framing, as in
+  /// `make_bottle_with_options`.
+  pub fn with_options(writer: W, btype: BottleType, header: &Header, options: &BottleOptions) -> io::Result<BottleSink<W>> {
+    options.check()?;
+    let mut pending = VecDeque::new();
+    pending.push_back(Bytes::from(encode_bottle_header(btype, header)?));
+    Ok(BottleSink { writer, options: *options, current: None, pending, closed: false })
+  }
+
+  /// The writer, back. If the sink wasn't closed, the bottle isn't finished.
+  pub fn into_inner(self) -> W {
+    self.writer
+  }
+
+  // write everything queued so far, including all of the current entry.
+  fn poll_written(&mut self) -> Poll<(), io::Error> {
+    loop {
+      while self.pending.len() < MAX_IO_SLICES {
+        let next = match self.current.as_mut() {
+          None => break,
+          Some(s) => s.poll()?
+        };
+        match next {
+          Async::Ready(Some(buffers)) => self.pending.extend(buffers.into_iter().filter(|b| !b.is_empty())),
+          Async::Ready(None) => self.current = None,
+          Async::NotReady => break
+        }
+      }
+
+      if self.pending.is_empty() {
+        if self.current.is_some() { return Ok(Async::NotReady) }
+        return self.writer.poll_flush();
+      }
+      try_ready!(write_pending(&mut self.writer, &mut self.pending));
+    }
+  }
+}
+
+impl<W> Sink for BottleSink<W> where W: AsyncWrite {
+  type SinkItem = BottleEntry;
+  type SinkError = io::Error;
+
+  fn start_send(&mut self, item: BottleEntry) -> StartSend<BottleEntry, io::Error> {
+    if self.closed { return Err(sink_closed_error()) }
+    if self.current.is_some() {
+      self.poll_written()?;
+      if self.current.is_some() { return Ok(AsyncSink::NotReady(item)) }
+    }
+    self.current = Some(Box::new(frame_child(item.stream, self.options)));
+    Ok(AsyncSink::Ready)
+  }
+
+  fn poll_complete(&mut self) -> Poll<(), io::Error> {
+    self.poll_written()
+  }
+
+  fn close(&mut self) -> Poll<(), io::Error> {
+    if !self.closed {
+      try_ready!(self.poll_written());
+      self.pending.push_back(END_OF_ALL_STREAMS_BYTES.clone());
+      self.closed = true;
+    }
+    self.poll_written()
+  }
+}
+
+
+// ----- errors
+
+fn write_zero_error() -> io::Error {
+  io::Error::new(io::ErrorKind::WriteZero, "Writer stopped accepting data")
+}
+
+fn sink_closed_error() -> io::Error {
+  io::Error::new(io::ErrorKind::BrokenPipe, "Bottle is already closed")
+}
@@ -0,0 +1,72 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+mod common;
+
+use futures::{Future, Stream};
+use lib4bottle::archive::ArchiveReader;
+use lib4bottle::json_manifest::JsonLine;
+use lib4bottle::ToHex;
+
+use common::Args;
+
+const USAGE: &str = "[options] <archive>
+  -l, --long                 show each entry's mode, and the archive's layers
+  -j, --json                 one line of JSON for each entry, with everything
+  -p, --password <password>  passphrase (default: $BOTTLE_PASSWORD)";
+
+fn main() {
+  let mut args = Args::new("4ls", USAGE);
+  let mut long = false;
+  let mut json = false;
+  let mut password = None;
+  let mut archive = None;
+
+  while let Some(arg) = args.next() {
+    match arg.as_ref() {
+      "-h" | "--help" => args.usage(),
+      "-l" | "--long" => long = true,
+      "-j" | "--json" => json = true,
+      "-p" | "--password" => password = Some(args.value(&arg)),
+      option if option.starts_with('-') && option != "-" => args.unknown(option),
+      _ if archive.is_some() => args.fail("only one archive, please"),
+      path => archive = Some(path.to_string())
+    }
+  }
+  let archive = archive.unwrap_or_else(|| args.usage());
+
+  let mut reader = ArchiveReader::new();
+  if let Some(key) = common::password(password) {
+    reader = reader.with_key(move |_| Ok(key.clone()));
+  }
+  let s = args.check(common::open_archive(&archive));
+  let entries = args.check(reader.list(s).collect().wait());
+
+  if json {
+    for entry in entries { println!("{}", JsonLine::from_entry(&entry)) }
+    return;
+  }
+
+  if long {
+    if let Some(entry) = entries.first() {
+      let layers: Vec<String> = entry.layers.iter().map(|layer| format!("{:?}", layer)).collect();
+      println!("layers: {}", layers.join(" > "));
+      for hash in &entry.hashes {
+        let signed_by = hash.signed_by.as_ref().map(|name| format!(" (signed by {})", name)).unwrap_or_default();
+        println!("{:?}: {}{}", hash.algorithm, hash.digest.to_hex(), signed_by);
+      }
+    }
+  }
+  for entry in entries {
+    let mut line = entry.path.to_string_lossy().to_string();
+    if entry.metadata.folder { line.push('/') }
+    if let Some(ref target) = entry.metadata.symlink { line = format!("{} -> {}", line, target) }
+    if long {
+      let mode = entry.metadata.posix_mode.map(|mode| format!("{:04o}", mode & 0o7777)).unwrap_or_else(|| "-".to_string());
+      let size = entry.metadata.size.map(|size| size.to_string()).unwrap_or_else(|| "-".to_string());
+      line = format!("{:>5} {:>12}  {}", mode, size, line);
+    }
+    println!("{}", line);
+  }
+}
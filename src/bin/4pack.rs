@@ -0,0 +1,100 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+mod common;
+
+use lib4bottle::archive::ArchiveWriter;
+use lib4bottle::compressed_bottle::find_codec;
+use lib4bottle::encrypted_bottle::{EncryptionType, find_cipher};
+use lib4bottle::file_bottle::Deterministic;
+use lib4bottle::hashing::HashAlgorithm;
+use std::fs;
+use std::io;
+
+use common::Args;
+
+const USAGE: &str = "[options] <path>...
+  -o, --output <file>        write the archive here (default: stdout)
+  -n, --name <name>          folder name, if more than one path is given
+  -H, --hash <sha256|sha512|blake3>
+                             hash the files, to check them when unpacking
+  -k, --crc32c               add a quick checksum after each file
+  -D, --deterministic        the same files always make the same archive
+                             (times are clamped to $SOURCE_DATE_EPOCH)
+  -L, --large-reads          read big files in bigger blocks
+  -d, --dedup                store repeated data only once
+  -c, --compress <lzma2|snappy|zstd>
+  -e, --encrypt              encrypt with a passphrase (see --password)
+  -C, --cipher <aes-256-gcm|xchacha20-poly1305>
+                             cipher to encrypt with (default: aes-256-gcm)
+  -p, --password <password>  passphrase (default: $BOTTLE_PASSWORD)
+  -P, --parity <data>:<parity>
+                             add parity shards, to repair damage";
+
+fn main() {
+  let mut args = Args::new("4pack", USAGE);
+  let mut writer = ArchiveWriter::new();
+  let mut output = None;
+  let mut encrypt = false;
+  let mut cipher = EncryptionType::Aes256Gcm;
+  let mut password = None;
+  let mut paths = 0;
+
+  while let Some(arg) = args.next() {
+    match arg.as_ref() {
+      "-h" | "--help" => args.usage(),
+      "-o" | "--output" => output = Some(args.value(&arg)),
+      "-n" | "--name" => writer = writer.folder_name(args.value(&arg)),
+      "-H" | "--hash" => {
+        let algorithm = match args.value(&arg).as_ref() {
+          "sha256" => HashAlgorithm::Sha256,
+          "sha512" => HashAlgorithm::Sha512,
+          "blake3" => HashAlgorithm::Blake3,
+          name => args.fail(&format!("unknown hash {}", name))
+        };
+        writer = writer.hash(algorithm);
+      }
+      "-k" | "--crc32c" => writer = writer.crc32c(),
+      "-D" | "--deterministic" => writer = writer.deterministic(Deterministic::from_source_date_epoch()),
+      "-L" | "--large-reads" => writer = writer.large_reads(),
+      "-d" | "--dedup" => writer = writer.dedup(),
+      "-c" | "--compress" => {
+        let name = args.value(&arg);
+        let compression_type = find_codec(&name).unwrap_or_else(|| args.fail(&format!("unknown compression {}", name)));
+        writer = writer.compress(compression_type);
+      }
+      "-e" | "--encrypt" => encrypt = true,
+      "-C" | "--cipher" => {
+        let name = args.value(&arg);
+        cipher = find_cipher(&name).unwrap_or_else(|| args.fail(&format!("unknown cipher {}", name)));
+      }
+      "-p" | "--password" => password = Some(args.value(&arg)),
+      "-P" | "--parity" => {
+        let value = args.value(&arg);
+        let shards: Vec<usize> = value.split(':').filter_map(|n| n.parse().ok()).collect();
+        if shards.len() != 2 { args.fail(&format!("parity should be <data>:<parity>, not {}", value)) }
+        writer = writer.parity(shards[0], shards[1]);
+      }
+      option if option.starts_with('-') && option != "-" => args.unknown(option),
+      path => {
+        writer = writer.add_path(path);
+        paths += 1;
+      }
+    }
+  }
+  if paths == 0 { args.usage() }
+  if encrypt {
+    match common::password(password) {
+      Some(key) => writer = writer.encrypt_with(key, vec![], cipher),
+      None => args.fail("--encrypt needs a password")
+    }
+  }
+
+  let s = args.check(writer.into_stream());
+  let result = match output {
+    Some(filename) => fs::File::create(filename).and_then(|file| common::write_all(s, io::BufWriter::new(file))),
+    None => common::write_all(s, io::stdout())
+  };
+  args.check(result);
+}
@@ -0,0 +1,58 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+mod common;
+
+use futures::Future;
+use lib4bottle::archive::ArchiveReader;
+use lib4bottle::extract_filter::{Decision, ExtractFilter};
+use lib4bottle::file_bottle::{ExistingFilePolicy, ExtractOptions};
+use std::fs;
+
+use common::Args;
+
+const USAGE: &str = "[options] <archive>
+  -C, --directory <folder>   unpack into this folder (default: .)
+  -f, --force                overwrite files that already exist
+  -v, --verbose              list each file as it's unpacked
+  -p, --password <password>  passphrase (default: $BOTTLE_PASSWORD)";
+
+fn main() {
+  let mut args = Args::new("4unpack", USAGE);
+  let mut target = ".".to_string();
+  let mut force = false;
+  let mut verbose = false;
+  let mut password = None;
+  let mut archive = None;
+
+  while let Some(arg) = args.next() {
+    match arg.as_ref() {
+      "-h" | "--help" => args.usage(),
+      "-C" | "--directory" => target = args.value(&arg),
+      "-f" | "--force" => force = true,
+      "-v" | "--verbose" => verbose = true,
+      "-p" | "--password" => password = Some(args.value(&arg)),
+      option if option.starts_with('-') && option != "-" => args.unknown(option),
+      _ if archive.is_some() => args.fail("only one archive, please"),
+      path => archive = Some(path.to_string())
+    }
+  }
+  let archive = archive.unwrap_or_else(|| args.usage());
+
+  let mut reader = ArchiveReader::new();
+  if let Some(key) = common::password(password) {
+    reader = reader.with_key(move |_| Ok(key.clone()));
+  }
+  let s = args.check(common::open_archive(&archive));
+  let mut options = ExtractOptions::default();
+  if force { options.existing = ExistingFilePolicy::Overwrite }
+  if verbose {
+    options = options.with_filter(ExtractFilter::new().callback(|entry| {
+      eprintln!("{}", entry.path.display());
+      Decision::Extract
+    }));
+  }
+  args.check(fs::create_dir_all(&target));
+  args.check(reader.extract(s, target, options).wait());
+}
@@ -0,0 +1,86 @@
+// bits shared by the 4pack, 4unpack, and 4ls commands.
+#![allow(dead_code)]
+
+use bytes::Bytes;
+use futures::Stream;
+use lib4bottle::archive::ByteStream;
+use lib4bottle::encrypted_bottle::KeySource;
+use lib4bottle::file_bottle::file_stream;
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+
+// where a passphrase is read from, if it isn't given with `--password`.
+const PASSWORD_VAR: &str = "BOTTLE_PASSWORD";
+
+pub struct Args {
+  command: &'static str,
+  usage: &'static str,
+  args: env::Args
+}
+
+impl Args {
+  pub fn new(command: &'static str, usage: &'static str) -> Args {
+    let mut args = env::args();
+    args.next();
+    Args { command, usage, args }
+  }
+
+  // the value after an option like `-o`.
+  pub fn value(&mut self, option: &str) -> String {
+    match self.args.next() {
+      Some(value) => value,
+      None => self.fail(&format!("{} needs a value", option))
+    }
+  }
+
+  pub fn usage(&self) -> ! {
+    println!("usage: {} {}", self.command, self.usage);
+    process::exit(0);
+  }
+
+  pub fn unknown(&self, option: &str) -> ! {
+    self.fail(&format!("unknown option {} (try --help)", option))
+  }
+
+  pub fn fail(&self, message: &str) -> ! {
+    eprintln!("{}: {}", self.command, message);
+    process::exit(1);
+  }
+
+  // exit with the error, if there is one.
+  pub fn check<T>(&self, result: io::Result<T>) -> T {
+    result.unwrap_or_else(|e| self.fail(&e.to_string()))
+  }
+}
+
+impl Iterator for Args {
+  type Item = String;
+
+  fn next(&mut self) -> Option<String> {
+    self.args.next()
+  }
+}
+
+pub fn password(given: Option<String>) -> Option<KeySource> {
+  given.or_else(|| env::var(PASSWORD_VAR).ok()).map(KeySource::Passphrase)
+}
+
+// an archive file as a stream, or stdin for "-".
+pub fn open_archive(path: &str) -> io::Result<ByteStream> {
+  let path = if path == "-" { "/dev/stdin" } else { path };
+  Ok(Box::new(file_stream(fs::File::open(path)?)))
+}
+
+// pull every buffer out of a bottle stream and write it.
+pub fn write_all<S, W>(s: S, mut writer: W) -> io::Result<()>
+  where
+    S: Stream<Item = Vec<Bytes>, Error = io::Error>,
+    W: io::Write
+{
+  for buffers in s.wait() {
+    for b in buffers? { writer.write_all(&b)?; }
+  }
+  writer.flush()
+}
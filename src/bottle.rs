@@ -1,45 +1,169 @@
-use futures::{Future, future, Stream, stream};
+use futures::{Async, Future, future, Poll, Stream, stream};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
 use std::io;
 use std::iter::Iterator;
+use std::rc::Rc;
 use bytes::Bytes;
 
 use bottle_header::{Header};
 use buffered_stream::{buffer_stream};
-use stream_helpers::{flatten_bytes, make_stream, make_stream_1};
-use stream_reader::{stream_read_exact};
+use error::{BottleError, DecodeLimit, TruncationContext};
+use framed_stream::{FrameReader, split_frames, truncated_error as truncated_bottle_error, unexpected_end_error};
+pub use framed_stream::{framed_vec_stream, framed_vec_stream_with_limit};
+use progress::{Progress, ProgressTracker, count_vec_in, count_vec_out};
+pub(crate) use spec::MAX_HEADER_SIZE;
+use spec::{self, MAGIC, VERSION};
+use stream_helpers::{flatten_bytes, make_vec_stream_1};
+use stream_reader::{StreamReader};
 use zint;
 
-static MAGIC: [u8; 4] = [ 0xf0, 0x9f, 0x8d, 0xbc ];
-const VERSION: u8 = 0;
-
-const MAX_HEADER_SIZE: usize = 4095;
 const MIN_BUFFER: usize = 1024;
 
+// default decode limits: nothing this crate writes comes close.
+const MAX_NESTING_DEPTH: usize = 256;
+const MAX_BUFFERED_BYTES: u64 = 1 << 30;
+
+pub use spec::{FIELD_INTERLEAVED, FIELD_STREAM_COUNT, MAX_INTERLEAVED};
+
 lazy_static! {
-  static ref END_OF_STREAM_BYTES: Bytes = Bytes::from(zint::encode_length(zint::END_OF_STREAM));
-  static ref END_OF_ALL_STREAMS_BYTES: Bytes = Bytes::from(zint::encode_length(zint::END_OF_ALL_STREAMS));
+  pub(crate) static ref END_OF_ALL_STREAMS_BYTES: Bytes = zint::encode_length_bytes(zint::END_OF_ALL_STREAMS);
 }
 
 // 0 - 15, defined in the spec
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum BottleType {
-  File = 0,
-  Hashed = 1,
-  Encrypted = 3,
-  Compressed = 4,
+  File = spec::TYPE_FILE as isize,
+  Hashed = spec::TYPE_HASHED as isize,
+  Encrypted = spec::TYPE_ENCRYPTED as isize,
+  Compressed = spec::TYPE_COMPRESSED as isize,
+  Index = spec::TYPE_INDEX as isize,
+  Volume = spec::TYPE_VOLUME as isize,
+  Dedup = spec::TYPE_DEDUP as isize,
+  Parity = spec::TYPE_PARITY as isize,
   // for tests:
-  Test = 10,
-  Test2 = 11
+  Test = spec::TYPE_TEST as isize,
+  Test2 = spec::TYPE_TEST2 as isize
+}
+
+impl TryFrom<u8> for BottleType {
+  type Error = io::Error;
+
+  fn try_from(btype: u8) -> Result<BottleType, io::Error> {
+    match btype {
+      spec::TYPE_FILE => Ok(BottleType::File),
+      spec::TYPE_HASHED => Ok(BottleType::Hashed),
+      spec::TYPE_ENCRYPTED => Ok(BottleType::Encrypted),
+      spec::TYPE_COMPRESSED => Ok(BottleType::Compressed),
+      spec::TYPE_INDEX => Ok(BottleType::Index),
+      spec::TYPE_VOLUME => Ok(BottleType::Volume),
+      spec::TYPE_DEDUP => Ok(BottleType::Dedup),
+      spec::TYPE_PARITY => Ok(BottleType::Parity),
+      spec::TYPE_TEST => Ok(BottleType::Test),
+      spec::TYPE_TEST2 => Ok(BottleType::Test2),
+      _ => Err(unknown_bottle_type_error(btype))
+    }
+  }
 }
 
 pub fn decode_bottle_type(btype: u8) -> Result<BottleType, io::Error> {
-  match btype {
-    0 => Ok(BottleType::File),
-    1 => Ok(BottleType::Hashed),
-    3 => Ok(BottleType::Encrypted),
-    4 => Ok(BottleType::Compressed),
-    10 => Ok(BottleType::Test),
-    11 => Ok(BottleType::Test2),
-    _ => Err(unknown_bottle_type_error(btype))
+  BottleType::try_from(btype)
+}
+
+/// How `make_bottle` cuts child streams into frames. Small frames get data
+/// out sooner (for pipes and sockets); big ones are cheaper to write (for
+/// disks).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BottleOptions {
+  /// buffer at least this much before writing a frame (except at the end
+  /// of a stream)
+  pub min_frame: usize,
+  /// never write a frame longer than this
+  pub max_frame: usize,
+  /// make every frame but the last exactly `max_frame` long
+  pub exact_frames: bool
+}
+
+impl Default for BottleOptions {
+  fn default() -> BottleOptions {
+    // prevent tiny packets by requiring it to buffer at least 1KB
+    BottleOptions { min_frame: MIN_BUFFER, max_frame: zint::MAX_LENGTH as usize, exact_frames: false }
+  }
+}
+
+impl BottleOptions {
+  pub(crate) fn check(&self) -> io::Result<()> {
+    if self.min_frame == 0 || self.min_frame > self.max_frame || self.max_frame > zint::MAX_LENGTH as usize {
+      return Err(invalid_frame_size_error(self.min_frame, self.max_frame));
+    }
+    Ok(())
+  }
+}
+
+/// How `read_bottle_with_options` reads a bottle.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReadOptions {
+  /// for salvaging a damaged archive: if the source ends early, fail with
+  /// `BottleError::TruncatedAt` (how many bytes were read, and what was
+  /// being read) instead of `TruncatedStream`, after everything before it
+  /// has been yielded. The child streams all end after that.
+  pub tolerate_truncation: bool,
+  pub limits: DecodeLimits
+}
+
+/// How much a reader will do for an archive from a stranger before giving
+/// up with `BottleError::DecodeLimitExceeded`. The defaults accept
+/// anything this crate writes, except a dedup bottle with more than 1GB of
+/// distinct data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DecodeLimits {
+  pub max_header_size: usize,
+  /// frames aren't buffered, but a huge frame length is usually garbage
+  pub max_frame_size: usize,
+  /// how many bottles deep (layers and folders) to go
+  pub max_nesting_depth: usize,
+  /// the most any one reader holds in memory: a digest, a parity stripe,
+  /// a sparse file's extent map, or all of a dedup bottle's chunks
+  pub max_buffered_bytes: u64,
+  /// child streams of an interleaved bottle that are open, or ended but
+  /// not read yet
+  pub max_open_streams: usize
+}
+
+impl Default for DecodeLimits {
+  fn default() -> DecodeLimits {
+    DecodeLimits {
+      max_header_size: MAX_HEADER_SIZE,
+      max_frame_size: zint::MAX_LENGTH as usize,
+      max_nesting_depth: MAX_NESTING_DEPTH,
+      max_buffered_bytes: MAX_BUFFERED_BYTES,
+      max_open_streams: MAX_INTERLEAVED
+    }
+  }
+}
+
+impl DecodeLimits {
+  pub(crate) fn check_depth(&self, depth: usize) -> io::Result<()> {
+    if depth > self.max_nesting_depth {
+      return Err(decode_limit_error(DecodeLimit::NestingDepth, depth as u64, self.max_nesting_depth as u64));
+    }
+    Ok(())
+  }
+
+  pub(crate) fn check_buffered(&self, size: u64) -> io::Result<()> {
+    if size > self.max_buffered_bytes {
+      return Err(decode_limit_error(DecodeLimit::BufferedBytes, size, self.max_buffered_bytes));
+    }
+    Ok(())
+  }
+
+  pub(crate) fn check_open_streams(&self, count: usize) -> io::Result<()> {
+    if count > self.max_open_streams {
+      return Err(decode_limit_error(DecodeLimit::OpenStreams, count as u64, self.max_open_streams as u64));
+    }
+    Ok(())
   }
 }
 
@@ -50,98 +174,781 @@ pub fn make_bottle<I, A>(btype: BottleType, header: &Header, streams: I)
     I: IntoIterator<Item = A>,
     A: Stream<Item = Vec<Bytes>, Error = io::Error>
 {
-  let combined = stream::iter(streams.into_iter().map(|s| {
-    // prevent tiny packets by requiring it to buffer at least 1KB
-    Ok::<_, io::Error>(framed_vec_stream(buffer_stream(s, MIN_BUFFER, false)))
-  })).flatten();
-  make_header_stream(btype, header).chain(combined).chain(make_stream_1(END_OF_ALL_STREAMS_BYTES.clone()))
-}
-
-// // convert a byte stream into a stream with each chunk prefixed by a length
-// // marker, suitable for embedding in a bottle.
-// pub fn framed_stream<S>(s: S) -> impl Stream<Item = Bytes, Error = io::Error>
-//   where S: Stream<Item = Bytes, Error = io::Error>
-// {
-//   let end_of_stream = make_stream_1(Bytes::from(zint::encode_length(zint::END_OF_STREAM)));
-//   s.map(|buffer| {
-//     make_stream_2(Bytes::from(zint::encode_length(buffer.len() as u32)), buffer)
-//   }).flatten().chain(end_of_stream)
-// }
+  // the defaults are always valid.
+  make_bottle_with_options(btype, header, streams, &BottleOptions::default()).unwrap()
+}
+
+/// Like `make_bottle`, with control over the framing. Fails if `min_frame`
+/// is zero or bigger than `max_frame`, or `max_frame` is bigger than
+/// `zint::MAX_LENGTH`.
+pub fn make_bottle_with_options<I, A>(btype: BottleType, header: &Header, streams: I, options: &BottleOptions)
+  -> io::Result<impl Stream<Item = Vec<Bytes>, Error = io::Error>>
+  where
+    I: IntoIterator<Item = A>,
+    A: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  options.check()?;
+  Ok(make_streamed_bottle(btype, header, stream::iter_ok::<_, io::Error>(streams), *options))
+}
+
+/// Like `make_bottle`, for when the child streams aren't all known up
+/// front: they're taken from a stream, as they become ready.
+pub fn make_bottle_from_stream<S, A>(btype: BottleType, header: &Header, streams: S)
+  -> impl Stream<Item = Vec<Bytes>, Error = io::Error>
+  where
+    S: Stream<Item = A, Error = io::Error>,
+    A: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  make_streamed_bottle(btype, header, streams, BottleOptions::default())
+}
+
+fn make_streamed_bottle<S, A>(btype: BottleType, header: &Header, streams: S, options: BottleOptions)
+  -> impl Stream<Item = Vec<Bytes>, Error = io::Error>
+  where
+    S: Stream<Item = A, Error = io::Error>,
+    A: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  let combined = streams.map(move |s| frame_child(s, options)).flatten();
+  make_header_stream(btype, header).chain(combined).chain(make_vec_stream_1(END_OF_ALL_STREAMS_BYTES.clone()))
+}
+
+// buffer and frame one child stream, ending with END_OF_STREAM.
+pub(crate) fn frame_child<A>(s: A, options: BottleOptions) -> impl Stream<Item = Vec<Bytes>, Error = io::Error>
+  where A: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  let buffered = if options.exact_frames {
+    buffer_stream(s, options.max_frame, true)
+  } else {
+    buffer_stream(s, options.min_frame, false)
+  };
+  framed_vec_stream_with_limit(buffered, options.max_frame)
+}
+
+/// Like `make_bottle`, but report progress as it goes: bytes in are the
+/// contents of the child streams, and bytes out are the whole bottle.
+pub fn make_bottle_with_progress<I, A, P>(btype: BottleType, header: &Header, streams: I, progress: P)
+  -> impl Stream<Item = Vec<Bytes>, Error = io::Error>
+  where
+    I: IntoIterator<Item = A>,
+    A: Stream<Item = Vec<Bytes>, Error = io::Error>,
+    P: Progress + 'static
+{
+  let tracker = ProgressTracker::new(progress);
+  let streams = streams.into_iter().map({
+    let tracker = tracker.clone();
+    move |s| count_vec_in(s, Some(tracker.clone()))
+  }).collect::<Vec<_>>();
+  count_vec_out(make_bottle(btype, header, streams), Some(tracker))
+}
+
+/// Generate a bottle from a known number of streams, recording the count in
+/// the header (as `FIELD_STREAM_COUNT`) so a reader can preallocate or
+/// report progress.
+pub fn make_counted_bottle<I, A>(btype: BottleType, header: &Header, streams: I)
+  -> impl Stream<Item = Vec<Bytes>, Error = io::Error>
+  where
+    I: IntoIterator<Item = A>,
+    I::IntoIter: ExactSizeIterator,
+    A: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  let streams = streams.into_iter();
+  let mut header = header.clone();
+  header.set_number(FIELD_STREAM_COUNT, streams.len() as u64);
+  make_bottle(btype, &header, streams)
+}
 
+/// Like `make_bottle`, but up to `max_active` child streams are read at
+/// once, and their frames are interleaved as each one has data, so a slow
+/// child doesn't hold up the rest. Children are started in order, as
+/// earlier ones finish. `read_bottle` sorts them back out. `max_active` is
+/// capped at `MAX_INTERLEAVED`.
+pub fn make_interleaved_bottle<I, A>(btype: BottleType, header: &Header, streams: I, max_active: usize)
+  -> impl Stream<Item = Vec<Bytes>, Error = io::Error>
+  where
+    I: IntoIterator<Item = A>,
+    A: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  let max_active = max_active.clamp(1, MAX_INTERLEAVED);
+  let mut header = header.clone();
+  header.set_number(FIELD_INTERLEAVED, max_active as u64);
+  let options = BottleOptions::default();
+  let children = streams.into_iter().map(move |s| buffer_stream(s, options.min_frame, false)).enumerate();
+  let interleaved = Interleaved { children, active: Vec::new(), max_active, next: 0, max_frame: options.max_frame, done: false };
+  make_header_stream(btype, &header).chain(interleaved)
+}
 
-// convert a byte stream into a stream with each chunk prefixed by a length
-// marker, suitable for embedding in a bottle. (each `Vec<Bytes>` gets a new
-// initial `Bytes`.)
-pub fn framed_vec_stream<S>(s: S) -> impl Stream<Item = Vec<Bytes>, Error = io::Error>
-  where S: Stream<Item = Vec<Bytes>, Error = io::Error>
+/// Wrap an external byte source (a file, a socket, a subprocess's stdout) so
+/// it can be passed to `make_bottle` as a child stream.
+pub fn child_from_bytes<S>(s: S) -> impl Stream<Item = Vec<Bytes>, Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
 {
-  s.map(|buffers| {
-    let mut new_buffers = Vec::with_capacity(buffers.len() + 1);
-    let total_length: usize = buffers.iter().fold(0, |sum, buf| sum + buf.len());
-    new_buffers.push(Bytes::from(zint::encode_length(total_length as u32)));
-    new_buffers.extend(buffers);
-    new_buffers
-  }).chain(make_stream_1(END_OF_STREAM_BYTES.clone()))
+  buffer_stream(s.map(|b| vec![ b ]), MIN_BUFFER, false)
+}
+
+/// Build a complete bottle in memory from plain byte buffers. This is the
+/// same as draining `make_bottle`, for callers who don't want to deal with
+/// streams or `Bytes`.
+pub fn bottle_to_vec(btype: BottleType, header: &Header, streams: Vec<Vec<u8>>) -> io::Result<Vec<u8>> {
+  let streams = streams.into_iter().map(|v| make_vec_stream_1(Bytes::from(v)));
+  make_bottle(btype, header, streams).collect().wait().map(|chunks| {
+    let mut rv: Vec<u8> = Vec::new();
+    for b in chunks.into_iter().flatten() { rv.extend(b.as_ref()) };
+    rv
+  })
+}
+
+/// Parse a complete in-memory bottle into its type, header, and the
+/// unframed contents of each child stream. Nested bottles are left as raw
+/// bytes.
+pub fn bottle_from_slice(data: &[u8]) -> io::Result<(BottleType, Header, Vec<Vec<u8>>)> {
+  let ( btype, header_length ) = check_magic(data)?;
+  if data.len() < 8 + header_length { return Err(truncated_bottle_error()) }
+  let header = Header::decode(&data[8 .. 8 + header_length])?;
+  if let Some(window) = header.get_number(FIELD_INTERLEAVED) {
+    let streams = interleaved_from_slice(&data[8 + header_length ..], window, DecodeLimits::default())?;
+    return Ok(( btype, header, streams ));
+  }
+
+  let mut cursor = io::Cursor::new(&data[8 + header_length ..]);
+  let mut streams: Vec<Vec<u8>> = Vec::new();
+  let mut current: Option<Vec<u8>> = None;
+  loop {
+    let length = zint::decode_length(&mut cursor)?;
+    match ( length, current.take() ) {
+      ( zint::END_OF_ALL_STREAMS, None ) => return Ok(( btype, header, streams )),
+      ( zint::END_OF_ALL_STREAMS, Some(_) ) => return Err(truncated_bottle_error()),
+      ( zint::END_OF_STREAM, stream ) => streams.push(stream.unwrap_or_default()),
+      ( n, stream ) => {
+        let mut stream = stream.unwrap_or_default();
+        let start = cursor.position() as usize;
+        let end = start + n as usize;
+        if end > cursor.get_ref().len() { return Err(truncated_bottle_error()) }
+        stream.extend(&cursor.get_ref()[start .. end]);
+        cursor.set_position(end as u64);
+        current = Some(stream);
+      }
+    }
+  }
+}
+
+// each stream, by id, with whether it's ended. ids may skip ahead, but no
+// further than the window.
+fn interleaved_from_slice(data: &[u8], window: u64, limits: DecodeLimits) -> io::Result<Vec<Vec<u8>>> {
+  let window = check_window(window, limits)?;
+  let mut cursor = io::Cursor::new(data);
+  let mut streams: Vec<( Vec<u8>, bool )> = Vec::new();
+  loop {
+    let id = zint::decode_length(&mut cursor)?;
+    if id == zint::END_OF_ALL_STREAMS {
+      if streams.iter().any(|&( _, ended )| !ended) { return Err(truncated_bottle_error()) }
+      return Ok(streams.into_iter().map(|( stream, _ )| stream).collect());
+    }
+    let id = id as usize;
+    if id >= streams.len() {
+      if id >= streams.len() + window { return Err(bad_stream_id_error(id)) }
+      streams.resize(id + 1, ( Vec::new(), false ));
+    }
+    if streams[id].1 { return Err(bad_stream_id_error(id)) }
+    match zint::decode_length(&mut cursor)? {
+      zint::END_OF_STREAM => streams[id].1 = true,
+      zint::END_OF_ALL_STREAMS => return Err(unexpected_end_error()),
+      n => {
+        let start = cursor.position() as usize;
+        let end = start + n as usize;
+        if end > data.len() { return Err(truncated_bottle_error()) }
+        streams[id].0.extend_from_slice(&data[start .. end]);
+        cursor.set_position(end as u64);
+      }
+    }
+  }
 }
 
 
 // ----- header
 
-// generate a stream that's just a bottle header (magic + header data).
+// generate a stream that's just a bottle header (magic + header data). if
+// the header is too big to encode, the stream is just that error, so a
+// writer fails before sending anything.
 pub fn make_header_stream(btype: BottleType, header: &Header) -> impl Stream<Item = Vec<Bytes>, Error = io::Error> {
+  stream::once(encode_bottle_header(btype, header).map(|buffer| vec![ Bytes::from(buffer) ]))
+}
+
+// magic, version, type, and header length, followed by the header itself.
+pub(crate) fn encode_bottle_header(btype: BottleType, header: &Header) -> io::Result<Vec<u8>> {
   let header_bytes = header.encode();
-  assert!(header_bytes.len() <= MAX_HEADER_SIZE);
-  let version: [u8; 4] = [
+  if header_bytes.len() > MAX_HEADER_SIZE { return Err(header_too_large_error(header_bytes.len())) }
+  let mut buffer = Vec::with_capacity(8 + header_bytes.len());
+  buffer.extend_from_slice(&MAGIC);
+  buffer.extend_from_slice(&[
     VERSION,
     0,
     ((btype as u8) << 4) | ((header_bytes.len() >> 8) & 0xf) as u8,
     (header_bytes.len() & 0xff) as u8
-  ];
-  make_stream(vec![ Bytes::from_static(&MAGIC), Bytes::from(&version[..]), Bytes::from(header_bytes) ])
+  ]);
+  buffer.extend(header_bytes);
+  Ok(buffer)
 }
 
 pub fn read_header<S>(s: S)
   -> impl Future<Item = (BottleType, Header, impl Stream<Item = Bytes, Error = io::Error>), Error = io::Error>
   where S: Stream<Item = Bytes, Error = io::Error>
 {
-  stream_read_exact(s, 8).and_then(|( buffers, s )| {
-    future::result(check_magic(flatten_bytes(buffers))).and_then(|( btype, header_length )| {
-      stream_read_exact(s, header_length).and_then(|( buffers, s )| {
-        future::result(Header::decode(flatten_bytes(buffers).as_ref())).map(|header| {
-          ( btype, header, s )
-        })
+  read_header_with_options(s, ReadOptions::default()).map(|( btype, header, _, s )| ( btype, header, s ))
+}
+
+// also returns the header's length, so the caller knows where it ended.
+fn read_header_with_options<S>(s: S, options: ReadOptions)
+  -> impl Future<Item = (BottleType, Header, usize, impl Stream<Item = Bytes, Error = io::Error>), Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  StreamReader::read_at_most(s, 8).and_then(move |( frame, s )| {
+    let cap = flatten_bytes(frame.vec);
+    let checked = if cap.len() < 8 { Err(truncated_header_error(options, cap.len())) } else { check_magic(&cap) };
+    let checked = checked.and_then(|( btype, header_length )| {
+      if header_length > options.limits.max_header_size {
+        return Err(decode_limit_error(DecodeLimit::HeaderSize, header_length as u64, options.limits.max_header_size as u64));
+      }
+      Ok(( btype, header_length ))
+    });
+    future::result(checked).and_then(move |( btype, header_length )| {
+      StreamReader::read_at_most(s, header_length).and_then(move |( frame, s )| {
+        let buffer = flatten_bytes(frame.vec);
+        if buffer.len() < header_length { return Err(truncated_header_error(options, 8 + buffer.len())) }
+        Header::decode(buffer.as_ref()).map(move |header| ( btype, header, header_length, s ))
       })
     })
   })
 }
 
-fn check_magic(buffer: Bytes) -> Result<(BottleType, usize), io::Error> {
-  if buffer.slice(0, 4) != &MAGIC[..] {
-    return Err(bad_magic_error());
+/// Read a bottle: the header, and then a stream of its child streams, each
+/// of which is a `Stream<Item = Bytes>` of the unframed data.
+///
+/// Child streams share the underlying stream, so they must be read in
+/// order. Asking for the next child before the current one is finished
+/// skips (and discards) the rest of the current one, and a skipped child
+/// will just end.
+pub fn read_bottle<S>(s: S)
+  -> impl Future<Item = (BottleType, Header, ChildStreams<impl Stream<Item = Bytes, Error = io::Error>>), Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  read_bottle_with_options(s, ReadOptions::default())
+}
+
+/// Like `read_bottle`, with a choice of how to handle a damaged source.
+pub fn read_bottle_with_options<S>(s: S, options: ReadOptions)
+  -> impl Future<Item = (BottleType, Header, ChildStreams<impl Stream<Item = Bytes, Error = io::Error>>), Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  read_header_with_options(s, options).and_then(move |( btype, header, header_length, s )| {
+    let mut state = ReaderState::new(s, 8 + header_length as u64, options);
+    if let Some(window) = header.get_number(FIELD_INTERLEAVED) {
+      state.demux = Some(Demux::new(check_window(window, options.limits)?, options.limits));
+    }
+    Ok(( btype, header, ChildStreams { state: Rc::new(RefCell::new(state)) } ))
+  })
+}
+
+/// Quick check for whether a buffer starts with the 4bottle magic. Buffers
+/// shorter than the magic are never bottles.
+pub fn peek_is_bottle(first_bytes: &[u8]) -> bool {
+  first_bytes.len() >= MAGIC.len() && first_bytes[0 .. MAGIC.len()] == MAGIC[..]
+}
+
+/// Check whether a stream starts with the 4bottle magic, without consuming
+/// it: the bytes read are pushed back onto the front of the returned stream.
+pub fn peek_is_bottle_stream<S>(s: S)
+  -> impl Future<Item = (bool, impl Stream<Item = Bytes, Error = io::Error>), Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  StreamReader::peek(s, MAGIC.len()).map(|( frame, s )| {
+    ( peek_is_bottle(flatten_bytes(frame.vec).as_ref()), s )
+  })
+}
+
+/// Read a bottle's type without consuming anything, like
+/// `peek_is_bottle_stream`. Fails if the stream isn't a bottle.
+pub fn peek_bottle_type<S>(s: S)
+  -> impl Future<Item = (BottleType, impl Stream<Item = Bytes, Error = io::Error>), Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  StreamReader::read_exact(s, 8).and_then(|( frame, s )| {
+    let ( btype, _ ) = check_magic(&flatten_bytes(frame.vec.clone()))?;
+    Ok(( btype, stream::iter_ok(frame.vec).chain(s) ))
+  })
+}
+
+/// Parse the 8 bytes at the start of a bottle (magic, version, type, and
+/// header length) into the bottle type and header length.
+pub fn parse_bottle_cap(cap: &[u8; 8]) -> Result<(BottleType, usize), BottleError> {
+  if cap[0 .. 4] != MAGIC[..] { return Err(BottleError::BadMagic) }
+  if cap[4] != VERSION || cap[5] != 0 { return Err(BottleError::BadVersion { found: cap[4], extra: cap[5] }) }
+  let n = cap[6] >> 4;
+  let btype = decode_bottle_type(n).map_err(|_| BottleError::UnknownType(n))?;
+  let header_length = (((cap[6] & 0xf) as usize) << 8) + (cap[7] as usize);
+  Ok(( btype, header_length ))
+}
+
+// `parse_bottle_cap` for any buffer, which is only a bottle if it's long
+// enough.
+pub(crate) fn check_magic(buffer: &[u8]) -> Result<(BottleType, usize), io::Error> {
+  let mut cap = [ 0u8; 8 ];
+  if buffer.len() < cap.len() { return Err(truncated_bottle_error()) }
+  cap.copy_from_slice(&buffer[0 .. 8]);
+  Ok(parse_bottle_cap(&cap)?)
+}
+
+
+// ----- reader
+
+#[derive(Clone, Copy, PartialEq)]
+enum ReaderMode {
+  // waiting for a new child stream, or the end of all streams
+  BetweenStreams,
+  // inside a child stream, waiting for a frame length
+  InStream,
+  // inside a frame, with this many bytes left
+  InFrame(usize),
+  // saw the end of all streams
+  Done
+}
+
+struct ReaderState<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  frames: FrameReader<S>,
+  mode: ReaderMode,
+  // which child stream is currently being read
+  child_id: usize,
+  options: ReadOptions,
+  // for an interleaved bottle, the child streams read so far
+  demux: Option<Demux>
+}
+
+impl<S> ReaderState<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  // `start` is where the child streams start in the source.
+  fn new(s: S, start: u64, options: ReadOptions) -> ReaderState<S> {
+    let mut frames = FrameReader::new(s);
+    frames.position = start;
+    ReaderState { frames, mode: ReaderMode::BetweenStreams, child_id: 0, options, demux: None }
+  }
+
+  // next chunk of data for the current child stream, or `None` at its end.
+  fn poll_child(&mut self) -> Poll<Option<Bytes>, io::Error> {
+    match self.poll_frames() {
+      Err(e) => Err(self.truncated(e)),
+      rv => rv
+    }
+  }
+
+  fn poll_length(&mut self) -> Poll<u32, io::Error> {
+    let length = match self.frames.poll_length() {
+      Err(e) => return Err(self.truncated(e)),
+      Ok(Async::NotReady) => return Ok(Async::NotReady),
+      Ok(Async::Ready(length)) => length
+    };
+    let max = self.options.limits.max_frame_size;
+    if length != zint::END_OF_STREAM && length != zint::END_OF_ALL_STREAMS && length as usize > max {
+      return Err(decode_limit_error(DecodeLimit::FrameSize, length as u64, max as u64));
+    }
+    Ok(Async::Ready(length))
+  }
+
+  // when tolerating truncation, say where it happened, and end everything.
+  fn truncated(&mut self, e: io::Error) -> io::Error {
+    let is_truncation = matches!(BottleError::find(&e), Some(&BottleError::TruncatedStream) | Some(&BottleError::TruncatedLength { .. }));
+    if !self.options.tolerate_truncation || !is_truncation { return e }
+    let context = match self.mode {
+      ReaderMode::BetweenStreams | ReaderMode::Done => TruncationContext::BetweenStreams,
+      ReaderMode::InStream => TruncationContext::FrameLength { child: self.child_id },
+      ReaderMode::InFrame(_) => TruncationContext::FrameData { child: self.child_id }
+    };
+    self.mode = ReaderMode::Done;
+    if let Some(ref mut demux) = self.demux { demux.mode = DemuxMode::Done }
+    BottleError::TruncatedAt { offset: self.frames.position, context }.into()
   }
-  if buffer[4] != VERSION || buffer[5] != 0 {
-    return Err(bad_version_error(buffer[4], buffer[5]));
+
+  fn poll_frames(&mut self) -> Poll<Option<Bytes>, io::Error> {
+    loop {
+      match self.mode {
+        ReaderMode::BetweenStreams | ReaderMode::Done => return Ok(Async::Ready(None)),
+        ReaderMode::InStream => {
+          match try_ready!(self.poll_length()) {
+            zint::END_OF_STREAM => {
+              self.mode = ReaderMode::BetweenStreams;
+              self.child_id += 1;
+              return Ok(Async::Ready(None));
+            }
+            zint::END_OF_ALL_STREAMS => return Err(unexpected_end_error()),
+            length => self.mode = ReaderMode::InFrame(length as usize)
+          }
+        }
+        ReaderMode::InFrame(remaining) => {
+          match try_ready!(self.frames.poll_bytes(remaining)) {
+            None => return Err(truncated_bottle_error()),
+            Some(b) => {
+              self.mode = if b.len() == remaining { ReaderMode::InStream } else { ReaderMode::InFrame(remaining - b.len()) };
+              return Ok(Async::Ready(Some(b)));
+            }
+          }
+        }
+      }
+    }
   }
-  let btype = decode_bottle_type((buffer[6] >> 4) & 0xf)?;
-  let header_length = ((buffer[6] & 0xf) as usize) << 8 + (buffer[7] as usize);
-  Ok((btype, header_length))
 }
 
+/// Stream of the child streams of a bottle, from `read_bottle`.
+#[must_use = "streams do nothing unless polled"]
+pub struct ChildStreams<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  state: Rc<RefCell<ReaderState<S>>>
+}
+
+impl<S> Stream for ChildStreams<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  type Item = ChildStream<S>;
+  type Error = io::Error;
 
-// ----- errors
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    let mut state = self.state.borrow_mut();
+    if state.demux.is_some() {
+      let id = try_ready!(state.poll_next_interleaved());
+      return Ok(Async::Ready(id.map(|id| ChildStream { id, state: self.state.clone() })));
+    }
+    loop {
+      match state.mode {
+        ReaderMode::Done => return Ok(Async::Ready(None)),
+        // skip whatever's left of the previous child.
+        ReaderMode::InStream | ReaderMode::InFrame(_) => {
+          try_ready!(state.poll_child());
+        }
+        ReaderMode::BetweenStreams => {
+          let id = state.child_id;
+          match try_ready!(state.poll_length()) {
+            zint::END_OF_ALL_STREAMS => {
+              state.mode = ReaderMode::Done;
+              return Ok(Async::Ready(None));
+            }
+            // empty stream: it's already over.
+            zint::END_OF_STREAM => state.child_id += 1,
+            length => state.mode = ReaderMode::InFrame(length as usize)
+          }
+          return Ok(Async::Ready(Some(ChildStream { id, state: self.state.clone() })));
+        }
+      }
+    }
+  }
+}
+
+/// One child stream of a bottle, with the framing removed.
+#[must_use = "streams do nothing unless polled"]
+pub struct ChildStream<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  id: usize,
+  state: Rc<RefCell<ReaderState<S>>>
+}
+
+impl<S> Stream for ChildStream<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  type Item = Bytes;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    let mut state = self.state.borrow_mut();
+    if state.demux.is_some() { return state.poll_interleaved(self.id) }
+    if state.child_id != self.id { return Ok(Async::Ready(None)) }
+    state.poll_child()
+  }
+}
+
+
+// ----- interleaved
+
+// writes the frames of several child streams as they're ready, round robin.
+struct Interleaved<I, B> {
+  children: I,
+  active: Vec<( usize, B )>,
+  max_active: usize,
+  // where to start looking next time, so no child gets starved
+  next: usize,
+  max_frame: usize,
+  done: bool
+}
+
+impl<I, B> Stream for Interleaved<I, B>
+  where
+    I: Iterator<Item = ( usize, B )>,
+    B: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  type Item = Vec<Bytes>;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    if self.done { return Ok(Async::Ready(None)) }
+    while self.active.len() < self.max_active {
+      match self.children.next() {
+        Some(child) => self.active.push(child),
+        None => break
+      }
+    }
+    if self.active.is_empty() {
+      self.done = true;
+      return Ok(Async::Ready(Some(vec![ END_OF_ALL_STREAMS_BYTES.clone() ])));
+    }
+    for i in 0 .. self.active.len() {
+      let index = (self.next + i) % self.active.len();
+      let id = self.active[index].0;
+      let id_bytes = Bytes::from(&zint::encode_length_to_bytes(id as u64)?[..]);
+      match self.active[index].1.poll()? {
+        Async::NotReady => (),
+        Async::Ready(Some(buffers)) => {
+          self.next = index + 1;
+          let mut frames = Vec::new();
+          for frame in split_frames(buffers, self.max_frame) {
+            frames.push(id_bytes.clone());
+            frames.extend(frame);
+          }
+          return Ok(Async::Ready(Some(frames)));
+        }
+        Async::Ready(None) => {
+          self.active.remove(index);
+          self.next = index;
+          return Ok(Async::Ready(Some(vec![ id_bytes, zint::encode_length_bytes(zint::END_OF_STREAM) ])));
+        }
+      }
+    }
+    Ok(Async::NotReady)
+  }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DemuxMode {
+  // waiting for a stream id, or the end of all streams
+  Id,
+  // waiting for the length of a frame for this stream
+  Length(usize),
+  // inside a frame for this stream, with this many bytes left
+  Frame(usize, usize),
+  Done
+}
+
+#[derive(Default)]
+struct ChildQueue {
+  data: VecDeque<Bytes>,
+  ended: bool,
+  // handed out and then passed over: anything more for it is dropped.
+  skipped: bool
+}
+
+// the writer's promise of how many streams it'll have open at once.
+fn check_window(window: u64, limits: DecodeLimits) -> io::Result<usize> {
+  if window == 0 || window > limits.max_open_streams as u64 {
+    return Err(decode_limit_error(DecodeLimit::OpenStreams, window, limits.max_open_streams as u64));
+  }
+  Ok(window as usize)
+}
 
-fn bad_magic_error() -> io::Error {
-  io::Error::new(io::ErrorKind::InvalidInput, "Incorrect magic (not a 4bottle archive)")
+// frames that arrived for a child stream before it was read are queued.
+struct Demux {
+  mode: DemuxMode,
+  // most streams the writer said it would have open at once
+  window: usize,
+  limits: DecodeLimits,
+  // streams that are still being read, by id
+  live: HashMap<usize, ChildQueue>,
+  // every id below this has appeared
+  seen: usize,
+  // the next child for `ChildStreams` to hand out
+  next_child: usize,
+  buffered: u64
 }
 
-fn bad_version_error(version: u8, extra: u8) -> io::Error {
-  io::Error::new(io::ErrorKind::InvalidInput, format!("Incompatible version: {}, {}", version, extra))
+impl Demux {
+  fn new(window: usize, limits: DecodeLimits) -> Demux {
+    Demux { mode: DemuxMode::Id, window, limits, live: HashMap::new(), seen: 0, next_child: 0, buffered: 0 }
+  }
+
+  // a frame (or the end) for this stream is next. ids may appear out of
+  // order, but never more open at once than the window. streams that have
+  // ended but haven't been read count against the limit too.
+  fn open(&mut self, id: usize) -> io::Result<()> {
+    if id < self.seen {
+      return match self.live.get(&id) {
+        Some(queue) if !queue.ended => Ok(()),
+        _ => Err(bad_stream_id_error(id))
+      };
+    }
+    if id >= self.seen + self.window { return Err(bad_stream_id_error(id)) }
+    self.limits.check_open_streams(self.live.len() + id + 1 - self.seen)?;
+    let open = self.live.values().filter(|queue| !queue.ended).count();
+    if open + (id + 1 - self.seen) > self.window { return Err(bad_stream_id_error(id)) }
+    for new_id in self.seen ..= id { self.live.insert(new_id, ChildQueue::default()); }
+    self.seen = id + 1;
+    Ok(())
+  }
+
+  // drop whatever's left of a child that's been passed over.
+  fn skip(&mut self, id: usize) {
+    let ended = match self.live.get_mut(&id) {
+      None => return,
+      Some(queue) => {
+        self.buffered -= queue.data.iter().map(|b| b.len() as u64).sum::<u64>();
+        queue.data.clear();
+        queue.skipped = true;
+        queue.ended
+      }
+    };
+    if ended { self.live.remove(&id); }
+  }
+}
+
+impl<S> ReaderState<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  fn demux(&mut self) -> &mut Demux {
+    self.demux.as_mut().unwrap()
+  }
+
+  // the next piece of any child stream: some data, or `None` for its end.
+  // `None` overall is the end of all streams.
+  fn poll_demux(&mut self) -> Poll<Option<( usize, Option<Bytes> )>, io::Error> {
+    loop {
+      match self.demux().mode {
+        DemuxMode::Done => return Ok(Async::Ready(None)),
+        DemuxMode::Id => {
+          let id = match self.frames.poll_length() {
+            Err(e) => return Err(self.truncated(e)),
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(id)) => id
+          };
+          let demux = self.demux();
+          if id == zint::END_OF_ALL_STREAMS {
+            if demux.live.values().any(|queue| !queue.ended) { return Err(unexpected_end_error()) }
+            demux.mode = DemuxMode::Done;
+            return Ok(Async::Ready(None));
+          }
+          demux.open(id as usize)?;
+          demux.mode = DemuxMode::Length(id as usize);
+        }
+        DemuxMode::Length(id) => {
+          match try_ready!(self.poll_length()) {
+            zint::END_OF_STREAM => {
+              self.demux().mode = DemuxMode::Id;
+              return Ok(Async::Ready(Some(( id, None ))));
+            }
+            zint::END_OF_ALL_STREAMS => return Err(unexpected_end_error()),
+            length => self.demux().mode = DemuxMode::Frame(id, length as usize)
+          }
+        }
+        DemuxMode::Frame(id, remaining) => {
+          let b = match self.frames.poll_bytes(remaining) {
+            Err(e) => return Err(self.truncated(e)),
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(None)) => return Err(self.truncated(truncated_bottle_error())),
+            Ok(Async::Ready(Some(b))) => b
+          };
+          self.demux().mode = if b.len() == remaining { DemuxMode::Id } else { DemuxMode::Frame(id, remaining - b.len()) };
+          return Ok(Async::Ready(Some(( id, Some(b) ))));
+        }
+      }
+    }
+  }
+
+  // queue a piece of a child stream that isn't being read right now.
+  fn queue(&mut self, id: usize, piece: Option<Bytes>) -> io::Result<()> {
+    let limits = self.options.limits;
+    let demux = self.demux();
+    let ended = match demux.live.get_mut(&id) {
+      None => return Ok(()),
+      Some(queue) => match piece {
+        None => {
+          queue.ended = true;
+          queue.skipped
+        }
+        Some(_) if queue.skipped => false,
+        Some(b) => {
+          demux.buffered += b.len() as u64;
+          queue.data.push_back(b);
+          false
+        }
+      }
+    };
+    if ended { demux.live.remove(&id); }
+    limits.check_buffered(demux.buffered)
+  }
+
+  // next chunk of data for one child stream, or `None` at its end.
+  fn poll_interleaved(&mut self, id: usize) -> Poll<Option<Bytes>, io::Error> {
+    loop {
+      {
+        let demux = self.demux();
+        let over = match demux.live.get_mut(&id) {
+          None => return Ok(Async::Ready(None)),
+          Some(queue) => {
+            if let Some(b) = queue.data.pop_front() {
+              demux.buffered -= b.len() as u64;
+              return Ok(Async::Ready(Some(b)));
+            }
+            if queue.skipped { return Ok(Async::Ready(None)) }
+            queue.ended
+          }
+        };
+        if over {
+          demux.live.remove(&id);
+          return Ok(Async::Ready(None));
+        }
+      }
+      match try_ready!(self.poll_demux()) {
+        None => return Ok(Async::Ready(None)),
+        Some(( piece_id, Some(b) )) if piece_id == id => return Ok(Async::Ready(Some(b))),
+        Some(( piece_id, piece )) => self.queue(piece_id, piece)?
+      }
+    }
+  }
+
+  // the id of the next child stream, once it's appeared. the one before it
+  // is passed over.
+  fn poll_next_interleaved(&mut self) -> Poll<Option<usize>, io::Error> {
+    loop {
+      let demux = self.demux();
+      let id = demux.next_child;
+      if id < demux.seen {
+        if id > 0 { demux.skip(id - 1) }
+        demux.next_child += 1;
+        return Ok(Async::Ready(Some(id)));
+      }
+      match try_ready!(self.poll_demux()) {
+        None => {
+          let demux = self.demux();
+          if demux.next_child > 0 { let last = demux.next_child - 1; demux.skip(last) }
+          return Ok(Async::Ready(None));
+        }
+        Some(( piece_id, piece )) => self.queue(piece_id, piece)?
+      }
+    }
+  }
 }
 
+
+// ----- errors
+
 fn unknown_bottle_type_error(btype: u8) -> io::Error {
-  io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown bottle type: {}", btype))
+  BottleError::UnknownType(btype).into()
+}
+
+fn truncated_header_error(options: ReadOptions, offset: usize) -> io::Error {
+  if !options.tolerate_truncation { return truncated_bottle_error() }
+  BottleError::TruncatedAt { offset: offset as u64, context: TruncationContext::Header }.into()
+}
+
+fn bad_stream_id_error(id: usize) -> io::Error {
+  BottleError::BadStreamId(id as u64).into()
+}
+
+fn header_too_large_error(size: usize) -> io::Error {
+  BottleError::HeaderTooLarge(size).into()
+}
+
+fn invalid_frame_size_error(min: usize, max: usize) -> io::Error {
+  BottleError::InvalidFrameSize { min, max }.into()
 }
 
+pub(crate) fn decode_limit_error(limit: DecodeLimit, size: u64, max: u64) -> io::Error {
+  BottleError::DecodeLimitExceeded { limit, size, max }.into()
+}
+
+
 
 
 /*
@@ -151,16 +958,6 @@ fn unknown_bottle_type_error(btype: u8) -> io::Error {
  */
 
 
-// impl futures::Sink for BottleSink {
-//   type SinkItem = futures::stream::BoxStream<Vec<u8>, io::Error>;
-//   type SinkError = io::Error;
-//
-//   pub start_send(&mut self, item: Self::SinkItem) {
-//   }
-// }
-
-
-
 // import { bufferStream, compoundStream, PullTransform, sourceStream, Transform, weld } from "stream-toolkit";
 // import { packHeader, unpackHeader } from "./bottle_header";
 // import { framingStream, unframingStream } from "./framed_stream";
@@ -0,0 +1,140 @@
+use bytes::Bytes;
+use futures::{Async, Poll, Stream};
+use std::io;
+
+use bottle::{BottleType, FIELD_INTERLEAVED, check_magic};
+use bottle_header::{Header};
+use error::BottleError;
+use framed_stream::{FrameReader, truncated_error, unexpected_end_error};
+use zint;
+
+/// One step in reading a bottle, from `decode_events`.
+#[derive(Clone, Debug)]
+pub enum BottleEvent {
+  /// the bottle's type and header, always first
+  Header(BottleType, Header),
+  /// a child stream begins
+  StreamStart,
+  /// the next piece of the current child stream, with the framing removed
+  Data(Bytes),
+  /// the current child stream is over
+  StreamEnd,
+  /// there are no more child streams, and this is the last event
+  BottleEnd
+}
+
+/// Read a bottle as a flat stream of events: the header, then each child
+/// stream's start, data, and end, then the end of the bottle. Nothing is
+/// buffered beyond what the source hands over, and nested bottles aren't
+/// opened: their data arrives as `Data` like any other child stream.
+///
+/// The stream ends after `BottleEnd`, and anything after the bottle is left
+/// unread, for `into_remainder`. Interleaved child streams aren't sorted
+/// out: a bottle with `FIELD_INTERLEAVED` fails with
+/// `BottleError::Interleaved` instead of sending its header.
+pub fn decode_events<S>(s: S) -> BottleEvents<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  BottleEvents { frames: FrameReader::new(s), state: State::Cap, buffer: Vec::new() }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+  // reading the first 8 bytes
+  Cap,
+  // reading a header of this many bytes
+  Header(BottleType, usize),
+  BetweenStreams,
+  InStream,
+  // a child stream with no data: its start has been sent, so now its end
+  EmptyStream,
+  // inside a frame, with this many bytes left
+  InFrame(usize),
+  Done
+}
+
+/// Stream of events from `decode_events`.
+#[must_use = "streams do nothing unless polled"]
+pub struct BottleEvents<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  frames: FrameReader<S>,
+  state: State,
+  // partially-read cap or header
+  buffer: Vec<u8>
+}
+
+impl<S> BottleEvents<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  /// How many bytes of the source have been read so far.
+  pub fn position(&self) -> u64 {
+    self.frames.position
+  }
+
+  /// The rest of the source stream. If the bottle isn't over yet, the
+  /// remainder starts in the middle of it.
+  pub fn into_remainder(self) -> impl Stream<Item = Bytes, Error = io::Error> {
+    self.frames.into_remainder()
+  }
+
+  // fill `buffer` up to `size` bytes, failing if the source ends first.
+  fn poll_buffer(&mut self, size: usize) -> Poll<(), io::Error> {
+    while self.buffer.len() < size {
+      let b = try_ready!(self.frames.poll_bytes(size - self.buffer.len())).ok_or_else(truncated_error)?;
+      self.buffer.extend_from_slice(&b);
+    }
+    Ok(Async::Ready(()))
+  }
+}
+
+impl<S> Stream for BottleEvents<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  type Item = BottleEvent;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    loop {
+      match self.state {
+        State::Cap => {
+          try_ready!(self.poll_buffer(8));
+          let ( btype, header_length ) = check_magic(&self.buffer)?;
+          self.buffer.clear();
+          self.state = State::Header(btype, header_length);
+        }
+        State::Header(btype, header_length) => {
+          try_ready!(self.poll_buffer(header_length));
+          let header = Header::decode(&self.buffer)?;
+          if header.get_number(FIELD_INTERLEAVED).is_some() { return Err(BottleError::Interleaved.into()) }
+          self.buffer = Vec::new();
+          self.state = State::BetweenStreams;
+          return Ok(Async::Ready(Some(BottleEvent::Header(btype, header))));
+        }
+        State::BetweenStreams => {
+          match try_ready!(self.frames.poll_length()) {
+            zint::END_OF_ALL_STREAMS => {
+              self.state = State::Done;
+              return Ok(Async::Ready(Some(BottleEvent::BottleEnd)));
+            }
+            zint::END_OF_STREAM => self.state = State::EmptyStream,
+            length => self.state = State::InFrame(length as usize)
+          }
+          return Ok(Async::Ready(Some(BottleEvent::StreamStart)));
+        }
+        State::InStream => {
+          match try_ready!(self.frames.poll_length()) {
+            zint::END_OF_STREAM => {
+              self.state = State::BetweenStreams;
+              return Ok(Async::Ready(Some(BottleEvent::StreamEnd)));
+            }
+            zint::END_OF_ALL_STREAMS => return Err(unexpected_end_error()),
+            length => self.state = State::InFrame(length as usize)
+          }
+        }
+        State::EmptyStream => {
+          self.state = State::BetweenStreams;
+          return Ok(Async::Ready(Some(BottleEvent::StreamEnd)));
+        }
+        State::InFrame(remaining) => {
+          let b = try_ready!(self.frames.poll_bytes(remaining)).ok_or_else(truncated_error)?;
+          self.state = if b.len() == remaining { State::InStream } else { State::InFrame(remaining - b.len()) };
+          return Ok(Async::Ready(Some(BottleEvent::Data(b))));
+        }
+        State::Done => return Ok(Async::Ready(None))
+      }
+    }
+  }
+}
@@ -1,23 +1,55 @@
-use std::error::Error;
 use std::fmt;
 use std::io;
 use std::str;
+
+use error::BottleError;
+use spec::{FIELD_KIND_BOOLEAN as KIND_BOOLEAN, FIELD_KIND_NUMBER as KIND_NUMBER, FIELD_KIND_STRING as KIND_STRING, MAX_FIELD_ID, MAX_HEADER_SIZE};
 use zint;
 
-const KIND_BOOLEAN: u8 = 3;
-const KIND_NUMBER: u8 = 2;
-const KIND_STRING: u8 = 0;
+// a header can be up to 4KB, which is enough room for 2000 empty fields.
+// nothing legitimate needs more than a few dozen.
+pub const MAX_FIELDS: usize = 256;
+
+pub use spec::MAX_FIELD_LENGTH;
 
+/// With the `serde` feature, a header is a list of fields, each with an
+/// `id` and a `value` of `"Boolean"`, `{ "Number": n }`, or
+/// `{ "String": s }`.
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "HeaderFields"))]
 pub struct Header {
   fields: Vec<Field>
 }
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 enum FieldValue {
   Boolean,
   Number(u64),
   String(String)
 }
 
+impl FieldValue {
+  fn kind(&self) -> u8 {
+    match *self {
+      FieldValue::Boolean => KIND_BOOLEAN,
+      FieldValue::Number(_) => KIND_NUMBER,
+      FieldValue::String(_) => KIND_STRING
+    }
+  }
+
+  fn content_length(&self) -> usize {
+    match *self {
+      FieldValue::Boolean => 0,
+      FieldValue::Number(value) => zint::packed_int_bytes(value),
+      FieldValue::String(ref value) => value.len()
+    }
+  }
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 struct Field {
   id: u8,
   value: FieldValue,
@@ -29,34 +61,150 @@ impl Header {
   }
 
   pub fn add_bool(&mut self, id: u8) {
-    assert!(id <= 15);
-    self.fields.push(Field { id: id, value: FieldValue::Boolean });
+    assert!(id <= MAX_FIELD_ID);
+    self.fields.push(Field { id, value: FieldValue::Boolean });
   }
 
   pub fn add_number(&mut self, id: u8, value: u64) {
-    assert!(id <= 15);
-    self.fields.push(Field { id: id, value: FieldValue::Number(value) });
+    assert!(id <= MAX_FIELD_ID);
+    self.fields.push(Field { id, value: FieldValue::Number(value) });
+  }
+
+  pub fn add_string<S: Into<String>>(&mut self, id: u8, value: S) {
+    let value = value.into();
+    assert!(id <= MAX_FIELD_ID);
+    assert!(value.len() <= MAX_FIELD_LENGTH);
+    self.fields.push(Field { id, value: FieldValue::String(value) });
+  }
+
+  /// Like `add_bool`, but fails with `HeaderFull` (calling the field
+  /// `name`) if there's no room left in the header.
+  pub fn try_add_bool(&mut self, id: u8, name: &str) -> io::Result<()> {
+    self.try_add(id, name, FieldValue::Boolean)
+  }
+
+  /// Like `add_number`, but fails with `HeaderFull` (calling the field
+  /// `name`) if there's no room left in the header.
+  pub fn try_add_number(&mut self, id: u8, name: &str, value: u64) -> io::Result<()> {
+    self.try_add(id, name, FieldValue::Number(value))
+  }
+
+  /// Like `add_string`, but fails with `HeaderFull` (calling the field
+  /// `name`) if the value is too long for a field, or for the room left
+  /// in the header, instead of panicking or failing later, at encoding.
+  pub fn try_add_string<S: Into<String>>(&mut self, id: u8, name: &str, value: S) -> io::Result<()> {
+    self.try_add(id, name, FieldValue::String(value.into()))
+  }
+
+  fn try_add(&mut self, id: u8, name: &str, value: FieldValue) -> io::Result<()> {
+    assert!(id <= MAX_FIELD_ID);
+    let size = 2 + value.content_length();
+    let remaining = self.remaining();
+    if size > remaining || value.content_length() > MAX_FIELD_LENGTH {
+      return Err(header_full_error(name, size, remaining));
+    }
+    self.fields.push(Field { id, value });
+    Ok(())
+  }
+
+  /// How many bytes this header takes, encoded.
+  pub fn encoded_size(&self) -> usize {
+    self.fields.iter().map(|f| 2 + f.value.content_length()).sum()
+  }
+
+  /// How many more bytes of fields will fit, under `MAX_HEADER_SIZE`. A
+  /// field takes 2 bytes, plus its content.
+  pub fn remaining(&self) -> usize {
+    MAX_HEADER_SIZE.saturating_sub(self.encoded_size())
+  }
+
+  /// Return the value of the first number field with this id, if any.
+  pub fn get_number(&self, id: u8) -> Option<u64> {
+    self.fields.iter().filter(|f| f.id == id).filter_map(|f| match f.value {
+      FieldValue::Number(value) => Some(value),
+      _ => None
+    }).next()
+  }
+
+  /// Return the value of the first string field with this id, if any.
+  pub fn get_string(&self, id: u8) -> Option<&str> {
+    self.fields.iter().filter(|f| f.id == id).filter_map(|f| match f.value {
+      FieldValue::String(ref value) => Some(value.as_ref()),
+      _ => None
+    }).next()
+  }
+
+  /// Return the values of every string field with this id, in order.
+  pub fn get_strings(&self, id: u8) -> Vec<&str> {
+    self.fields.iter().filter(|f| f.id == id).filter_map(|f| match f.value {
+      FieldValue::String(ref value) => Some(value.as_ref()),
+      _ => None
+    }).collect()
+  }
+
+  /// Return true if there's a boolean field with this id.
+  pub fn get_bool(&self, id: u8) -> bool {
+    self.fields.iter().any(|f| f.id == id && f.value.kind() == KIND_BOOLEAN)
+  }
+
+  /// Replace any boolean fields with this id by a single one, or add it.
+  pub fn set_bool(&mut self, id: u8) {
+    self.set(id, FieldValue::Boolean);
+  }
+
+  /// Replace any number fields with this id by a single one with the new
+  /// value, or add it.
+  pub fn set_number(&mut self, id: u8, value: u64) {
+    self.set(id, FieldValue::Number(value));
   }
 
-  pub fn add_string(&mut self, id: u8, value: String) {
-    assert!(id <= 15);
-    self.fields.push(Field { id: id, value: FieldValue::String(value) });
+  /// Replace any string fields with this id by a single one with the new
+  /// value, or add it.
+  pub fn set_string<S: Into<String>>(&mut self, id: u8, value: S) {
+    let value = value.into();
+    assert!(value.len() <= MAX_FIELD_LENGTH);
+    self.set(id, FieldValue::String(value));
+  }
+
+  /// Remove every boolean field with this id.
+  pub fn remove_bool(&mut self, id: u8) {
+    self.remove(id, KIND_BOOLEAN);
+  }
+
+  /// Remove every number field with this id.
+  pub fn remove_number(&mut self, id: u8) {
+    self.remove(id, KIND_NUMBER);
+  }
+
+  /// Remove every string field with this id.
+  pub fn remove_string(&mut self, id: u8) {
+    self.remove(id, KIND_STRING);
+  }
+
+  // a replaced field keeps the position of the first one it replaces, so
+  // re-encoding doesn't shuffle the header.
+  fn set(&mut self, id: u8, value: FieldValue) {
+    assert!(id <= MAX_FIELD_ID);
+    let kind = value.kind();
+    match self.fields.iter().position(|f| f.id == id && f.value.kind() == kind) {
+      Some(i) => {
+        self.remove(id, kind);
+        self.fields.insert(i, Field { id, value });
+      }
+      None => self.fields.push(Field { id, value })
+    }
+  }
+
+  fn remove(&mut self, id: u8, kind: u8) {
+    self.fields.retain(|f| !(f.id == id && f.value.kind() == kind));
   }
 
   pub fn write<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
-    for ref f in &self.fields {
-      let content_length: usize = match f.value {
-        FieldValue::Boolean => 0,
-        FieldValue::Number(value) => zint::bytes_needed(value),
-        FieldValue::String(ref value) => value.len()
-      };
-      let kind: u8 = match f.value {
-        FieldValue::Boolean => KIND_BOOLEAN,
-        FieldValue::Number(_) => KIND_NUMBER,
-        FieldValue::String(_) => KIND_STRING
-      };
+    for f in &self.fields {
+      let content_length = f.value.content_length();
+      let kind = f.value.kind();
       writer.write_all(&[
-        (kind << 6) | (f.id << 2) | (((content_length >> 8) & 0x2) as u8),
+        (kind << 6) | (f.id << 2) | (((content_length >> 8) & 0x3) as u8),
         (content_length & 0xff) as u8
       ])?;
 
@@ -77,25 +225,62 @@ impl Header {
     cursor.into_inner()
   }
 
+  /// Scan an encoded header for the first string field with this id and
+  /// return its raw bytes, without decoding the rest of the header.
+  pub fn get_raw_string(buffer: &[u8], id: u8) -> Option<&[u8]> {
+    Header::scan(buffer, KIND_STRING, id)
+  }
+
+  /// Scan an encoded header for the first number field with this id and
+  /// return its raw (packed) bytes, without decoding the rest of the header.
+  pub fn get_raw_number(buffer: &[u8], id: u8) -> Option<&[u8]> {
+    Header::scan(buffer, KIND_NUMBER, id)
+  }
+
+  // walk the field framing only. a truncated header just means "not found";
+  // `decode` is the place to complain about it.
+  fn scan(buffer: &[u8], kind: u8, id: u8) -> Option<&[u8]> {
+    let mut i: usize = 0;
+    while i + 2 <= buffer.len() {
+      let field_kind = (buffer[i] & 0xc0) >> 6;
+      let field_id = (buffer[i] & 0x3c) >> 2;
+      let length: usize = (((buffer[i] & 0x3) as usize) << 8) + buffer[i + 1] as usize;
+      i += 2;
+      if i + length > buffer.len() { return None }
+      if field_kind == kind && field_id == id { return Some(&buffer[i .. i + length]) }
+      i += length;
+    }
+    None
+  }
+
   pub fn decode(buffer: &[u8]) -> io::Result<Header> {
+    Header::decode_with_limit(buffer, MAX_FIELDS)
+  }
+
+  /// Decode a header, refusing (with `InvalidData`) if it contains more
+  /// than `max_fields` fields.
+  pub fn decode_with_limit(buffer: &[u8], max_fields: usize) -> io::Result<Header> {
     let mut header = Header::new();
     let mut i: usize = 0;
     while i < buffer.len() {
+      if header.fields.len() >= max_fields { return Err(too_many_fields_error(max_fields)) }
       if i + 2 > buffer.len() { return Err(truncated_error()) }
       let kind = (buffer[i] & 0xc0) >> 6;
       let id = (buffer[i] & 0x3c) >> 2;
-      let length: usize = (((buffer[i] & 0x3) as usize) << 8) + (buffer[i + 1] & 0xff) as usize;
+      let length: usize = (((buffer[i] & 0x3) as usize) << 8) + buffer[i + 1] as usize;
       i += 2;
       if i + length > buffer.len() { return Err(truncated_error()) }
 
       let content = &buffer[i .. i + length];
       let value = match kind {
+        KIND_BOOLEAN if length > 0 => return Err(BottleError::BooleanHasContent.into()),
         KIND_BOOLEAN => FieldValue::Boolean,
-        KIND_NUMBER => FieldValue::Number(zint::decode_packed_int(content.as_ref())?),
+        KIND_NUMBER if length > 8 => return Err(BottleError::NumberTooLong.into()),
+        KIND_NUMBER => FieldValue::Number(zint::decode_packed_int(&mut io::Cursor::new(content), length)?),
         KIND_STRING => FieldValue::String(str::from_utf8(content).map_err(convert_error)?.to_string()),
         _ => return Err(unknown_kind_error())
       };
-      header.fields.push(Field { id: id, value: value });
+      header.fields.push(Field { id, value });
       i += length;
     }
     Ok(header)
@@ -112,15 +297,47 @@ impl fmt::Debug for Header {
   }
 }
 
+// a header as deserialized, before it's checked against the limits that
+// the `add_*` methods assert.
+#[cfg(feature = "serde")]
+#[derive(::serde::Deserialize)]
+struct HeaderFields {
+  fields: Vec<Field>
+}
+
+#[cfg(feature = "serde")]
+impl ::std::convert::TryFrom<HeaderFields> for Header {
+  type Error = String;
+
+  fn try_from(header: HeaderFields) -> Result<Header, String> {
+    if header.fields.len() > MAX_FIELDS { return Err(BottleError::TooManyFields(MAX_FIELDS).to_string()) }
+    for f in &header.fields {
+      if f.id > MAX_FIELD_ID { return Err(format!("Field id {} is over {}", f.id, MAX_FIELD_ID)) }
+      if let FieldValue::String(ref value) = f.value {
+        if value.len() > MAX_FIELD_LENGTH { return Err(format!("Field {} is longer than {} bytes", f.id, MAX_FIELD_LENGTH)) }
+      }
+    }
+    Ok(Header { fields: header.fields })
+  }
+}
+
 // convert a UTF-8 decoding error into a normal I/O error
 fn convert_error(e: str::Utf8Error) -> io::Error {
-  io::Error::new(io::ErrorKind::InvalidInput, e.description())
+  BottleError::BadUtf8(e).into()
 }
 
 fn truncated_error() -> io::Error {
-  io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated header")
+  BottleError::TruncatedHeader.into()
+}
+
+fn too_many_fields_error(max_fields: usize) -> io::Error {
+  BottleError::TooManyFields(max_fields).into()
+}
+
+fn header_full_error(name: &str, size: usize, remaining: usize) -> io::Error {
+  BottleError::HeaderFull { field: name.to_string(), size, remaining }.into()
 }
 
 fn unknown_kind_error() -> io::Error {
-  io::Error::new(io::ErrorKind::InvalidInput, "Unknown field kind")
+  BottleError::UnknownFieldKind.into()
 }
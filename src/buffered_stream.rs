@@ -1,13 +1,27 @@
 use bytes::Bytes;
 use std::collections::VecDeque;
 use std::io;
+use std::mem;
 use futures::{Async, Poll, Stream};
 use futures::stream::Fuse;
 
 /*
  * Stream<Vec<Bytes>> that buffers data until it reaches a desired block size,
- * then emits a single block. If `exact` is set, each block will be exactly
- * `block_size`, even if it has to split up a `Bytes`.
+ * then emits a single block. It only pulls from the inner stream while it
+ * has less than a block queued, so a slow consumer slows down the producer
+ * instead of piling up data here.
+ *
+ * A block is emitted:
+ *   - as soon as `block_size` bytes are queued;
+ *   - when the inner stream ends, with whatever is left (possibly short);
+ *   - when the inner stream fails, with whatever is queued, and the error
+ *     comes on the next poll;
+ *   - when the inner stream is `NotReady`, with whatever is queued, but
+ *     only if `flush_when_idle` is set and `exact` isn't.
+ *
+ * If `exact` is set, each block will be exactly `block_size`, even if it has
+ * to split up a `Bytes`, and only the last block may be shorter. Otherwise,
+ * a block is whole items, and can run past `block_size` by up to one item.
  *
  * In theory, this doesn't copy buffers, just creates new `Vec`s holding
  * different sets of `Bytes`.
@@ -24,9 +38,11 @@ pub struct BufferedStream<T> where T: Stream<Item = Vec<Bytes>, Error = io::Erro
   items: VecDeque<Bytes>,
   total: usize,
   err: Option<io::Error>,
+  done: bool,
   stream: Fuse<T>,
   block_size: usize,
-  exact: bool
+  exact: bool,
+  flush_when_idle: bool
 }
 
 impl<T> BufferedStream<T>
@@ -38,18 +54,33 @@ impl<T> BufferedStream<T>
       items: VecDeque::new(),
       total: 0,
       err: None,
+      done: false,
       stream: s.fuse(),
-      block_size: block_size,
-      exact: exact
+      block_size,
+      exact,
+      flush_when_idle: false
     }
   }
 
+  /// Emit a short block whenever the inner stream has nothing more yet,
+  /// trading bigger blocks for lower latency. Off by default, and ignored
+  /// when `exact` is set.
+  pub fn flush_when_idle(mut self, flush: bool) -> BufferedStream<T> {
+    self.flush_when_idle = flush;
+    self
+  }
+
+  // one block: `block_size` bytes (or more, if not exact), or whatever's
+  // left if that's less.
   fn drain(&mut self) -> Vec<Bytes> {
-    let mut rv = Vec::<Bytes>::new();
+    let mut rv = Vec::<Bytes>::with_capacity(self.items.len());
     let mut count = 0;
 
-    while self.items.len() > 0 && count < self.block_size {
-      let chunk = self.items.pop_front().unwrap();
+    while count < self.block_size {
+      let chunk = match self.items.pop_front() {
+        Some(chunk) => chunk,
+        None => break
+      };
       if (count + chunk.len() <= self.block_size) || !self.exact {
         count += chunk.len();
         self.total -= chunk.len();
@@ -74,12 +105,87 @@ impl<T> Stream for BufferedStream<T>
   type Error = io::Error;
 
   fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-    if let Some(err) = self.err.take() {
-      return Err(err)
+    loop {
+      // a full block goes out before anything else is read.
+      if self.total >= self.block_size {
+        return Ok(Async::Ready(Some(self.drain())))
+      }
+
+      // mimic streams lib: send anything queued up before the error.
+      if self.err.is_some() || self.done {
+        if !self.items.is_empty() { return Ok(Async::Ready(Some(self.drain()))) }
+        return match self.err.take() {
+          Some(err) => Err(err),
+          None => Ok(Async::Ready(None))
+        }
+      }
+
+      match self.stream.poll() {
+        Ok(Async::NotReady) => {
+          if self.flush_when_idle && !self.exact && !self.items.is_empty() {
+            return Ok(Async::Ready(Some(self.drain())))
+          }
+          return Ok(Async::NotReady)
+        }
+        Ok(Async::Ready(Some(item))) => {
+          self.total += item.iter().fold(0, |sum, buffer| { sum + buffer.len() });
+          self.items.extend(item);
+        }
+        Ok(Async::Ready(None)) => self.done = true,
+        Err(e) => self.err = Some(e)
+      }
     }
+  }
+}
+
+
+/*
+ * Stream<Vec<Bytes>> that merges consecutive items into one until they add
+ * up to at least `target_bytes`, then emits the merged item. Unlike
+ * `BufferedStream`, items are never re-cut, so whatever framing they carry
+ * (like the output of `make_bottle`) passes through intact. It's meant to
+ * sit right before a sink, to turn many small writes into fewer big ones.
+ */
 
-    if self.total >= self.block_size {
-      return Ok(Async::Ready(Some(self.drain())))
+pub fn batch_writes<T>(s: T, target_bytes: usize) -> BatchedStream<T>
+  where T: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  BatchedStream {
+    batch: Vec::new(),
+    total: 0,
+    err: None,
+    stream: s.fuse(),
+    target_bytes
+  }
+}
+
+#[must_use = "streams do nothing unless polled"]
+pub struct BatchedStream<T> where T: Stream<Item = Vec<Bytes>, Error = io::Error> {
+  batch: Vec<Bytes>,
+  total: usize,
+  err: Option<io::Error>,
+  stream: Fuse<T>,
+  target_bytes: usize
+}
+
+impl<T> BatchedStream<T>
+  where T: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  fn flush(&mut self) -> Vec<Bytes> {
+    self.total = 0;
+    mem::take(&mut self.batch)
+  }
+}
+
+impl<T> Stream for BatchedStream<T>
+  where T: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  type Item = Vec<Bytes>;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    if let Some(err) = self.err.take() {
+      return Err(err)
     }
 
     loop {
@@ -90,24 +196,23 @@ impl<T> Stream for BufferedStream<T>
 
         Ok(Async::Ready(Some(item))) => {
           self.total += item.iter().fold(0, |sum, buffer| { sum + buffer.len() });
-          self.items.extend(item);
-          if self.total >= self.block_size {
-            return Ok(Async::Ready(Some(self.drain())))
+          self.batch.extend(item);
+          if self.total >= self.target_bytes {
+            return Ok(Async::Ready(Some(self.flush())))
           }
-          // otherwise, fall thru and try for more.
         }
 
         Ok(Async::Ready(None)) => {
-          return Ok(Async::Ready(if self.items.len() > 0 { Some(self.drain()) } else { None }))
+          return Ok(Async::Ready(if !self.batch.is_empty() { Some(self.flush()) } else { None }))
         }
 
-        // mimic streams lib: send anything queued up first.
+        // same as `BufferedStream`: send anything queued up first.
         Err(e) => {
-          if self.items.len() == 0 {
+          if self.batch.is_empty() {
             return Err(e)
           } else {
             self.err = Some(e);
-            return Ok(Async::Ready(Some(self.drain())))
+            return Ok(Async::Ready(Some(self.flush())))
           }
         }
       }
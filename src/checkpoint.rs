@@ -0,0 +1,190 @@
+use bytes::Bytes;
+use futures::Stream;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use archive::path_bottle;
+use bottle::{BottleOptions, BottleType, encode_bottle_header, framed_vec_stream};
+use bottle_header::{Header};
+use buffered_stream::{buffer_stream};
+use error::BottleError;
+use file_bottle::{FileMetadata, WriteSettings};
+use hashing::{Hasher};
+use to_hex::{FromHex, ToHex};
+use zint;
+
+// folder name used when none is given (same as `ArchiveWriter`).
+const DEFAULT_FOLDER_NAME: &str = "archive";
+
+// header fields for an encoded checkpoint
+const FIELD_BYTES_WRITTEN: u8 = 0;
+const FIELD_ENTRIES: u8 = 1;
+const FIELD_DIGEST: u8 = 0;
+
+/// How far a `CheckpointingWriter` got: the first `bytes_written` bytes of
+/// the output hold the first `entries` top-level entries, and hash (with
+/// SHA-256) to `digest`.
+///
+/// The hasher's internal state can't be saved, so resuming re-reads the
+/// partial output to check it against `digest`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+  pub bytes_written: u64,
+  pub entries: usize,
+  pub digest: Bytes
+}
+
+impl Checkpoint {
+  pub fn encode(&self) -> Vec<u8> {
+    let mut header = Header::new();
+    header.add_number(FIELD_BYTES_WRITTEN, self.bytes_written);
+    header.add_number(FIELD_ENTRIES, self.entries as u64);
+    header.add_string(FIELD_DIGEST, self.digest.to_hex());
+    header.encode()
+  }
+
+  pub fn decode(data: &[u8]) -> io::Result<Checkpoint> {
+    let header = Header::decode(data)?;
+    let bytes_written = header.get_number(FIELD_BYTES_WRITTEN).ok_or_else(bad_checkpoint_error)?;
+    let entries = header.get_number(FIELD_ENTRIES).ok_or_else(bad_checkpoint_error)? as usize;
+    let hex = header.get_string(FIELD_DIGEST).ok_or_else(bad_checkpoint_error)?;
+    if hex.len() != 64 || !hex.bytes().all(|c| c.is_ascii_hexdigit()) { return Err(bad_checkpoint_error()) }
+    Ok(Checkpoint { bytes_written, entries, digest: Bytes::from(hex.from_hex()) })
+  }
+}
+
+/*
+ * Writes a folder bottle of files and folders, like `ArchiveWriter` does
+ * for more than one path, but blocking, and calling back with a
+ * `Checkpoint` after the header and after each top-level entry. If the
+ * job is interrupted, `resume` picks up from the last checkpoint instead
+ * of starting over.
+ *
+ * Only the plain folder bottle is written; hashing, compression, and
+ * encryption don't have a resumable state, so they'd have to be applied
+ * to the finished file.
+ */
+#[derive(Default)]
+pub struct CheckpointingWriter {
+  paths: Vec<PathBuf>,
+  folder_name: Option<String>
+}
+
+impl CheckpointingWriter {
+  pub fn new() -> CheckpointingWriter {
+    CheckpointingWriter::default()
+  }
+
+  /// Add a file or folder (recursively) as the next top-level entry.
+  pub fn add_path<P: Into<PathBuf>>(mut self, path: P) -> CheckpointingWriter {
+    self.paths.push(path.into());
+    self
+  }
+
+  pub fn folder_name<S: Into<String>>(mut self, name: S) -> CheckpointingWriter {
+    self.folder_name = Some(name.into());
+    self
+  }
+
+  /// Write the whole archive from the start.
+  pub fn write<W, F>(&self, mut writer: W, mut on_checkpoint: F) -> io::Result<()>
+    where
+      W: Write,
+      F: FnMut(&Checkpoint)
+  {
+    if self.paths.is_empty() { return Err(nothing_to_archive_error()) }
+    let metadata = FileMetadata {
+      filename: self.folder_name.clone().unwrap_or_else(|| DEFAULT_FOLDER_NAME.to_string()),
+      folder: true,
+      ..FileMetadata::default()
+    };
+    let mut out = CheckpointOutput { writer: &mut writer, hasher: Hasher::sha256(), bytes_written: 0 };
+    out.write(&encode_bottle_header(BottleType::File, &metadata.try_to_header()?)?)?;
+    out.writer.flush()?;
+    on_checkpoint(&out.checkpoint(0));
+    self.write_entries(out, 0, &mut on_checkpoint)
+  }
+
+  /// Continue an interrupted `write` into `file`, from `checkpoint`. The
+  /// first `bytes_written` bytes are re-hashed and must match, or this
+  /// fails with `BottleError::CheckpointMismatch` and nothing is changed.
+  /// Anything after them (a partly-written entry) is thrown away.
+  pub fn resume<F>(&self, file: &mut fs::File, checkpoint: &Checkpoint, mut on_checkpoint: F) -> io::Result<()>
+    where F: FnMut(&Checkpoint)
+  {
+    if checkpoint.entries > self.paths.len() { return Err(bad_checkpoint_error()) }
+    file.seek(SeekFrom::Start(0))?;
+    let mut hasher = Hasher::sha256();
+    let mut buffer = vec![ 0u8; 64 * 1024 ];
+    let mut remaining = checkpoint.bytes_written;
+    while remaining > 0 {
+      let size = remaining.min(buffer.len() as u64) as usize;
+      let n = file.read(&mut buffer[0 .. size])?;
+      if n == 0 { return Err(checkpoint_mismatch_error()) }
+      hasher.update(&buffer[0 .. n]);
+      remaining -= n as u64;
+    }
+    if hasher.clone().finish() != checkpoint.digest { return Err(checkpoint_mismatch_error()) }
+
+    file.set_len(checkpoint.bytes_written)?;
+    file.seek(SeekFrom::Start(checkpoint.bytes_written))?;
+    let out = CheckpointOutput { writer: file, hasher, bytes_written: checkpoint.bytes_written };
+    self.write_entries(out, checkpoint.entries, &mut on_checkpoint)
+  }
+
+  // write entries from `start` on, framed the same way `make_bottle` does,
+  // then end the bottle. the last checkpoint is before the end marker, so
+  // resuming from it only has to write that.
+  fn write_entries<W, F>(&self, mut out: CheckpointOutput<W>, start: usize, on_checkpoint: &mut F) -> io::Result<()>
+    where
+      W: Write,
+      F: FnMut(&Checkpoint)
+  {
+    for ( i, path ) in self.paths.iter().enumerate().skip(start) {
+      let s = framed_vec_stream(buffer_stream(path_bottle(path.clone(), WriteSettings::default(), None)?, BottleOptions::default().min_frame, false));
+      for buffers in s.wait() {
+        for b in buffers? { out.write(&b)? }
+      }
+      out.writer.flush()?;
+      on_checkpoint(&out.checkpoint(i + 1));
+    }
+    out.write(&zint::encode_length(zint::END_OF_ALL_STREAMS))?;
+    out.writer.flush()
+  }
+}
+
+// a writer that keeps a running hash and count of what went through it.
+struct CheckpointOutput<W: Write> {
+  writer: W,
+  hasher: Hasher,
+  bytes_written: u64
+}
+
+impl<W: Write> CheckpointOutput<W> {
+  fn write(&mut self, data: &[u8]) -> io::Result<()> {
+    self.writer.write_all(data)?;
+    self.hasher.update(data);
+    self.bytes_written += data.len() as u64;
+    Ok(())
+  }
+
+  fn checkpoint(&self, entries: usize) -> Checkpoint {
+    Checkpoint { bytes_written: self.bytes_written, entries, digest: self.hasher.clone().finish() }
+  }
+}
+
+
+// ----- errors
+
+fn bad_checkpoint_error() -> io::Error {
+  BottleError::BadCheckpoint.into()
+}
+
+fn checkpoint_mismatch_error() -> io::Error {
+  BottleError::CheckpointMismatch.into()
+}
+
+fn nothing_to_archive_error() -> io::Error {
+  BottleError::NothingToArchive.into()
+}
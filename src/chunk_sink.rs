@@ -0,0 +1,90 @@
+use bytes::Bytes;
+use futures::{Async, Future, Poll, Stream};
+use std::io;
+use std::mem;
+
+/// Somewhere an archive can be uploaded in numbered parts, like an object
+/// store's multipart upload. The calls block, so run `upload_parts` on a
+/// thread pool if that matters.
+pub trait ChunkSink {
+  /// Upload one part. Parts are numbered from 1, and arrive in order.
+  fn upload_part(&mut self, part_number: usize, data: Vec<Bytes>) -> io::Result<()>;
+
+  /// Every part has been uploaded.
+  fn complete(&mut self) -> io::Result<()>;
+
+  /// Something failed: throw away whatever was uploaded so far.
+  fn abort(&mut self);
+}
+
+/// Feed a bottle stream into a `ChunkSink`, one part at a time. Parts are
+/// cut only between the buffer groups the bottle emits (so a frame is
+/// never split across parts), as soon as at least `part_size` bytes are
+/// collected, which keeps memory use near `part_size` no matter how big
+/// the archive is. Only the last part may be smaller.
+///
+/// If the stream or an upload fails, the sink is aborted.
+pub fn upload_parts<S, K>(s: S, sink: K, part_size: usize) -> UploadParts<S, K>
+  where
+    S: Stream<Item = Vec<Bytes>, Error = io::Error>,
+    K: ChunkSink
+{
+  UploadParts { stream: s, sink: Some(sink), part_size, part: Vec::new(), part_length: 0, parts: 0 }
+}
+
+/// Future for `upload_parts`. It resolves to the sink and the number of
+/// parts uploaded.
+#[must_use = "futures do nothing unless polled"]
+pub struct UploadParts<S, K> {
+  stream: S,
+  sink: Option<K>,
+  part_size: usize,
+  part: Vec<Bytes>,
+  part_length: usize,
+  parts: usize
+}
+
+impl<S, K> UploadParts<S, K> where S: Stream<Item = Vec<Bytes>, Error = io::Error>, K: ChunkSink {
+  fn upload(&mut self) -> io::Result<()> {
+    self.parts += 1;
+    self.part_length = 0;
+    let part = mem::take(&mut self.part);
+    self.sink.as_mut().expect("polled after completion").upload_part(self.parts, part)
+  }
+
+  fn poll_parts(&mut self) -> Poll<(), io::Error> {
+    loop {
+      match try_ready!(self.stream.poll()) {
+        Some(buffers) => {
+          for b in buffers.into_iter().filter(|b| !b.is_empty()) {
+            self.part_length += b.len();
+            self.part.push(b);
+          }
+          if self.part_length >= self.part_size { self.upload()? }
+        },
+        None => {
+          // an empty stream still gets one (empty) part.
+          if !self.part.is_empty() || self.parts == 0 { self.upload()? }
+          self.sink.as_mut().expect("polled after completion").complete()?;
+          return Ok(Async::Ready(()));
+        }
+      }
+    }
+  }
+}
+
+impl<S, K> Future for UploadParts<S, K> where S: Stream<Item = Vec<Bytes>, Error = io::Error>, K: ChunkSink {
+  type Item = ( K, usize );
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<( K, usize ), io::Error> {
+    match self.poll_parts() {
+      Ok(Async::Ready(())) => Ok(Async::Ready(( self.sink.take().expect("polled after completion"), self.parts ))),
+      Ok(Async::NotReady) => Ok(Async::NotReady),
+      Err(e) => {
+        if let Some(mut sink) = self.sink.take() { sink.abort() }
+        Err(e)
+      }
+    }
+  }
+}
@@ -0,0 +1,485 @@
+use bytes::Bytes;
+use futures::{Async, Future, Poll, Stream, stream};
+use futures::stream::Fuse;
+use futures_cpupool::CpuPool;
+use snap;
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::{Arc, RwLock};
+use xz2;
+use zstd::stream::{raw, zio};
+use zstd::zstd_safe::CParameter;
+
+use bottle::{BottleType, DecodeLimits, ReadOptions, make_bottle, make_bottle_from_stream, read_bottle_with_options};
+use bottle_header::{Header};
+use buffered_stream::buffer_stream;
+use error::BottleError;
+use stream_helpers::{concat_limited, make_vec_stream_1};
+
+pub(crate) const FIELD_COMPRESSION_TYPE: u8 = 0;
+const FIELD_BLOCK_SIZE: u8 = 1;
+const FIELD_LEVEL: u8 = 2;
+const FIELD_LONG_DISTANCE: u8 = 0;
+
+/// Ids below this are reserved for codecs built into the crate.
+pub const FIRST_CUSTOM_CODEC: u8 = 16;
+
+const LZMA_PRESET: u32 = 6;
+const ZSTD_LEVEL: i32 = 3;
+
+// input is cut into blocks this big for parallel compression.
+const PARALLEL_BLOCK_SIZE: usize = 1024 * 1024;
+
+// every snappy frame stream starts with this chunk.
+const SNAPPY_STREAM_ID: [u8; 10] = [ 0xff, 0x06, 0x00, 0x00, 0x73, 0x4e, 0x61, 0x50, 0x70, 0x59 ];
+
+lazy_static! {
+  static ref CODECS: RwLock<HashMap<u8, Arc<dyn Codec>>> = {
+    let mut codecs: HashMap<u8, Arc<dyn Codec>> = HashMap::new();
+    for codec in [ Arc::new(Lzma2Codec) as Arc<dyn Codec>, Arc::new(SnappyCodec), Arc::new(ZstdCodec) ] {
+      codecs.insert(codec.id(), codec);
+    }
+    RwLock::new(codecs)
+  };
+}
+
+/// A stream of bytes going into or out of a codec.
+pub type ByteStream<'a> = Box<dyn Stream<Item = Bytes, Error = io::Error> + 'a>;
+
+/// A compression format, stored in a compressed bottle's header by its id.
+/// The built-in codecs are registered this way too, and a downstream crate
+/// can add its own (brotli, lz4, ...) with `register_codec`.
+pub trait Codec: Send + Sync {
+  /// The id stored in a compressed bottle's header.
+  fn id(&self) -> u8;
+
+  /// A short name, like "zstd", for command lines and messages.
+  fn name(&self) -> &str;
+
+  /// Compress a stream. Fails right away (before reading anything) if
+  /// the options don't make sense for this codec.
+  fn wrap_encode<'a>(&self, s: ByteStream<'a>, options: &CompressOptions) -> io::Result<ByteStream<'a>>;
+
+  /// Decompress a stream this codec compressed.
+  fn wrap_decode<'a>(&self, s: ByteStream<'a>) -> io::Result<ByteStream<'a>>;
+}
+
+/// Make a codec available to every writer and reader, under its id, which
+/// must be `FIRST_CUSTOM_CODEC` or more. Registering an id again replaces
+/// it. Readers only know an id once it's registered, so do this before
+/// reading bottles that use it.
+pub fn register_codec<C: Codec + 'static>(codec: C) -> io::Result<CompressionType> {
+  let id = codec.id();
+  if id < FIRST_CUSTOM_CODEC { return Err(reserved_compression_type_error(id)) }
+  CODECS.write().unwrap().insert(id, Arc::new(codec));
+  Ok(CompressionType::Custom(id))
+}
+
+/// Find a registered codec by its name.
+pub fn find_codec(name: &str) -> Option<CompressionType> {
+  CODECS.read().unwrap().values().find(|codec| codec.name() == name).map(|codec| decode_id(codec.id()))
+}
+
+// compression types, as stored in a header field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompressionType {
+  Lzma2,
+  Snappy,
+  Zstd,
+  /// registered with `register_codec`
+  Custom(u8)
+}
+
+impl CompressionType {
+  /// The id stored in a compressed bottle's header.
+  pub fn id(&self) -> u8 {
+    match *self {
+      CompressionType::Lzma2 => 0,
+      CompressionType::Snappy => 1,
+      CompressionType::Zstd => 2,
+      CompressionType::Custom(id) => id
+    }
+  }
+
+  /// The registered codec, which is missing only for a custom id that was
+  /// never registered.
+  pub fn codec(&self) -> io::Result<Arc<dyn Codec>> {
+    let id = self.id();
+    CODECS.read().unwrap().get(&id).cloned().ok_or_else(|| unknown_compression_type_error(id as u64))
+  }
+}
+
+fn decode_id(id: u8) -> CompressionType {
+  match id {
+    0 => CompressionType::Lzma2,
+    1 => CompressionType::Snappy,
+    2 => CompressionType::Zstd,
+    id => CompressionType::Custom(id)
+  }
+}
+
+pub fn decode_compression_type(n: u64) -> Result<CompressionType, io::Error> {
+  match n {
+    n if n <= u8::MAX as u64 && CODECS.read().unwrap().contains_key(&(n as u8)) => Ok(decode_id(n as u8)),
+    _ => Err(unknown_compression_type_error(n))
+  }
+}
+
+/// How to compress: the codec, and how hard it should try.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompressOptions {
+  pub compression_type: CompressionType,
+  /// 0 - 9 for LZMA2, or 1 - 22 for zstd (snappy has no levels). `None`
+  /// means the codec's usual default.
+  pub level: Option<i32>,
+  /// zstd only: look for matches much further back (up to 128MB), which
+  /// helps big archives with repeated files.
+  pub long_distance: bool
+}
+
+impl CompressOptions {
+  pub fn new(compression_type: CompressionType) -> CompressOptions {
+    CompressOptions { compression_type, level: None, long_distance: false }
+  }
+
+  pub fn level(mut self, level: i32) -> CompressOptions {
+    self.level = Some(level);
+    self
+  }
+
+  pub fn long_distance(mut self, long_distance: bool) -> CompressOptions {
+    self.long_distance = long_distance;
+    self
+  }
+
+  // the level isn't needed to decompress, but it's recorded so the bottle
+  // can be rebuilt the same way (see `transcode`).
+  fn to_header(self) -> Header {
+    let mut header = Header::new();
+    header.add_number(FIELD_COMPRESSION_TYPE, self.compression_type.id() as u64);
+    if let Some(level) = self.level { header.add_number(FIELD_LEVEL, level as u64) }
+    if self.long_distance { header.add_bool(FIELD_LONG_DISTANCE) }
+    header
+  }
+
+  /// How a compressed bottle's header says it was compressed.
+  pub fn from_header(header: &Header) -> io::Result<CompressOptions> {
+    Ok(CompressOptions {
+      compression_type: decode_compression_type(header.get_number(FIELD_COMPRESSION_TYPE).unwrap_or(0))?,
+      level: header.get_number(FIELD_LEVEL).map(|n| n as i32),
+      long_distance: header.get_bool(FIELD_LONG_DISTANCE)
+    })
+  }
+}
+
+/// Wrap a bottle (or any byte stream) in a compressed bottle. LZMA2 is
+/// stored as an xz stream, snappy in its framing format, and zstd as a
+/// single zstd frame. Fails if `compression_type` is a custom id that
+/// isn't registered.
+pub fn compress_bottle<'a, S>(s: S, compression_type: CompressionType)
+  -> io::Result<impl Stream<Item = Vec<Bytes>, Error = io::Error> + 'a>
+  where S: Stream<Item = Vec<Bytes>, Error = io::Error> + 'a
+{
+  compress_bottle_with_options(s, &CompressOptions::new(compression_type))
+}
+
+/// Like `compress_bottle`, with a choice of level. Fails if the level
+/// doesn't make sense for the codec.
+pub fn compress_bottle_with_options<'a, S>(s: S, options: &CompressOptions)
+  -> io::Result<impl Stream<Item = Vec<Bytes>, Error = io::Error> + 'a>
+  where S: Stream<Item = Vec<Bytes>, Error = io::Error> + 'a
+{
+  let header = options.to_header();
+  let codec = options.compression_type.codec()?;
+  let compressed = codec.wrap_encode(Box::new(s.map(stream::iter_ok).flatten()), options)?.map(|b| vec![ b ]);
+  Ok(make_bottle(BottleType::Compressed, &header, vec![ compressed ]))
+}
+
+/// Compress on a pool of `n_threads` threads. The input is cut into 1MB
+/// blocks, and each is compressed on its own, into its own child stream,
+/// so the blocks can be decompressed in parallel too. This costs a little
+/// compression, since no block can refer back to an earlier one.
+pub fn compress_bottle_parallel<S>(s: S, options: &CompressOptions, n_threads: usize)
+  -> io::Result<impl Stream<Item = Vec<Bytes>, Error = io::Error>>
+  where S: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  // check the options now, instead of on every block.
+  let codec = options.compression_type.codec()?;
+  let _ = codec.wrap_encode(Box::new(stream::empty()), options)?;
+  let options = *options;
+  let n_threads = cmp::max(n_threads, 1);
+  let pool = CpuPool::new(n_threads);
+
+  let mut header = options.to_header();
+  header.add_number(FIELD_BLOCK_SIZE, PARALLEL_BLOCK_SIZE as u64);
+  let blocks = buffer_stream(s, PARALLEL_BLOCK_SIZE, true).map(move |buffers| {
+    let codec = codec.clone();
+    pool.spawn_fn(move || codec.wrap_encode(Box::new(stream::iter_ok(buffers)), &options)?.concat2().wait())
+  }).buffered(n_threads).map(make_vec_stream_1);
+  Ok(make_bottle_from_stream(BottleType::Compressed, &header, blocks))
+}
+
+/// Read a compressed bottle, returning its header and the decompressed
+/// inner stream.
+pub fn decompress_bottle<'a, S>(s: S)
+  -> impl Future<Item = (Header, impl Stream<Item = Bytes, Error = io::Error> + 'a), Error = io::Error> + 'a
+  where S: Stream<Item = Bytes, Error = io::Error> + 'a
+{
+  decompress_bottle_with_limits(s, DecodeLimits::default())
+}
+
+pub(crate) fn decompress_bottle_with_limits<'a, S>(s: S, limits: DecodeLimits)
+  -> impl Future<Item = (Header, impl Stream<Item = Bytes, Error = io::Error> + 'a), Error = io::Error> + 'a
+  where S: Stream<Item = Bytes, Error = io::Error> + 'a
+{
+  read_bottle_with_options(s, ReadOptions { limits, ..ReadOptions::default() }).and_then(|( btype, header, children )| {
+    if btype != BottleType::Compressed { return Err(not_compressed_error(btype)) }
+    let codec = decode_compression_type(header.get_number(FIELD_COMPRESSION_TYPE).unwrap_or(0))?.codec()?;
+    // usually there's one child stream, but parallel compression makes
+    // one per block, each compressed separately.
+    let s = children.and_then(move |child| codec.wrap_decode(Box::new(child))).flatten();
+    Ok(( header, s ))
+  })
+}
+
+/// Like `decompress_bottle`, but if the bottle was compressed in blocks
+/// (by `compress_bottle_parallel`), decompress up to `in_flight` of them at
+/// a time on `pool`. Other bottles are decompressed as usual, since their
+/// single stream can't be split up.
+///
+/// Each block is held in memory while it's decompressed, so the block size
+/// the writer recorded has to fit in `max_buffered_bytes`, and a block that
+/// decompresses to more than that size is refused.
+pub fn decompress_bottle_parallel<S>(s: S, pool: &CpuPool, in_flight: usize, limits: DecodeLimits)
+  -> impl Future<Item = (Header, Box<dyn Stream<Item = Bytes, Error = io::Error>>), Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error> + 'static
+{
+  let pool = pool.clone();
+  read_bottle_with_options(s, ReadOptions { limits, ..ReadOptions::default() }).and_then(move |( btype, header, children )| {
+    if btype != BottleType::Compressed { return Err(not_compressed_error(btype)) }
+    let codec = decode_compression_type(header.get_number(FIELD_COMPRESSION_TYPE).unwrap_or(0))?.codec()?;
+    let s: Box<dyn Stream<Item = Bytes, Error = io::Error>> = if let Some(block_size) = header.get_number(FIELD_BLOCK_SIZE) {
+      limits.check_buffered(block_size)?;
+      let block_limits = DecodeLimits { max_buffered_bytes: block_size, ..limits };
+      Box::new(children.and_then(move |child| concat_limited(child, limits)).map(move |block| {
+        let codec = codec.clone();
+        pool.spawn_fn(move || concat_limited(codec.wrap_decode(Box::new(stream::once(Ok(block))))?, block_limits).wait())
+      }).buffered(cmp::max(in_flight, 1)).filter(|b| !b.is_empty()))
+    } else {
+      Box::new(children.and_then(move |child| codec.wrap_decode(Box::new(child))).flatten())
+    };
+    Ok(( header, s ))
+  })
+}
+
+// ----- built-in codecs
+
+struct Lzma2Codec;
+
+impl Codec for Lzma2Codec {
+  fn id(&self) -> u8 { 0 }
+  fn name(&self) -> &str { "lzma2" }
+
+  fn wrap_encode<'a>(&self, s: ByteStream<'a>, options: &CompressOptions) -> io::Result<ByteStream<'a>> {
+    let level = match options.level {
+      None => LZMA_PRESET,
+      Some(n) if (0 ..= 9).contains(&n) => n as u32,
+      Some(n) => return Err(bad_level_error(n))
+    };
+    Ok(coder_stream(s, Box::new(xz2::write::XzEncoder::new(Vec::new(), level))))
+  }
+
+  fn wrap_decode<'a>(&self, s: ByteStream<'a>) -> io::Result<ByteStream<'a>> {
+    Ok(coder_stream(s, Box::new(xz2::write::XzDecoder::new(Vec::new()))))
+  }
+}
+
+struct SnappyCodec;
+
+impl Codec for SnappyCodec {
+  fn id(&self) -> u8 { 1 }
+  fn name(&self) -> &str { "snappy" }
+
+  fn wrap_encode<'a>(&self, s: ByteStream<'a>, options: &CompressOptions) -> io::Result<ByteStream<'a>> {
+    if let Some(n) = options.level { return Err(bad_level_error(n)) }
+    Ok(coder_stream(s, Box::new(snap::write::FrameEncoder::new(Vec::new()))))
+  }
+
+  fn wrap_decode<'a>(&self, s: ByteStream<'a>) -> io::Result<ByteStream<'a>> {
+    Ok(coder_stream(s, Box::new(SnappyDecoder { buffer: Vec::new() })))
+  }
+}
+
+struct ZstdCodec;
+
+impl Codec for ZstdCodec {
+  fn id(&self) -> u8 { 2 }
+  fn name(&self) -> &str { "zstd" }
+
+  fn wrap_encode<'a>(&self, s: ByteStream<'a>, options: &CompressOptions) -> io::Result<ByteStream<'a>> {
+    let level = options.level.unwrap_or(ZSTD_LEVEL);
+    if !(1 ..= 22).contains(&level) { return Err(bad_level_error(level)) }
+    let mut encoder = raw::Encoder::new(level)?;
+    if options.long_distance { encoder.set_parameter(CParameter::EnableLongDistanceMatching(true))? }
+    Ok(coder_stream(s, Box::new(zio::Writer::new(Vec::new(), encoder))))
+  }
+
+  fn wrap_decode<'a>(&self, s: ByteStream<'a>) -> io::Result<ByteStream<'a>> {
+    Ok(coder_stream(s, Box::new(zio::Writer::new(Vec::new(), raw::Decoder::new()?))))
+  }
+}
+
+fn coder_stream<'a>(s: ByteStream<'a>, coder: Box<dyn Coder>) -> ByteStream<'a> {
+  Box::new(CoderStream { stream: s.fuse(), coder, done: false })
+}
+
+// push-style (de)compressor: feed it buffers, and collect whatever it has
+// ready after each one.
+trait Coder {
+  fn process(&mut self, data: &[u8]) -> io::Result<Bytes>;
+  fn finish(&mut self) -> io::Result<Bytes>;
+}
+
+impl Coder for xz2::write::XzEncoder<Vec<u8>> {
+  fn process(&mut self, data: &[u8]) -> io::Result<Bytes> {
+    self.write_all(data)?;
+    Ok(Bytes::from(mem::take(self.get_mut())))
+  }
+
+  fn finish(&mut self) -> io::Result<Bytes> {
+    self.try_finish()?;
+    Ok(Bytes::from(mem::take(self.get_mut())))
+  }
+}
+
+impl Coder for xz2::write::XzDecoder<Vec<u8>> {
+  fn process(&mut self, data: &[u8]) -> io::Result<Bytes> {
+    self.write_all(data)?;
+    Ok(Bytes::from(mem::take(self.get_mut())))
+  }
+
+  fn finish(&mut self) -> io::Result<Bytes> {
+    xz2::write::XzDecoder::finish(self).map(Bytes::from)
+  }
+}
+
+impl Coder for snap::write::FrameEncoder<Vec<u8>> {
+  fn process(&mut self, data: &[u8]) -> io::Result<Bytes> {
+    self.write_all(data)?;
+    Ok(Bytes::from(mem::take(self.get_mut())))
+  }
+
+  fn finish(&mut self) -> io::Result<Bytes> {
+    self.flush()?;
+    Ok(Bytes::from(mem::take(self.get_mut())))
+  }
+}
+
+impl<'a> Coder for zio::Writer<Vec<u8>, raw::Encoder<'a>> {
+  fn process(&mut self, data: &[u8]) -> io::Result<Bytes> {
+    self.write_all(data)?;
+    Ok(Bytes::from(mem::take(self.writer_mut())))
+  }
+
+  fn finish(&mut self) -> io::Result<Bytes> {
+    zio::Writer::finish(self)?;
+    Ok(Bytes::from(mem::take(self.writer_mut())))
+  }
+}
+
+impl<'a> Coder for zio::Writer<Vec<u8>, raw::Decoder<'a>> {
+  fn process(&mut self, data: &[u8]) -> io::Result<Bytes> {
+    self.write_all(data)?;
+    Ok(Bytes::from(mem::take(self.writer_mut())))
+  }
+
+  // zstd notices if the frame was cut short.
+  fn finish(&mut self) -> io::Result<Bytes> {
+    match zio::Writer::finish(self) {
+      Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(truncated_error()),
+      result => result?
+    }
+    Ok(Bytes::from(mem::take(self.writer_mut())))
+  }
+}
+
+// snap only decodes from a reader, so collect whole chunks (a type byte
+// and 3-byte length, then data) and decode them as a little stream of
+// their own.
+struct SnappyDecoder {
+  buffer: Vec<u8>
+}
+
+impl Coder for SnappyDecoder {
+  fn process(&mut self, data: &[u8]) -> io::Result<Bytes> {
+    self.buffer.extend_from_slice(data);
+    let mut end = 0;
+    while end + 4 <= self.buffer.len() {
+      let length = (self.buffer[end + 1] as usize) + ((self.buffer[end + 2] as usize) << 8) + ((self.buffer[end + 3] as usize) << 16);
+      if end + 4 + length > self.buffer.len() { break }
+      end += 4 + length;
+    }
+    if end == 0 { return Ok(Bytes::new()) }
+
+    let chunks: Vec<u8> = self.buffer.drain(0 .. end).collect();
+    let mut rv = Vec::new();
+    snap::read::FrameDecoder::new(io::Cursor::new(&SNAPPY_STREAM_ID[..]).chain(&chunks[..])).read_to_end(&mut rv)?;
+    Ok(Bytes::from(rv))
+  }
+
+  fn finish(&mut self) -> io::Result<Bytes> {
+    if !self.buffer.is_empty() { return Err(truncated_error()) }
+    Ok(Bytes::new())
+  }
+}
+
+#[must_use = "streams do nothing unless polled"]
+struct CoderStream<'a> {
+  stream: Fuse<ByteStream<'a>>,
+  coder: Box<dyn Coder>,
+  done: bool
+}
+
+impl<'a> Stream for CoderStream<'a> {
+  type Item = Bytes;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    while !self.done {
+      let output = match try_ready!(self.stream.poll()) {
+        Some(b) => self.coder.process(&b)?,
+        None => {
+          self.done = true;
+          self.coder.finish()?
+        }
+      };
+      if !output.is_empty() { return Ok(Async::Ready(Some(output))) }
+    }
+    Ok(Async::Ready(None))
+  }
+}
+
+
+// ----- errors
+
+fn unknown_compression_type_error(n: u64) -> io::Error {
+  BottleError::UnknownCompressionType(n).into()
+}
+
+fn reserved_compression_type_error(n: u8) -> io::Error {
+  BottleError::ReservedCompressionType(n).into()
+}
+
+fn not_compressed_error(btype: BottleType) -> io::Error {
+  BottleError::WrongType { expected: BottleType::Compressed, found: btype }.into()
+}
+
+fn bad_level_error(level: i32) -> io::Error {
+  BottleError::BadCompressionLevel(level).into()
+}
+
+fn truncated_error() -> io::Error {
+  BottleError::TruncatedCompression.into()
+}
@@ -0,0 +1,213 @@
+use blake3;
+use bytes::Bytes;
+use futures::{Future, Stream, future, stream};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::rc::Rc;
+
+use bottle::{BottleType, DecodeLimits, ReadOptions, make_bottle_from_stream, read_bottle_with_options};
+use bottle_header::{Header};
+use error::BottleError;
+use stream_helpers::concat_limited;
+use zint;
+
+const FIELD_MIN_CHUNK: u8 = 0;
+const FIELD_AVG_CHUNK: u8 = 1;
+const FIELD_MAX_CHUNK: u8 = 2;
+
+// chunk sizes for content-defined chunking.
+const MIN_CHUNK: usize = 2 * 1024;
+const AVG_CHUNK: usize = 8 * 1024;
+const MAX_CHUNK: usize = 64 * 1024;
+
+// "normalized chunking" (from FastCDC): it's harder to cut before the
+// average size, and easier after, so chunk sizes bunch up near it.
+const MASK_SMALL: u64 = !(u64::MAX >> 14);
+const MASK_LARGE: u64 = !(u64::MAX >> 12);
+
+// each child stream is one record: a new chunk, or a reference to an
+// earlier one by its index.
+const RECORD_CHUNK: u8 = 0;
+const RECORD_REFERENCE: u8 = 1;
+
+lazy_static! {
+  // random values for the gear hash, from splitmix64 so they're the same
+  // everywhere.
+  static ref GEAR: [u64; 256] = {
+    let mut table = [ 0u64; 256 ];
+    let mut state: u64 = 0x4b6f_7474_6c65_2121;
+    for entry in table.iter_mut() {
+      state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+      let mut z = state;
+      z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+      z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+      *entry = z ^ (z >> 31);
+    }
+    table
+  };
+
+  static ref CHUNK_TAG: Bytes = Bytes::from(vec![ RECORD_CHUNK ]);
+}
+
+/// Wrap a bottle (or any byte stream) in a dedup bottle: the data is cut
+/// into chunks by content (so an insert only changes the chunks around
+/// it), and each chunk is stored only the first time it appears. Later
+/// copies are stored as a reference to the first, by BLAKE3 hash.
+///
+/// This pays off for backups full of repeated files, and should go
+/// before compression, which can't see repeats that far apart.
+pub fn dedup_bottle<S>(s: S) -> impl Stream<Item = Vec<Bytes>, Error = io::Error>
+  where S: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  let mut header = Header::new();
+  header.add_number(FIELD_MIN_CHUNK, MIN_CHUNK as u64);
+  header.add_number(FIELD_AVG_CHUNK, AVG_CHUNK as u64);
+  header.add_number(FIELD_MAX_CHUNK, MAX_CHUNK as u64);
+
+  let chunker = Rc::new(RefCell::new(Chunker::new()));
+  let last = chunker.clone();
+  let chunks = s.map(move |buffers| stream::iter_ok(chunker.borrow_mut().push(buffers))).flatten().chain(
+    future::lazy(move || Ok::<_, io::Error>(last.borrow_mut().finish())).into_stream().map(stream::iter_ok).flatten()
+  );
+
+  let mut seen: HashMap<[u8; 32], u64> = HashMap::new();
+  let records = chunks.map(move |chunk| {
+    let hash = *blake3::hash(&chunk).as_bytes();
+    let record = match seen.get(&hash) {
+      Some(&index) => {
+        let mut reference = vec![ RECORD_REFERENCE ];
+        reference.extend(zint::encode_packed_int(index));
+        vec![ Bytes::from(reference) ]
+      }
+      None => {
+        let index = seen.len() as u64;
+        seen.insert(hash, index);
+        vec![ CHUNK_TAG.clone(), chunk ]
+      }
+    };
+    stream::once::<_, io::Error>(Ok(record))
+  });
+  make_bottle_from_stream(BottleType::Dedup, &header, records)
+}
+
+/// Read a dedup bottle back into the original stream.
+///
+/// Every unique chunk is kept in memory until the end, since a reference
+/// may point back to any of them.
+pub fn reassemble_bottle<S>(s: S)
+  -> impl Future<Item = (Header, impl Stream<Item = Bytes, Error = io::Error>), Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  reassemble_bottle_with_limits(s, DecodeLimits::default())
+}
+
+// every chunk is kept, for references, so they all count toward
+// `max_buffered_bytes`.
+pub(crate) fn reassemble_bottle_with_limits<S>(s: S, limits: DecodeLimits)
+  -> impl Future<Item = (Header, impl Stream<Item = Bytes, Error = io::Error>), Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  read_bottle_with_options(s, ReadOptions { limits, ..ReadOptions::default() }).and_then(move |( btype, header, children )| {
+    if btype != BottleType::Dedup { return Err(not_dedup_error(btype)) }
+    let mut chunks: Vec<Bytes> = Vec::new();
+    let mut stored: u64 = 0;
+    let s = children.and_then(move |child| concat_limited(child, limits)).and_then(move |record| {
+      if record.is_empty() { return Err(bad_record_error()) }
+      match record[0] {
+        RECORD_CHUNK => {
+          let chunk = record.slice_from(1);
+          stored += chunk.len() as u64;
+          limits.check_buffered(stored)?;
+          chunks.push(chunk.clone());
+          Ok(chunk)
+        }
+        RECORD_REFERENCE => {
+          let index = zint::decode_packed_int(&mut &record[1..], record.len() - 1)?;
+          chunks.get(index as usize).cloned().ok_or_else(|| bad_reference_error(index))
+        }
+        _ => Err(bad_record_error())
+      }
+    });
+    Ok(( header, s ))
+  })
+}
+
+// buffers data until a chunk boundary is found, using a gear hash that
+// only looks at the bytes since the start of the chunk.
+struct Chunker {
+  pending: Vec<u8>,
+  // how far into the current chunk we've hashed
+  scanned: usize,
+  hash: u64
+}
+
+impl Chunker {
+  fn new() -> Chunker {
+    Chunker { pending: Vec::new(), scanned: 0, hash: 0 }
+  }
+
+  fn push(&mut self, buffers: Vec<Bytes>) -> Vec<Bytes> {
+    for b in buffers.iter() { self.pending.extend_from_slice(b) }
+    let mut cuts = Vec::new();
+    let mut start = 0;
+    while let Some(size) = self.scan(start) {
+      start += size;
+      cuts.push(start);
+    }
+    if cuts.is_empty() { return Vec::new() }
+
+    let all = Bytes::from(mem::take(&mut self.pending));
+    self.pending = all[start..].to_vec();
+    let mut prev = 0;
+    cuts.into_iter().map(|cut| {
+      let chunk = all.slice(prev, cut);
+      prev = cut;
+      chunk
+    }).collect()
+  }
+
+  fn finish(&mut self) -> Vec<Bytes> {
+    if self.pending.is_empty() { return Vec::new() }
+    vec![ Bytes::from(mem::take(&mut self.pending)) ]
+  }
+
+  // find the end of the chunk starting at `start`, if we have enough data.
+  fn scan(&mut self, start: usize) -> Option<usize> {
+    while start + self.scanned < self.pending.len() {
+      let size = self.scanned + 1;
+      // nothing before the minimum chunk size can be a boundary, so don't
+      // bother hashing it.
+      let cut = if size > MIN_CHUNK {
+        self.hash = (self.hash << 1).wrapping_add(GEAR[self.pending[start + self.scanned] as usize]);
+        let mask = if size < AVG_CHUNK { MASK_SMALL } else { MASK_LARGE };
+        self.hash & mask == 0 || size >= MAX_CHUNK
+      } else {
+        false
+      };
+      self.scanned = size;
+      if cut {
+        self.scanned = 0;
+        self.hash = 0;
+        return Some(size);
+      }
+    }
+    None
+  }
+}
+
+
+// ----- errors
+
+fn not_dedup_error(btype: BottleType) -> io::Error {
+  BottleError::WrongType { expected: BottleType::Dedup, found: btype }.into()
+}
+
+fn bad_record_error() -> io::Error {
+  BottleError::BadChunkRecord.into()
+}
+
+fn bad_reference_error(index: u64) -> io::Error {
+  BottleError::BadChunkReference(index).into()
+}
@@ -0,0 +1,115 @@
+use bytes::Bytes;
+use futures::{Future, Stream, future, stream};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+
+use archive::{ArchiveReader};
+use file_bottle::{FileMetadata};
+use hashing::{Hasher};
+
+/// How an entry changed between two archives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+  Added,
+  Removed,
+  /// the contents changed, or it's a different kind of thing now (a folder
+  /// became a file, or a symlink points somewhere else).
+  Modified,
+  /// the contents are the same, but the mode, owner, or times changed.
+  MetadataOnly
+}
+
+/// One path that's different in the second archive. `old` is missing for
+/// an added entry, and `new` for a removed one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiffEntry {
+  pub path: PathBuf,
+  pub change: Change,
+  pub old: Option<FileMetadata>,
+  pub new: Option<FileMetadata>
+}
+
+/// Compare two archives that aren't encrypted. (Use `diff_archives` to
+/// supply keys.)
+pub fn diff_bottles<A, B>(a: A, b: B) -> impl Stream<Item = DiffEntry, Error = io::Error>
+  where
+    A: Stream<Item = Bytes, Error = io::Error> + 'static,
+    B: Stream<Item = Bytes, Error = io::Error> + 'static
+{
+  diff_archives(( ArchiveReader::new(), a ), ( ArchiveReader::new(), b ))
+}
+
+/*
+ * Compare two archives by path, and the hash of each file's contents,
+ * without extracting either. Each archive is read once, start to finish,
+ * and only the metadata and a digest of each entry are kept. Changes are
+ * emitted in path order, once both are read. Access times, and how the
+ * contents were stored (sparse, with a CRC32C, or as a reference to a
+ * parent archive), don't count as changes.
+ */
+pub fn diff_archives<A, B>(a: ( ArchiveReader, A ), b: ( ArchiveReader, B ))
+  -> impl Stream<Item = DiffEntry, Error = io::Error>
+  where
+    A: Stream<Item = Bytes, Error = io::Error> + 'static,
+    B: Stream<Item = Bytes, Error = io::Error> + 'static
+{
+  let ( reader_a, a ) = a;
+  let ( reader_b, b ) = b;
+  summarize(reader_a, a).and_then(move |old| {
+    summarize(reader_b, b).map(move |new| stream::iter_ok(compare(old, new)))
+  }).flatten_stream()
+}
+
+type Summary = BTreeMap<PathBuf, ( FileMetadata, Bytes )>;
+
+// the metadata and content digest of every entry, by path.
+fn summarize<S>(reader: ArchiveReader, s: S) -> impl Future<Item = Summary, Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error> + 'static
+{
+  reader.entries(s).and_then(|entry| -> Box<dyn Future<Item = ( PathBuf, ( FileMetadata, Bytes ) ), Error = io::Error>> {
+    let path = entry.path;
+    let metadata = entry.metadata;
+    // a reference to a parent archive has the digest instead of contents.
+    if let Some(digest) = metadata.reference.clone() { return Box::new(future::ok(( path, ( metadata, digest ) ))) }
+    Box::new(entry.content.fold(Hasher::blake3(), |mut hasher, data| {
+      hasher.update(&data);
+      Ok::<_, io::Error>(hasher)
+    }).map(move |hasher| ( path, ( metadata, hasher.finish() ) )))
+  }).collect().map(|entries| entries.into_iter().collect())
+}
+
+fn compare(old: Summary, mut new: Summary) -> Vec<DiffEntry> {
+  let mut changes = Vec::new();
+  for ( path, ( old_metadata, old_digest ) ) in old {
+    let change = match new.remove(&path) {
+      None => Some(( Change::Removed, None )),
+      Some(( new_metadata, new_digest )) => {
+        if old_digest != new_digest || !same_kind(&old_metadata, &new_metadata) {
+          Some(( Change::Modified, Some(new_metadata) ))
+        } else if comparable(&old_metadata) != comparable(&new_metadata) {
+          Some(( Change::MetadataOnly, Some(new_metadata) ))
+        } else {
+          None
+        }
+      }
+    };
+    if let Some(( change, new_metadata )) = change {
+      changes.push(DiffEntry { path, change, old: Some(old_metadata), new: new_metadata });
+    }
+  }
+  for ( path, ( new_metadata, _ ) ) in new {
+    changes.push(DiffEntry { path, change: Change::Added, old: None, new: Some(new_metadata) });
+  }
+  changes.sort_by(|x, y| x.path.cmp(&y.path));
+  changes
+}
+
+fn same_kind(a: &FileMetadata, b: &FileMetadata) -> bool {
+  a.folder == b.folder && a.symlink == b.symlink && a.hardlink == b.hardlink
+}
+
+// the metadata, without the parts that don't count as a change.
+fn comparable(metadata: &FileMetadata) -> FileMetadata {
+  FileMetadata { accessed_nanos: None, sparse: false, crc32c: false, reference: None, ..metadata.clone() }
+}
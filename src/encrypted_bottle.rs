@@ -0,0 +1,609 @@
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use aes_gcm::aead::{Aead, OsRng, Payload};
+use aes_gcm::aead::rand_core::RngCore;
+use argon2::{Algorithm, Argon2, Params, Version};
+use bytes::Bytes;
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use futures::{Async, Future, Poll, Stream};
+use futures::stream::Fuse;
+use hkdf::Hkdf;
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, RwLock};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use bottle::{BottleType, DecodeLimits, ReadOptions, encode_bottle_header, make_bottle, read_bottle_with_options};
+use bottle_header::{Header};
+use error::BottleError;
+use buffered_stream::{buffer_stream};
+use stream_helpers::flatten_bytes;
+use to_hex::{FromHex, ToHex};
+
+pub(crate) const FIELD_ENCRYPTION_TYPE: u8 = 0;
+const FIELD_KDF_ITERATIONS: u8 = 1;
+const FIELD_NONCE_PREFIX: u8 = 2;
+const FIELD_KDF_TYPE: u8 = 3;
+const FIELD_KDF_MEMORY: u8 = 4;
+const FIELD_KDF_LANES: u8 = 5;
+
+const FIELD_RECIPIENTS: u8 = 0;
+const FIELD_KDF_SALT: u8 = 1;
+const FIELD_WRAPPED_KEYS: u8 = 2;
+
+const FIELD_HEADER_BOUND: u8 = 0;
+
+/*
+ * The plaintext is cut into segments, and each is sealed separately, so a
+ * reader never has to hold more than one segment. The nonce for each is
+ * the 7-byte random prefix from the header, a 4-byte segment counter, and
+ * a byte marking the final segment, so segments can't be reordered, and
+ * the stream can't be cut short at a segment boundary. (A cipher with a
+ * nonce longer than 12 bytes gets zeros between the prefix and counter.)
+ *
+ * Every segment is also sealed with the bottle's cap and header as
+ * associated data, so none of the header (recipients, key parameters, or
+ * the nonce) can be changed without breaking every tag. Bottles from
+ * before this don't have the `FIELD_HEADER_BOUND` flag, and are opened
+ * without it; removing the flag from a newer bottle breaks the tags too.
+ */
+const SEGMENT_SIZE: usize = 64 * 1024;
+const AES_GCM_NONCE_SIZE: usize = 12;
+const AES_GCM_TAG_SIZE: usize = 16;
+const XCHACHA_NONCE_SIZE: usize = 24;
+const XCHACHA_TAG_SIZE: usize = 16;
+const NONCE_PREFIX_SIZE: usize = 7;
+const SALT_SIZE: usize = 16;
+const WRAP_INFO: &[u8] = b"4bottle key wrap";
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+// argon2id defaults (memory is in KiB), and the most a bottle may ask a
+// reader to spend on one key.
+const ARGON2_MEMORY: u32 = 19 * 1024;
+const ARGON2_ITERATIONS: u32 = 2;
+const ARGON2_LANES: u32 = 1;
+const MAX_ARGON2_MEMORY: u64 = 1024 * 1024;
+const MAX_ARGON2_ITERATIONS: u64 = 64;
+const MAX_ARGON2_LANES: u64 = 16;
+const MAX_PBKDF2_ITERATIONS: u64 = 10_000_000;
+
+/// Every cipher takes a key this big.
+pub const KEY_SIZE: usize = 32;
+
+/// Ids below this are reserved for ciphers built into the crate.
+pub const FIRST_CUSTOM_CIPHER: u8 = 16;
+
+lazy_static! {
+  static ref CIPHERS: RwLock<HashMap<u8, Arc<dyn Cipher>>> = {
+    let mut ciphers: HashMap<u8, Arc<dyn Cipher>> = HashMap::new();
+    for cipher in [ Arc::new(Aes256GcmCipher) as Arc<dyn Cipher>, Arc::new(XChaCha20Poly1305Cipher) ] {
+      ciphers.insert(cipher.id(), cipher);
+    }
+    RwLock::new(ciphers)
+  };
+}
+
+// key derivation functions, as stored in a header field. bottles from
+// before argon2id don't have the field, and use PBKDF2.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum KdfType {
+  Pbkdf2Sha256 = 0,
+  Argon2id = 1
+}
+
+/// An AEAD cipher, stored in an encrypted bottle's header by its id. The
+/// built-in ciphers are registered this way too, and hardware-backed or
+/// FIPS-validated ones can be added with `register_cipher`.
+pub trait Cipher: Send + Sync {
+  /// The id stored in an encrypted bottle's header.
+  fn id(&self) -> u8;
+
+  /// A short name, like "aes-256-gcm", for command lines and messages.
+  fn name(&self) -> &str;
+
+  /// How many bytes are in a nonce: at least 12.
+  fn nonce_size(&self) -> usize;
+
+  /// How many bytes sealing adds to each segment.
+  fn tag_size(&self) -> usize;
+
+  /// Get ready to seal and open with a `KEY_SIZE`-byte key.
+  fn with_key(&self, key: &[u8]) -> io::Result<Box<dyn CipherKey>>;
+}
+
+/// A `Cipher` holding its key. No nonce is ever used twice with one key.
+pub trait CipherKey {
+  /// The sealed data, with its tag.
+  fn seal(&self, nonce: &[u8], associated_data: &[u8], data: &[u8]) -> io::Result<Vec<u8>>;
+
+  /// The data, or `DecryptionFailed` if anything was altered.
+  fn open(&self, nonce: &[u8], associated_data: &[u8], data: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// Make a cipher available to every writer and reader, under its id,
+/// which must be `FIRST_CUSTOM_CIPHER` or more. Registering an id again
+/// replaces it. Readers only know an id once it's registered, so do this
+/// before reading bottles that use it.
+pub fn register_cipher<C: Cipher + 'static>(cipher: C) -> io::Result<EncryptionType> {
+  let id = cipher.id();
+  if id < FIRST_CUSTOM_CIPHER { return Err(reserved_encryption_type_error(id)) }
+  CIPHERS.write().unwrap().insert(id, Arc::new(cipher));
+  Ok(EncryptionType::Custom(id))
+}
+
+/// Find a registered cipher by its name.
+pub fn find_cipher(name: &str) -> Option<EncryptionType> {
+  CIPHERS.read().unwrap().values().find(|cipher| cipher.name() == name).map(|cipher| decode_id(cipher.id()))
+}
+
+// encryption types, as stored in a header field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EncryptionType {
+  Aes256Gcm,
+  XChaCha20Poly1305,
+  /// registered with `register_cipher`
+  Custom(u8)
+}
+
+impl EncryptionType {
+  /// The id stored in an encrypted bottle's header.
+  pub fn id(&self) -> u8 {
+    match *self {
+      EncryptionType::Aes256Gcm => 0,
+      EncryptionType::XChaCha20Poly1305 => 1,
+      EncryptionType::Custom(id) => id
+    }
+  }
+
+  /// The registered cipher, which is missing only for a custom id that
+  /// was never registered.
+  pub fn cipher(&self) -> io::Result<Arc<dyn Cipher>> {
+    let id = self.id();
+    CIPHERS.read().unwrap().get(&id).cloned().ok_or_else(|| unknown_encryption_type_error(id as u64))
+  }
+}
+
+fn decode_id(id: u8) -> EncryptionType {
+  match id {
+    0 => EncryptionType::Aes256Gcm,
+    1 => EncryptionType::XChaCha20Poly1305,
+    id => EncryptionType::Custom(id)
+  }
+}
+
+pub fn decode_encryption_type(n: u64) -> Result<EncryptionType, io::Error> {
+  match n {
+    n if n <= u8::MAX as u64 && CIPHERS.read().unwrap().contains_key(&(n as u8)) => Ok(decode_id(n as u8)),
+    _ => Err(unknown_encryption_type_error(n))
+  }
+}
+
+/// Where the key comes from: 32 raw bytes, or a passphrase to stretch with
+/// Argon2id (the salt and cost parameters are stored in the header).
+///
+/// To encrypt for several people, use `PublicKeys`: a random key is
+/// generated and wrapped for each X25519 public key, in the header. To
+/// decrypt, pass your `PrivateKey`, and each wrapped key is tried.
+#[derive(Clone)]
+pub enum KeySource {
+  Raw(Vec<u8>),
+  Passphrase(String),
+  PublicKeys(Vec<[u8; 32]>),
+  PrivateKey([u8; 32])
+}
+
+/// Make a new X25519 key pair for `KeySource::PublicKeys`: the private key,
+/// then the public key.
+pub fn generate_key_pair() -> ( [u8; 32], [u8; 32] ) {
+  let mut secret = [ 0u8; 32 ];
+  OsRng.fill_bytes(&mut secret);
+  ( secret, public_key(&secret) )
+}
+
+/// The X25519 public key for a private key.
+pub fn public_key(secret: &[u8; 32]) -> [u8; 32] {
+  PublicKey::from(&StaticSecret::from(*secret)).to_bytes()
+}
+
+/// What an encrypted bottle's header says about its key, for the callback
+/// passed to `decrypt_bottle`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncryptionInfo {
+  pub encryption_type: EncryptionType,
+  pub recipients: Vec<String>,
+  // if set, the bottle was sealed with a passphrase.
+  pub passphrase: bool,
+  // how many public keys the content key was wrapped for.
+  pub wrapped_keys: usize
+}
+
+/// Wrap a bottle (or any byte stream) in an AES-256-GCM encrypted bottle.
+/// Recipients are stored in the header as hints for whoever has to find
+/// the key. The header is authenticated along with the data: if any of it
+/// is altered, decryption fails.
+pub fn encrypt_bottle<S>(s: S, key: KeySource, recipients: Vec<String>)
+  -> io::Result<impl Stream<Item = Vec<Bytes>, Error = io::Error>>
+  where S: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  encrypt_bottle_with_cipher(s, key, recipients, EncryptionType::Aes256Gcm)
+}
+
+/// Like `encrypt_bottle`, with a choice of cipher.
+pub fn encrypt_bottle_with_cipher<S>(s: S, key: KeySource, recipients: Vec<String>, encryption_type: EncryptionType)
+  -> io::Result<impl Stream<Item = Vec<Bytes>, Error = io::Error>>
+  where S: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  let cipher = encryption_type.cipher()?;
+  let mut header = Header::new();
+  header.add_number(FIELD_ENCRYPTION_TYPE, encryption_type.id() as u64);
+  for r in recipients { header.try_add_string(FIELD_RECIPIENTS, &format!("recipient {:?}", r), r)? }
+
+  let key = match key {
+    KeySource::Raw(key) => key,
+    KeySource::Passphrase(passphrase) => {
+      let mut salt = [ 0u8; SALT_SIZE ];
+      OsRng.fill_bytes(&mut salt);
+      header.add_number(FIELD_KDF_TYPE, KdfType::Argon2id as u64);
+      header.add_number(FIELD_KDF_ITERATIONS, ARGON2_ITERATIONS as u64);
+      header.add_number(FIELD_KDF_MEMORY, ARGON2_MEMORY as u64);
+      header.add_number(FIELD_KDF_LANES, ARGON2_LANES as u64);
+      header.add_string(FIELD_KDF_SALT, salt.to_hex());
+      stretch_argon2(&passphrase, &salt, ARGON2_MEMORY, ARGON2_ITERATIONS, ARGON2_LANES)?
+    }
+    KeySource::PublicKeys(public_keys) => {
+      if public_keys.is_empty() { return Err(no_recipients_error()) }
+      let mut key = vec![ 0u8; KEY_SIZE ];
+      OsRng.fill_bytes(&mut key);
+      for public_key in public_keys {
+        header.try_add_string(FIELD_WRAPPED_KEYS, "wrapped key", wrap_key(&*cipher, &key, &public_key)?.to_hex())?;
+      }
+      key
+    }
+    KeySource::PrivateKey(_) => return Err(no_recipients_error())
+  };
+  let mut prefix = [ 0u8; 8 ];
+  OsRng.fill_bytes(&mut prefix[0 .. NONCE_PREFIX_SIZE]);
+  let prefix = u64::from_le_bytes(prefix);
+  header.add_number(FIELD_NONCE_PREFIX, prefix);
+  header.add_bool(FIELD_HEADER_BOUND);
+
+  let mut sealer = Sealer::new(&*cipher, &key, prefix, associated_data(&header)?)?;
+  let segments = mark_last(buffer_stream(s, SEGMENT_SIZE, true)).and_then(move |( segment, last )| {
+    sealer.seal(&flatten_bytes(segment), last).map(|b| vec![ b ])
+  });
+  Ok(make_bottle(BottleType::Encrypted, &header, vec![ segments ]))
+}
+
+/// Read an encrypted bottle, returning its header and the decrypted inner
+/// stream. `resolve` is called once, with what the header says about the
+/// key, and returns the key to use. If a segment fails to decrypt (or the
+/// header was altered), the stream fails with `InvalidData`.
+pub fn decrypt_bottle<S, F>(s: S, resolve: F)
+  -> impl Future<Item = (Header, impl Stream<Item = Bytes, Error = io::Error>), Error = io::Error>
+  where
+    S: Stream<Item = Bytes, Error = io::Error>,
+    F: FnOnce(&EncryptionInfo) -> io::Result<KeySource>
+{
+  decrypt_bottle_with_limits(s, resolve, DecodeLimits::default())
+}
+
+pub(crate) fn decrypt_bottle_with_limits<S, F>(s: S, resolve: F, limits: DecodeLimits)
+  -> impl Future<Item = (Header, impl Stream<Item = Bytes, Error = io::Error>), Error = io::Error>
+  where
+    S: Stream<Item = Bytes, Error = io::Error>,
+    F: FnOnce(&EncryptionInfo) -> io::Result<KeySource>
+{
+  read_bottle_with_options(s, ReadOptions { limits, ..ReadOptions::default() }).and_then(move |( btype, header, children )| {
+    if btype != BottleType::Encrypted { return Err(not_encrypted_error(btype)) }
+    let encryption_type = decode_encryption_type(header.get_number(FIELD_ENCRYPTION_TYPE).unwrap_or(0))?;
+    let cipher = encryption_type.cipher()?;
+    let salt = match header.get_string(FIELD_KDF_SALT) {
+      Some(hex) => Some(decode_salt(hex)?),
+      None => None
+    };
+    let wrapped_keys = header.get_strings(FIELD_WRAPPED_KEYS);
+    let info = EncryptionInfo {
+      encryption_type,
+      recipients: header.get_strings(FIELD_RECIPIENTS).iter().map(|s| s.to_string()).collect(),
+      passphrase: salt.is_some(),
+      wrapped_keys: wrapped_keys.len()
+    };
+
+    let key = match ( resolve(&info)?, salt ) {
+      ( KeySource::Raw(key), _ ) => key,
+      ( KeySource::Passphrase(passphrase), Some(salt) ) => stretch_from_header(&header, &passphrase, &salt)?,
+      ( KeySource::Passphrase(_), None ) => return Err(no_passphrase_error()),
+      ( KeySource::PrivateKey(secret), _ ) => unwrap_key(&*cipher, &wrapped_keys, &secret)?,
+      ( KeySource::PublicKeys(_), _ ) => return Err(no_matching_key_error())
+    };
+    let mut opener = Sealer::new(&*cipher, &key, header.get_number(FIELD_NONCE_PREFIX).unwrap_or(0), associated_data(&header)?)?;
+
+    let sealed = children.take(1).flatten().map(|b| vec![ b ]);
+    let s = mark_last(buffer_stream(sealed, SEGMENT_SIZE + cipher.tag_size(), true)).and_then(move |( segment, last )| {
+      opener.open(&flatten_bytes(segment), last)
+    });
+    Ok(( header, s ))
+  })
+}
+
+// the header is re-encoded from what was read, so it's the header as
+// understood that's checked, not the exact bytes.
+fn associated_data(header: &Header) -> io::Result<Vec<u8>> {
+  if !header.get_bool(FIELD_HEADER_BOUND) { return Ok(Vec::new()) }
+  encode_bottle_header(BottleType::Encrypted, header)
+}
+
+// the parameters came from a stranger, so make sure they won't tie us up
+// for hours (or eat all our memory) first.
+fn stretch_from_header(header: &Header, passphrase: &str, salt: &[u8]) -> io::Result<Vec<u8>> {
+  match header.get_number(FIELD_KDF_TYPE).unwrap_or(KdfType::Pbkdf2Sha256 as u64) {
+    0 => {
+      let iterations = header.get_number(FIELD_KDF_ITERATIONS).unwrap_or(PBKDF2_ITERATIONS as u64);
+      if iterations == 0 || iterations > MAX_PBKDF2_ITERATIONS { return Err(bad_kdf_error()) }
+      Ok(stretch_pbkdf2(passphrase, salt, iterations as u32))
+    }
+    1 => {
+      let memory = header.get_number(FIELD_KDF_MEMORY).ok_or_else(bad_kdf_error)?;
+      let iterations = header.get_number(FIELD_KDF_ITERATIONS).ok_or_else(bad_kdf_error)?;
+      let lanes = header.get_number(FIELD_KDF_LANES).ok_or_else(bad_kdf_error)?;
+      if memory > MAX_ARGON2_MEMORY || iterations > MAX_ARGON2_ITERATIONS || lanes > MAX_ARGON2_LANES {
+        return Err(bad_kdf_error());
+      }
+      stretch_argon2(passphrase, salt, memory as u32, iterations as u32, lanes as u32)
+    }
+    n => Err(unknown_kdf_error(n))
+  }
+}
+
+fn stretch_pbkdf2(passphrase: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
+  let mut key = vec![ 0u8; KEY_SIZE ];
+  pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, iterations, &mut key);
+  key
+}
+
+// argon2 checks the lower bounds itself (like memory of at least 8 KiB
+// per lane).
+fn stretch_argon2(passphrase: &str, salt: &[u8], memory: u32, iterations: u32, lanes: u32) -> io::Result<Vec<u8>> {
+  let params = Params::new(memory, iterations, lanes, Some(KEY_SIZE)).map_err(|_| bad_kdf_error())?;
+  let mut key = vec![ 0u8; KEY_SIZE ];
+  Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+    .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+    .map_err(|_| bad_kdf_error())?;
+  Ok(key)
+}
+
+// seal the content key (with the bottle's cipher) with a key agreed
+// between a throwaway key pair and the recipient's public key. the
+// wrapping key is never reused, so the nonce can be zero. a wrapped key is
+// the ephemeral public key, then the sealed content key.
+fn wrap_key(cipher: &dyn Cipher, key: &[u8], public_key: &[u8; 32]) -> io::Result<Vec<u8>> {
+  let ( ephemeral, ephemeral_public ) = generate_key_pair();
+  let shared = StaticSecret::from(ephemeral).diffie_hellman(&PublicKey::from(*public_key));
+  let wrapping_key = wrapping_key(cipher, shared.as_bytes(), &ephemeral_public, public_key)?;
+  let sealed = wrapping_key.seal(&vec![ 0u8; cipher.nonce_size() ], &[], key)?;
+  let mut wrapped = ephemeral_public.to_vec();
+  wrapped.extend_from_slice(&sealed);
+  Ok(wrapped)
+}
+
+// try our private key on each wrapped key, until one opens.
+fn unwrap_key(cipher: &dyn Cipher, wrapped_keys: &[&str], secret: &[u8; 32]) -> io::Result<Vec<u8>> {
+  let secret = StaticSecret::from(*secret);
+  let public_key = PublicKey::from(&secret).to_bytes();
+  let wrapped_key_size = 32 + KEY_SIZE + cipher.tag_size();
+  if wrapped_keys.iter().any(|hex| hex.len() != wrapped_key_size * 2 || !hex.chars().all(|c| c.is_ascii_hexdigit())) {
+    return Err(bad_wrapped_key_error());
+  }
+  for wrapped in wrapped_keys.iter().map(|hex| hex.from_hex()) {
+    let mut ephemeral_public = [ 0u8; 32 ];
+    ephemeral_public.copy_from_slice(&wrapped[0 .. 32]);
+    let shared = secret.diffie_hellman(&PublicKey::from(ephemeral_public));
+    let wrapping_key = wrapping_key(cipher, shared.as_bytes(), &ephemeral_public, &public_key)?;
+    if let Ok(key) = wrapping_key.open(&vec![ 0u8; cipher.nonce_size() ], &[], &wrapped[32 ..]) { return Ok(key) }
+  }
+  Err(no_matching_key_error())
+}
+
+fn wrapping_key(cipher: &dyn Cipher, shared: &[u8], ephemeral_public: &[u8; 32], public_key: &[u8; 32])
+  -> io::Result<Box<dyn CipherKey>>
+{
+  let mut salt = ephemeral_public.to_vec();
+  salt.extend_from_slice(public_key);
+  let mut key = [ 0u8; KEY_SIZE ];
+  Hkdf::<Sha256>::new(Some(&salt), shared).expand(WRAP_INFO, &mut key).map_err(|_| encrypt_error())?;
+  cipher.with_key(&key)
+}
+
+// `from_hex` trusts its input, and this came from a stranger.
+fn decode_salt(hex: &str) -> io::Result<Vec<u8>> {
+  if hex.len() != SALT_SIZE * 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) { return Err(bad_salt_error()) }
+  Ok(hex.from_hex())
+}
+
+// seals or opens consecutive segments.
+struct Sealer {
+  key: Box<dyn CipherKey>,
+  nonce_size: usize,
+  prefix: u64,
+  counter: u32,
+  associated_data: Vec<u8>
+}
+
+impl Sealer {
+  fn new(cipher: &dyn Cipher, key: &[u8], prefix: u64, associated_data: Vec<u8>) -> io::Result<Sealer> {
+    if key.len() != KEY_SIZE { return Err(bad_key_error()) }
+    Ok(Sealer { key: cipher.with_key(key)?, nonce_size: cipher.nonce_size(), prefix, counter: 0, associated_data })
+  }
+
+  fn next_nonce(&mut self, last: bool) -> io::Result<Vec<u8>> {
+    let n = self.nonce_size;
+    let mut nonce = vec![ 0u8; n ];
+    nonce[0 .. NONCE_PREFIX_SIZE].copy_from_slice(&self.prefix.to_le_bytes()[0 .. NONCE_PREFIX_SIZE]);
+    nonce[n - 5 .. n - 1].copy_from_slice(&self.counter.to_be_bytes());
+    nonce[n - 1] = if last { 1 } else { 0 };
+    self.counter = self.counter.checked_add(1).ok_or_else(too_many_segments_error)?;
+    Ok(nonce)
+  }
+
+  fn seal(&mut self, data: &[u8], last: bool) -> io::Result<Bytes> {
+    let nonce = self.next_nonce(last)?;
+    self.key.seal(&nonce, &self.associated_data, data).map(Bytes::from)
+  }
+
+  fn open(&mut self, data: &[u8], last: bool) -> io::Result<Bytes> {
+    let nonce = self.next_nonce(last)?;
+    self.key.open(&nonce, &self.associated_data, data).map(Bytes::from)
+  }
+}
+
+// ----- built-in ciphers
+
+struct Aes256GcmCipher;
+
+impl Cipher for Aes256GcmCipher {
+  fn id(&self) -> u8 { 0 }
+  fn name(&self) -> &str { "aes-256-gcm" }
+  fn nonce_size(&self) -> usize { AES_GCM_NONCE_SIZE }
+  fn tag_size(&self) -> usize { AES_GCM_TAG_SIZE }
+
+  fn with_key(&self, key: &[u8]) -> io::Result<Box<dyn CipherKey>> {
+    Ok(Box::new(Aes256Gcm::new_from_slice(key).map_err(|_| bad_key_error())?))
+  }
+}
+
+impl CipherKey for Aes256Gcm {
+  fn seal(&self, nonce: &[u8], associated_data: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    let payload = Payload { msg: data, aad: associated_data };
+    self.encrypt(Nonce::from_slice(nonce), payload).map_err(|_| encrypt_error())
+  }
+
+  fn open(&self, nonce: &[u8], associated_data: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    let payload = Payload { msg: data, aad: associated_data };
+    self.decrypt(Nonce::from_slice(nonce), payload).map_err(|_| decrypt_error())
+  }
+}
+
+struct XChaCha20Poly1305Cipher;
+
+impl Cipher for XChaCha20Poly1305Cipher {
+  fn id(&self) -> u8 { 1 }
+  fn name(&self) -> &str { "xchacha20-poly1305" }
+  fn nonce_size(&self) -> usize { XCHACHA_NONCE_SIZE }
+  fn tag_size(&self) -> usize { XCHACHA_TAG_SIZE }
+
+  fn with_key(&self, key: &[u8]) -> io::Result<Box<dyn CipherKey>> {
+    Ok(Box::new(XChaCha20Poly1305::new_from_slice(key).map_err(|_| bad_key_error())?))
+  }
+}
+
+impl CipherKey for XChaCha20Poly1305 {
+  fn seal(&self, nonce: &[u8], associated_data: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    let payload = Payload { msg: data, aad: associated_data };
+    self.encrypt(XNonce::from_slice(nonce), payload).map_err(|_| encrypt_error())
+  }
+
+  fn open(&self, nonce: &[u8], associated_data: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    let payload = Payload { msg: data, aad: associated_data };
+    self.decrypt(XNonce::from_slice(nonce), payload).map_err(|_| decrypt_error())
+  }
+}
+
+// pair each item with whether it's the last one. an empty stream still
+// gets one (empty) last item, so there's always a final segment to seal.
+fn mark_last<S>(s: S) -> LastMarked<S> where S: Stream<Item = Vec<Bytes>, Error = io::Error> {
+  LastMarked { stream: s.fuse(), pending: None, started: false, done: false }
+}
+
+struct LastMarked<S> where S: Stream<Item = Vec<Bytes>, Error = io::Error> {
+  stream: Fuse<S>,
+  pending: Option<Vec<Bytes>>,
+  started: bool,
+  done: bool
+}
+
+impl<S> Stream for LastMarked<S> where S: Stream<Item = Vec<Bytes>, Error = io::Error> {
+  type Item = ( Vec<Bytes>, bool );
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    if self.done { return Ok(Async::Ready(None)) }
+    loop {
+      match try_ready!(self.stream.poll()) {
+        Some(item) => {
+          self.started = true;
+          if let Some(previous) = self.pending.replace(item) {
+            return Ok(Async::Ready(Some(( previous, false ))));
+          }
+        }
+        None => {
+          self.done = true;
+          return Ok(Async::Ready(match self.pending.take() {
+            Some(item) => Some(( item, true )),
+            None if !self.started => Some(( Vec::new(), true )),
+            None => None
+          }));
+        }
+      }
+    }
+  }
+}
+
+
+// ----- errors
+
+fn unknown_encryption_type_error(n: u64) -> io::Error {
+  BottleError::UnknownEncryptionType(n).into()
+}
+
+fn reserved_encryption_type_error(n: u8) -> io::Error {
+  BottleError::ReservedEncryptionType(n).into()
+}
+
+fn not_encrypted_error(btype: BottleType) -> io::Error {
+  BottleError::WrongType { expected: BottleType::Encrypted, found: btype }.into()
+}
+
+fn bad_key_error() -> io::Error {
+  BottleError::BadKeyLength(KEY_SIZE).into()
+}
+
+fn bad_salt_error() -> io::Error {
+  BottleError::BadSalt.into()
+}
+
+fn unknown_kdf_error(n: u64) -> io::Error {
+  BottleError::UnknownKdf(n).into()
+}
+
+fn bad_kdf_error() -> io::Error {
+  BottleError::BadKdfParameters.into()
+}
+
+fn no_recipients_error() -> io::Error {
+  BottleError::NoRecipients.into()
+}
+
+fn bad_wrapped_key_error() -> io::Error {
+  BottleError::BadWrappedKey.into()
+}
+
+fn no_matching_key_error() -> io::Error {
+  BottleError::NoMatchingKey.into()
+}
+
+fn no_passphrase_error() -> io::Error {
+  BottleError::NoPassphrase.into()
+}
+
+fn too_many_segments_error() -> io::Error {
+  BottleError::TooManySegments.into()
+}
+
+fn encrypt_error() -> io::Error {
+  BottleError::EncryptionFailed.into()
+}
+
+fn decrypt_error() -> io::Error {
+  BottleError::DecryptionFailed.into()
+}
@@ -0,0 +1,383 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str;
+
+use bottle::{BottleType, MAX_HEADER_SIZE};
+use hashing::HashAlgorithm;
+
+/// Everything that can be wrong with a bottle, beyond plain I/O failures.
+///
+/// Streams still fail with `io::Error`, but each of these is carried inside
+/// one (with a matching `ErrorKind`), so `BottleError::find` can get it
+/// back out for code that needs to tell them apart.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum BottleError {
+  // bottle framing
+  BadMagic,
+  BadVersion { found: u8, extra: u8 },
+  UnknownType(u8),
+  HeaderTooLarge(usize),
+  HeaderFull { field: String, size: usize, remaining: usize },
+  TruncatedStream,
+  TruncatedAt { offset: u64, context: TruncationContext },
+  TruncatedLength { expected: usize, got: usize },
+  UnexpectedEnd,
+  FrameOverflow(u64),
+  PackedIntTooLong(usize),
+  LimitExceeded(u64),
+  InvalidFrameSize { min: usize, max: usize },
+  StreamCountMismatch { expected: u64, found: u64 },
+  BadStreamId(u64),
+  Interleaved,
+  DecodeLimitExceeded { limit: DecodeLimit, size: u64, max: u64 },
+
+  // headers
+  TruncatedHeader,
+  TooManyFields(usize),
+  BooleanHasContent,
+  NumberTooLong,
+  UnknownFieldKind,
+  BadUtf8(#[cfg_attr(feature = "serde", serde(with = "::serde_support::utf8_error"))] str::Utf8Error),
+
+  // a bottle of the wrong type was passed to a reader
+  WrongType { expected: BottleType, found: BottleType },
+  UnexpectedType(BottleType),
+  NotAppendable(BottleType),
+
+  // layers
+  UnknownCompressionType(u64),
+  ReservedCompressionType(u8),
+  BadCompressionLevel(i32),
+  TruncatedCompression,
+  UnknownEncryptionType(u64),
+  ReservedEncryptionType(u8),
+  BadKeyLength(usize),
+  BadSalt,
+  UnknownKdf(u64),
+  BadKdfParameters,
+  NoPassphrase,
+  NoKey,
+  NoRecipients,
+  BadWrappedKey,
+  NoMatchingKey,
+  KeyNotReusable,
+  TooManySegments,
+  EncryptionFailed,
+  DecryptionFailed,
+  UnknownHashAlgorithm(u64),
+  ReservedHashAlgorithm(u64),
+  WrongDigestSize { algorithm: HashAlgorithm, expected: usize, found: usize },
+  MissingHashStream,
+  Signed,
+  NotSigned,
+  HashMismatch,
+  DigestCanceled,
+
+  // files
+  MissingFilename,
+  UnsafeFilename(String),
+  UnsafeLink(String),
+  BadXattr(String),
+  XattrTooLarge(String),
+  XattrsNotSupported,
+  LinkTargetNotFound(PathBuf),
+  NoFilename(PathBuf),
+  NotAFolder(PathBuf),
+  AlreadyExists(PathBuf),
+  NothingToArchive,
+  BadPattern(String),
+  BadCheckpoint,
+  CheckpointMismatch,
+  BadSparseMap,
+  FileChanged(PathBuf),
+  MissingChecksum,
+  ChecksumMismatch { expected: u32, found: u32 },
+  BadReference(String),
+  MissingParentEntry(PathBuf),
+  BadManifest,
+
+  // indexes
+  NoIndex,
+  BadIndex,
+  NoSuchEntry(usize),
+  TrailingData,
+
+  // volumes
+  VolumeTooSmall(usize),
+  UnfinishedVolume,
+  VolumeOutOfOrder { expected: u64, found: u64 },
+  WrongVolumeSet,
+  ExtraVolume,
+  MissingVolume,
+  VolumeSizeMismatch { expected: u64, got: u64 },
+
+  // dedup
+  BadChunkRecord,
+  BadChunkReference(u64),
+
+  // parity
+  BadShardCount { data_shards: usize, parity_shards: usize },
+  BadShardSize(u64),
+  BadParityStripe(u64),
+  ParityExhausted(u64),
+
+  // http
+  BadUrl(String),
+  HttpStatus(u16),
+  BadHttpResponse,
+  RangesNotSupported,
+  UploadFailed(String),
+  TooManyParts(usize)
+}
+
+/// Which of the `DecodeLimits` a bottle went over, for
+/// `BottleError::DecodeLimitExceeded`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum DecodeLimit {
+  HeaderSize,
+  FrameSize,
+  NestingDepth,
+  BufferedBytes,
+  OpenStreams
+}
+
+/// What a reader was in the middle of when its source ended, for
+/// `BottleError::TruncatedAt`. Child streams are counted from 0.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum TruncationContext {
+  Header,
+  BetweenStreams,
+  FrameLength { child: usize },
+  FrameData { child: usize }
+}
+
+impl BottleError {
+  /// The `BottleError` inside an `io::Error`, if there is one.
+  pub fn find(e: &io::Error) -> Option<&BottleError> {
+    e.get_ref().and_then(|inner| inner.downcast_ref::<BottleError>())
+  }
+
+  pub fn kind(&self) -> io::ErrorKind {
+    match *self {
+      BottleError::TruncatedStream |
+      BottleError::TruncatedAt { .. } |
+      BottleError::TruncatedLength { .. } |
+      BottleError::TruncatedHeader |
+      BottleError::TruncatedCompression |
+      BottleError::DigestCanceled => io::ErrorKind::UnexpectedEof,
+      BottleError::UnexpectedEnd |
+      BottleError::PackedIntTooLong(_) |
+      BottleError::LimitExceeded(_) |
+      BottleError::TooManyFields(_) |
+      BottleError::BooleanHasContent |
+      BottleError::NumberTooLong |
+      BottleError::UnexpectedType(_) |
+      BottleError::BadSalt |
+      BottleError::UnknownKdf(_) |
+      BottleError::BadKdfParameters |
+      BottleError::BadWrappedKey |
+      BottleError::DecryptionFailed |
+      BottleError::MissingHashStream |
+      BottleError::WrongDigestSize { .. } |
+      BottleError::HashMismatch |
+      BottleError::MissingFilename |
+      BottleError::UnsafeFilename(_) |
+      BottleError::UnsafeLink(_) |
+      BottleError::BadXattr(_) |
+      BottleError::NoIndex |
+      BottleError::BadIndex |
+      BottleError::TrailingData |
+      BottleError::StreamCountMismatch { .. } |
+      BottleError::BadStreamId(_) |
+      BottleError::DecodeLimitExceeded { .. } |
+      BottleError::BadCheckpoint |
+      BottleError::CheckpointMismatch |
+      BottleError::BadSparseMap |
+      BottleError::MissingChecksum |
+      BottleError::ChecksumMismatch { .. } |
+      BottleError::BadReference(_) |
+      BottleError::BadManifest |
+      BottleError::VolumeOutOfOrder { .. } |
+      BottleError::WrongVolumeSet |
+      BottleError::ExtraVolume |
+      BottleError::VolumeSizeMismatch { .. } |
+      BottleError::BadChunkRecord |
+      BottleError::BadChunkReference(_) |
+      BottleError::BadShardSize(_) |
+      BottleError::BadParityStripe(_) |
+      BottleError::ParityExhausted(_) |
+      BottleError::BadHttpResponse => io::ErrorKind::InvalidData,
+      BottleError::MissingVolume |
+      BottleError::FileChanged(_) => io::ErrorKind::UnexpectedEof,
+      BottleError::AlreadyExists(_) => io::ErrorKind::AlreadyExists,
+      BottleError::LinkTargetNotFound(_) |
+      BottleError::MissingParentEntry(_) => io::ErrorKind::NotFound,
+      BottleError::Interleaved |
+      BottleError::XattrsNotSupported |
+      BottleError::RangesNotSupported => io::ErrorKind::Unsupported,
+      BottleError::HttpStatus(_) |
+      BottleError::UploadFailed(_) => io::ErrorKind::Other,
+      _ => io::ErrorKind::InvalidInput
+    }
+  }
+}
+
+impl fmt::Display for BottleError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      BottleError::BadMagic => write!(f, "Incorrect magic (not a 4bottle archive)"),
+      BottleError::BadVersion { found, extra } => write!(f, "Incompatible version: {}, {}", found, extra),
+      BottleError::UnknownType(btype) => write!(f, "Unknown bottle type: {}", btype),
+      BottleError::HeaderTooLarge(size) => write!(f, "Header too large: {} bytes (limit {})", size, MAX_HEADER_SIZE),
+      BottleError::HeaderFull { ref field, size, remaining } => {
+        write!(f, "No room in the header for {}: it needs {} bytes, and {} are left (limit {})", field, size, remaining, MAX_HEADER_SIZE)
+      }
+      BottleError::TruncatedStream => write!(f, "Truncated bottle"),
+      BottleError::TruncatedAt { offset, context } => write!(f, "Truncated bottle at byte {}, {}", offset, context),
+      BottleError::TruncatedLength { expected, got } => write!(f, "Truncated length: expected {} bytes, got {}", expected, got),
+      BottleError::UnexpectedEnd => write!(f, "End of all streams inside a stream"),
+      BottleError::FrameOverflow(n) => write!(f, "Frame too long: {} bytes", n),
+      BottleError::PackedIntTooLong(n) => write!(f, "Packed int too long: {} bytes", n),
+      BottleError::LimitExceeded(max) => write!(f, "Stream exceeded limit of {} bytes", max),
+      BottleError::InvalidFrameSize { min, max } => write!(f, "Invalid frame sizes: min {}, max {}", min, max),
+      BottleError::StreamCountMismatch { expected, found } => write!(f, "Expected {} child streams, found {}", expected, found),
+      BottleError::BadStreamId(id) => write!(f, "Interleaved frame for a stream that isn't open: {}", id),
+      BottleError::Interleaved => write!(f, "Interleaved child streams can only be read by the streaming reader"),
+      BottleError::DecodeLimitExceeded { limit, size, max } => write!(f, "Bottle is over the {} limit: {} (limit {})", limit, size, max),
+      BottleError::TruncatedHeader => write!(f, "Truncated header"),
+      BottleError::TooManyFields(max) => write!(f, "Too many header fields (limit {})", max),
+      BottleError::BooleanHasContent => write!(f, "Boolean field has content"),
+      BottleError::NumberTooLong => write!(f, "Number field is longer than 8 bytes"),
+      BottleError::UnknownFieldKind => write!(f, "Unknown field kind"),
+      BottleError::BadUtf8(ref e) => write!(f, "{}", e),
+      BottleError::WrongType { expected, found } => write!(f, "Not {} bottle: {:?}", type_name(expected), found),
+      BottleError::UnexpectedType(btype) => write!(f, "Unexpected bottle type in archive: {:?}", btype),
+      BottleError::NotAppendable(btype) => write!(f, "Can't append child streams to this {:?} bottle", btype),
+      BottleError::UnknownCompressionType(n) => write!(f, "Unknown compression type: {}", n),
+      BottleError::ReservedCompressionType(n) => write!(f, "Compression type {} is reserved (custom ids start at 16)", n),
+      BottleError::BadCompressionLevel(n) => write!(f, "Invalid compression level: {}", n),
+      BottleError::TruncatedCompression => write!(f, "Truncated compressed stream"),
+      BottleError::UnknownEncryptionType(n) => write!(f, "Unknown encryption type: {}", n),
+      BottleError::ReservedEncryptionType(n) => write!(f, "Encryption type {} is reserved (custom ids start at 16)", n),
+      BottleError::BadKeyLength(size) => write!(f, "Key must be {} bytes", size),
+      BottleError::BadSalt => write!(f, "Invalid passphrase salt"),
+      BottleError::UnknownKdf(n) => write!(f, "Unknown key derivation function: {}", n),
+      BottleError::BadKdfParameters => write!(f, "Invalid key derivation parameters"),
+      BottleError::NoPassphrase => write!(f, "Bottle wasn't encrypted with a passphrase"),
+      BottleError::NoKey => write!(f, "Archive is encrypted, but no key was given"),
+      BottleError::NoRecipients => write!(f, "No public keys to encrypt for"),
+      BottleError::BadWrappedKey => write!(f, "Invalid wrapped key"),
+      BottleError::NoMatchingKey => write!(f, "Bottle wasn't encrypted for this key"),
+      BottleError::KeyNotReusable => write!(f, "A private key can't be used to encrypt again; give a new key"),
+      BottleError::TooManySegments => write!(f, "Too many segments to encrypt"),
+      BottleError::EncryptionFailed => write!(f, "Encryption failed"),
+      BottleError::DecryptionFailed => write!(f, "Decryption failed (wrong key, or corrupted data)"),
+      BottleError::UnknownHashAlgorithm(n) => write!(f, "Unknown hash algorithm: {}", n),
+      BottleError::ReservedHashAlgorithm(n) => write!(f, "Hash algorithm id {} is reserved (custom ids start at 16)", n),
+      BottleError::WrongDigestSize { algorithm, expected, found } => {
+        write!(f, "Digest is {} bytes, but {:?} digests are {} (wrong hash algorithm?)", found, algorithm, expected)
+      }
+      BottleError::MissingHashStream => write!(f, "Hashed bottle is missing a stream"),
+      BottleError::Signed => write!(f, "Hashed bottle is signed (use a verifier)"),
+      BottleError::NotSigned => write!(f, "Hashed bottle is not signed"),
+      BottleError::HashMismatch => write!(f, "Hash mismatch"),
+      BottleError::DigestCanceled => write!(f, "Stream ended before its digest was finished"),
+      BottleError::MissingFilename => write!(f, "File bottle has no filename"),
+      BottleError::UnsafeFilename(ref filename) => write!(f, "Unsafe filename in bottle: {:?}", filename),
+      BottleError::UnsafeLink(ref target) => write!(f, "Unsafe link target in bottle: {:?}", target),
+      BottleError::BadXattr(ref field) => write!(f, "Invalid extended attribute: {:?}", field),
+      BottleError::XattrTooLarge(ref name) => write!(f, "Extended attribute too large to store: {}", name),
+      BottleError::XattrsNotSupported => write!(f, "Extended attributes need the xattr feature"),
+      BottleError::LinkTargetNotFound(ref path) => write!(f, "Link target wasn't extracted: {}", path.display()),
+      BottleError::NoFilename(ref path) => write!(f, "No filename in path: {}", path.display()),
+      BottleError::NotAFolder(ref path) => write!(f, "Not a folder: {}", path.display()),
+      BottleError::AlreadyExists(ref path) => write!(f, "Already exists: {}", path.display()),
+      BottleError::NothingToArchive => write!(f, "Nothing to archive"),
+      BottleError::BadPattern(ref glob) => write!(f, "Invalid pattern: {:?}", glob),
+      BottleError::BadCheckpoint => write!(f, "Invalid checkpoint"),
+      BottleError::CheckpointMismatch => write!(f, "Partial archive doesn't match the checkpoint"),
+      BottleError::BadSparseMap => write!(f, "Invalid sparse file map"),
+      BottleError::FileChanged(ref path) => write!(f, "File changed while it was read: {}", path.display()),
+      BottleError::MissingChecksum => write!(f, "File bottle is missing its CRC32C"),
+      BottleError::ChecksumMismatch { expected, found } => write!(f, "CRC32C mismatch: expected {:08x}, found {:08x}", expected, found),
+      BottleError::BadReference(ref s) => write!(f, "Bad reference to a parent archive: {}", s),
+      BottleError::MissingParentEntry(ref path) => write!(f, "Parent archive has no copy of {}", path.display()),
+      BottleError::BadManifest => write!(f, "Invalid manifest"),
+      BottleError::NoIndex => write!(f, "Bottle has no index"),
+      BottleError::BadIndex => write!(f, "Bottle index is damaged"),
+      BottleError::NoSuchEntry(n) => write!(f, "No entry {} in index", n),
+      BottleError::TrailingData => write!(f, "Data after the end of the bottle"),
+      BottleError::VolumeTooSmall(size) => write!(f, "Volume size {} is too small", size),
+      BottleError::UnfinishedVolume => write!(f, "Previous volume wasn't read to the end"),
+      BottleError::VolumeOutOfOrder { expected, found } => write!(f, "Expected volume {}, got volume {}", expected, found),
+      BottleError::WrongVolumeSet => write!(f, "Volume is from a different set"),
+      BottleError::ExtraVolume => write!(f, "Volume after the last one"),
+      BottleError::MissingVolume => write!(f, "Missing the last volume"),
+      BottleError::VolumeSizeMismatch { expected, got } => write!(f, "Volume set should have {} bytes, got {}", expected, got),
+      BottleError::BadChunkRecord => write!(f, "Invalid dedup chunk record"),
+      BottleError::BadChunkReference(n) => write!(f, "Reference to unknown chunk {}", n),
+      BottleError::BadShardCount { data_shards, parity_shards } => {
+        write!(f, "Invalid shard count: {} data, {} parity", data_shards, parity_shards)
+      }
+      BottleError::BadShardSize(n) => write!(f, "Invalid parity shard size {}", n),
+      BottleError::BadParityStripe(n) => write!(f, "Invalid parity stripe {}", n),
+      BottleError::ParityExhausted(n) => write!(f, "Too many damaged shards to repair stripe {}", n),
+      BottleError::BadUrl(ref url) => write!(f, "Invalid URL: {}", url),
+      BottleError::HttpStatus(status) => write!(f, "HTTP request failed: {}", status),
+      BottleError::BadHttpResponse => write!(f, "Invalid HTTP response"),
+      BottleError::RangesNotSupported => write!(f, "Server doesn't support range requests"),
+      BottleError::UploadFailed(ref code) => write!(f, "Upload failed: {}", code),
+      BottleError::TooManyParts(max) => write!(f, "Too many parts to upload (limit {}); use bigger parts", max)
+    }
+  }
+}
+
+impl error::Error for BottleError {}
+
+impl fmt::Display for TruncationContext {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      TruncationContext::Header => write!(f, "in the header"),
+      TruncationContext::BetweenStreams => write!(f, "between child streams"),
+      TruncationContext::FrameLength { child } => write!(f, "in a frame length of child stream {}", child),
+      TruncationContext::FrameData { child } => write!(f, "in a frame of child stream {}", child)
+    }
+  }
+}
+
+impl fmt::Display for DecodeLimit {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      DecodeLimit::HeaderSize => write!(f, "header size"),
+      DecodeLimit::FrameSize => write!(f, "frame size"),
+      DecodeLimit::NestingDepth => write!(f, "nesting depth"),
+      DecodeLimit::BufferedBytes => write!(f, "buffered bytes"),
+      DecodeLimit::OpenStreams => write!(f, "open streams")
+    }
+  }
+}
+
+impl From<BottleError> for io::Error {
+  fn from(e: BottleError) -> io::Error {
+    io::Error::new(e.kind(), e)
+  }
+}
+
+fn type_name(btype: BottleType) -> &'static str {
+  match btype {
+    BottleType::File => "a file",
+    BottleType::Hashed => "a hashed",
+    BottleType::Encrypted => "an encrypted",
+    BottleType::Compressed => "a compressed",
+    BottleType::Index => "an index",
+    BottleType::Volume => "a volume",
+    BottleType::Dedup => "a dedup",
+    BottleType::Parity => "a parity",
+    BottleType::Test | BottleType::Test2 => "a test"
+  }
+}
@@ -0,0 +1,153 @@
+use glob::{MatchOptions, Pattern};
+use std::cell::Cell;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use archive::EntryInfo;
+use error::BottleError;
+
+type Callback = Rc<dyn Fn(&EntryInfo) -> Decision>;
+
+// `*` and `?` stay inside one path segment; `**` crosses them.
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+  case_sensitive: true,
+  require_literal_separator: true,
+  require_literal_leading_dot: false
+};
+
+/// What to do with an entry, from an `ExtractFilter` callback.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+  Extract,
+  /// Leave it out (for a folder, everything inside it too).
+  Skip,
+  /// Leave it out, and stop extracting: the rest of the bottle isn't read.
+  Stop
+}
+
+// `ExtractFilter::choose`: like `Decision`, but a folder can also be
+// searched without being extracted itself.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Choice {
+  Extract,
+  Search,
+  Skip,
+  Stop
+}
+
+/// Which entries `extract_bottle` should write, by path relative to the
+/// target folder (like "stuff/inner/c.txt").
+///
+/// An entry is picked if it matches any `include` glob or `prefix` (or
+/// there aren't any), and no `exclude` glob. Then the callback, if there
+/// is one, gets the final say. Folders that aren't picked are still
+/// searched for entries that are, unless they're excluded.
+///
+/// Skipped entries aren't decoded: their frames are passed over unread.
+#[derive(Clone, Default)]
+pub struct ExtractFilter {
+  include: Vec<Pattern>,
+  exclude: Vec<Pattern>,
+  prefixes: Vec<PathBuf>,
+  callback: Option<Callback>,
+  stopped: Rc<Cell<bool>>
+}
+
+impl ExtractFilter {
+  pub fn new() -> ExtractFilter {
+    ExtractFilter::default()
+  }
+
+  pub fn include(mut self, glob: &str) -> io::Result<ExtractFilter> {
+    self.include.push(compile(glob)?);
+    Ok(self)
+  }
+
+  pub fn exclude(mut self, glob: &str) -> io::Result<ExtractFilter> {
+    self.exclude.push(compile(glob)?);
+    Ok(self)
+  }
+
+  /// Pick everything at or under `path`.
+  pub fn prefix<P: Into<PathBuf>>(mut self, path: P) -> ExtractFilter {
+    self.prefixes.push(path.into());
+    self
+  }
+
+  pub fn callback<F>(mut self, callback: F) -> ExtractFilter where F: Fn(&EntryInfo) -> Decision + 'static {
+    self.callback = Some(Rc::new(callback));
+    self
+  }
+
+  // `observer` sees each entry the callback picks (or every entry that's
+  // picked, without a callback).
+  pub(crate) fn observe<F>(mut self, observer: F) -> ExtractFilter where F: Fn(&EntryInfo) + 'static {
+    let callback = self.callback.take();
+    self.callback = Some(Rc::new(move |entry| {
+      let decision = callback.as_ref().map(|f| f(entry)).unwrap_or(Decision::Extract);
+      if decision == Decision::Extract { observer(entry) }
+      decision
+    }));
+    self
+  }
+
+  pub fn is_stopped(&self) -> bool {
+    self.stopped.get()
+  }
+
+  pub(crate) fn reset(&self) {
+    self.stopped.set(false);
+  }
+
+  /// What to do with this entry. A folder that isn't picked may still be
+  /// searched for entries inside it that are.
+  pub(crate) fn choose(&self, entry: &EntryInfo) -> Choice {
+    if self.stopped.get() { return Choice::Stop }
+    if self.exclude.iter().any(|p| p.matches_path_with(&entry.path, MATCH_OPTIONS)) { return Choice::Skip }
+    if !self.picks(&entry.path) {
+      return if entry.metadata.folder && self.may_contain(&entry.path) { Choice::Search } else { Choice::Skip };
+    }
+    match self.callback.as_ref().map(|f| f(entry)).unwrap_or(Decision::Extract) {
+      Decision::Extract => Choice::Extract,
+      Decision::Skip => Choice::Skip,
+      Decision::Stop => {
+        self.stopped.set(true);
+        Choice::Stop
+      }
+    }
+  }
+
+  // could anything inside this folder be picked? globs can match anywhere,
+  // but prefixes only match inside the folders along the way.
+  fn may_contain(&self, folder: &Path) -> bool {
+    !self.include.is_empty() || self.prefixes.iter().any(|prefix| prefix.starts_with(folder))
+  }
+
+  fn picks(&self, path: &Path) -> bool {
+    if self.include.is_empty() && self.prefixes.is_empty() { return true }
+    self.include.iter().any(|p| p.matches_path_with(path, MATCH_OPTIONS)) ||
+      self.prefixes.iter().any(|prefix| path.starts_with(prefix))
+  }
+}
+
+impl fmt::Debug for ExtractFilter {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let include: Vec<&str> = self.include.iter().map(|p| p.as_str()).collect();
+    let exclude: Vec<&str> = self.exclude.iter().map(|p| p.as_str()).collect();
+    write!(f, "ExtractFilter(include={:?}, exclude={:?}, prefixes={:?}, callback={})",
+      include, exclude, self.prefixes, self.callback.is_some())
+  }
+}
+
+fn compile(glob: &str) -> io::Result<Pattern> {
+  Pattern::new(glob).map_err(|_| bad_pattern_error(glob))
+}
+
+
+// ----- errors
+
+fn bad_pattern_error(glob: &str) -> io::Error {
+  BottleError::BadPattern(glob.to_string()).into()
+}
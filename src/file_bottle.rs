@@ -0,0 +1,1097 @@
+use bytes::Bytes;
+use futures::{Async, Future, Poll, Stream, future, stream};
+use futures::future::Loop;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{self as unix_fs, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+#[cfg(unix)]
+use users;
+
+use archive::EntryInfo;
+use bottle::{BottleType, DecodeLimits, ReadOptions, child_from_bytes, make_bottle, read_bottle_with_options};
+use bottle_header::{Header};
+use error::BottleError;
+use extract_filter::{Choice, ExtractFilter};
+use hashing::crc32c;
+use incremental::Manifest;
+use progress::{Progress, ProgressTracker, count_in, count_vec_out};
+use sparse::{data_extents, encode_extents, extent_stream, sparse_data};
+use to_hex::{FromHex, ToHex};
+#[cfg(feature = "xattr")]
+use bottle_header::MAX_FIELD_LENGTH;
+#[cfg(feature = "xattr")]
+use xattr;
+
+// header fields, from the 4bottle spec:
+const FIELD_FILENAME: u8 = 0;
+const FIELD_MIME_TYPE: u8 = 1;
+const FIELD_USERNAME: u8 = 2;
+const FIELD_GROUP: u8 = 3;
+const FIELD_SYMLINK: u8 = 4;
+const FIELD_HARDLINK: u8 = 5;
+const FIELD_XATTR: u8 = 6;
+const FIELD_REFERENCE: u8 = 7;
+
+const FIELD_SIZE: u8 = 0;
+const FIELD_POSIX_MODE: u8 = 1;
+const FIELD_CREATED_NANOS: u8 = 2;
+const FIELD_MODIFIED_NANOS: u8 = 3;
+const FIELD_ACCESSED_NANOS: u8 = 4;
+
+pub(crate) const FIELD_FOLDER: u8 = 0;
+const FIELD_SPARSE: u8 = 1;
+const FIELD_CRC32C: u8 = 2;
+
+const READ_BLOCK_SIZE: usize = 64 * 1024;
+
+/// With `WriteSettings::large_reads`, files at least this big are read
+/// with `large_file_stream`.
+pub const LARGE_FILE_SIZE: u64 = 1024 * 1024;
+
+/// Block size for `large_file_stream`.
+pub const LARGE_READ_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Everything a file bottle's header can say about a file. Times are
+/// nanoseconds since the epoch.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct FileMetadata {
+  pub filename: String,
+  pub mime_type: Option<String>,
+  pub size: Option<u64>,
+  pub posix_mode: Option<u32>,
+  pub created_nanos: Option<u64>,
+  pub modified_nanos: Option<u64>,
+  pub accessed_nanos: Option<u64>,
+  pub username: Option<String>,
+  pub group: Option<String>,
+  // a folder bottle's child streams are the bottles of its contents.
+  pub folder: bool,
+  /// a symlink: where it points, as stored on disk.
+  pub symlink: Option<String>,
+  /// a hard link to an earlier file in the same archive, by its path from
+  /// this file's folder, like a relative symlink (so "../a.txt").
+  pub hardlink: Option<String>,
+  /// extended attributes by name, including POSIX ACLs (on linux, they're
+  /// "system.posix_acl_access" and "system.posix_acl_default"). These are
+  /// only read from disk with the `xattr` feature.
+  #[cfg_attr(feature = "serde", serde(with = "::serde_support::hex_xattrs"))]
+  pub xattrs: Vec<( String, Vec<u8> )>,
+  /// the file has holes: its bottle has two child streams, a map of where
+  /// the data is, and then just that data. `size` is the whole size.
+  pub sparse: bool,
+  /// after the contents is one more child stream: the CRC32C of all the
+  /// child streams before it, as 4 bytes, big-endian.
+  pub crc32c: bool,
+  /// unchanged since a parent archive (see `incremental`): the BLAKE3
+  /// digest of the contents, which aren't stored again.
+  #[cfg_attr(feature = "serde", serde(with = "::serde_support::hex_bytes_option"))]
+  pub reference: Option<Bytes>
+}
+
+impl FileMetadata {
+  /// Collect metadata for a file on disk. The filename is the last path
+  /// segment only.
+  pub fn from_path<P: AsRef<Path>>(path: P) -> io::Result<FileMetadata> {
+    let path = path.as_ref();
+    let stat = fs::metadata(path)?;
+    let filename = path.file_name().ok_or_else(|| no_filename_error(path))?.to_string_lossy().to_string();
+    let ( username, group ) = owner(&stat);
+    Ok(FileMetadata {
+      filename,
+      mime_type: None,
+      size: Some(stat.len()),
+      posix_mode: posix_mode(&stat),
+      created_nanos: stat.created().ok().and_then(to_nanos),
+      modified_nanos: stat.modified().ok().and_then(to_nanos),
+      accessed_nanos: stat.accessed().ok().and_then(to_nanos),
+      username,
+      group,
+      folder: stat.is_dir(),
+      symlink: None,
+      hardlink: None,
+      xattrs: read_xattrs(path)?,
+      sparse: false,
+      crc32c: false,
+      reference: None
+    })
+  }
+
+  /// Collect metadata for a symlink itself, instead of what it points to.
+  pub fn from_symlink<P: AsRef<Path>>(path: P) -> io::Result<FileMetadata> {
+    let path = path.as_ref();
+    let stat = fs::symlink_metadata(path)?;
+    let target = fs::read_link(path)?;
+    let filename = path.file_name().ok_or_else(|| no_filename_error(path))?.to_string_lossy().to_string();
+    let ( username, group ) = owner(&stat);
+    Ok(FileMetadata {
+      filename,
+      created_nanos: stat.created().ok().and_then(to_nanos),
+      modified_nanos: stat.modified().ok().and_then(to_nanos),
+      accessed_nanos: stat.accessed().ok().and_then(to_nanos),
+      username,
+      group,
+      symlink: Some(target.to_string_lossy().to_string()),
+      ..FileMetadata::default()
+    })
+  }
+
+  pub fn is_link(&self) -> bool {
+    self.symlink.is_some() || self.hardlink.is_some()
+  }
+
+  /// Panics if the metadata doesn't fit in a header; use `try_to_header`
+  /// for metadata from somewhere else.
+  pub fn to_header(&self) -> Header {
+    self.try_to_header().expect("file metadata too big for a header")
+  }
+
+  /// Fails with `HeaderFull`, naming the field that didn't fit (like a
+  /// very long symlink target, or one xattr too many), instead of
+  /// panicking or failing once the bottle is written.
+  pub fn try_to_header(&self) -> io::Result<Header> {
+    let mut header = Header::new();
+    header.try_add_string(FIELD_FILENAME, "filename", self.filename.clone())?;
+    if let Some(ref s) = self.mime_type { header.try_add_string(FIELD_MIME_TYPE, "mime type", s.clone())? }
+    if let Some(ref s) = self.username { header.try_add_string(FIELD_USERNAME, "username", s.clone())? }
+    if let Some(ref s) = self.group { header.try_add_string(FIELD_GROUP, "group", s.clone())? }
+    if let Some(ref s) = self.symlink { header.try_add_string(FIELD_SYMLINK, "symlink", s.clone())? }
+    if let Some(ref s) = self.hardlink { header.try_add_string(FIELD_HARDLINK, "hardlink", s.clone())? }
+    if let Some(ref digest) = self.reference { header.try_add_string(FIELD_REFERENCE, "reference", digest.to_hex())? }
+    for ( name, value ) in self.xattrs.iter() {
+      header.try_add_string(FIELD_XATTR, &format!("xattr {}", name), format!("{}={}", name, value.to_hex()))?;
+    }
+    if let Some(n) = self.size { header.try_add_number(FIELD_SIZE, "size", n)? }
+    if let Some(n) = self.posix_mode { header.try_add_number(FIELD_POSIX_MODE, "posix mode", n as u64)? }
+    if let Some(n) = self.created_nanos { header.try_add_number(FIELD_CREATED_NANOS, "created time", n)? }
+    if let Some(n) = self.modified_nanos { header.try_add_number(FIELD_MODIFIED_NANOS, "modified time", n)? }
+    if let Some(n) = self.accessed_nanos { header.try_add_number(FIELD_ACCESSED_NANOS, "accessed time", n)? }
+    if self.folder { header.try_add_bool(FIELD_FOLDER, "folder")? }
+    if self.sparse { header.try_add_bool(FIELD_SPARSE, "sparse")? }
+    if self.crc32c { header.try_add_bool(FIELD_CRC32C, "crc32c")? }
+    Ok(header)
+  }
+
+  pub fn from_header(header: &Header) -> io::Result<FileMetadata> {
+    let filename = header.get_string(FIELD_FILENAME).ok_or_else(missing_filename_error)?;
+    let sparse = header.get_bool(FIELD_SPARSE);
+    if sparse && header.get_number(FIELD_SIZE).is_none() { return Err(bad_sparse_map_error()) }
+    Ok(FileMetadata {
+      filename: filename.to_string(),
+      mime_type: header.get_string(FIELD_MIME_TYPE).map(|s| s.to_string()),
+      size: header.get_number(FIELD_SIZE),
+      posix_mode: header.get_number(FIELD_POSIX_MODE).map(|n| n as u32),
+      created_nanos: header.get_number(FIELD_CREATED_NANOS),
+      modified_nanos: header.get_number(FIELD_MODIFIED_NANOS),
+      accessed_nanos: header.get_number(FIELD_ACCESSED_NANOS),
+      username: header.get_string(FIELD_USERNAME).map(|s| s.to_string()),
+      group: header.get_string(FIELD_GROUP).map(|s| s.to_string()),
+      folder: header.get_bool(FIELD_FOLDER),
+      symlink: header.get_string(FIELD_SYMLINK).map(|s| s.to_string()),
+      hardlink: header.get_string(FIELD_HARDLINK).map(|s| s.to_string()),
+      xattrs: header.get_strings(FIELD_XATTR).into_iter().map(decode_xattr).collect::<io::Result<Vec<_>>>()?,
+      sparse,
+      crc32c: header.get_bool(FIELD_CRC32C),
+      reference: header.get_string(FIELD_REFERENCE).map(decode_reference).transpose()?
+    })
+  }
+}
+
+/// Settings for archives that come out byte-identical every time the same
+/// tree is archived, for build systems and attestations. Entries are
+/// always in name order; on top of that, creation and access times are
+/// dropped, modification times are clamped, the owner is fixed, and files
+/// are never stored sparse (which depends on how the filesystem happened to
+/// allocate them).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Deterministic {
+  /// later modification times (in nanoseconds) are clamped to this. At 0
+  /// (the default), every time is 0.
+  pub max_modified_nanos: u64,
+  /// stored as every entry's owner, or nothing if `None`.
+  pub username: Option<String>,
+  pub group: Option<String>,
+  /// store modes as 0o755 (folders, and files with any execute bit) or
+  /// 0o644, so the umask of whoever checked out the tree doesn't matter.
+  pub normalize_modes: bool
+}
+
+impl Default for Deterministic {
+  fn default() -> Deterministic {
+    Deterministic { max_modified_nanos: 0, username: None, group: None, normalize_modes: true }
+  }
+}
+
+impl Deterministic {
+  /// Clamp modification times to `SOURCE_DATE_EPOCH` (in seconds) from the
+  /// environment, the way reproducible builds expect, or to 0 if it isn't
+  /// set.
+  pub fn from_source_date_epoch() -> Deterministic {
+    let seconds = env::var("SOURCE_DATE_EPOCH").ok().and_then(|s| s.trim().parse::<u64>().ok()).unwrap_or(0);
+    Deterministic { max_modified_nanos: seconds.saturating_mul(1_000_000_000), ..Deterministic::default() }
+  }
+
+  fn apply(&self, metadata: &mut FileMetadata) {
+    metadata.created_nanos = None;
+    metadata.accessed_nanos = None;
+    metadata.modified_nanos = metadata.modified_nanos.map(|n| n.min(self.max_modified_nanos));
+    metadata.username = self.username.clone();
+    metadata.group = self.group.clone();
+    if self.normalize_modes {
+      metadata.posix_mode = metadata.posix_mode.map(|mode| {
+        if metadata.folder || mode & 0o111 != 0 { 0o755 } else { 0o644 }
+      });
+    }
+    metadata.xattrs.sort();
+  }
+}
+
+// how each file and folder bottle is written.
+#[derive(Clone, Default)]
+pub(crate) struct WriteSettings {
+  pub crc32c: bool,
+  pub deterministic: Option<Rc<Deterministic>>,
+  pub large_reads: bool
+}
+
+impl WriteSettings {
+  pub fn crc32c(crc32c: bool) -> WriteSettings {
+    WriteSettings { crc32c, ..WriteSettings::default() }
+  }
+
+  pub fn deterministic(deterministic: Deterministic) -> WriteSettings {
+    WriteSettings { deterministic: Some(Rc::new(deterministic)), ..WriteSettings::default() }
+  }
+
+  fn settle(&self, metadata: &mut FileMetadata) {
+    if let Some(ref d) = self.deterministic { d.apply(metadata) }
+  }
+
+  fn content_stream(&self, file: fs::File, size: u64) -> ByteStream {
+    if self.large_reads && size >= LARGE_FILE_SIZE { Box::new(large_file_stream(file)) } else { Box::new(file_stream(file)) }
+  }
+}
+
+/// Read a file as a stream of blocks. Reads are blocking, one block per poll.
+pub fn file_stream(mut file: fs::File) -> impl Stream<Item = Bytes, Error = io::Error> {
+  stream::poll_fn(move || {
+    let mut buffer = vec![ 0; READ_BLOCK_SIZE ];
+    loop {
+      match file.read(&mut buffer) {
+        Ok(0) => return Ok(Async::Ready(None)),
+        Ok(n) => {
+          buffer.truncate(n);
+          return Ok(Async::Ready(Some(Bytes::from(buffer))));
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+        Err(e) => return Err(e)
+      }
+    }
+  })
+}
+
+/// Read a big file as a stream of `LARGE_READ_BLOCK_SIZE` blocks (only the
+/// last one is short), after telling the OS it'll be read front to back,
+/// where it can be told. Fewer, bigger reads cost less per byte than
+/// `file_stream`'s, at the price of more memory per block.
+pub fn large_file_stream(mut file: fs::File) -> impl Stream<Item = Bytes, Error = io::Error> {
+  advise_sequential(&file);
+  let mut done = false;
+  stream::poll_fn(move || {
+    if done { return Ok(Async::Ready(None)) }
+    let mut buffer = vec![ 0; LARGE_READ_BLOCK_SIZE ];
+    let mut filled = 0;
+    while filled < buffer.len() {
+      match file.read(&mut buffer[filled ..]) {
+        Ok(0) => break,
+        Ok(n) => filled += n,
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+        Err(e) => return Err(e)
+      }
+    }
+    if filled < buffer.len() { done = true }
+    if filled == 0 { return Ok(Async::Ready(None)) }
+    buffer.truncate(filled);
+    Ok(Async::Ready(Some(Bytes::from(buffer))))
+  })
+}
+
+// only a hint: it's fine if the kernel ignores it.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn advise_sequential(file: &fs::File) {
+  let _ = ::rustix::fs::fadvise(file, 0, None, ::rustix::fs::Advice::Sequential);
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn advise_sequential(_file: &fs::File) {
+}
+
+/// Build a file bottle for a single file: its metadata in the header, and
+/// its contents as the only child stream. A file with holes is stored
+/// sparse, without the holes.
+pub fn file_bottle<P: AsRef<Path>>(path: P) -> io::Result<impl Stream<Item = Vec<Bytes>, Error = io::Error>> {
+  tracked_file_bottle(path.as_ref(), None, &WriteSettings::default())
+}
+
+/// Like `file_bottle`, with a CRC32C of the contents after them, so a
+/// reader can catch damage without a hashed bottle.
+pub fn file_bottle_with_crc32c<P: AsRef<Path>>(path: P) -> io::Result<impl Stream<Item = Vec<Bytes>, Error = io::Error>> {
+  tracked_file_bottle(path.as_ref(), None, &WriteSettings::crc32c(true))
+}
+
+/// Like `file_bottle`, but the same file always makes the same bottle
+/// (see `Deterministic`).
+pub fn file_bottle_deterministic<P: AsRef<Path>>(path: P, deterministic: Deterministic)
+  -> io::Result<impl Stream<Item = Vec<Bytes>, Error = io::Error>>
+{
+  tracked_file_bottle(path.as_ref(), None, &WriteSettings::deterministic(deterministic))
+}
+
+/// Like `file_bottle`, but report progress as the file is read.
+pub fn file_bottle_with_progress<P, Pr>(path: P, progress: Pr) -> io::Result<impl Stream<Item = Vec<Bytes>, Error = io::Error>>
+  where P: AsRef<Path>, Pr: Progress + 'static
+{
+  let tracker = ProgressTracker::new(progress);
+  Ok(count_vec_out(tracked_file_bottle(path.as_ref(), Some(tracker.clone()), &WriteSettings::default())?, Some(tracker)))
+}
+
+pub(crate) fn tracked_file_bottle(path: &Path, tracker: Option<ProgressTracker>, settings: &WriteSettings)
+  -> io::Result<impl Stream<Item = Vec<Bytes>, Error = io::Error>>
+{
+  let mut metadata = FileMetadata::from_path(path)?;
+  settings.settle(&mut metadata);
+  let file = fs::File::open(path)?;
+  if let Some(ref t) = tracker { t.set_entry(path) }
+
+  // only look for holes if fewer blocks are allocated than the size needs.
+  let stat = file.metadata()?;
+  let size = stat.len();
+  let mut children: Vec<ByteStream> = Vec::new();
+  if allocated_size(&stat) < size && settings.deterministic.is_none() {
+    let extents = data_extents(&file, size)?;
+    if extents.iter().map(|&( _, length )| length).sum::<u64>() < size {
+      metadata.size = Some(size);
+      metadata.sparse = true;
+      children.push(Box::new(stream::once(Ok(encode_extents(&extents)))));
+      children.push(Box::new(count_in(extent_stream(path, file, extents), tracker)));
+    } else {
+      children.push(Box::new(count_in(settings.content_stream(file, size), tracker)));
+    }
+  } else {
+    children.push(Box::new(count_in(settings.content_stream(file, size), tracker)));
+  }
+  if settings.crc32c {
+    metadata.crc32c = true;
+    children = with_crc32c(children);
+  }
+  Ok(make_bottle(BottleType::File, &metadata.try_to_header()?, children.into_iter().map(child_from_bytes)))
+}
+
+// each child is checksummed as it goes by, and the checksum is one more
+// child at the end.
+fn with_crc32c(children: Vec<ByteStream>) -> Vec<ByteStream> {
+  let crc = Rc::new(Cell::new(0));
+  let mut checked: Vec<ByteStream> = children.into_iter().map(|child| {
+    let crc = crc.clone();
+    Box::new(child.map(move |b| {
+      crc.set(crc32c(crc.get(), &b));
+      b
+    })) as ByteStream
+  }).collect();
+  checked.push(Box::new(future::lazy(move || Ok(Bytes::from(crc.get().to_be_bytes().to_vec()))).into_stream()));
+  checked
+}
+
+/// Build a folder bottle for a directory tree. Each entry becomes a nested
+/// file or folder bottle, in name order, so the same tree always archives
+/// the same way. Files aren't opened until their turn in the stream.
+///
+/// Symlinks are stored as links (not followed), and a file with more than
+/// one hard link in the tree is stored once, with the later paths stored
+/// as hard links to the first. Anything else (sockets, devices) is skipped.
+pub fn archive_directory<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error>>> {
+  tracked_directory(path.as_ref(), None, WriteSettings::default(), None)
+}
+
+/// Like `archive_directory`, with a CRC32C after each file's contents
+/// (see `file_bottle_with_crc32c`).
+pub fn archive_directory_with_crc32c<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error>>> {
+  tracked_directory(path.as_ref(), None, WriteSettings::crc32c(true), None)
+}
+
+/// Like `archive_directory`, but the same tree always makes the same
+/// bytes (see `Deterministic`).
+pub fn archive_directory_deterministic<P: AsRef<Path>>(path: P, deterministic: Deterministic)
+  -> io::Result<Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error>>>
+{
+  tracked_directory(path.as_ref(), None, WriteSettings::deterministic(deterministic), None)
+}
+
+/// Like `archive_directory`, but report progress, including each file or
+/// folder as it's reached.
+pub fn archive_directory_with_progress<P, Pr>(path: P, progress: Pr) -> io::Result<Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error>>>
+  where P: AsRef<Path>, Pr: Progress + 'static
+{
+  let tracker = ProgressTracker::new(progress);
+  Ok(Box::new(count_vec_out(tracked_directory(path.as_ref(), Some(tracker.clone()), WriteSettings::default(), None)?, Some(tracker))))
+}
+
+// with a `parent`, files that it already has are stored as references.
+pub(crate) fn tracked_directory(path: &Path, tracker: Option<ProgressTracker>, settings: WriteSettings, parent: Option<Rc<Manifest>>)
+  -> io::Result<Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error>>>
+{
+  let name = path.file_name().ok_or_else(|| no_filename_error(path))?;
+  tracked_tree(path, PathBuf::from(name), Rc::new(RefCell::new(HashMap::new())), tracker, settings, parent)
+}
+
+// files seen so far with more than one link, by (device, inode), and the
+// path inside the archive of the first one.
+type SeenLinks = Rc<RefCell<HashMap<( u64, u64 ), PathBuf>>>;
+
+// `archive_path` is where this folder is inside the archive.
+fn tracked_tree(
+  path: &Path,
+  archive_path: PathBuf,
+  links: SeenLinks,
+  tracker: Option<ProgressTracker>,
+  settings: WriteSettings,
+  parent: Option<Rc<Manifest>>
+) -> io::Result<Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error>>> {
+  let mut metadata = FileMetadata::from_path(path)?;
+  if !metadata.folder { return Err(not_a_folder_error(path)) }
+  // a folder's "size" would just be the filesystem's block size.
+  metadata.size = None;
+  settings.settle(&mut metadata);
+
+  let mut entries = fs::read_dir(path)?.map(|entry| entry.map(|e| e.path())).collect::<io::Result<Vec<_>>>()?;
+  entries.sort();
+
+  if let Some(ref t) = tracker { t.set_entry(path) }
+  let mut children: Vec<Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error>>> = Vec::new();
+  for entry in entries {
+    let file_type = fs::symlink_metadata(&entry)?.file_type();
+    let tracker = tracker.clone();
+    let links = links.clone();
+    let parent = parent.clone();
+    let settings = settings.clone();
+    let entry_path = archive_path.join(entry.file_name().unwrap_or_default());
+    if file_type.is_dir() {
+      children.push(Box::new(future::lazy(move || tracked_tree(&entry, entry_path, links, tracker, settings, parent)).flatten_stream()));
+    } else if file_type.is_file() {
+      children.push(Box::new(future::lazy(move || linked_file_bottle(&entry, entry_path, links, tracker, settings, parent)).flatten_stream()));
+    } else if file_type.is_symlink() {
+      children.push(Box::new(future::lazy(move || {
+        let mut metadata = FileMetadata::from_symlink(&entry)?;
+        settings.settle(&mut metadata);
+        link_bottle(metadata)
+      }).flatten_stream()));
+    }
+  }
+  Ok(Box::new(make_bottle(BottleType::File, &metadata.try_to_header()?, children)))
+}
+
+// a file, unless it's another link to a file we already stored, or it's
+// unchanged from the parent.
+fn linked_file_bottle(
+  path: &Path,
+  archive_path: PathBuf,
+  links: SeenLinks,
+  tracker: Option<ProgressTracker>,
+  settings: WriteSettings,
+  parent: Option<Rc<Manifest>>
+) -> io::Result<Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error>>> {
+  if let Some(key) = hard_link_key(&fs::symlink_metadata(path)?) {
+    let earlier = links.borrow().get(&key).cloned();
+    match earlier {
+      Some(earlier) => {
+        let mut metadata = FileMetadata::from_path(path)?;
+        metadata.size = None;
+        metadata.hardlink = Some(relative_to(archive_path.parent().unwrap_or_else(|| Path::new("")), &earlier));
+        settings.settle(&mut metadata);
+        if let Some(ref t) = tracker { t.set_entry(path) }
+        return link_bottle(metadata);
+      }
+      None => { links.borrow_mut().insert(key, archive_path.clone()); }
+    }
+  }
+  if let Some(parent) = parent {
+    if let Some(mut metadata) = parent.reference_for(path, &archive_path)? {
+      settings.settle(&mut metadata);
+      if let Some(ref t) = tracker { t.set_entry(path) }
+      return link_bottle(metadata);
+    }
+  }
+  Ok(Box::new(tracked_file_bottle(path, tracker, &settings)?))
+}
+
+// the path to `path` from inside `folder`, both inside the archive.
+fn relative_to(folder: &Path, path: &Path) -> String {
+  let common = folder.components().zip(path.components()).take_while(|&( a, b )| a == b).count();
+  let mut relative = PathBuf::new();
+  for _ in common .. folder.components().count() { relative.push("..") }
+  for c in path.components().skip(common) { relative.push(c) }
+  relative.to_string_lossy().to_string()
+}
+
+// links (and references) have no contents.
+fn link_bottle(metadata: FileMetadata) -> io::Result<Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error>>> {
+  Ok(Box::new(make_bottle(BottleType::File, &metadata.try_to_header()?, Vec::<stream::Empty<Vec<Bytes>, io::Error>>::new())))
+}
+
+/// What to do when extracting a file that already exists. Folders that
+/// already exist are always merged into.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExistingFilePolicy {
+  Overwrite,
+  Skip,
+  Error
+}
+
+#[derive(Clone, Debug)]
+pub struct ExtractOptions {
+  pub existing: ExistingFilePolicy,
+  pub restore_permissions: bool,
+  pub restore_times: bool,
+  pub progress: Option<ProgressTracker>,
+  pub filter: Option<ExtractFilter>,
+  /// write a copy of what each symlink or hard link points to, instead of
+  /// the link.
+  pub dereference_links: bool,
+  /// set extended attributes and ACLs from the bottle (this needs the
+  /// `xattr` feature)
+  pub restore_xattrs: bool,
+  /// refuse bottles that nest folders (or frames, or headers) beyond these
+  pub limits: DecodeLimits
+}
+
+impl Default for ExtractOptions {
+  fn default() -> ExtractOptions {
+    ExtractOptions {
+      existing: ExistingFilePolicy::Error,
+      restore_permissions: true,
+      restore_times: true,
+      progress: None,
+      filter: None,
+      dereference_links: false,
+      restore_xattrs: false,
+      limits: DecodeLimits::default()
+    }
+  }
+}
+
+impl ExtractOptions {
+  /// Report progress while extracting: bytes in are read from the bottle,
+  /// and bytes out are written to files.
+  pub fn with_progress<P: Progress + 'static>(mut self, progress: P) -> ExtractOptions {
+    self.progress = Some(ProgressTracker::new(progress));
+    self
+  }
+
+  /// Only extract the entries that `filter` picks.
+  pub fn with_filter(mut self, filter: ExtractFilter) -> ExtractOptions {
+    self.filter = Some(filter);
+    self
+  }
+}
+
+/// Recreate a file or folder bottle inside `target_dir`, returning the
+/// paths that were written (skipped files aren't listed). Filenames that
+/// would escape `target_dir` are refused.
+pub fn extract_bottle<S, P>(s: S, target_dir: P, options: ExtractOptions) -> impl Future<Item = Vec<PathBuf>, Error = io::Error>
+  where
+    S: Stream<Item = Bytes, Error = io::Error> + 'static,
+    P: AsRef<Path>
+{
+  if let Some(ref filter) = options.filter { filter.reset() }
+  let s = count_in(s, options.progress.clone());
+  extract_entry(Box::new(s), target_dir.as_ref().to_path_buf(), PathBuf::new(), options)
+}
+
+type ByteStream = Box<dyn Stream<Item = Bytes, Error = io::Error>>;
+type ExtractFuture = Box<dyn Future<Item = Vec<PathBuf>, Error = io::Error>>;
+type ChildrenFuture<C> = Box<dyn Future<Item = Loop<Vec<PathBuf>, ( C, Vec<PathBuf> )>, Error = io::Error>>;
+
+// nested bottles each have their own stream type, so recursion needs boxes.
+// `relative` is the folder `dir`, relative to the target folder.
+fn extract_entry(s: ByteStream, dir: PathBuf, relative: PathBuf, options: ExtractOptions) -> ExtractFuture {
+  if let Err(e) = options.limits.check_depth(relative.components().count() + 1) { return Box::new(future::err(e)) }
+  let read_options = ReadOptions { limits: options.limits, ..ReadOptions::default() };
+  Box::new(read_bottle_with_options(s, read_options).and_then(move |( btype, header, children )| -> ExtractFuture {
+    let entry = if btype == BottleType::File {
+      FileMetadata::from_header(&header).and_then(|metadata| {
+        let filename = safe_filename(&metadata.filename)?;
+        Ok(( dir.join(filename), relative.join(filename), metadata ))
+      })
+    } else {
+      Err(not_a_file_bottle_error(btype))
+    };
+    let ( path, relative, metadata ) = match entry {
+      Ok(entry) => entry,
+      Err(e) => return Box::new(future::err(e))
+    };
+
+    // skipped entries leave their contents for the parent to skip.
+    let choice = match options.filter {
+      Some(ref filter) => {
+        let info = EntryInfo { path: relative.clone(), metadata: metadata.clone(), layers: vec![ BottleType::File ], hashes: Vec::new() };
+        filter.choose(&info)
+      }
+      None => Choice::Extract
+    };
+    match choice {
+      Choice::Skip | Choice::Stop => return Box::new(future::ok(Vec::new())),
+      Choice::Search => return extract_children(children, path, relative, options),
+      Choice::Extract => ()
+    }
+    // the folders above may have been searched instead of created.
+    if options.filter.is_some() {
+      if let Err(e) = fs::create_dir_all(&dir) { return Box::new(future::err(e)) }
+    }
+
+    if let Some(ref t) = options.progress { t.set_entry(&path) }
+    if metadata.is_link() {
+      let folder = relative.parent().unwrap_or_else(|| Path::new(""));
+      return Box::new(future::result(extract_link(&path, &dir, folder, &metadata, &options)));
+    }
+    if metadata.folder {
+      if let Err(e) = create_folder(&path) { return Box::new(future::err(e)) }
+      let folder = path.clone();
+      Box::new(extract_children(children, folder, relative, options.clone()).and_then(move |more| {
+        restore_metadata(&path, &metadata, &options)?;
+        let mut paths = vec![ path ];
+        paths.extend(more);
+        Ok(paths)
+      }))
+    } else {
+      let file = match create_file(&path, options.existing) {
+        Ok(Some(file)) => file,
+        Ok(None) => return Box::new(future::ok(Vec::new())),
+        Err(e) => return Box::new(future::err(e))
+      };
+      let tracker = options.progress.clone();
+      if metadata.sparse {
+        // the holes are left unwritten, and `set_len` covers one at the end.
+        let size = metadata.size.unwrap_or(0);
+        return Box::new(sparse_data(content_children(children, &metadata), size, options.limits).and_then(move |data| {
+          data.fold(file, move |mut file, ( offset, b )| {
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&b)?;
+            if let Some(ref t) = tracker { t.add_out(b.len()) }
+            Ok::<_, io::Error>(file)
+          })
+        }).and_then(move |file| {
+          file.set_len(size)?;
+          restore_metadata(&path, &metadata, &options)?;
+          Ok(vec![ path ])
+        }));
+      }
+      Box::new(content_children(children, &metadata).flatten().fold(file, move |mut file, b| {
+        file.write_all(&b)?;
+        if let Some(ref t) = tracker { t.add_out(b.len()) }
+        Ok::<_, io::Error>(file)
+      }).and_then(move |_| {
+        restore_metadata(&path, &metadata, &options)?;
+        Ok(vec![ path ])
+      }))
+    }
+  }))
+}
+
+/// The child streams holding a file's contents: one, or two for a sparse
+/// file. If there's a CRC32C, it's checked after the last one is read, and
+/// the stream fails instead of ending if it doesn't match. (Skipping any
+/// of the contents will make it not match.)
+pub(crate) fn content_children<C, A>(children: C, metadata: &FileMetadata) -> Box<dyn Stream<Item = ByteStream, Error = io::Error>>
+  where
+    C: Stream<Item = A, Error = io::Error> + 'static,
+    A: Stream<Item = Bytes, Error = io::Error> + 'static
+{
+  let count: usize = if metadata.sparse { 2 } else { 1 };
+  let children = children.map(|child| Box::new(child) as ByteStream);
+  if !metadata.crc32c { return Box::new(children.take(count as u64)) }
+  Box::new(CheckedChildren { children, remaining: count, crc: Rc::new(Cell::new(0)), checksum: None, stored: Vec::new(), done: false })
+}
+
+struct CheckedChildren<C> {
+  children: C,
+  // content children not handed out yet
+  remaining: usize,
+  crc: Rc<Cell<u32>>,
+  checksum: Option<ByteStream>,
+  stored: Vec<u8>,
+  done: bool
+}
+
+impl<C> Stream for CheckedChildren<C> where C: Stream<Item = ByteStream, Error = io::Error> {
+  type Item = ByteStream;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    if self.remaining > 0 {
+      let child = try_ready!(self.children.poll()).ok_or_else(missing_checksum_error)?;
+      self.remaining -= 1;
+      let crc = self.crc.clone();
+      return Ok(Async::Ready(Some(Box::new(child.map(move |b| {
+        crc.set(crc32c(crc.get(), &b));
+        b
+      })))));
+    }
+    while !self.done {
+      match self.checksum {
+        None => self.checksum = Some(try_ready!(self.children.poll()).ok_or_else(missing_checksum_error)?),
+        Some(ref mut s) => match try_ready!(s.poll()) {
+          Some(b) => {
+            self.stored.extend_from_slice(&b);
+            if self.stored.len() > 4 { return Err(missing_checksum_error()) }
+          }
+          None => {
+            if self.stored.len() != 4 { return Err(missing_checksum_error()) }
+            let expected = u32::from_be_bytes([ self.stored[0], self.stored[1], self.stored[2], self.stored[3] ]);
+            if expected != self.crc.get() { return Err(checksum_mismatch_error(expected, self.crc.get())) }
+            self.done = true;
+          }
+        }
+      }
+    }
+    Ok(Async::Ready(None))
+  }
+}
+
+// extract each child of a folder in order, until a filter says to stop.
+// the stop is checked before asking for the next child, so the rest of the
+// bottle isn't read.
+fn extract_children<C, A>(children: C, dir: PathBuf, relative: PathBuf, options: ExtractOptions) -> ExtractFuture
+  where
+    C: Stream<Item = A, Error = io::Error> + 'static,
+    A: Stream<Item = Bytes, Error = io::Error> + 'static
+{
+  Box::new(future::loop_fn(( children, Vec::new() ), move |( children, mut paths )| -> ChildrenFuture<C> {
+    if options.filter.as_ref().map(|f| f.is_stopped()).unwrap_or(false) {
+      return Box::new(future::ok(Loop::Break(paths)));
+    }
+    let dir = dir.clone();
+    let relative = relative.clone();
+    let options = options.clone();
+    Box::new(children.into_future().map_err(|( e, _ )| e).and_then(move |( child, children )| -> ChildrenFuture<C> {
+      match child {
+        None => Box::new(future::ok(Loop::Break(paths))),
+        Some(child) => Box::new(extract_entry(Box::new(child), dir, relative, options).map(move |more| {
+          paths.extend(more);
+          Loop::Continue(( children, paths ))
+        }))
+      }
+    }))
+  }))
+}
+
+// a link's target is checked first, so it can't point outside the target
+// folder. a hard link (or a dereferenced symlink) needs its target to be
+// extracted already.
+fn extract_link(path: &Path, dir: &Path, folder: &Path, metadata: &FileMetadata, options: &ExtractOptions) -> io::Result<Vec<PathBuf>> {
+  let target = metadata.symlink.as_ref().or(metadata.hardlink.as_ref()).map(|s| s.as_str()).unwrap_or("");
+  let source = check_link(dir, folder, target)?;
+  let copy = options.dereference_links || metadata.hardlink.is_some();
+  if copy && !fs::symlink_metadata(&source).map(|stat| stat.is_file()).unwrap_or(false) {
+    return Err(link_target_not_found_error(&source));
+  }
+  if !make_room(path, options.existing)? { return Ok(Vec::new()) }
+
+  if options.dereference_links {
+    fs::copy(&source, path)?;
+    if metadata.hardlink.is_some() { restore_metadata(path, metadata, options)? }
+  } else if metadata.hardlink.is_some() {
+    fs::hard_link(&source, path)?;
+  } else {
+    make_symlink(target, path)?;
+  }
+  Ok(vec![ path.to_path_buf() ])
+}
+
+// following `target` from `folder` (relative to the target folder, which
+// `dir` is inside of) has to stay inside the target folder, and the walk is
+// done against what's already on disk, like `RESOLVE_BENEATH`: a symlink
+// anywhere along the way is refused, since it could lead back out (a chain
+// of links that each look safe can add up to one that isn't). a ".." also
+// has to back out of a real folder, or a link extracted later could take
+// that folder's place. returns the target's path, with no links in it.
+fn check_link(dir: &Path, folder: &Path, target: &str) -> io::Result<PathBuf> {
+  if target.is_empty() { return Err(unsafe_link_error(target)) }
+  let mut root = dir.to_path_buf();
+  for _ in folder.components() { root.pop(); }
+  let mut path = dir.to_path_buf();
+  let mut depth = folder.components().count();
+  for c in Path::new(target).components() {
+    match c {
+      Component::Normal(name) => {
+        path.push(name);
+        depth += 1;
+        if fs::symlink_metadata(&path).map(|stat| stat.file_type().is_symlink()).unwrap_or(false) {
+          return Err(unsafe_link_error(target));
+        }
+      }
+      Component::CurDir => (),
+      Component::ParentDir if depth > 0 => {
+        if !fs::symlink_metadata(&path).map(|stat| stat.is_dir()).unwrap_or(false) {
+          return Err(unsafe_link_error(target));
+        }
+        path.pop();
+        depth -= 1;
+      }
+      _ => return Err(unsafe_link_error(target))
+    }
+  }
+  debug_assert!(path.starts_with(&root));
+  Ok(path)
+}
+
+// a filename has to be a single, normal path segment.
+pub(crate) fn safe_filename(filename: &str) -> io::Result<&Path> {
+  let path = Path::new(filename);
+  let mut components = path.components();
+  match ( components.next(), components.next() ) {
+    ( Some(Component::Normal(_)), None ) => Ok(path),
+    _ => Err(unsafe_filename_error(filename))
+  }
+}
+
+fn create_folder(path: &Path) -> io::Result<()> {
+  match fs::symlink_metadata(path) {
+    Ok(ref stat) if stat.is_dir() => Ok(()),
+    Ok(_) => Err(already_exists_error(path)),
+    Err(_) => fs::create_dir(path)
+  }
+}
+
+// `None` means skip it.
+fn create_file(path: &Path, existing: ExistingFilePolicy) -> io::Result<Option<fs::File>> {
+  if !make_room(path, existing)? { return Ok(None) }
+  fs::OpenOptions::new().write(true).create_new(true).open(path).map(Some)
+}
+
+// `false` means skip it. an existing file (or symlink) is removed rather
+// than written through.
+fn make_room(path: &Path, existing: ExistingFilePolicy) -> io::Result<bool> {
+  if let Ok(stat) = fs::symlink_metadata(path) {
+    match existing {
+      _ if stat.is_dir() => return Err(already_exists_error(path)),
+      ExistingFilePolicy::Error => return Err(already_exists_error(path)),
+      ExistingFilePolicy::Skip => return Ok(false),
+      ExistingFilePolicy::Overwrite => fs::remove_file(path)?
+    }
+  }
+  Ok(true)
+}
+
+// times first: permissions might not let us open it afterwards. xattrs
+// (which need write permission) go before permissions, which also leaves
+// an ACL's mask matching the mode.
+pub(crate) fn restore_metadata(path: &Path, metadata: &FileMetadata, options: &ExtractOptions) -> io::Result<()> {
+  if options.restore_times {
+    if let Some(nanos) = metadata.modified_nanos {
+      fs::File::open(path)?.set_modified(UNIX_EPOCH + Duration::from_nanos(nanos))?;
+    }
+  }
+  if options.restore_xattrs && !metadata.xattrs.is_empty() {
+    write_xattrs(path, &metadata.xattrs)?;
+  }
+  if options.restore_permissions {
+    if let Some(mode) = metadata.posix_mode { set_mode(path, mode)? }
+  }
+  Ok(())
+}
+
+// the rest of the unix-only metadata. elsewhere, files have no mode or
+// owner, and can't be sparse or hard links as far as an archive knows.
+
+#[cfg(unix)]
+fn posix_mode(stat: &fs::Metadata) -> Option<u32> {
+  Some(stat.mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn posix_mode(_stat: &fs::Metadata) -> Option<u32> {
+  None
+}
+
+#[cfg(unix)]
+fn owner(stat: &fs::Metadata) -> ( Option<String>, Option<String> ) {
+  (
+    users::get_user_by_uid(stat.uid()).map(|u| u.name().to_string_lossy().to_string()),
+    users::get_group_by_gid(stat.gid()).map(|g| g.name().to_string_lossy().to_string())
+  )
+}
+
+#[cfg(not(unix))]
+fn owner(_stat: &fs::Metadata) -> ( Option<String>, Option<String> ) {
+  ( None, None )
+}
+
+#[cfg(unix)]
+fn allocated_size(stat: &fs::Metadata) -> u64 {
+  stat.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn allocated_size(stat: &fs::Metadata) -> u64 {
+  stat.len()
+}
+
+// device and inode, for a file with more than one name.
+#[cfg(unix)]
+fn hard_link_key(stat: &fs::Metadata) -> Option<( u64, u64 )> {
+  if stat.nlink() > 1 { Some(( stat.dev(), stat.ino() )) } else { None }
+}
+
+#[cfg(not(unix))]
+fn hard_link_key(_stat: &fs::Metadata) -> Option<( u64, u64 )> {
+  None
+}
+
+#[cfg(unix)]
+fn make_symlink(target: &str, path: &Path) -> io::Result<()> {
+  unix_fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn make_symlink(_target: &str, _path: &Path) -> io::Result<()> {
+  Err(io::Error::new(io::ErrorKind::Unsupported, "symlinks aren't supported on this platform"))
+}
+
+#[cfg(unix)]
+pub(crate) fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+  fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+// only the read-only flag can be set.
+#[cfg(not(unix))]
+pub(crate) fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+  let mut permissions = fs::metadata(path)?.permissions();
+  permissions.set_readonly(mode & 0o222 == 0);
+  fs::set_permissions(path, permissions)
+}
+
+// sorted by name, so the same file always archives the same way. a
+// filesystem without xattrs just has none.
+#[cfg(feature = "xattr")]
+fn read_xattrs(path: &Path) -> io::Result<Vec<( String, Vec<u8> )>> {
+  let names = match xattr::list(path) {
+    Ok(names) => names,
+    Err(ref e) if e.kind() == io::ErrorKind::Unsupported => return Ok(Vec::new()),
+    Err(e) => return Err(e)
+  };
+  let mut names: Vec<String> = names.filter_map(|name| name.into_string().ok()).collect();
+  names.sort();
+  let mut xattrs = Vec::new();
+  for name in names {
+    if let Some(value) = xattr::get(path, &name)? {
+      // "name=" plus the value in hex has to fit in a header field.
+      if name.len() + 1 + value.len() * 2 > MAX_FIELD_LENGTH { return Err(xattr_too_large_error(&name)) }
+      xattrs.push(( name, value ));
+    }
+  }
+  Ok(xattrs)
+}
+
+#[cfg(not(feature = "xattr"))]
+fn read_xattrs(_path: &Path) -> io::Result<Vec<( String, Vec<u8> )>> {
+  Ok(Vec::new())
+}
+
+#[cfg(feature = "xattr")]
+fn write_xattrs(path: &Path, xattrs: &[( String, Vec<u8> )]) -> io::Result<()> {
+  for ( name, value ) in xattrs.iter() { xattr::set(path, name, value)? }
+  Ok(())
+}
+
+#[cfg(not(feature = "xattr"))]
+fn write_xattrs(_path: &Path, _xattrs: &[( String, Vec<u8> )]) -> io::Result<()> {
+  Err(xattrs_not_supported_error())
+}
+
+// "name=hex", split at the last "=" since a name could have one.
+fn decode_xattr(field: &str) -> io::Result<( String, Vec<u8> )> {
+  let split = field.rfind('=').ok_or_else(|| bad_xattr_error(field))?;
+  let ( name, hex ) = ( &field[.. split], &field[split + 1 ..] );
+  if name.is_empty() || hex.len() % 2 != 0 || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+    return Err(bad_xattr_error(field));
+  }
+  Ok(( name.to_string(), hex.from_hex() ))
+}
+
+fn decode_reference(hex: &str) -> io::Result<Bytes> {
+  if hex.len() != 64 || !hex.bytes().all(|c| c.is_ascii_hexdigit()) { return Err(bad_reference_error(hex)) }
+  Ok(Bytes::from(hex.from_hex()))
+}
+
+fn to_nanos(t: SystemTime) -> Option<u64> {
+  t.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64)
+}
+
+fn no_filename_error(path: &Path) -> io::Error {
+  BottleError::NoFilename(path.to_path_buf()).into()
+}
+
+fn not_a_folder_error(path: &Path) -> io::Error {
+  BottleError::NotAFolder(path.to_path_buf()).into()
+}
+
+fn not_a_file_bottle_error(btype: BottleType) -> io::Error {
+  BottleError::WrongType { expected: BottleType::File, found: btype }.into()
+}
+
+fn unsafe_filename_error(filename: &str) -> io::Error {
+  BottleError::UnsafeFilename(filename.to_string()).into()
+}
+
+fn already_exists_error(path: &Path) -> io::Error {
+  BottleError::AlreadyExists(path.to_path_buf()).into()
+}
+
+fn unsafe_link_error(target: &str) -> io::Error {
+  BottleError::UnsafeLink(target.to_string()).into()
+}
+
+fn link_target_not_found_error(path: &Path) -> io::Error {
+  BottleError::LinkTargetNotFound(path.to_path_buf()).into()
+}
+
+fn bad_xattr_error(field: &str) -> io::Error {
+  BottleError::BadXattr(field.to_string()).into()
+}
+
+#[cfg(feature = "xattr")]
+fn xattr_too_large_error(name: &str) -> io::Error {
+  BottleError::XattrTooLarge(name.to_string()).into()
+}
+
+#[cfg(not(feature = "xattr"))]
+fn xattrs_not_supported_error() -> io::Error {
+  BottleError::XattrsNotSupported.into()
+}
+
+fn missing_filename_error() -> io::Error {
+  BottleError::MissingFilename.into()
+}
+
+fn bad_sparse_map_error() -> io::Error {
+  BottleError::BadSparseMap.into()
+}
+
+fn missing_checksum_error() -> io::Error {
+  BottleError::MissingChecksum.into()
+}
+
+fn checksum_mismatch_error(expected: u32, found: u32) -> io::Error {
+  BottleError::ChecksumMismatch { expected, found }.into()
+}
+
+fn bad_reference_error(hex: &str) -> io::Error {
+  BottleError::BadReference(hex.to_string()).into()
+}
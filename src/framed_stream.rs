@@ -0,0 +1,191 @@
+use bytes::Bytes;
+use futures::{Async, Poll, Stream, future, stream};
+use futures::stream::Fuse;
+use std::cmp;
+use std::io;
+use std::mem;
+
+use error::BottleError;
+use stream_helpers::make_vec_stream_1;
+use zint;
+
+lazy_static! {
+  static ref END_OF_STREAM_BYTES: Bytes = zint::encode_length_bytes(zint::END_OF_STREAM);
+}
+
+// convert a byte stream into a stream with each chunk prefixed by a length
+// marker, suitable for embedding in a bottle. (each `Vec<Bytes>` gets a new
+// initial `Bytes`.) chunks too long for one frame are split across several,
+// so a stream can be any length.
+pub fn framed_vec_stream<S>(s: S) -> impl Stream<Item = Vec<Bytes>, Error = io::Error>
+  where S: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  framed_vec_stream_with_limit(s, zint::MAX_LENGTH as usize)
+}
+
+/// Like `framed_vec_stream`, but no frame is longer than `max_frame`.
+/// If `max_frame` is zero or more than `zint::MAX_LENGTH`, the stream is
+/// just an `InvalidFrameSize` error.
+pub fn framed_vec_stream_with_limit<S>(s: S, max_frame: usize) -> impl Stream<Item = Vec<Bytes>, Error = io::Error>
+  where S: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  if max_frame == 0 || max_frame > zint::MAX_LENGTH as usize {
+    return future::Either::A(stream::once(Err(invalid_frame_size_error(max_frame))));
+  }
+  future::Either::B(
+    s.map(move |buffers| stream::iter_ok(split_frames(buffers, max_frame))).flatten()
+      .chain(make_vec_stream_1(END_OF_STREAM_BYTES.clone()))
+  )
+}
+
+// cut a chunk into length-prefixed frames of at most `limit` bytes. empty
+// buffers are dropped, since an empty frame would look like END_OF_STREAM.
+pub(crate) fn split_frames(buffers: Vec<Bytes>, limit: usize) -> Vec<Vec<Bytes>> {
+  // almost always, the whole chunk fits in one frame.
+  let mut frames = Vec::with_capacity(1);
+  let mut frame = Vec::with_capacity(buffers.len() + 1);
+  frame.push(Bytes::new());
+  let mut length = 0;
+  for mut b in buffers {
+    while !b.is_empty() {
+      let n = cmp::min(b.len(), limit - length);
+      frame.push(b.split_to(n));
+      length += n;
+      if length == limit {
+        frame[0] = zint::encode_length_bytes(length as u32);
+        frames.push(mem::replace(&mut frame, vec![ Bytes::new() ]));
+        length = 0;
+      }
+    }
+  }
+  if length > 0 {
+    frame[0] = zint::encode_length_bytes(length as u32);
+    frames.push(frame);
+  }
+  frames
+}
+
+/// Undo `framed_vec_stream`: read length-prefixed frames and emit their
+/// contents, ending at END_OF_STREAM. Anything after that is left unread,
+/// and can be picked up with `into_remainder`.
+pub fn unframed_stream<S>(s: S) -> UnframedStream<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  UnframedStream { reader: FrameReader::new(s), remaining: 0, done: false }
+}
+
+#[must_use = "streams do nothing unless polled"]
+pub struct UnframedStream<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  reader: FrameReader<S>,
+  // bytes left in the current frame
+  remaining: usize,
+  done: bool
+}
+
+impl<S> UnframedStream<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  /// The rest of the source stream. If this stream hasn't ended yet, the
+  /// remainder starts in the middle of the framing.
+  pub fn into_remainder(self) -> impl Stream<Item = Bytes, Error = io::Error> {
+    self.reader.into_remainder()
+  }
+}
+
+impl<S> Stream for UnframedStream<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  type Item = Bytes;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    while !self.done {
+      if self.remaining > 0 {
+        let b = try_ready!(self.reader.poll_bytes(self.remaining)).ok_or_else(truncated_error)?;
+        self.remaining -= b.len();
+        return Ok(Async::Ready(Some(b)));
+      }
+      match try_ready!(self.reader.poll_length()) {
+        zint::END_OF_STREAM => self.done = true,
+        zint::END_OF_ALL_STREAMS => return Err(unexpected_end_error()),
+        length => self.remaining = length as usize
+      }
+    }
+    Ok(Async::Ready(None))
+  }
+}
+
+// low-level reading shared by the unframer and the bottle reader: chunks of
+// at most N bytes, and zint lengths that may be split across chunks.
+pub(crate) struct FrameReader<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  stream: Fuse<S>,
+  // unread leftovers from the last chunk
+  saved: Option<Bytes>,
+  // partially-read length prefix
+  length_buffer: Vec<u8>,
+  // bytes handed out so far
+  pub(crate) position: u64
+}
+
+impl<S> FrameReader<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  pub(crate) fn new(s: S) -> FrameReader<S> {
+    FrameReader { stream: s.fuse(), saved: None, length_buffer: Vec::with_capacity(4), position: 0 }
+  }
+
+  pub(crate) fn into_remainder(self) -> impl Stream<Item = Bytes, Error = io::Error> {
+    stream::iter_ok(self.saved).chain(self.stream)
+  }
+
+  // return up to `count` bytes, or `None` at the end of the stream.
+  pub(crate) fn poll_bytes(&mut self, count: usize) -> Poll<Option<Bytes>, io::Error> {
+    loop {
+      if let Some(mut b) = self.saved.take() {
+        if b.len() > count {
+          self.saved = Some(b.split_off(count));
+        }
+        self.position += b.len() as u64;
+        return Ok(Async::Ready(Some(b)));
+      }
+      match self.stream.poll()? {
+        Async::NotReady => return Ok(Async::NotReady),
+        Async::Ready(None) => return Ok(Async::Ready(None)),
+        Async::Ready(Some(b)) => {
+          if !b.is_empty() { self.saved = Some(b) }
+        }
+      }
+    }
+  }
+
+  // read a complete zint length, even if it's split across chunks.
+  pub(crate) fn poll_length(&mut self) -> Poll<u32, io::Error> {
+    loop {
+      let needed = if self.length_buffer.is_empty() { 1 } else { zint::length_of_length(self.length_buffer[0]) };
+      if self.length_buffer.len() == needed {
+        let length = zint::decode_length(&mut io::Cursor::new(&self.length_buffer));
+        self.length_buffer.clear();
+        return length.map(Async::Ready);
+      }
+      match self.poll_bytes(needed - self.length_buffer.len())? {
+        Async::NotReady => return Ok(Async::NotReady),
+        Async::Ready(Some(b)) => self.length_buffer.extend_from_slice(&b),
+        Async::Ready(None) => {
+          if self.length_buffer.is_empty() { return Err(truncated_error()) }
+          // let `decode_length` explain how much was missing.
+          let rv = zint::decode_length(&mut io::Cursor::new(&self.length_buffer)).and(Err(truncated_error()));
+          self.length_buffer.clear();
+          return rv;
+        }
+      }
+    }
+  }
+}
+
+
+// ----- errors
+
+pub(crate) fn truncated_error() -> io::Error {
+  BottleError::TruncatedStream.into()
+}
+
+pub(crate) fn unexpected_end_error() -> io::Error {
+  BottleError::UnexpectedEnd.into()
+}
+
+// there's no minimum frame size here; only the buffering has one.
+fn invalid_frame_size_error(max: usize) -> io::Error {
+  BottleError::InvalidFrameSize { min: 0, max }.into()
+}
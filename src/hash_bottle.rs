@@ -0,0 +1,259 @@
+use bytes::Bytes;
+use futures::{Async, Future, Poll, Stream, future};
+use std::cell::RefCell;
+use std::io;
+use std::mem;
+use std::rc::Rc;
+
+use bottle::{BottleType, ChildStream, ChildStreams, DecodeLimits, ReadOptions, make_bottle, read_bottle_with_options};
+use bottle_header::{Header};
+use error::BottleError;
+use hashing::{HashAlgorithm, Hasher, decode_hash_algorithm};
+
+const FIELD_HASH_TYPE: u8 = 0;
+const FIELD_SIGNED_BY: u8 = 0;
+
+/// Wrap a bottle (or any byte stream) in a hashed bottle: the inner stream
+/// is the first child, and its digest is the second. Fails if `algorithm`
+/// is a custom id that's reserved or isn't registered.
+pub fn hash_bottle<S>(s: S, algorithm: HashAlgorithm) -> io::Result<impl Stream<Item = Vec<Bytes>, Error = io::Error>>
+  where S: Stream<Item = Vec<Bytes>, Error = io::Error> + 'static
+{
+  let mut header = Header::new();
+  header.add_number(FIELD_HASH_TYPE, algorithm.id());
+  write_hash_bottle(s, algorithm, header, unsigned())
+}
+
+/// Like `hash_bottle`, but the second child is whatever `signer` makes of
+/// the digest (a signed blob), and `signed_by` is stored in the header to
+/// help a reader find the right verifier.
+pub fn hash_bottle_signed<S, F, Fut>(s: S, algorithm: HashAlgorithm, signed_by: String, signer: F)
+  -> io::Result<impl Stream<Item = Vec<Bytes>, Error = io::Error>>
+  where
+    S: Stream<Item = Vec<Bytes>, Error = io::Error> + 'static,
+    F: FnOnce(Bytes) -> Fut + 'static,
+    Fut: Future<Item = Bytes, Error = io::Error> + 'static
+{
+  let mut header = Header::new();
+  header.add_number(FIELD_HASH_TYPE, algorithm.id());
+  header.try_add_string(FIELD_SIGNED_BY, "signed by", signed_by)?;
+  write_hash_bottle(s, algorithm, header, Box::new(move |digest| Box::new(signer(digest))))
+}
+
+fn write_hash_bottle<S>(s: S, algorithm: HashAlgorithm, header: Header, signer: Transform)
+  -> io::Result<impl Stream<Item = Vec<Bytes>, Error = io::Error>>
+  where S: Stream<Item = Vec<Bytes>, Error = io::Error> + 'static
+{
+  // the digest stream isn't polled until the inner stream is done.
+  let hasher = Rc::new(RefCell::new(Some(Hasher::new(algorithm)?)));
+  let inner_hasher = hasher.clone();
+  let inner = s.map(move |buffers| {
+    if let Some(ref mut h) = *inner_hasher.borrow_mut() {
+      for b in &buffers { h.update(b) }
+    }
+    buffers
+  });
+  let digest = future::lazy(move || {
+    signer(hasher.borrow_mut().take().map(|h| h.finish()).unwrap_or_default())
+  }).map(|b| vec![ b ]).into_stream();
+
+  let streams: Vec<Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error>>> = vec![ Box::new(inner), Box::new(digest) ];
+  Ok(make_bottle(BottleType::Hashed, &header, streams))
+}
+
+/// Read a hashed bottle, returning its header and the inner stream. The
+/// inner stream is hashed as it's read, and fails at the end (instead of
+/// ending) if the digest doesn't match.
+pub fn verify_hash_bottle<S>(s: S)
+  -> impl Future<Item = (Header, VerifiedStream<impl Stream<Item = Bytes, Error = io::Error>>), Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  verify_hash_bottle_with_limits(s, DecodeLimits::default())
+}
+
+pub(crate) fn verify_hash_bottle_with_limits<S>(s: S, limits: DecodeLimits)
+  -> impl Future<Item = (Header, VerifiedStream<impl Stream<Item = Bytes, Error = io::Error>>), Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  read_verified(s, limits, |header| {
+    if header.get_string(FIELD_SIGNED_BY).is_some() { return Err(signed_error()) }
+    Ok(unsigned())
+  })
+}
+
+/// Read a signed hashed bottle. `verifier` is called with the header's
+/// `signed_by` and the signed blob, and returns the digest it vouches for
+/// (or an error if the signature is bad). The inner stream fails at the end
+/// if that digest doesn't match.
+pub fn verify_hash_bottle_signed<S, F, Fut>(s: S, verifier: F)
+  -> impl Future<Item = (Header, VerifiedStream<impl Stream<Item = Bytes, Error = io::Error>>), Error = io::Error>
+  where
+    S: Stream<Item = Bytes, Error = io::Error>,
+    F: FnOnce(&str, Bytes) -> Fut + 'static,
+    Fut: Future<Item = Bytes, Error = io::Error> + 'static
+{
+  verify_hash_bottle_signed_with_limits(s, verifier, DecodeLimits::default())
+}
+
+pub(crate) fn verify_hash_bottle_signed_with_limits<S, F, Fut>(s: S, verifier: F, limits: DecodeLimits)
+  -> impl Future<Item = (Header, VerifiedStream<impl Stream<Item = Bytes, Error = io::Error>>), Error = io::Error>
+  where
+    S: Stream<Item = Bytes, Error = io::Error>,
+    F: FnOnce(&str, Bytes) -> Fut + 'static,
+    Fut: Future<Item = Bytes, Error = io::Error> + 'static
+{
+  read_verified(s, limits, |header| {
+    let signed_by = header.get_string(FIELD_SIGNED_BY).ok_or_else(not_signed_error)?.to_string();
+    Ok(Box::new(move |blob| Box::new(verifier(&signed_by, blob))))
+  })
+}
+
+/// What a hashed bottle says about itself, as reported by `list_bottle`.
+/// Nothing is verified: for a signed bottle, `digest` is the signed blob.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct HashInfo {
+  pub algorithm: HashAlgorithm,
+  pub signed_by: Option<String>,
+  #[cfg_attr(feature = "serde", serde(with = "::serde_support::hex_bytes"))]
+  pub digest: Bytes
+}
+
+pub(crate) fn hash_info(header: &Header, digest: Bytes) -> io::Result<HashInfo> {
+  Ok(HashInfo {
+    algorithm: decode_hash_algorithm(header.get_number(FIELD_HASH_TYPE).unwrap_or(0))?,
+    signed_by: header.get_string(FIELD_SIGNED_BY).map(|s| s.to_string()),
+    digest
+  })
+}
+
+fn read_verified<S, F>(s: S, limits: DecodeLimits, make_verifier: F)
+  -> impl Future<Item = (Header, VerifiedStream<impl Stream<Item = Bytes, Error = io::Error>>), Error = io::Error>
+  where
+    S: Stream<Item = Bytes, Error = io::Error>,
+    F: FnOnce(&Header) -> io::Result<Transform>
+{
+  read_bottle_with_options(s, ReadOptions { limits, ..ReadOptions::default() }).and_then(move |( btype, header, children )| {
+    if btype != BottleType::Hashed { return Err(not_hashed_error(btype)) }
+    let algorithm = decode_hash_algorithm(header.get_number(FIELD_HASH_TYPE).unwrap_or(0))?;
+    let verifier = make_verifier(&header)?;
+    let stream = VerifiedStream {
+      children,
+      mode: VerifyMode::Start,
+      algorithm,
+      hasher: Some(Hasher::new(algorithm)?),
+      verifier: Some(verifier),
+      digest: Vec::new(),
+      limits
+    };
+    Ok(( header, stream ))
+  })
+}
+
+// turns a digest into a signed blob, or back.
+type Transform = Box<dyn FnOnce(Bytes) -> Box<dyn Future<Item = Bytes, Error = io::Error>>>;
+
+fn unsigned() -> Transform {
+  Box::new(|digest| Box::new(future::ok(digest)))
+}
+
+enum VerifyMode<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  Start,
+  Inner(ChildStream<S>),
+  BeforeDigest,
+  Digest(ChildStream<S>),
+  Verifying(Box<dyn Future<Item = Bytes, Error = io::Error>>),
+  Done
+}
+
+/// The inner stream of a hashed bottle, from `verify_hash_bottle`.
+#[must_use = "streams do nothing unless polled"]
+pub struct VerifiedStream<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  children: ChildStreams<S>,
+  mode: VerifyMode<S>,
+  algorithm: HashAlgorithm,
+  hasher: Option<Hasher>,
+  verifier: Option<Transform>,
+  digest: Vec<u8>,
+  limits: DecodeLimits
+}
+
+impl<S> Stream for VerifiedStream<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  type Item = Bytes;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    loop {
+      match self.mode {
+        VerifyMode::Start => {
+          let child = try_ready!(self.children.poll()).ok_or_else(missing_stream_error)?;
+          self.mode = VerifyMode::Inner(child);
+          continue;
+        }
+        VerifyMode::Inner(ref mut child) => {
+          if let Some(b) = try_ready!(child.poll()) {
+            if let Some(ref mut hasher) = self.hasher { hasher.update(&b) }
+            return Ok(Async::Ready(Some(b)));
+          }
+        }
+        VerifyMode::BeforeDigest => {
+          let child = try_ready!(self.children.poll()).ok_or_else(missing_stream_error)?;
+          self.mode = VerifyMode::Digest(child);
+          continue;
+        }
+        VerifyMode::Digest(ref mut child) => {
+          if let Some(b) = try_ready!(child.poll()) {
+            self.limits.check_buffered((self.digest.len() + b.len()) as u64)?;
+            self.digest.extend_from_slice(&b);
+            continue;
+          }
+        }
+        VerifyMode::Verifying(ref mut f) => {
+          let digest = try_ready!(f.poll());
+          let size = self.algorithm.digest_size();
+          if digest.len() != size { return Err(wrong_digest_size_error(self.algorithm, size, digest.len())) }
+          let expected = self.hasher.take().map(|h| h.finish());
+          if expected != Some(digest) { return Err(hash_mismatch_error()) }
+        }
+        VerifyMode::Done => return Ok(Async::Ready(None))
+      }
+
+      // a child stream (or the verifier) just finished.
+      self.mode = match self.mode {
+        VerifyMode::Inner(_) => VerifyMode::BeforeDigest,
+        VerifyMode::Digest(_) => {
+          let verifier = self.verifier.take().ok_or_else(hash_mismatch_error)?;
+          VerifyMode::Verifying(verifier(Bytes::from(mem::take(&mut self.digest))))
+        }
+        _ => VerifyMode::Done
+      };
+    }
+  }
+}
+
+
+// ----- errors
+
+fn not_hashed_error(btype: BottleType) -> io::Error {
+  BottleError::WrongType { expected: BottleType::Hashed, found: btype }.into()
+}
+
+fn missing_stream_error() -> io::Error {
+  BottleError::MissingHashStream.into()
+}
+
+fn signed_error() -> io::Error {
+  BottleError::Signed.into()
+}
+
+fn not_signed_error() -> io::Error {
+  BottleError::NotSigned.into()
+}
+
+fn wrong_digest_size_error(algorithm: HashAlgorithm, expected: usize, found: usize) -> io::Error {
+  BottleError::WrongDigestSize { algorithm, expected, found }.into()
+}
+
+fn hash_mismatch_error() -> io::Error {
+  BottleError::HashMismatch.into()
+}
@@ -0,0 +1,183 @@
+use blake3;
+use bytes::Bytes;
+use futures::{Future, Stream, future};
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, RwLock};
+
+use error::BottleError;
+
+/// Ids below this are reserved for algorithms built into the crate.
+pub const FIRST_CUSTOM_HASH: u64 = 16;
+
+type HashFactory = Arc<dyn Fn() -> Box<dyn CustomHasher> + Send + Sync>;
+
+lazy_static! {
+  static ref CUSTOM_HASHES: RwLock<HashMap<u64, ( usize, HashFactory )>> = RwLock::new(HashMap::new());
+
+  // CRC32C (Castagnoli, reflected), a byte at a time.
+  static ref CRC32C_TABLE: [u32; 256] = {
+    let mut table = [ 0u32; 256 ];
+    for ( i, entry ) in table.iter_mut().enumerate() {
+      let mut crc = i as u32;
+      for _ in 0 .. 8 { crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 } }
+      *entry = crc;
+    }
+    table
+  };
+}
+
+// hash types, as stored in a header field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum HashAlgorithm {
+  Sha512,
+  Sha256,
+  Blake3,
+  /// registered with `register_hash_algorithm`
+  Custom(u64)
+}
+
+impl HashAlgorithm {
+  /// The id stored in a hashed bottle's header.
+  pub fn id(&self) -> u64 {
+    match *self {
+      HashAlgorithm::Sha512 => 0,
+      HashAlgorithm::Sha256 => 1,
+      HashAlgorithm::Blake3 => 2,
+      HashAlgorithm::Custom(id) => id
+    }
+  }
+
+  /// How many bytes are in a digest (0 for an unregistered custom id).
+  pub fn digest_size(&self) -> usize {
+    match *self {
+      HashAlgorithm::Sha512 => 64,
+      HashAlgorithm::Sha256 | HashAlgorithm::Blake3 => 32,
+      HashAlgorithm::Custom(id) => CUSTOM_HASHES.read().unwrap().get(&id).map(|&( size, _ )| size).unwrap_or(0)
+    }
+  }
+}
+
+pub fn decode_hash_algorithm(n: u64) -> Result<HashAlgorithm, io::Error> {
+  match n {
+    0 => Ok(HashAlgorithm::Sha512),
+    1 => Ok(HashAlgorithm::Sha256),
+    2 => Ok(HashAlgorithm::Blake3),
+    _ if CUSTOM_HASHES.read().unwrap().contains_key(&n) => Ok(HashAlgorithm::Custom(n)),
+    _ => Err(unknown_hash_algorithm_error(n))
+  }
+}
+
+/// A hash algorithm from outside the crate. Each stream that's hashed gets
+/// a new one from the factory given to `register_hash_algorithm`.
+pub trait CustomHasher: Send {
+  fn update(&mut self, data: &[u8]);
+  fn finish(self: Box<Self>) -> Bytes;
+  fn box_clone(&self) -> Box<dyn CustomHasher>;
+}
+
+impl Clone for Box<dyn CustomHasher> {
+  fn clone(&self) -> Box<dyn CustomHasher> {
+    self.box_clone()
+  }
+}
+
+/// Make a custom algorithm available to every hasher and reader, under
+/// an id of `FIRST_CUSTOM_HASH` or more. Registering an id again replaces
+/// it. Readers only know an id once it's registered, so do this before
+/// reading bottles that use it.
+pub fn register_hash_algorithm<F>(id: u64, digest_size: usize, factory: F) -> io::Result<HashAlgorithm>
+  where F: Fn() -> Box<dyn CustomHasher> + Send + Sync + 'static
+{
+  if id < FIRST_CUSTOM_HASH { return Err(reserved_hash_algorithm_error(id)) }
+  CUSTOM_HASHES.write().unwrap().insert(id, ( digest_size, Arc::new(factory) ));
+  Ok(HashAlgorithm::Custom(id))
+}
+
+/// Incremental hasher over any of the supported algorithms.
+#[derive(Clone)]
+pub enum Hasher {
+  Sha512(Sha512),
+  Sha256(Sha256),
+  Blake3(Box<blake3::Hasher>),
+  Custom(Box<dyn CustomHasher>)
+}
+
+impl Hasher {
+  /// Fails if `algorithm` is a custom id that's reserved, or isn't
+  /// registered.
+  pub fn new(algorithm: HashAlgorithm) -> io::Result<Hasher> {
+    Ok(match algorithm {
+      HashAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+      HashAlgorithm::Sha256 => Hasher::sha256(),
+      HashAlgorithm::Blake3 => Hasher::blake3(),
+      HashAlgorithm::Custom(id) if id < FIRST_CUSTOM_HASH => return Err(reserved_hash_algorithm_error(id)),
+      HashAlgorithm::Custom(id) => {
+        let factory = CUSTOM_HASHES.read().unwrap().get(&id).map(|entry| entry.1.clone());
+        Hasher::Custom(factory.ok_or_else(|| unknown_hash_algorithm_error(id))?())
+      }
+    })
+  }
+
+  // for the crate's own digests, which never need a lookup.
+  pub(crate) fn sha256() -> Hasher {
+    Hasher::Sha256(Sha256::new())
+  }
+
+  pub(crate) fn blake3() -> Hasher {
+    Hasher::Blake3(Box::new(blake3::Hasher::new()))
+  }
+
+  pub fn update(&mut self, data: &[u8]) {
+    match *self {
+      Hasher::Sha512(ref mut h) => h.update(data),
+      Hasher::Sha256(ref mut h) => h.update(data),
+      Hasher::Blake3(ref mut h) => { h.update(data); }
+      Hasher::Custom(ref mut h) => h.update(data)
+    }
+  }
+
+  pub fn finish(self) -> Bytes {
+    match self {
+      Hasher::Sha512(h) => Bytes::from(h.finalize().to_vec()),
+      Hasher::Sha256(h) => Bytes::from(h.finalize().to_vec()),
+      Hasher::Blake3(h) => Bytes::from(h.finalize().as_bytes().to_vec()),
+      Hasher::Custom(h) => h.finish()
+    }
+  }
+}
+
+/// Continue a CRC32C over more data, starting from 0. It's no defense
+/// against tampering, but it's cheap enough to check on every read.
+pub fn crc32c(crc: u32, data: &[u8]) -> u32 {
+  let mut crc = !crc;
+  for &b in data { crc = CRC32C_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8) }
+  !crc
+}
+
+/// Hash the fully-serialized bytes of a bottle (what would land on disk),
+/// as opposed to the inner payload hash that a hashed bottle stores. Useful
+/// for publishing a checksum alongside a `.4b` file.
+pub fn hash_bottle_bytes<S>(s: S, algorithm: HashAlgorithm) -> impl Future<Item = Bytes, Error = io::Error>
+  where S: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  future::result(Hasher::new(algorithm)).and_then(|hasher| {
+    s.fold(hasher, |mut hasher, buffers| {
+      for b in &buffers { hasher.update(b) }
+      Ok::<_, io::Error>(hasher)
+    })
+  }).map(|hasher| hasher.finish())
+}
+
+
+// ----- errors
+
+fn unknown_hash_algorithm_error(n: u64) -> io::Error {
+  BottleError::UnknownHashAlgorithm(n).into()
+}
+
+fn reserved_hash_algorithm_error(n: u64) -> io::Error {
+  BottleError::ReservedHashAlgorithm(n).into()
+}
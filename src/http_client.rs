@@ -0,0 +1,102 @@
+#[cfg(feature = "s3")]
+use bytes::Bytes;
+use std::io::{self, Read};
+use std::time::Duration;
+#[cfg(feature = "s3")]
+use ureq::Request;
+use ureq::{Agent, AgentBuilder, ErrorKind, Response};
+use url::Url;
+
+use error::BottleError;
+
+/// Parse an `http://` or `https://` URL.
+pub(crate) fn parse_url(url: &str) -> io::Result<Url> {
+  let parsed = Url::parse(url).map_err(|_| bad_url_error(url))?;
+  if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none_or(|host| host.is_empty()) {
+    return Err(bad_url_error(url));
+  }
+  Ok(parsed)
+}
+
+/// A client that gives up on a request if the server goes quiet for
+/// `timeout`, and follows up to `redirects` redirects.
+pub(crate) fn agent(timeout: Option<Duration>, redirects: u32) -> Agent {
+  let mut builder = AgentBuilder::new().redirects(redirects);
+  if let Some(timeout) = timeout { builder = builder.timeout_read(timeout).timeout_write(timeout) }
+  builder.build()
+}
+
+/// The response to a request, even if its status is an error, so the
+/// caller can decide what it means. Only failing to get a response at all
+/// is an error.
+pub(crate) fn response(result: Result<Response, ureq::Error>) -> io::Result<Response> {
+  match result {
+    Ok(response) | Err(ureq::Error::Status(_, response)) => Ok(response),
+    Err(ureq::Error::Transport(t)) => Err(match t.kind() {
+      ErrorKind::InvalidUrl | ErrorKind::UnknownScheme => bad_url_error(t.url().map(|u| u.as_str()).unwrap_or("")),
+      ErrorKind::BadStatus | ErrorKind::BadHeader | ErrorKind::TooManyRedirects => bad_response_error(),
+      _ => io::Error::other(t)
+    })
+  }
+}
+
+/// Read exactly `length` bytes of body.
+pub(crate) fn read_exact(response: Response, length: usize) -> io::Result<Vec<u8>> {
+  let mut data = vec![ 0u8; length ];
+  response.into_reader().read_exact(&mut data).map_err(|e| {
+    if e.kind() == io::ErrorKind::UnexpectedEof { BottleError::TruncatedStream.into() } else { e }
+  })?;
+  Ok(data)
+}
+
+/// Read the whole body, unless it's longer than `max`: the only bodies
+/// worth reading whole are small ones, like S3's XML replies.
+#[cfg(feature = "s3")]
+pub(crate) fn read_body(response: Response, max: usize) -> io::Result<Vec<u8>> {
+  let mut data = Vec::new();
+  response.into_reader().take(max as u64 + 1).read_to_end(&mut data)?;
+  if data.len() > max { return Err(bad_response_error()) }
+  Ok(data)
+}
+
+/// Send `body` without copying it into one buffer first, and return the
+/// response the way `response` does.
+#[cfg(feature = "s3")]
+pub(crate) fn send(request: Request, body: &[Bytes]) -> io::Result<Response> {
+  let length: usize = body.iter().map(|b| b.len()).sum();
+  response(request.set("Content-Length", &length.to_string()).send(BytesReader { body, offset: 0 }))
+}
+
+#[cfg(feature = "s3")]
+struct BytesReader<'a> {
+  body: &'a [Bytes],
+  offset: usize
+}
+
+#[cfg(feature = "s3")]
+impl<'a> Read for BytesReader<'a> {
+  fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+    while let Some(( first, rest )) = self.body.split_first() {
+      if self.offset < first.len() {
+        let n = buffer.len().min(first.len() - self.offset);
+        buffer[.. n].copy_from_slice(&first[self.offset .. self.offset + n]);
+        self.offset += n;
+        return Ok(n);
+      }
+      self.body = rest;
+      self.offset = 0;
+    }
+    Ok(0)
+  }
+}
+
+
+// ----- errors
+
+pub(crate) fn bad_url_error(url: &str) -> io::Error {
+  BottleError::BadUrl(url.to_string()).into()
+}
+
+pub(crate) fn bad_response_error() -> io::Error {
+  BottleError::BadHttpResponse.into()
+}
@@ -0,0 +1,292 @@
+use bytes::Bytes;
+use futures::{Future, Stream, future};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+
+use archive::{ArchiveReader, ByteStream};
+use bottle_header::{Header};
+use error::BottleError;
+use file_bottle::{ExtractOptions, FileMetadata, WriteSettings, restore_metadata, set_mode, tracked_directory};
+use hashing::{Hasher};
+use to_hex::{FromHex, ToHex};
+use zint;
+
+// header fields for each entry of an encoded manifest
+const FIELD_PATH: u8 = 0;
+const FIELD_DIGEST: u8 = 1;
+const FIELD_SIZE: u8 = 0;
+const FIELD_MODIFIED_NANOS: u8 = 1;
+
+const READ_BLOCK_SIZE: usize = 64 * 1024;
+
+type References = HashMap<Bytes, Vec<( PathBuf, FileMetadata )>>;
+
+/*
+ * Incremental archives: a folder bottle where each file that a parent
+ * archive already has is stored as a reference instead, with its metadata
+ * and the BLAKE3 digest of its contents, but no contents. Restoring one
+ * (`extract_incremental`) needs the parent archive too.
+ *
+ * The parent is described by a `Manifest`. A file with the same path,
+ * size, and modified time as in the parent is assumed to be unchanged
+ * without reading it. Anything else is hashed and matched by digest, so a
+ * file that moved is still a reference.
+ */
+
+/// One file in a `Manifest`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+  pub path: PathBuf,
+  pub size: Option<u64>,
+  pub modified_nanos: Option<u64>,
+  /// BLAKE3 of the contents
+  pub digest: Bytes
+}
+
+/// The files in an archive, to make an incremental archive against.
+#[derive(Clone, Debug, Default)]
+pub struct Manifest {
+  entries: Vec<ManifestEntry>,
+  by_path: HashMap<PathBuf, usize>,
+  digests: HashSet<Bytes>
+}
+
+impl Manifest {
+  pub fn new() -> Manifest {
+    Manifest::default()
+  }
+
+  /// Read the files in an archive, hashing each one. An incremental
+  /// archive works too: its references are listed as the files they
+  /// stand for, so the next archive in a rotation can use it as a parent.
+  pub fn from_archive<S>(reader: ArchiveReader, s: S) -> impl Future<Item = Manifest, Error = io::Error>
+    where S: Stream<Item = Bytes, Error = io::Error> + 'static
+  {
+    reader.entries(s).and_then(|entry| -> Box<dyn Future<Item = Option<ManifestEntry>, Error = io::Error>> {
+      let metadata = entry.metadata;
+      if metadata.folder || metadata.is_link() { return Box::new(future::ok(None)) }
+      let path = entry.path;
+      let size = metadata.size;
+      let modified_nanos = metadata.modified_nanos;
+      match metadata.reference {
+        Some(digest) => Box::new(future::ok(Some(ManifestEntry { path, size, modified_nanos, digest }))),
+        None => Box::new(hash_stream(entry.content).map(move |digest| Some(ManifestEntry { path, size, modified_nanos, digest })))
+      }
+    }).filter_map(|entry| entry).fold(Manifest::new(), |mut manifest, entry| {
+      manifest.add(entry);
+      Ok::<_, io::Error>(manifest)
+    })
+  }
+
+  pub fn add(&mut self, entry: ManifestEntry) {
+    self.digests.insert(entry.digest.clone());
+    self.by_path.insert(entry.path.clone(), self.entries.len());
+    self.entries.push(entry);
+  }
+
+  pub fn entries(&self) -> &[ManifestEntry] {
+    &self.entries
+  }
+
+  pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<&ManifestEntry> {
+    self.by_path.get(path.as_ref()).map(|&index| &self.entries[index])
+  }
+
+  pub fn contains_digest(&self, digest: &[u8]) -> bool {
+    self.digests.contains(digest)
+  }
+
+  /// Each entry as a header, after its length.
+  pub fn encode(&self) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    for entry in self.entries.iter() {
+      let mut header = Header::new();
+      for c in entry.path.iter() { header.add_string(FIELD_PATH, c.to_string_lossy().to_string()) }
+      header.add_string(FIELD_DIGEST, entry.digest.to_hex());
+      if let Some(n) = entry.size { header.add_number(FIELD_SIZE, n) }
+      if let Some(n) = entry.modified_nanos { header.add_number(FIELD_MODIFIED_NANOS, n) }
+      let data = header.encode();
+      buffer.extend_from_slice(&zint::encode_length(data.len() as u32));
+      buffer.extend_from_slice(&data);
+    }
+    buffer
+  }
+
+  pub fn decode(data: &[u8]) -> io::Result<Manifest> {
+    let mut manifest = Manifest::new();
+    let mut cursor = Cursor::new(data);
+    while (cursor.position() as usize) < data.len() {
+      let length = zint::decode_length(&mut cursor).map_err(|_| bad_manifest_error())? as usize;
+      let start = cursor.position() as usize;
+      if start + length > data.len() { return Err(bad_manifest_error()) }
+      let header = Header::decode(&data[start .. start + length])?;
+      cursor.set_position((start + length) as u64);
+
+      let path: PathBuf = header.get_strings(FIELD_PATH).into_iter().collect();
+      let hex = header.get_string(FIELD_DIGEST).ok_or_else(bad_manifest_error)?;
+      if path.as_os_str().is_empty() || hex.len() != 64 || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(bad_manifest_error());
+      }
+      manifest.add(ManifestEntry {
+        path,
+        size: header.get_number(FIELD_SIZE),
+        modified_nanos: header.get_number(FIELD_MODIFIED_NANOS),
+        digest: Bytes::from(hex.from_hex())
+      });
+    }
+    Ok(manifest)
+  }
+
+  // the metadata for a reference, if the parent already has this file.
+  pub(crate) fn reference_for(&self, path: &Path, archive_path: &Path) -> io::Result<Option<FileMetadata>> {
+    let mut metadata = FileMetadata::from_path(path)?;
+    let unchanged = self.get(archive_path).filter(|entry| {
+      entry.size == metadata.size && entry.modified_nanos.is_some() && entry.modified_nanos == metadata.modified_nanos
+    });
+    let digest = match unchanged {
+      Some(entry) => entry.digest.clone(),
+      None => hash_file(path)?
+    };
+    if !self.contains_digest(&digest) { return Ok(None) }
+    metadata.reference = Some(digest);
+    Ok(Some(metadata))
+  }
+}
+
+/// Like `archive_directory`, but files that `parent` already has are
+/// stored as references to it.
+pub fn archive_directory_incremental<P: AsRef<Path>>(path: P, parent: Manifest)
+  -> io::Result<Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error>>>
+{
+  tracked_directory(path.as_ref(), None, WriteSettings::default(), Some(Rc::new(parent)))
+}
+
+/// Extract an incremental archive into `target_dir` (like
+/// `extract_bottle`), and then fill in each reference from the parent
+/// archive. The parent is only read if something refers to it, and it has
+/// to be a whole archive, not another incremental one.
+pub fn extract_incremental<S, P, T>(incremental: ( ArchiveReader, S ), parent: ( ArchiveReader, P ), target_dir: T, options: ExtractOptions)
+  -> impl Future<Item = Vec<PathBuf>, Error = io::Error>
+  where
+    S: Stream<Item = Bytes, Error = io::Error> + 'static,
+    P: Stream<Item = Bytes, Error = io::Error> + 'static,
+    T: AsRef<Path>
+{
+  let target = target_dir.as_ref().to_path_buf();
+  let found: Rc<RefCell<Vec<( PathBuf, FileMetadata )>>> = Rc::new(RefCell::new(Vec::new()));
+  let seen = found.clone();
+  let fill_options = options.clone();
+  let mut options = options;
+  options.filter = Some(options.filter.take().unwrap_or_default().observe(move |entry| {
+    if entry.metadata.reference.is_some() && !entry.metadata.folder && !entry.metadata.is_link() {
+      seen.borrow_mut().push(( entry.path.clone(), entry.metadata.clone() ));
+    }
+  }));
+
+  let ( reader, s ) = incremental;
+  let extract_target = target.clone();
+  let extracted = reader.extract(s, extract_target, options);
+
+  extracted.and_then(move |paths| -> Box<dyn Future<Item = Vec<PathBuf>, Error = io::Error>> {
+    // skipped files weren't written, so they aren't filled in either.
+    let mut wanted: References = HashMap::new();
+    {
+      let written: HashSet<&PathBuf> = paths.iter().collect();
+      for ( relative, metadata ) in found.borrow_mut().drain(..) {
+        let path = target.join(&relative);
+        if !written.contains(&path) { continue }
+        wanted.entry(metadata.reference.clone().unwrap_or_default()).or_default().push(( path, metadata ));
+      }
+    }
+    if wanted.is_empty() { return Box::new(future::ok(paths)) }
+    Box::new(fill_references(parent, &target, wanted, fill_options).map(move |_| paths))
+  })
+}
+
+// each file in the parent that might be wanted (by its size) is copied
+// out to a scratch file while it's hashed, and from there to each file
+// that refers to it.
+fn fill_references<P>(parent: ( ArchiveReader, P ), target: &Path, wanted: References, options: ExtractOptions)
+  -> impl Future<Item = (), Error = io::Error>
+  where P: Stream<Item = Bytes, Error = io::Error> + 'static
+{
+  let sizes: HashSet<Option<u64>> = wanted.values().flat_map(|v| v.iter().map(|( _, metadata )| metadata.size)).collect();
+  let wanted = Rc::new(RefCell::new(wanted));
+  let remaining = wanted.clone();
+  let scratch = target.join(format!(".4bottle-parent-{}", process::id()));
+
+  let ( reader, s ) = parent;
+  reader.entries(s).for_each(move |entry| -> Box<dyn Future<Item = (), Error = io::Error>> {
+    let metadata = &entry.metadata;
+    let possible = sizes.contains(&metadata.size) || sizes.contains(&None) || metadata.size.is_none();
+    if metadata.folder || metadata.is_link() || metadata.reference.is_some() || !possible || wanted.borrow().is_empty() {
+      return Box::new(future::ok(()));
+    }
+    let file = match fs::File::create(&scratch) {
+      Ok(file) => file,
+      Err(e) => return Box::new(future::err(e))
+    };
+    let scratch = scratch.clone();
+    let wanted = wanted.clone();
+    let options = options.clone();
+    Box::new(entry.content.fold(( file, Hasher::blake3() ), |( mut file, mut hasher ), data| {
+      file.write_all(&data)?;
+      hasher.update(&data);
+      Ok::<_, io::Error>(( file, hasher ))
+    }).and_then(move |( _, hasher )| {
+      let found = wanted.borrow_mut().remove(&hasher.finish());
+      for ( path, metadata ) in found.unwrap_or_default() { fill(&scratch, &path, &metadata, &options)? }
+      fs::remove_file(&scratch)
+    }))
+  }).and_then(move |_| {
+    match remaining.borrow().values().flat_map(|v| v.iter().map(|( path, _ )| path)).min() {
+      Some(path) => Err(missing_parent_entry_error(path)),
+      None => Ok(())
+    }
+  })
+}
+
+// the placeholder was extracted empty, and its mode may not let us write.
+// writing over it (instead of replacing it) keeps any hard links to it.
+fn fill(scratch: &Path, path: &Path, metadata: &FileMetadata, options: &ExtractOptions) -> io::Result<()> {
+  set_mode(path, 0o600)?;
+  fs::copy(scratch, path)?;
+  restore_metadata(path, metadata, options)
+}
+
+fn hash_stream(s: ByteStream) -> impl Future<Item = Bytes, Error = io::Error> {
+  s.fold(Hasher::blake3(), |mut hasher, data| {
+    hasher.update(&data);
+    Ok::<_, io::Error>(hasher)
+  }).map(|hasher| hasher.finish())
+}
+
+fn hash_file(path: &Path) -> io::Result<Bytes> {
+  let mut file = fs::File::open(path)?;
+  let mut hasher = Hasher::blake3();
+  let mut buffer = vec![ 0; READ_BLOCK_SIZE ];
+  loop {
+    match file.read(&mut buffer) {
+      Ok(0) => return Ok(hasher.finish()),
+      Ok(n) => hasher.update(&buffer[.. n]),
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+      Err(e) => return Err(e)
+    }
+  }
+}
+
+
+// ----- errors
+
+fn bad_manifest_error() -> io::Error {
+  BottleError::BadManifest.into()
+}
+
+fn missing_parent_entry_error(path: &Path) -> io::Error {
+  BottleError::MissingParentEntry(path.to_path_buf()).into()
+}
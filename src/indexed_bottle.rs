@@ -0,0 +1,88 @@
+use bytes::Bytes;
+use futures::{Future, Stream, future};
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::rc::Rc;
+
+use bottle::{BottleType, make_bottle};
+use bottle_header::{Header};
+use error::BottleError;
+use stream_helpers::make_vec_stream_1;
+
+const FIELD_ENTRY_COUNT: u8 = 0;
+
+// the footer is the offset of the index bottle, as 8 bytes LSB.
+pub const FOOTER_SIZE: usize = 8;
+
+/*
+ * A bottle followed by an index, so a reader that can seek doesn't have to
+ * stream through the whole thing to get to one child stream:
+ *
+ *     [bottle] [index bottle] [footer]
+ *
+ * The index bottle has one child stream: the byte offset (8 bytes LSB) of
+ * each of the first bottle's child streams, from the start. The footer is
+ * the offset of the index bottle. A streaming reader sees a normal bottle,
+ * and can ignore the rest.
+ */
+pub fn make_indexed_bottle<I, A>(btype: BottleType, header: &Header, streams: I)
+  -> impl Stream<Item = Vec<Bytes>, Error = io::Error>
+  where
+    I: IntoIterator<Item = A>,
+    A: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  let position = Rc::new(Cell::new(0u64));
+  let offsets = Rc::new(RefCell::new(Vec::new()));
+
+  // a child stream isn't polled until everything before it has been
+  // emitted, so that's when to note where it starts.
+  let children = {
+    let position = position.clone();
+    let offsets = offsets.clone();
+    streams.into_iter().map(move |s| {
+      let position = position.clone();
+      let offsets = offsets.clone();
+      future::lazy(move || {
+        offsets.borrow_mut().push(position.get());
+        future::ok::<_, io::Error>(s)
+      }).flatten_stream()
+    }).collect::<Vec<_>>()
+  };
+  let counted_position = position.clone();
+  let bottle = make_bottle(btype, header, children).map(move |buffers| {
+    let length: usize = buffers.iter().map(|b| b.len()).sum();
+    counted_position.set(counted_position.get() + length as u64);
+    buffers
+  });
+
+  let index = future::lazy(move || {
+    let offsets = offsets.borrow();
+    let mut header = Header::new();
+    header.add_number(FIELD_ENTRY_COUNT, offsets.len() as u64);
+    let mut data = Vec::with_capacity(offsets.len() * 8);
+    for offset in offsets.iter() { data.extend_from_slice(&offset.to_le_bytes()) }
+    let footer = Bytes::from(&position.get().to_le_bytes()[..]);
+    let index = make_bottle(BottleType::Index, &header, vec![ make_vec_stream_1(Bytes::from(data)) ]);
+    future::ok::<_, io::Error>(index.chain(make_vec_stream_1(footer)))
+  }).flatten_stream();
+
+  bottle.chain(index)
+}
+
+/// Decode the contents of an index bottle's child stream.
+pub(crate) fn decode_offsets(header: &Header, data: &[u8]) -> io::Result<Vec<u64>> {
+  let count = header.get_number(FIELD_ENTRY_COUNT).unwrap_or(0) as usize;
+  if count.checked_mul(8) != Some(data.len()) { return Err(bad_index_error()) }
+  Ok(data.chunks(8).map(|chunk| {
+    let mut buffer = [ 0u8; 8 ];
+    buffer.copy_from_slice(chunk);
+    u64::from_le_bytes(buffer)
+  }).collect())
+}
+
+
+// ----- errors
+
+fn bad_index_error() -> io::Error {
+  BottleError::BadIndex.into()
+}
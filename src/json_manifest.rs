@@ -0,0 +1,138 @@
+use bytes::Bytes;
+use futures::Stream;
+use std::fmt;
+use std::io;
+
+use archive::{EntryInfo, list_bottle};
+use file_bottle::FileMetadata;
+use hash_bottle::HashInfo;
+use hashing::HashAlgorithm;
+use to_hex::ToHex;
+
+/// One entry of an archive as a line of JSON (without the line feed), for
+/// tools that want a manifest without linking this library. The layout is
+/// the same as the `serde` feature's: `path`, `metadata` (every field of
+/// `FileMetadata`, `null` if missing), `layers`, and `hashes`, with bytes
+/// as hex strings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JsonLine(String);
+
+impl JsonLine {
+  pub fn from_entry(entry: &EntryInfo) -> JsonLine {
+    let mut out = String::new();
+    out.push_str("{\"path\":");
+    string(&mut out, &entry.path.to_string_lossy());
+    out.push_str(",\"metadata\":");
+    metadata(&mut out, &entry.metadata);
+    out.push_str(",\"layers\":[");
+    for ( i, layer ) in entry.layers.iter().enumerate() {
+      if i > 0 { out.push(',') }
+      string(&mut out, &format!("{:?}", layer));
+    }
+    out.push_str("],\"hashes\":[");
+    for ( i, hash ) in entry.hashes.iter().enumerate() {
+      if i > 0 { out.push(',') }
+      hash_info(&mut out, hash);
+    }
+    out.push_str("]}");
+    JsonLine(out)
+  }
+
+  pub fn as_str(&self) -> &str {
+    &self.0
+  }
+
+  pub fn into_string(self) -> String {
+    self.0
+  }
+}
+
+impl fmt::Display for JsonLine {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+/// List an archive that isn't encrypted as a JSON-lines manifest: one
+/// `JsonLine` per file and folder, in the order `list_bottle` finds them.
+/// (For an encrypted archive, map `ArchiveReader::list` through
+/// `JsonLine::from_entry`.)
+pub fn export_manifest<S>(s: S) -> impl Stream<Item = JsonLine, Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error> + 'static
+{
+  list_bottle(s).map(|entry| JsonLine::from_entry(&entry))
+}
+
+fn metadata(out: &mut String, m: &FileMetadata) {
+  out.push_str("{\"filename\":");
+  string(out, &m.filename);
+  field(out, "mime_type", m.mime_type.as_deref(), string);
+  field(out, "size", m.size, number);
+  field(out, "posix_mode", m.posix_mode.map(|n| n as u64), number);
+  field(out, "created_nanos", m.created_nanos, number);
+  field(out, "modified_nanos", m.modified_nanos, number);
+  field(out, "accessed_nanos", m.accessed_nanos, number);
+  field(out, "username", m.username.as_deref(), string);
+  field(out, "group", m.group.as_deref(), string);
+  field(out, "folder", Some(m.folder), boolean);
+  field(out, "symlink", m.symlink.as_deref(), string);
+  field(out, "hardlink", m.hardlink.as_deref(), string);
+  out.push_str(",\"xattrs\":[");
+  for ( i, ( name, value ) ) in m.xattrs.iter().enumerate() {
+    if i > 0 { out.push(',') }
+    out.push('[');
+    string(out, name);
+    out.push(',');
+    string(out, &value.to_hex());
+    out.push(']');
+  }
+  out.push(']');
+  field(out, "sparse", Some(m.sparse), boolean);
+  field(out, "crc32c", Some(m.crc32c), boolean);
+  field(out, "reference", m.reference.as_ref().map(|digest| digest.to_hex()).as_deref(), string);
+  out.push('}');
+}
+
+fn hash_info(out: &mut String, hash: &HashInfo) {
+  out.push_str("{\"algorithm\":");
+  match hash.algorithm {
+    HashAlgorithm::Custom(id) => out.push_str(&format!("{{\"Custom\":{}}}", id)),
+    algorithm => string(out, &format!("{:?}", algorithm))
+  }
+  field(out, "signed_by", hash.signed_by.as_deref(), string);
+  field(out, "digest", Some(hash.digest.to_hex().as_str()), string);
+  out.push('}');
+}
+
+// `,"name":value`, or null.
+fn field<T, F: Fn(&mut String, T)>(out: &mut String, name: &str, value: Option<T>, write: F) {
+  out.push_str(&format!(",\"{}\":", name));
+  match value {
+    Some(value) => write(out, value),
+    None => out.push_str("null")
+  }
+}
+
+fn number(out: &mut String, n: u64) {
+  out.push_str(&n.to_string());
+}
+
+fn boolean(out: &mut String, b: bool) {
+  out.push_str(if b { "true" } else { "false" });
+}
+
+fn string(out: &mut String, s: &str) {
+  out.push('"');
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      '\r' => out.push_str("\\r"),
+      '\t' => out.push_str("\\t"),
+      c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+      c => out.push(c)
+    }
+  }
+  out.push('"');
+}
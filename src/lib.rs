@@ -1,20 +1,84 @@
-#![feature(conservative_impl_trait)]
-
+extern crate aes_gcm;
+extern crate argon2;
+extern crate blake3;
 extern crate bytes;
+extern crate chacha20poly1305;
+#[macro_use]
 extern crate futures;
+extern crate futures_core;
+extern crate futures_cpupool;
+extern crate glob;
+extern crate hkdf;
+#[cfg(feature = "s3")]
+extern crate hmac;
+extern crate pbkdf2;
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+extern crate rustix;
+#[cfg(feature = "serde")]
+extern crate serde;
+extern crate sha2;
+extern crate snap;
+extern crate tokio_io;
+#[cfg(feature = "http")]
+extern crate ureq;
+#[cfg(feature = "http")]
+extern crate url;
+#[cfg(unix)]
+extern crate users;
+extern crate x25519_dalek;
+#[cfg(feature = "xattr")]
+extern crate xattr;
+extern crate xz2;
+extern crate zstd;
 
 #[macro_use]
 extern crate lazy_static;
 
 pub mod zint;
+pub mod archive;
+pub mod async_io;
 pub mod bottle_header;
 pub mod bottle;
+pub mod bottle_events;
+pub mod checkpoint;
+pub mod chunk_sink;
 // pub mod compound_stream;
 // pub mod bytes_stream;
 pub mod buffered_stream;
+pub mod compressed_bottle;
+pub mod dedup_bottle;
+pub mod diff;
+pub mod encrypted_bottle;
+pub mod error;
+pub mod extract_filter;
+pub mod file_bottle;
+pub mod framed_stream;
 // pub mod byte_stream;
+pub mod hash_bottle;
+pub mod hashing;
+#[cfg(feature = "http")]
+mod http_client;
+pub mod incremental;
+pub mod indexed_bottle;
+pub mod json_manifest;
+pub mod parity_bottle;
+pub mod progress;
+#[cfg(feature = "http")]
+pub mod range_reader;
+#[cfg(feature = "s3")]
+pub mod s3_sink;
+#[cfg(feature = "serde")]
+mod serde_support;
+pub mod sparse;
+pub mod spec;
+pub mod std_future;
 pub mod stream_helpers;
 pub mod stream_reader;
+pub mod sync;
+pub mod transcode;
+pub mod validate;
+pub mod volume_bottle;
+pub mod walk;
 
 pub mod to_hex;
 pub use to_hex::{FromHex, ToHex};
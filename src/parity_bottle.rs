@@ -0,0 +1,256 @@
+use blake3;
+use bytes::Bytes;
+use futures::{Future, Stream, stream};
+use std::io;
+
+use bottle::{BottleType, DecodeLimits, ReadOptions, make_bottle_from_stream, read_bottle_with_options};
+use bottle_header::{Header};
+use buffered_stream::{buffer_stream};
+use error::BottleError;
+use stream_helpers::concat_limited;
+
+pub(crate) const FIELD_DATA_SHARDS: u8 = 0;
+pub(crate) const FIELD_PARITY_SHARDS: u8 = 1;
+const FIELD_SHARD_SIZE: u8 = 2;
+
+const SHARD_SIZE: usize = 4096;
+
+// the shard size comes from the header, so a reader won't trust anything
+// bigger than this.
+const MAX_SHARD_SIZE: usize = 1 << 20;
+
+// each shard is stored after the start of its BLAKE3 hash, so a damaged
+// shard can be spotted, and rebuilt from the others.
+const CHECK_SIZE: usize = 8;
+
+// each stripe starts with how much data is in it, since the last one is
+// padded out to a whole stripe.
+const LENGTH_SIZE: usize = 4;
+
+// every shard in a stripe needs its own element of GF(2^8).
+const MAX_SHARDS: usize = 256;
+
+lazy_static! {
+  // exp and log tables for GF(2^8), using the polynomial 0x11d. exp is
+  // doubled so a sum of two logs never needs a modulo.
+  static ref GF_TABLES: ( [u8; 512], [u8; 256] ) = {
+    let mut exp = [ 0u8; 512 ];
+    let mut log = [ 0u8; 256 ];
+    let mut x: u16 = 1;
+    for ( i, e ) in exp.iter_mut().take(255).enumerate() {
+      *e = x as u8;
+      log[x as usize] = i as u8;
+      x <<= 1;
+      if x & 0x100 != 0 { x ^= 0x11d }
+    }
+    for i in 255 .. 512 { exp[i] = exp[i - 255] }
+    ( exp, log )
+  };
+}
+
+/// Wrap a bottle (or any byte stream) in a parity bottle, to survive bit
+/// rot: the data is cut into stripes of `data_shards` shards, and each
+/// stripe gets `parity_shards` more shards of Reed-Solomon parity. Each
+/// shard is checked with a hash when it's read, and as long as no more
+/// than `parity_shards` shards in a stripe are damaged, the rest can
+/// rebuild it.
+///
+/// Only the contents are protected: damage to the bottle's header or
+/// framing still can't be read past.
+///
+/// There can be at most 256 shards in a stripe, counting both kinds.
+pub fn with_parity<S>(s: S, data_shards: usize, parity_shards: usize)
+  -> io::Result<impl Stream<Item = Vec<Bytes>, Error = io::Error>>
+  where S: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  check_shards(data_shards, parity_shards, SHARD_SIZE)?;
+  let mut header = Header::new();
+  header.add_number(FIELD_DATA_SHARDS, data_shards as u64);
+  header.add_number(FIELD_PARITY_SHARDS, parity_shards as u64);
+  header.add_number(FIELD_SHARD_SIZE, SHARD_SIZE as u64);
+
+  let stripes = buffer_stream(s, data_shards * SHARD_SIZE - LENGTH_SIZE, true).map(move |buffers| {
+    stream::once::<_, io::Error>(Ok(encode_stripe(buffers, data_shards, parity_shards, SHARD_SIZE)))
+  });
+  Ok(make_bottle_from_stream(BottleType::Parity, &header, stripes))
+}
+
+/// Read a parity bottle back into the original stream, rebuilding any
+/// damaged shards. Fails if a stripe has more damaged shards than parity
+/// shards.
+pub fn repair_bottle<S>(s: S)
+  -> impl Future<Item = (Header, impl Stream<Item = Bytes, Error = io::Error>), Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  repair_bottle_with_limits(s, DecodeLimits::default())
+}
+
+pub(crate) fn repair_bottle_with_limits<S>(s: S, limits: DecodeLimits)
+  -> impl Future<Item = (Header, impl Stream<Item = Bytes, Error = io::Error>), Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  read_bottle_with_options(s, ReadOptions { limits, ..ReadOptions::default() }).and_then(move |( btype, header, children )| {
+    if btype != BottleType::Parity { return Err(not_parity_error(btype)) }
+    let number = |field| header.get_number(field).unwrap_or(0).min(usize::MAX as u64) as usize;
+    let ( data_shards, parity_shards, shard_size ) = ( number(FIELD_DATA_SHARDS), number(FIELD_PARITY_SHARDS), number(FIELD_SHARD_SIZE) );
+    check_shards(data_shards, parity_shards, shard_size)?;
+    limits.check_buffered(((data_shards + parity_shards) * (CHECK_SIZE + shard_size)) as u64)?;
+
+    let mut index = 0;
+    let s = children.and_then(move |child| concat_limited(child, limits)).and_then(move |stripe| {
+      index += 1;
+      decode_stripe(&stripe, index - 1, data_shards, parity_shards, shard_size)
+    });
+    Ok(( header, s ))
+  })
+}
+
+// once these pass, a stripe's size can't overflow.
+fn check_shards(data_shards: usize, parity_shards: usize, shard_size: usize) -> io::Result<()> {
+  let count = data_shards.saturating_add(parity_shards);
+  if data_shards == 0 || parity_shards == 0 || count > MAX_SHARDS {
+    return Err(bad_shard_count_error(data_shards, parity_shards));
+  }
+  if shard_size > MAX_SHARD_SIZE || data_shards * shard_size <= LENGTH_SIZE {
+    return Err(bad_shard_size_error(shard_size));
+  }
+  Ok(())
+}
+
+// the stripe is the data's length, the data, and zeros, cut into data
+// shards, followed by the parity shards.
+fn encode_stripe(buffers: Vec<Bytes>, data_shards: usize, parity_shards: usize, shard_size: usize) -> Vec<Bytes> {
+  let mut block = Vec::with_capacity(data_shards * shard_size);
+  let length: usize = buffers.iter().map(|b| b.len()).sum();
+  block.extend_from_slice(&(length as u32).to_le_bytes());
+  for b in buffers.iter() { block.extend_from_slice(b) }
+  block.resize(data_shards * shard_size, 0);
+
+  let shards: Vec<&[u8]> = block.chunks(shard_size).collect();
+  (0 .. data_shards + parity_shards).map(|i| {
+    let parity;
+    let shard = if i < data_shards {
+      shards[i]
+    } else {
+      parity = combine(&matrix_row(i, data_shards), &shards, shard_size);
+      &parity[..]
+    };
+    let mut record = Vec::with_capacity(CHECK_SIZE + shard_size);
+    record.extend_from_slice(&blake3::hash(shard).as_bytes()[.. CHECK_SIZE]);
+    record.extend_from_slice(shard);
+    Bytes::from(record)
+  }).collect()
+}
+
+fn decode_stripe(stripe: &[u8], index: u64, data_shards: usize, parity_shards: usize, shard_size: usize) -> io::Result<Bytes> {
+  let record_size = CHECK_SIZE + shard_size;
+  let size = (data_shards + parity_shards).checked_mul(record_size);
+  if size != Some(stripe.len()) { return Err(bad_stripe_error(index)) }
+
+  // the first good shards, by their row in the encoding matrix.
+  let good: Vec<( usize, &[u8] )> = stripe.chunks(record_size).enumerate().filter(|&( _, record )| {
+    blake3::hash(&record[CHECK_SIZE ..]).as_bytes()[.. CHECK_SIZE] == record[.. CHECK_SIZE]
+  }).map(|( i, record )| ( i, &record[CHECK_SIZE ..] )).take(data_shards).collect();
+  if good.len() < data_shards { return Err(parity_exhausted_error(index)) }
+
+  let mut block = Vec::with_capacity(data_shards * shard_size);
+  if good.iter().enumerate().all(|( j, &( i, _ ) )| i == j) {
+    for &( _, shard ) in good.iter() { block.extend_from_slice(shard) }
+  } else {
+    let matrix = good.iter().map(|&( i, _ )| matrix_row(i, data_shards)).collect();
+    let shards: Vec<&[u8]> = good.iter().map(|&( _, shard )| shard).collect();
+    // any `data_shards` rows can be inverted, so this can't fail.
+    for row in invert(matrix).ok_or_else(|| parity_exhausted_error(index))? {
+      block.extend(combine(&row, &shards, shard_size));
+    }
+  }
+
+  let mut length = [ 0u8; LENGTH_SIZE ];
+  length.copy_from_slice(&block[.. LENGTH_SIZE]);
+  let length = u32::from_le_bytes(length) as usize;
+  if length > block.len() - LENGTH_SIZE { return Err(bad_stripe_error(index)) }
+  Ok(Bytes::from(block).slice(LENGTH_SIZE, LENGTH_SIZE + length))
+}
+
+
+// ----- GF(2^8)
+
+fn gf_mul(a: u8, b: u8) -> u8 {
+  if a == 0 || b == 0 { return 0 }
+  let ( ref exp, ref log ) = *GF_TABLES;
+  exp[log[a as usize] as usize + log[b as usize] as usize]
+}
+
+fn gf_inv(a: u8) -> u8 {
+  let ( ref exp, ref log ) = *GF_TABLES;
+  exp[255 - log[a as usize] as usize]
+}
+
+// row `i` of the encoding matrix: the identity for the data shards, then a
+// Cauchy matrix (1 / (i + j)) for parity. every square piece of a Cauchy
+// matrix can be inverted, so any `data_shards` rows can rebuild the data.
+fn matrix_row(i: usize, data_shards: usize) -> Vec<u8> {
+  (0 .. data_shards).map(|j| {
+    if i < data_shards { (i == j) as u8 } else { gf_inv((i ^ j) as u8) }
+  }).collect()
+}
+
+// multiply a matrix row by a column of shards.
+fn combine(row: &[u8], shards: &[&[u8]], shard_size: usize) -> Vec<u8> {
+  let mut out = vec![ 0u8; shard_size ];
+  for ( &c, shard ) in row.iter().zip(shards.iter()) {
+    if c == 0 { continue }
+    let table: Vec<u8> = (0 .. 256).map(|b| gf_mul(c, b as u8)).collect();
+    for ( o, &b ) in out.iter_mut().zip(shard.iter()) { *o ^= table[b as usize] }
+  }
+  out
+}
+
+// gauss-jordan elimination.
+fn invert(mut matrix: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+  let n = matrix.len();
+  let mut inverse: Vec<Vec<u8>> = (0 .. n).map(|i| (0 .. n).map(|j| (i == j) as u8).collect()).collect();
+  for col in 0 .. n {
+    let pivot = (col .. n).find(|&r| matrix[r][col] != 0)?;
+    matrix.swap(col, pivot);
+    inverse.swap(col, pivot);
+    let scale = gf_inv(matrix[col][col]);
+    for j in 0 .. n {
+      matrix[col][j] = gf_mul(matrix[col][j], scale);
+      inverse[col][j] = gf_mul(inverse[col][j], scale);
+    }
+    let ( pivot_row, inverse_row ) = ( matrix[col].clone(), inverse[col].clone() );
+    for r in 0 .. n {
+      let f = matrix[r][col];
+      if r == col || f == 0 { continue }
+      for j in 0 .. n {
+        matrix[r][j] ^= gf_mul(f, pivot_row[j]);
+        inverse[r][j] ^= gf_mul(f, inverse_row[j]);
+      }
+    }
+  }
+  Some(inverse)
+}
+
+
+// ----- errors
+
+fn not_parity_error(btype: BottleType) -> io::Error {
+  BottleError::WrongType { expected: BottleType::Parity, found: btype }.into()
+}
+
+fn bad_shard_count_error(data_shards: usize, parity_shards: usize) -> io::Error {
+  BottleError::BadShardCount { data_shards, parity_shards }.into()
+}
+
+fn bad_shard_size_error(shard_size: usize) -> io::Error {
+  BottleError::BadShardSize(shard_size as u64).into()
+}
+
+fn bad_stripe_error(index: u64) -> io::Error {
+  BottleError::BadParityStripe(index).into()
+}
+
+fn parity_exhausted_error(index: u64) -> io::Error {
+  BottleError::ParityExhausted(index).into()
+}
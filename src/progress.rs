@@ -0,0 +1,106 @@
+use bytes::Bytes;
+use futures::Stream;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Something that wants to hear about progress on a long encode or
+/// decode, like a progress bar. It's called with running totals each time
+/// data moves, and whenever a new file or folder starts.
+///
+/// For encoding, bytes in are read from the source files and bytes out
+/// are the bottle. For extracting, it's the other way around.
+pub trait Progress {
+  fn update(&self, bytes_in: u64, bytes_out: u64, entry: Option<&Path>);
+}
+
+impl<F> Progress for F where F: Fn(u64, u64, Option<&Path>) {
+  fn update(&self, bytes_in: u64, bytes_out: u64, entry: Option<&Path>) {
+    self(bytes_in, bytes_out, entry)
+  }
+}
+
+struct TrackerState {
+  bytes_in: Cell<u64>,
+  bytes_out: Cell<u64>,
+  entry: RefCell<Option<PathBuf>>,
+  progress: Box<dyn Progress>
+}
+
+/// Running totals for one encode or decode, shared by each stage that
+/// moves data, and passed on to a `Progress`.
+#[derive(Clone)]
+pub struct ProgressTracker {
+  state: Rc<TrackerState>
+}
+
+impl ProgressTracker {
+  pub fn new<P: Progress + 'static>(progress: P) -> ProgressTracker {
+    let state = TrackerState {
+      bytes_in: Cell::new(0),
+      bytes_out: Cell::new(0),
+      entry: RefCell::new(None),
+      progress: Box::new(progress)
+    };
+    ProgressTracker { state: Rc::new(state) }
+  }
+
+  pub fn bytes_in(&self) -> u64 {
+    self.state.bytes_in.get()
+  }
+
+  pub fn bytes_out(&self) -> u64 {
+    self.state.bytes_out.get()
+  }
+
+  pub fn entry(&self) -> Option<PathBuf> {
+    self.state.entry.borrow().clone()
+  }
+
+  pub(crate) fn add_in(&self, n: usize) {
+    self.state.bytes_in.set(self.state.bytes_in.get() + n as u64);
+    self.report();
+  }
+
+  pub(crate) fn add_out(&self, n: usize) {
+    self.state.bytes_out.set(self.state.bytes_out.get() + n as u64);
+    self.report();
+  }
+
+  pub(crate) fn set_entry(&self, path: &Path) {
+    *self.state.entry.borrow_mut() = Some(path.to_path_buf());
+    self.report();
+  }
+
+  fn report(&self) {
+    let entry = self.state.entry.borrow();
+    self.state.progress.update(self.bytes_in(), self.bytes_out(), entry.as_ref().map(|p| p.as_path()));
+  }
+}
+
+impl fmt::Debug for ProgressTracker {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "ProgressTracker(in={}, out={}, entry={:?})", self.bytes_in(), self.bytes_out(), self.entry())
+  }
+}
+
+// count buffers going by, as bytes in or out.
+pub(crate) fn count_in<S>(s: S, tracker: Option<ProgressTracker>) -> impl Stream<Item = Bytes, Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  s.inspect(move |b| if let Some(ref t) = tracker { t.add_in(b.len()) })
+}
+
+pub(crate) fn count_vec_in<S>(s: S, tracker: Option<ProgressTracker>) -> impl Stream<Item = Vec<Bytes>, Error = io::Error>
+  where S: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  s.inspect(move |v| if let Some(ref t) = tracker { t.add_in(v.iter().map(|b| b.len()).sum()) })
+}
+
+pub(crate) fn count_vec_out<S>(s: S, tracker: Option<ProgressTracker>) -> impl Stream<Item = Vec<Bytes>, Error = io::Error>
+  where S: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  s.inspect(move |v| if let Some(ref t) = tracker { t.add_out(v.iter().map(|b| b.len()).sum()) })
+}
@@ -0,0 +1,192 @@
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::time::Duration;
+
+use ureq::Agent;
+use url::Url;
+
+use error::BottleError;
+use http_client::{self, bad_response_error};
+
+// default for how much to fetch past each read, so a run of small reads
+// (like walking frame headers) doesn't become a run of tiny requests.
+pub const DEFAULT_READ_AHEAD: usize = 256 * 1024;
+
+// ureq's own default.
+const MAX_REDIRECTS: u32 = 5;
+
+/// Somewhere that can hand out byte ranges of a file, like an HTTP server
+/// or object store.
+pub trait RangeSource {
+  /// Total size of the file.
+  fn size(&mut self) -> io::Result<u64>;
+
+  /// Fetch `length` bytes starting at `offset`. The range is always
+  /// inside the file.
+  fn fetch(&mut self, offset: u64, length: usize) -> io::Result<Vec<u8>>;
+}
+
+/*
+ * Turn a `RangeSource` into a `Read + Seek`, buffering one block at a
+ * time. Each read that misses the buffer fetches the bytes it needs, plus
+ * the read-ahead. This is the source `SeekableBottleReader` needs, so an
+ * archive on a server can be listed and partly extracted without
+ * downloading all of it. Like that reader, it blocks: tokio-io 0.1 has no
+ * `AsyncSeek` to implement.
+ */
+pub struct RangeReader<S: RangeSource> {
+  source: S,
+  size: u64,
+  position: u64,
+  buffer: Vec<u8>,
+  buffer_offset: u64,
+  read_ahead: usize,
+  requests: usize
+}
+
+impl<S: RangeSource> RangeReader<S> {
+  /// Ask the source for its size, and start at the beginning.
+  pub fn new(mut source: S) -> io::Result<RangeReader<S>> {
+    let size = source.size()?;
+    Ok(RangeReader {
+      source,
+      size,
+      position: 0,
+      buffer: Vec::new(),
+      buffer_offset: 0,
+      read_ahead: DEFAULT_READ_AHEAD,
+      requests: 0
+    })
+  }
+
+  /// Fetch this many extra bytes on each request (0 fetches only what's
+  /// asked for).
+  pub fn read_ahead(mut self, bytes: usize) -> Self {
+    self.read_ahead = bytes;
+    self
+  }
+
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  /// How many ranges have been fetched from the source so far.
+  pub fn requests(&self) -> usize {
+    self.requests
+  }
+
+  pub fn into_inner(self) -> S {
+    self.source
+  }
+
+  fn buffered(&self) -> Option<&[u8]> {
+    let end = self.buffer_offset + self.buffer.len() as u64;
+    if self.position < self.buffer_offset || self.position >= end { return None }
+    Some(&self.buffer[(self.position - self.buffer_offset) as usize ..])
+  }
+}
+
+impl<S: RangeSource> Read for RangeReader<S> {
+  fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+    if buffer.is_empty() || self.position >= self.size { return Ok(0) }
+    if self.buffered().is_none() {
+      let wanted = buffer.len().saturating_add(self.read_ahead) as u64;
+      let length = cmp::min(wanted, self.size - self.position) as usize;
+      let data = self.source.fetch(self.position, length)?;
+      self.requests += 1;
+      if data.is_empty() { return Err(BottleError::TruncatedStream.into()) }
+      self.buffer = data;
+      self.buffer_offset = self.position;
+    }
+    let n = {
+      let available = self.buffered().unwrap_or(&[]);
+      let n = cmp::min(available.len(), buffer.len());
+      buffer[.. n].copy_from_slice(&available[.. n]);
+      n
+    };
+    self.position += n as u64;
+    Ok(n)
+  }
+}
+
+impl<S: RangeSource> Seek for RangeReader<S> {
+  fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+    let target = match position {
+      SeekFrom::Start(n) => Some(n),
+      SeekFrom::End(n) => self.size.checked_add_signed(n),
+      SeekFrom::Current(n) => self.position.checked_add_signed(n)
+    };
+    self.position = target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the file"))?;
+    Ok(self.position)
+  }
+}
+
+
+/// A file on an `http://` or `https://` server (or object store) that
+/// honors `Range` requests. Redirects are followed.
+pub struct HttpSource {
+  url: Url,
+  agent: Agent
+}
+
+impl HttpSource {
+  /// Parse the URL. Nothing is fetched yet.
+  pub fn new(url: &str) -> io::Result<HttpSource> {
+    Ok(HttpSource { url: http_client::parse_url(url)?, agent: http_client::agent(None, MAX_REDIRECTS) })
+  }
+
+  /// Give up on a request if the server goes quiet for this long.
+  pub fn timeout(mut self, timeout: Duration) -> Self {
+    self.agent = http_client::agent(Some(timeout), MAX_REDIRECTS);
+    self
+  }
+
+  // fetch a range, and check that the server sent that range back.
+  fn fetch_range(&self, first: u64, last: u64) -> io::Result<( u64, Vec<u8> )> {
+    let request = self.agent.request_url("GET", &self.url)
+      .set("Range", &format!("bytes={}-{}", first, last))
+      .set("Accept-Encoding", "identity");
+    let response = http_client::response(request.call())?;
+    if response.status() == 200 { return Err(ranges_not_supported_error()) }
+    if response.status() != 206 { return Err(BottleError::HttpStatus(response.status()).into()) }
+    let ( start, end, total ) = response.header("content-range").and_then(parse_content_range).ok_or_else(bad_response_error)?;
+    if start != first || end > last { return Err(bad_response_error()) }
+
+    let length = ( end - start + 1 ) as usize;
+    if let Some(n) = response.header("content-length") {
+      if n.parse::<u64>().ok() != Some(length as u64) { return Err(bad_response_error()) }
+    }
+    Ok(( total, http_client::read_exact(response, length)? ))
+  }
+}
+
+impl RangeSource for HttpSource {
+  fn size(&mut self) -> io::Result<u64> {
+    // servers are more consistent about ranges than about HEAD.
+    Ok(self.fetch_range(0, 0)?.0)
+  }
+
+  fn fetch(&mut self, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+    if length == 0 { return Ok(Vec::new()) }
+    Ok(self.fetch_range(offset, offset + length as u64 - 1)?.1)
+  }
+}
+
+// "bytes 0-99/1234" -> ( 0, 99, 1234 )
+fn parse_content_range(value: &str) -> Option<( u64, u64, u64 )> {
+  let value = value.strip_prefix("bytes ")?;
+  let slash = value.find('/')?;
+  let dash = value[.. slash].find('-')?;
+  let start = value[.. dash].trim().parse::<u64>().ok()?;
+  let end = value[dash + 1 .. slash].trim().parse::<u64>().ok()?;
+  let total = value[slash + 1 ..].trim().parse::<u64>().ok()?;
+  if end < start || end >= total { return None }
+  Some(( start, end, total ))
+}
+
+
+// ----- errors
+
+fn ranges_not_supported_error() -> io::Error {
+  BottleError::RangesNotSupported.into()
+}
@@ -0,0 +1,263 @@
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ureq::{Agent, Response};
+use url::{Host, Url};
+
+use chunk_sink::ChunkSink;
+use error::BottleError;
+use http_client::{self, bad_response_error, bad_url_error};
+use to_hex::ToHex;
+
+/// S3 refuses parts smaller than this, except the last one.
+pub const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// Most parts one upload can have. At `MIN_PART_SIZE`, that's about 50GB,
+/// so bigger archives need bigger parts.
+pub const MAX_PARTS: usize = 10_000;
+
+// S3's replies are a few short XML elements; anything bigger isn't one.
+const MAX_RESPONSE_BODY: usize = 64 * 1024;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone, Debug)]
+pub struct S3Credentials {
+  pub access_key_id: String,
+  pub secret_access_key: String,
+  // for temporary credentials
+  pub session_token: Option<String>
+}
+
+/*
+ * A `ChunkSink` that writes an S3 multipart upload, signed with AWS
+ * signature version 4, to a path-style `https://` endpoint. Plain
+ * `http://` is only allowed to a loopback host (like a local MinIO), since
+ * the data would otherwise cross the network in the clear. Redirects
+ * aren't followed, because the signature covers the host. The upload is
+ * started by the first part, finished by `complete`, and deleted by
+ * `abort`.
+ */
+pub struct S3Sink {
+  url: Url,
+  host: String,
+  region: String,
+  path: String,
+  credentials: S3Credentials,
+  agent: Agent,
+  upload_id: Option<String>,
+  etags: Vec<String>
+}
+
+impl S3Sink {
+  /// Upload to `key` in `bucket`, through an endpoint like
+  /// `https://s3.us-east-1.amazonaws.com`.
+  pub fn new(endpoint: &str, region: &str, bucket: &str, key: &str, credentials: S3Credentials) -> io::Result<S3Sink> {
+    let url = http_client::parse_url(endpoint)?;
+    if url.scheme() != "https" && !is_loopback(&url) { return Err(bad_url_error(endpoint)) }
+    let host = match url.port() {
+      Some(port) => format!("{}:{}", url.host_str().unwrap_or(""), port),
+      None => url.host_str().unwrap_or("").to_string()
+    };
+    let path = format!("{}/{}/{}", url.path().trim_end_matches('/'), bucket, key);
+    Ok(S3Sink {
+      url,
+      host,
+      region: region.to_string(),
+      path: uri_encode(&path, false),
+      credentials,
+      agent: http_client::agent(None, 0),
+      upload_id: None,
+      etags: Vec::new()
+    })
+  }
+
+  /// Give up on a request if the server goes quiet for this long.
+  pub fn timeout(mut self, timeout: Duration) -> Self {
+    self.agent = http_client::agent(Some(timeout), 0);
+    self
+  }
+
+  /// The id S3 gave this upload, once it's started.
+  pub fn upload_id(&self) -> Option<&str> {
+    self.upload_id.as_deref()
+  }
+
+  // send a signed request for the object, and fail unless it worked.
+  fn request(&self, method: &str, query: &[( &str, &str )], body: &[Bytes]) -> io::Result<Response> {
+    let mut hasher = Sha256::new();
+    for b in body { hasher.update(b) }
+    let payload_hash = hasher.finalize().to_hex();
+    let amz_date = amz_date(SystemTime::now());
+
+    let mut query: Vec<( String, String )> = query.iter().map(|&( k, v )| ( uri_encode(k, true), uri_encode(v, true) )).collect();
+    query.sort();
+    let query = query.iter().map(|( k, v )| format!("{}={}", k, v)).collect::<Vec<_>>().join("&");
+
+    let authorization = authorization(
+      &self.credentials, &self.region, method, &self.path, &query, &self.host, &amz_date, &payload_hash
+    );
+    let mut url = self.url.clone();
+    url.set_path(&self.path);
+    url.set_query(if query.is_empty() { None } else { Some(&query) });
+    let mut request = self.agent.request_url(method, &url)
+      .set("Authorization", &authorization)
+      .set("x-amz-content-sha256", &payload_hash)
+      .set("x-amz-date", &amz_date);
+    if let Some(ref token) = self.credentials.session_token { request = request.set("x-amz-security-token", token) }
+
+    let response = http_client::send(request, body)?;
+    let status = response.status();
+    if status / 100 != 2 {
+      let body = http_client::read_body(response, MAX_RESPONSE_BODY).unwrap_or_default();
+      return Err(match xml_field(&body, "Code") {
+        Some(code) => BottleError::UploadFailed(code).into(),
+        None => BottleError::HttpStatus(status).into()
+      });
+    }
+    Ok(response)
+  }
+
+  fn start(&mut self) -> io::Result<String> {
+    let body = http_client::read_body(self.request("POST", &[ ( "uploads", "" ) ], &[])?, MAX_RESPONSE_BODY)?;
+    let upload_id = xml_field(&body, "UploadId").ok_or_else(bad_response_error)?;
+    self.upload_id = Some(upload_id.clone());
+    Ok(upload_id)
+  }
+}
+
+impl ChunkSink for S3Sink {
+  fn upload_part(&mut self, part_number: usize, data: Vec<Bytes>) -> io::Result<()> {
+    if part_number > MAX_PARTS { return Err(BottleError::TooManyParts(MAX_PARTS).into()) }
+    let upload_id = match self.upload_id.clone() {
+      Some(id) => id,
+      None => self.start()?
+    };
+    let part_number = part_number.to_string();
+    let response = self.request("PUT", &[ ( "partNumber", &part_number ), ( "uploadId", &upload_id ) ], &data)?;
+    let etag = response.header("ETag").ok_or_else(bad_response_error)?.to_string();
+    self.etags.push(etag);
+    Ok(())
+  }
+
+  fn complete(&mut self) -> io::Result<()> {
+    let upload_id = match self.upload_id.clone() {
+      Some(id) => id,
+      None => self.start()?
+    };
+    let mut xml = "<CompleteMultipartUpload>".to_string();
+    for ( i, etag ) in self.etags.iter().enumerate() {
+      xml.push_str(&format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", i + 1, xml_escape(etag)));
+    }
+    xml.push_str("</CompleteMultipartUpload>");
+
+    // S3 can report a failure to finish inside a 200 response.
+    let response = self.request("POST", &[ ( "uploadId", &upload_id ) ], &[ Bytes::from(xml) ])?;
+    let body = http_client::read_body(response, MAX_RESPONSE_BODY)?;
+    if let Some(code) = xml_field(&body, "Code") { return Err(BottleError::UploadFailed(code).into()) }
+    self.upload_id = None;
+    Ok(())
+  }
+
+  fn abort(&mut self) {
+    if let Some(upload_id) = self.upload_id.take() {
+      // best effort: if this fails too, a lifecycle rule has to clean up.
+      let _ = self.request("DELETE", &[ ( "uploadId", &upload_id ) ], &[]);
+    }
+  }
+}
+
+// a local gateway, where plain http never leaves the machine.
+fn is_loopback(url: &Url) -> bool {
+  match url.host() {
+    Some(Host::Domain(domain)) => domain.eq_ignore_ascii_case("localhost"),
+    Some(Host::Ipv4(ip)) => ip.is_loopback(),
+    Some(Host::Ipv6(ip)) => ip.is_loopback(),
+    None => false
+  }
+}
+
+// the signature version 4 `Authorization` header, signing the host, date,
+// and payload hash.
+#[allow(clippy::too_many_arguments)]
+fn authorization(
+  credentials: &S3Credentials,
+  region: &str,
+  method: &str,
+  path: &str,
+  query: &str,
+  host: &str,
+  amz_date: &str,
+  payload_hash: &str
+) -> String {
+  let mut headers = vec![
+    ( "host", host ),
+    ( "x-amz-content-sha256", payload_hash ),
+    ( "x-amz-date", amz_date )
+  ];
+  if let Some(ref token) = credentials.session_token { headers.push(( "x-amz-security-token", token )) }
+  let signed_headers = headers.iter().map(|&( name, _ )| name).collect::<Vec<_>>().join(";");
+  let canonical_headers = headers.iter().map(|&( name, value )| format!("{}:{}\n", name, value.trim())).collect::<String>();
+  let canonical_request = format!("{}\n{}\n{}\n{}\n{}\n{}", method, path, query, canonical_headers, signed_headers, payload_hash);
+
+  let date = &amz_date[.. 8];
+  let scope = format!("{}/{}/s3/aws4_request", date, region);
+  let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, Sha256::digest(canonical_request.as_bytes()).to_hex());
+
+  let mut key = hmac_sha256(format!("AWS4{}", credentials.secret_access_key).as_bytes(), date.as_bytes());
+  for part in &[ region, "s3", "aws4_request" ] { key = hmac_sha256(&key, part.as_bytes()) }
+  let signature = hmac_sha256(&key, string_to_sign.as_bytes()).to_hex();
+  format!(
+    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+    credentials.access_key_id, scope, signed_headers, signature
+  )
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+  let mut mac = HmacSha256::new_from_slice(key).expect("hmac takes any key size");
+  mac.update(data);
+  mac.finalize().into_bytes().to_vec()
+}
+
+// "20130524T000000Z"
+fn amz_date(time: SystemTime) -> String {
+  let seconds = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+  let ( days, seconds ) = ( seconds / 86400, seconds % 86400 );
+
+  // days since 1970 to a civil date, from Howard Hinnant's algorithms.
+  let z = days as i64 + 719_468;
+  let era = z.div_euclid(146_097);
+  let day_of_era = z - era * 146_097;
+  let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let mp = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+  format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+// percent-encode everything but the unreserved characters (and `/`, in a path).
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+  s.bytes().map(|b| match b {
+    b'A' ..= b'Z' | b'a' ..= b'z' | b'0' ..= b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+    b'/' if !encode_slash => "/".to_string(),
+    _ => format!("%{:02X}", b)
+  }).collect()
+}
+
+fn xml_escape(s: &str) -> String {
+  s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// the text of the first `<name>` element, which is all we need from S3's replies.
+fn xml_field(body: &[u8], name: &str) -> Option<String> {
+  let body = String::from_utf8_lossy(body);
+  let open = format!("<{}>", name);
+  let start = body.find(&open)? + open.len();
+  let end = body[start ..].find(&format!("</{}>", name))? + start;
+  Some(body[start .. end].to_string())
+}
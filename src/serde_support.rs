@@ -0,0 +1,101 @@
+// helpers for `#[serde(with = ...)]`, for the fields whose types don't
+// have a serde representation of their own.
+
+/// `Bytes` as a hex string, the way digests are shown everywhere else.
+pub mod hex_bytes {
+  use bytes::Bytes;
+  use serde::{Deserialize, Deserializer, Serializer};
+  use serde::de::Error;
+
+  use to_hex::ToHex;
+
+  pub fn serialize<S: Serializer>(bytes: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&bytes.to_hex())
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    super::decode_hex(&hex).map(Bytes::from).ok_or_else(|| D::Error::custom(format!("invalid hex: {:?}", hex)))
+  }
+}
+
+/// `Option<Bytes>` as a hex string, or nothing.
+pub mod hex_bytes_option {
+  use bytes::Bytes;
+  use serde::{Deserialize, Deserializer, Serializer};
+  use serde::de::Error;
+
+  use to_hex::ToHex;
+
+  pub fn serialize<S: Serializer>(bytes: &Option<Bytes>, serializer: S) -> Result<S::Ok, S::Error> {
+    match *bytes {
+      Some(ref b) => serializer.serialize_some(&b.to_hex()),
+      None => serializer.serialize_none()
+    }
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Bytes>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+      Some(hex) => super::decode_hex(&hex).map(|b| Some(Bytes::from(b))).ok_or_else(|| D::Error::custom(format!("invalid hex: {:?}", hex))),
+      None => Ok(None)
+    }
+  }
+}
+
+/// Extended attributes as `[ name, hex value ]` pairs.
+pub mod hex_xattrs {
+  use serde::{Deserialize, Deserializer, Serializer};
+  use serde::de::Error;
+  use serde::ser::SerializeSeq;
+
+  use to_hex::ToHex;
+
+  pub fn serialize<S: Serializer>(xattrs: &[( String, Vec<u8> )], serializer: S) -> Result<S::Ok, S::Error> {
+    let mut seq = serializer.serialize_seq(Some(xattrs.len()))?;
+    for ( name, value ) in xattrs { seq.serialize_element(&( name, value.to_hex() ))? }
+    seq.end()
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<( String, Vec<u8> )>, D::Error> {
+    Vec::<( String, String )>::deserialize(deserializer)?.into_iter().map(|( name, hex )| {
+      super::decode_hex(&hex).map(|value| ( name, value )).ok_or_else(|| D::Error::custom(format!("invalid hex: {:?}", hex)))
+    }).collect()
+  }
+}
+
+/// A `Utf8Error` as where it happened (`valid_up_to` and `error_len`).
+/// There's no way to build one directly, so deserializing decodes a
+/// made-up string that fails in the same place.
+pub mod utf8_error {
+  use serde::{Deserialize, Deserializer, Serialize, Serializer};
+  use serde::de::Error;
+  use std::str::{self, Utf8Error};
+
+  #[derive(Serialize, Deserialize)]
+  struct Position {
+    valid_up_to: usize,
+    error_len: Option<u8>
+  }
+
+  pub fn serialize<S: Serializer>(e: &Utf8Error, serializer: S) -> Result<S::Ok, S::Error> {
+    Position { valid_up_to: e.valid_up_to(), error_len: e.error_len().map(|n| n as u8) }.serialize(serializer)
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Utf8Error, D::Error> {
+    let position = Position::deserialize(deserializer)?;
+    let mut data = vec![ b'.'; position.valid_up_to ];
+    data.extend_from_slice(match position.error_len {
+      None => &[ 0xe2 ],
+      Some(1) => &[ 0xff ],
+      Some(2) => &[ 0xf0, 0x90, 0x28 ],
+      Some(3) => &[ 0xf0, 0x90, 0x80, 0x28 ],
+      Some(n) => return Err(D::Error::custom(format!("invalid UTF-8 error length: {}", n)))
+    });
+    Ok(str::from_utf8(&data).expect_err("made-up string is invalid"))
+  }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+  if !hex.len().is_multiple_of(2) { return None }
+  (0 .. hex.len()).step_by(2).map(|i| hex.get(i .. i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok())).collect()
+}
@@ -0,0 +1,208 @@
+use bytes::{Buf, Bytes, IntoBuf};
+use futures::{Async, Future, Poll, Stream, future, stream};
+use std::cell::Cell;
+use std::cmp;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::rc::Rc;
+
+use bottle::DecodeLimits;
+use error::BottleError;
+use stream_helpers::concat_limited;
+
+// one extent in the map: offset and length, each a little-endian u64.
+const EXTENT_SIZE: usize = 16;
+
+const READ_BLOCK_SIZE: usize = 64 * 1024;
+
+lazy_static! {
+  static ref ZEROS: Bytes = Bytes::from(vec![ 0u8; READ_BLOCK_SIZE ]);
+}
+
+/// Where a file's data is: a sorted list of ( offset, length ) extents,
+/// with holes (that read as zeros) in between. Uses `SEEK_DATA` and
+/// `SEEK_HOLE` where they exist; elsewhere, the whole file is one extent.
+pub fn data_extents(file: &fs::File, size: u64) -> io::Result<Vec<( u64, u64 )>> {
+  let extents = find_extents(file, size)?;
+  Ok(extents.into_iter().filter(|&( _, length )| length > 0).collect())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn find_extents(file: &fs::File, size: u64) -> io::Result<Vec<( u64, u64 )>> {
+  use rustix::fs::{SeekFrom as RawSeekFrom, seek};
+  use rustix::io::Errno;
+
+  let mut extents = Vec::new();
+  let mut offset = 0;
+  while offset < size {
+    let start = match seek(file, RawSeekFrom::Data(offset)) {
+      Ok(start) => start,
+      // nothing but hole from here to the end.
+      Err(Errno::NXIO) => break,
+      // the filesystem can't tell us, so it's all data.
+      Err(Errno::INVAL) if offset == 0 => return Ok(vec![ ( 0, size ) ]),
+      Err(e) => return Err(e.into())
+    };
+    let end = cmp::min(seek(file, RawSeekFrom::Hole(start))?, size);
+    if end <= start { break }
+    extents.push(( start, end - start ));
+    offset = end;
+  }
+  Ok(extents)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn find_extents(_file: &fs::File, size: u64) -> io::Result<Vec<( u64, u64 )>> {
+  Ok(vec![ ( 0, size ) ])
+}
+
+pub(crate) fn encode_extents(extents: &[( u64, u64 )]) -> Bytes {
+  let mut buffer = Vec::with_capacity(extents.len() * EXTENT_SIZE);
+  for &( offset, length ) in extents.iter() {
+    buffer.extend_from_slice(&offset.to_le_bytes());
+    buffer.extend_from_slice(&length.to_le_bytes());
+  }
+  Bytes::from(buffer)
+}
+
+// extents have to be in order, not overlap, not be empty, and fit in the
+// file.
+fn decode_extents(data: &[u8], size: u64) -> io::Result<Vec<( u64, u64 )>> {
+  if !data.len().is_multiple_of(EXTENT_SIZE) { return Err(bad_sparse_map_error()) }
+  let mut buf = data.into_buf();
+  let mut extents = Vec::with_capacity(data.len() / EXTENT_SIZE);
+  let mut end = 0;
+  while buf.has_remaining() {
+    let offset = buf.get_u64_le();
+    let length = buf.get_u64_le();
+    if length == 0 || offset < end || offset.checked_add(length).map(|e| e > size).unwrap_or(true) {
+      return Err(bad_sparse_map_error());
+    }
+    end = offset + length;
+    extents.push(( offset, length ));
+  }
+  Ok(extents)
+}
+
+/// Read just the extents of a file, in order.
+pub(crate) fn extent_stream(path: &Path, mut file: fs::File, extents: Vec<( u64, u64 )>)
+  -> impl Stream<Item = Bytes, Error = io::Error>
+{
+  let path = path.to_path_buf();
+  let mut extents = extents.into_iter();
+  // what's left of the current extent: offset and length.
+  let mut current = ( 0, 0 );
+  stream::poll_fn(move || {
+    while current.1 == 0 {
+      match extents.next() {
+        None => return Ok(Async::Ready(None)),
+        Some(extent) => {
+          file.seek(SeekFrom::Start(extent.0))?;
+          current = extent;
+        }
+      }
+    }
+    let mut buffer = vec![ 0; cmp::min(current.1, READ_BLOCK_SIZE as u64) as usize ];
+    let n = loop {
+      match file.read(&mut buffer) {
+        Ok(0) => return Err(file_changed_error(&path)),
+        Ok(n) => break n,
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+        Err(e) => return Err(e)
+      }
+    };
+    buffer.truncate(n);
+    current = ( current.0 + n as u64, current.1 - n as u64 );
+    Ok(Async::Ready(Some(Bytes::from(buffer))))
+  })
+}
+
+type ByteStream = Box<dyn Stream<Item = Bytes, Error = io::Error>>;
+
+/// Read the two child streams of a sparse file bottle (the extent map,
+/// then the data) into a stream of the data, each piece with its offset
+/// in the file. `children` should be from `content_children`, which ends
+/// after the data. The map is held in memory, so it can't be bigger than
+/// `max_buffered_bytes`.
+pub(crate) fn sparse_data<C, A>(children: C, size: u64, limits: DecodeLimits) -> impl Future<Item = SparseData, Error = io::Error>
+  where
+    C: Stream<Item = A, Error = io::Error> + 'static,
+    A: Stream<Item = Bytes, Error = io::Error> + 'static
+{
+  children.into_future().map_err(|( e, _ )| e).and_then(move |( map, children )| {
+    map.ok_or_else(bad_sparse_map_error).map(|map| concat_limited(map, limits).map(move |map| ( map, children )))
+  }).flatten().and_then(move |( map, children )| {
+    let extents = decode_extents(&map, size)?;
+    let data: ByteStream = Box::new(children.flatten());
+    Ok(SparseData { extents, index: 0, done: 0, data, pending: None })
+  })
+}
+
+/// The whole contents of a sparse file, with the holes filled in with
+/// zeros.
+pub(crate) fn dense_stream(data: SparseData, size: u64) -> impl Stream<Item = Bytes, Error = io::Error> {
+  // how far into the file we've written
+  let at = Rc::new(Cell::new(0));
+  let end = at.clone();
+  data.map(move |( offset, b )| {
+    let zeros = zero_blocks(offset - at.get());
+    at.set(offset + b.len() as u64);
+    stream::iter_ok(zeros.chain(Some(b)))
+  }).flatten().chain(future::lazy(move || Ok(stream::iter_ok(zero_blocks(size - end.get())))).flatten_stream())
+}
+
+fn zero_blocks(count: u64) -> impl Iterator<Item = Bytes> {
+  let block = READ_BLOCK_SIZE as u64;
+  (0 .. count.div_ceil(block)).map(move |i| ZEROS.slice_to(cmp::min(block, count - i * block) as usize))
+}
+
+/// Stream of a sparse file's data, and where each piece goes, from
+/// `sparse_data`.
+pub(crate) struct SparseData {
+  extents: Vec<( u64, u64 )>,
+  index: usize,
+  // how much of the current extent we've read
+  done: u64,
+  data: ByteStream,
+  pending: Option<Bytes>
+}
+
+impl Stream for SparseData {
+  type Item = ( u64, Bytes );
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    let chunk = match self.pending.take() {
+      Some(b) => Some(b),
+      None => try_ready!(self.data.poll())
+    };
+    let chunk = match ( chunk, self.extents.get(self.index) ) {
+      ( None, None ) => return Ok(Async::Ready(None)),
+      ( Some(chunk), Some(_) ) => chunk,
+      // too much data, or too little.
+      _ => return Err(bad_sparse_map_error())
+    };
+    let ( offset, length ) = self.extents[self.index];
+    let n = cmp::min(length - self.done, chunk.len() as u64) as usize;
+    if n < chunk.len() { self.pending = Some(chunk.slice_from(n)) }
+    let item = ( offset + self.done, chunk.slice_to(n) );
+    self.done += n as u64;
+    if self.done == length {
+      self.index += 1;
+      self.done = 0;
+    }
+    Ok(Async::Ready(Some(item)))
+  }
+}
+
+
+// ----- errors
+
+fn bad_sparse_map_error() -> io::Error {
+  BottleError::BadSparseMap.into()
+}
+
+fn file_changed_error(path: &Path) -> io::Error {
+  BottleError::FileChanged(path.to_path_buf()).into()
+}
@@ -0,0 +1,62 @@
+// the 4bottle wire format, in one place: what a reader or writer in another
+// language (like the original javascript lib4bottle) has to agree with.
+// field ids for each bottle type live with the code for that type.
+
+/// Every bottle starts with these 4 bytes.
+pub const MAGIC: [u8; 4] = [ 0xf0, 0x9f, 0x8d, 0xbc ];
+
+/// Format version, the byte after the magic. The byte after that is
+/// reserved, and must be 0.
+pub const VERSION: u8 = 0;
+
+/// Magic, version, reserved byte, then 4 bits of bottle type and 12 bits of
+/// header length.
+pub const CAP_SIZE: usize = 8;
+
+pub const MAX_HEADER_SIZE: usize = 4095;
+
+// each header field starts with 2 bytes: 2 bits of kind, 4 bits of id, and
+// 10 bits of length.
+pub const FIELD_KIND_STRING: u8 = 0;
+pub const FIELD_KIND_NUMBER: u8 = 2;
+pub const FIELD_KIND_BOOLEAN: u8 = 3;
+
+pub const MAX_FIELD_ID: u8 = 15;
+pub const MAX_FIELD_LENGTH: usize = 1023;
+
+/// Number field reserved in every bottle type's header for the count of
+/// child streams, when the writer knows it up front.
+pub const FIELD_STREAM_COUNT: u8 = 15;
+
+/// Number field reserved in every bottle type's header: the child streams
+/// are interleaved, with at most this many open at once. Each frame is
+/// prefixed by its stream's id (as a zint, counting from 0) as well as its
+/// length, a stream ends with its id and END_OF_STREAM, and the id
+/// END_OF_ALL_STREAMS ends them all.
+pub const FIELD_INTERLEAVED: u8 = 14;
+
+/// Most streams an interleaved bottle may have open at once. Readers
+/// refuse a bigger FIELD_INTERLEAVED, since each open stream costs memory.
+pub const MAX_INTERLEAVED: usize = 1024;
+
+// bottle types, 0 - 15.
+pub const TYPE_FILE: u8 = 0;
+pub const TYPE_HASHED: u8 = 1;
+pub const TYPE_ENCRYPTED: u8 = 3;
+pub const TYPE_COMPRESSED: u8 = 4;
+pub const TYPE_INDEX: u8 = 5;
+pub const TYPE_VOLUME: u8 = 6;
+pub const TYPE_DEDUP: u8 = 7;
+pub const TYPE_PARITY: u8 = 8;
+// for tests:
+pub const TYPE_TEST: u8 = 10;
+pub const TYPE_TEST2: u8 = 11;
+
+// child streams are a series of frames, each prefixed by its length (as a
+// zint), and ended by a length of END_OF_STREAM. after the last child
+// stream comes END_OF_ALL_STREAMS.
+pub const END_OF_STREAM: u32 = 0;
+pub const END_OF_ALL_STREAMS: u32 = 0xffffffff;
+
+/// Longest frame a zint length can describe.
+pub const MAX_LENGTH: u32 = (1 << 28) - 1;
@@ -0,0 +1,99 @@
+use bytes::Bytes;
+use futures::{Async, Future, Stream};
+use futures::executor::{Notify, NotifyHandle, Spawn, spawn};
+use futures_core;
+use std::future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+use bottle::{BottleType, ChildStreams, read_bottle, read_header};
+use bottle_header::{Header};
+
+/*
+ * Adapters from this crate's futures and streams (futures 0.1) to
+ * `std::future::Future`, so they can be `.await`ed from a modern tokio
+ * app. The readers and writers are still built from futures 0.1 streams,
+ * like `file_stream` or `make_stream`: this only changes how results are
+ * waited on.
+ */
+
+/// A futures 0.1 future (or stream), wrapped to be polled by a
+/// `std::future` executor.
+#[must_use = "futures do nothing unless polled"]
+pub struct Compat<T> {
+  inner: Spawn<T>
+}
+
+/// Wrap any futures 0.1 future as a `std::future::Future`.
+pub fn compat<F: Future>(f: F) -> Compat<F> {
+  Compat { inner: spawn(f) }
+}
+
+/// Wrap any futures 0.1 stream as a `futures_core::Stream`.
+pub fn compat_stream<S: Stream>(s: S) -> Compat<S> {
+  Compat { inner: spawn(s) }
+}
+
+impl<F> future::Future for Compat<F> where F: Future + Unpin {
+  type Output = Result<F::Item, F::Error>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+    match self.get_mut().inner.poll_future_notify(&waker_handle(cx), 0) {
+      Ok(Async::Ready(item)) => Poll::Ready(Ok(item)),
+      Ok(Async::NotReady) => Poll::Pending,
+      Err(e) => Poll::Ready(Err(e))
+    }
+  }
+}
+
+impl<S> futures_core::Stream for Compat<S> where S: Stream + Unpin {
+  type Item = Result<S::Item, S::Error>;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+    match self.get_mut().inner.poll_stream_notify(&waker_handle(cx), 0) {
+      Ok(Async::Ready(item)) => Poll::Ready(item.map(Ok)),
+      Ok(Async::NotReady) => Poll::Pending,
+      Err(e) => Poll::Ready(Some(Err(e)))
+    }
+  }
+}
+
+impl<T> Compat<T> {
+  pub fn into_inner(self) -> T {
+    self.inner.into_inner()
+  }
+}
+
+/// Like `bottle::read_header`: the bottle's type and header, and the rest
+/// of the stream.
+pub fn read_header_std<S>(s: S)
+  -> impl future::Future<Output = io::Result<( BottleType, Header, Compat<impl Stream<Item = Bytes, Error = io::Error>> )>>
+  where S: Stream<Item = Bytes, Error = io::Error> + Unpin
+{
+  compat(read_header(s).map(|( btype, header, s )| ( btype, header, compat_stream(s) )))
+}
+
+/// Like `bottle::read_bottle`: the bottle's type and header, and its child
+/// streams, each of which can be wrapped with `compat_stream` in turn.
+#[allow(clippy::type_complexity)]
+pub fn read_bottle_std<S>(s: S)
+  -> impl future::Future<Output = io::Result<( BottleType, Header, Compat<ChildStreams<impl Stream<Item = Bytes, Error = io::Error>>> )>>
+  where S: Stream<Item = Bytes, Error = io::Error> + Unpin
+{
+  compat(read_bottle(s).map(|( btype, header, children )| ( btype, header, compat_stream(children) )))
+}
+
+// wakes the std task when the futures 0.1 task is notified.
+struct WakerNotify(Waker);
+
+impl Notify for WakerNotify {
+  fn notify(&self, _id: usize) {
+    self.0.wake_by_ref();
+  }
+}
+
+fn waker_handle(cx: &Context) -> NotifyHandle {
+  NotifyHandle::from(Arc::new(WakerNotify(cx.waker().clone())))
+}
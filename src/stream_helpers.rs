@@ -1,38 +1,215 @@
 use bytes::Bytes;
-use futures::{Future, Stream, stream};
+use futures::{Async, Future, Poll, Stream, stream, task};
+use futures::sync::oneshot;
+use std::cmp;
 use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use bottle::DecodeLimits;
+use error::BottleError;
+use hashing::{HashAlgorithm, Hasher};
 use stream_reader::{ByteFrame};
 use to_hex::ToHex;
 
 pub fn make_framed_stream_1(b1: Bytes) -> impl Stream<Item = ByteFrame, Error = io::Error> {
   let length = b1.len();
-  stream::iter(vec![ Ok(ByteFrame::new(vec![ b1 ], length)) ])
+  stream::iter_ok(vec![ ByteFrame::new(vec![ b1 ], length) ])
 }
 
 pub fn make_framed_stream_3(b1: Bytes, b2: Bytes, b3: Bytes) -> impl Stream<Item = ByteFrame, Error = io::Error> {
   let length = b1.len() + b2.len() + b3.len();
-  stream::iter(vec![ Ok(ByteFrame::new(vec![ b1, b2, b3 ], length)) ])
+  stream::iter_ok(vec![ ByteFrame::new(vec![ b1, b2, b3 ], length) ])
 }
 
 pub fn make_stream(v: Vec<Bytes>) -> impl Stream<Item = Bytes, Error = io::Error> {
-  stream::iter(v.into_iter().map(|b| Ok(b)))
+  stream::iter_ok(v)
 }
 
 pub fn make_stream_1(b1: Bytes) -> impl Stream<Item = Bytes, Error = io::Error> {
-  stream::iter(vec![ Ok(b1) ])
+  stream::iter_ok(vec![ b1 ])
+}
+
+pub fn make_vec_stream_1(b1: Bytes) -> impl Stream<Item = Vec<Bytes>, Error = io::Error> {
+  stream::iter_ok(vec![ vec![ b1 ] ])
 }
 
 pub fn make_stream_2(b1: Bytes, b2: Bytes) -> impl Stream<Item = Vec<Bytes>, Error = io::Error> {
-  stream::iter(vec![ Ok(vec![ b1 ]), Ok(vec![ b2 ]) ])
+  stream::iter_ok(vec![ vec![ b1 ], vec![ b2 ] ])
 }
 
 pub fn make_stream_3(b1: Bytes, b2: Bytes, b3: Bytes) -> impl Stream<Item = Vec<Bytes>, Error = io::Error> {
-  stream::iter(vec![ Ok(vec![ b1 ]), Ok(vec![ b2 ]), Ok(vec![ b3 ]) ])
+  stream::iter_ok(vec![ vec![ b1 ], vec![ b2 ], vec![ b3 ] ])
 }
 
 pub fn make_stream_4(b1: Bytes, b2: Bytes, b3: Bytes, b4: Bytes) -> impl Stream<Item = Vec<Bytes>, Error = io::Error> {
-  stream::iter(vec![ Ok(vec![ b1 ]), Ok(vec![ b2 ]), Ok(vec![ b3 ]), Ok(vec![ b4 ]) ])
+  stream::iter_ok(vec![ vec![ b1 ], vec![ b2 ], vec![ b3 ], vec![ b4 ] ])
+}
+
+// slow a stream down (for tests): only pass a poll through to the inner
+// stream every `delay + 1` polls, answering `NotReady` in between. there are
+// no timers involved, so backpressure tests stay deterministic.
+pub fn rate_limit_chunks<S: Stream>(s: S, delay: usize) -> RateLimitedStream<S> {
+  RateLimitedStream { stream: s, delay, waited: 0 }
+}
+
+#[must_use = "streams do nothing unless polled"]
+pub struct RateLimitedStream<S: Stream> {
+  stream: S,
+  delay: usize,
+  waited: usize
+}
+
+impl<S: Stream> Stream for RateLimitedStream<S> {
+  type Item = S::Item;
+  type Error = S::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    if self.waited < self.delay {
+      self.waited += 1;
+      // we're ready right away, really. just make them ask again.
+      task::current().notify();
+      return Ok(Async::NotReady);
+    }
+    self.waited = 0;
+    self.stream.poll()
+  }
+}
+
+/// Anything a stream can carry that has a size in bytes: a raw buffer, or
+/// the `Vec<Bytes>` of a bottle stream.
+pub trait ByteCount {
+  fn byte_count(&self) -> usize;
+}
+
+impl ByteCount for Bytes {
+  fn byte_count(&self) -> usize {
+    self.len()
+  }
+}
+
+impl ByteCount for Vec<Bytes> {
+  fn byte_count(&self) -> usize {
+    self.iter().map(|b| b.len()).sum()
+  }
+}
+
+/// Limit a stream to about `bytes_per_sec` (at least 1), using a token
+/// bucket that holds one second's worth, so short bursts go through at full
+/// speed. A buffer bigger than what's in the bucket still goes out whole,
+/// and the next one waits until it's paid for. Works on a raw byte stream
+/// or a bottle stream.
+pub fn throttle_stream<S>(s: S, bytes_per_sec: u64) -> ThrottledStream<S>
+  where S: Stream, S::Item: ByteCount
+{
+  let rate = cmp::max(bytes_per_sec, 1) as f64;
+  ThrottledStream { stream: s, rate, tokens: rate, refilled: Instant::now(), pending: None, timer: None }
+}
+
+#[must_use = "streams do nothing unless polled"]
+pub struct ThrottledStream<S> where S: Stream, S::Item: ByteCount {
+  stream: S,
+  rate: f64,
+  // bytes that can go out now. below zero, a big buffer is being paid off.
+  tokens: f64,
+  refilled: Instant,
+  // the next item, held until there are tokens for it.
+  pending: Option<S::Item>,
+  // started the first time we have to wait.
+  timer: Option<Timer>
+}
+
+impl<S> ThrottledStream<S> where S: Stream, S::Item: ByteCount {
+  fn refill(&mut self) {
+    let now = Instant::now();
+    let elapsed = now.duration_since(self.refilled).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+    self.refilled = now;
+  }
+
+  fn wake_later(&mut self) {
+    let wake_at = Instant::now() + Duration::from_secs_f64(-self.tokens / self.rate);
+    self.timer.get_or_insert_with(Timer::new).wake_at(wake_at, task::current());
+  }
+}
+
+// there are no timers in futures 0.1, so each throttled stream gets one
+// thread that sleeps until the next deadline. it's stopped and joined when
+// the stream is dropped.
+struct Timer {
+  shared: Arc<( Mutex<TimerState>, Condvar )>,
+  thread: Option<thread::JoinHandle<()>>
+}
+
+#[derive(Default)]
+struct TimerState {
+  wake: Option<( Instant, task::Task )>,
+  stopped: bool
+}
+
+impl Timer {
+  fn new() -> Timer {
+    let shared = Arc::new(( Mutex::new(TimerState::default()), Condvar::new() ));
+    let inner = shared.clone();
+    let thread = thread::spawn(move || {
+      let ( ref lock, ref condvar ) = *inner;
+      let mut state = lock.lock().unwrap();
+      while !state.stopped {
+        let now = Instant::now();
+        state = match state.wake.as_ref().map(|&( at, _ )| at) {
+          None => condvar.wait(state).unwrap(),
+          Some(at) if at > now => condvar.wait_timeout(state, at - now).unwrap().0,
+          Some(_) => {
+            if let Some(( _, task )) = state.wake.take() { task.notify() }
+            state
+          }
+        };
+      }
+    });
+    Timer { shared, thread: Some(thread) }
+  }
+
+  // replaces any earlier deadline: there's only ever one poll waiting.
+  fn wake_at(&self, at: Instant, task: task::Task) {
+    let ( ref lock, ref condvar ) = *self.shared;
+    lock.lock().unwrap().wake = Some(( at, task ));
+    condvar.notify_one();
+  }
+}
+
+impl Drop for Timer {
+  fn drop(&mut self) {
+    {
+      let ( ref lock, ref condvar ) = *self.shared;
+      lock.lock().unwrap().stopped = true;
+      condvar.notify_one();
+    }
+    if let Some(thread) = self.thread.take() { let _ = thread.join(); }
+  }
+}
+
+impl<S> Stream for ThrottledStream<S> where S: Stream, S::Item: ByteCount {
+  type Item = S::Item;
+  type Error = S::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    let item = match self.pending.take() {
+      Some(item) => item,
+      None => match try_ready!(self.stream.poll()) {
+        Some(item) => item,
+        None => return Ok(Async::Ready(None))
+      }
+    };
+    self.refill();
+    if self.tokens < 0.0 {
+      self.pending = Some(item);
+      self.wake_later();
+      return Ok(Async::NotReady);
+    }
+    self.tokens -= item.byte_count() as f64;
+    Ok(Async::Ready(Some(item)))
+  }
 }
 
 // convert a stream into a vector of hex output (for tests)
@@ -68,7 +245,86 @@ pub fn drain_stream<S>(s: S) -> Vec<u8>
 pub fn flatten_stream<S>(s: S) -> impl Stream<Item = Bytes, Error = io::Error>
   where S: Stream<Item = Vec<Bytes>, Error = io::Error>
 {
-  s.map(|vec| stream::iter(vec.into_iter().map(|b| Ok(b)))).flatten()
+  s.map(stream::iter_ok::<_, io::Error>).flatten()
+}
+
+// pass a byte stream through unchanged, but fail with `InvalidData` as soon
+// as the cumulative size goes over `max` bytes. a blunt safety valve for
+// reading from untrusted sources.
+pub fn limit_bytes<S>(s: S, max: u64) -> impl Stream<Item = Bytes, Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  let mut total: u64 = 0;
+  s.and_then(move |b| {
+    total += b.len() as u64;
+    if total > max {
+      Err(BottleError::LimitExceeded(max).into())
+    } else {
+      Ok(b)
+    }
+  })
+}
+
+// join a child stream into one buffer, failing as soon as it's bigger than
+// `max_buffered_bytes`.
+pub(crate) fn concat_limited<S>(s: S, limits: DecodeLimits) -> impl Future<Item = Bytes, Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  s.fold(( Vec::new(), 0u64 ), move |( mut buffers, size ), b| {
+    let size = size + b.len() as u64;
+    limits.check_buffered(size)?;
+    buffers.push(b);
+    Ok::<_, io::Error>(( buffers, size ))
+  }).map(|( buffers, _ )| flatten_bytes(buffers))
+}
+
+/// Pass a stream through unchanged, hashing everything that goes by. The
+/// future resolves to the digest once the stream has ended, so a writer can
+/// record a checksum of the whole archive file as it's written. If the
+/// stream fails or is dropped early, the future fails instead.
+pub fn tee_digest<S>(s: S, algorithm: HashAlgorithm)
+  -> io::Result<( TeeDigest<S>, impl Future<Item = Bytes, Error = io::Error> )>
+  where S: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  let ( tx, rx ) = oneshot::channel();
+  let tee = TeeDigest { stream: s, hasher: Some(Hasher::new(algorithm)?), digest: Some(tx) };
+  Ok(( tee, rx.map_err(|_| digest_canceled_error()) ))
+}
+
+#[must_use = "streams do nothing unless polled"]
+pub struct TeeDigest<S> where S: Stream<Item = Vec<Bytes>, Error = io::Error> {
+  stream: S,
+  hasher: Option<Hasher>,
+  digest: Option<oneshot::Sender<Bytes>>
+}
+
+impl<S> Stream for TeeDigest<S> where S: Stream<Item = Vec<Bytes>, Error = io::Error> {
+  type Item = Vec<Bytes>;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    match self.stream.poll() {
+      Ok(Async::Ready(Some(buffers))) => {
+        if let Some(ref mut hasher) = self.hasher {
+          for b in &buffers { hasher.update(b) }
+        }
+        Ok(Async::Ready(Some(buffers)))
+      }
+      Ok(Async::Ready(None)) => {
+        if let ( Some(hasher), Some(tx) ) = ( self.hasher.take(), self.digest.take() ) {
+          // nobody listening is fine.
+          let _ = tx.send(hasher.finish());
+        }
+        Ok(Async::Ready(None))
+      }
+      Ok(Async::NotReady) => Ok(Async::NotReady),
+      Err(e) => {
+        // dropping the sender fails the digest too.
+        self.digest = None;
+        Err(e)
+      }
+    }
+  }
 }
 
 // convert a `Vec<Bytes>` into a `Bytes`, with copying. ☹️
@@ -81,3 +337,10 @@ pub fn flatten_bytes(vec: Vec<Bytes>) -> Bytes {
   for b in vec { rv.extend(b.as_ref()) };
   Bytes::from(rv)
 }
+
+
+// ----- errors
+
+fn digest_canceled_error() -> io::Error {
+  BottleError::DigestCanceled.into()
+}
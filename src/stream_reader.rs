@@ -45,12 +45,12 @@ impl<S> StreamReader<S> where S: Stream<Item = Bytes, Error = io::Error> {
     -> impl Future<Item = StreamReaderResult<S>, Error = io::Error>
   {
     let mut saved = VecDeque::new();
-    saved.extend(prefix.into_iter());
+    saved.extend(prefix);
     StreamReader {
       stream: Some(s.fuse()),
-      count: count,
-      mode: mode,
-      saved: saved,
+      count,
+      mode,
+      saved,
       total_saved: 0
     }
   }
@@ -83,6 +83,29 @@ impl<S> StreamReader<S> where S: Stream<Item = Bytes, Error = io::Error> {
     })
   }
 
+  /// Look at up to `count` bytes at the front of a stream without
+  /// consuming them: the returned stream still starts with the same bytes.
+  /// The frame is short only if the stream ends first.
+  pub fn peek(s: S, count: usize)
+    -> impl Future<Item = (ByteFrame, impl Stream<Item = Bytes, Error = io::Error>), Error = io::Error>
+  {
+    StreamReader::read(s, count, StreamReaderMode::AtMost, None).map(|result| {
+      let unread = result.frame.vec.clone().into_iter().chain(result.remainder);
+      ( result.frame, stream::iter_ok(unread).chain(result.stream) )
+    })
+  }
+
+  /// Skip exactly `count` bytes of a stream, returning a new `Stream`
+  /// representing everything afterwards. Skipped buffers are dropped as
+  /// they arrive, instead of being collected into a frame.
+  ///
+  /// If the stream ends first, an EOF error is returned.
+  pub fn skip_exact(s: S, count: usize)
+    -> impl Future<Item = impl Stream<Item = Bytes, Error = io::Error>, Error = io::Error>
+  {
+    StreamSkipper { stream: Some(s), count }.map(|( remainder, s )| stream::iter_ok(remainder).chain(s))
+  }
+
   /// Drain up to `count` bytes from the saved deque, returning a new vector
   /// to avoid copying buffers.
   ///
@@ -94,7 +117,7 @@ impl<S> StreamReader<S> where S: Stream<Item = Bytes, Error = io::Error> {
     let mut vec: Vec<Bytes> = Vec::new();
     let mut length = 0;
 
-    while self.saved.len() > 0 && length < self.count {
+    while !self.saved.is_empty() && length < self.count {
       let chunk = self.saved.pop_front().unwrap();
       if (length + chunk.len() <= self.count) || self.mode == StreamReaderMode::Lazy {
         length += chunk.len();
@@ -109,7 +132,7 @@ impl<S> StreamReader<S> where S: Stream<Item = Bytes, Error = io::Error> {
       }
     }
 
-    ByteFrame { vec: vec, length: length }
+    ByteFrame { vec, length }
   }
 
   /*
@@ -123,7 +146,7 @@ impl<S> StreamReader<S> where S: Stream<Item = Bytes, Error = io::Error> {
     assert!(self.saved.len() <= 1);
     let remainder = self.saved.pop_front();
     let stream = self.stream.take().unwrap().into_inner();
-    StreamReaderResult { frame: frame, remainder: remainder, stream: stream }
+    StreamReaderResult { frame, remainder, stream }
   }
 }
 
@@ -169,6 +192,40 @@ impl<S> Future for StreamReader<S> where S: Stream<Item = Bytes, Error = io::Err
 }
 
 
+// ----- StreamSkipper
+
+// drop `count` bytes, and return whatever was left of the last buffer.
+struct StreamSkipper<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  stream: Option<S>,
+  count: usize
+}
+
+impl<S> Future for StreamSkipper<S> where S: Stream<Item = Bytes, Error = io::Error> {
+  type Item = ( Option<Bytes>, S );
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    loop {
+      if self.count == 0 {
+        return Ok(Async::Ready(( None, self.stream.take().expect("polling stream twice") )))
+      }
+
+      match self.stream.as_mut().expect("polling stream twice").poll()? {
+        Async::NotReady => return Ok(Async::NotReady),
+        Async::Ready(None) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "EOF")),
+        Async::Ready(Some(buffer)) => {
+          if buffer.len() > self.count {
+            let remainder = buffer.slice_from(self.count);
+            return Ok(Async::Ready(( Some(remainder), self.stream.take().unwrap() )))
+          }
+          self.count -= buffer.len();
+        }
+      }
+    }
+  }
+}
+
+
 // ----- StreamReaderResult
 
 pub struct StreamReaderResult<S> where S: Stream<Item = Bytes, Error = io::Error> {
@@ -182,7 +239,7 @@ impl<S> StreamReaderResult<S> where S: Stream<Item = Bytes, Error = io::Error> {
   /// "un-read". This consumes the result, returning the frame and the new
   /// combined stream.
   pub fn into_stream(self) -> (ByteFrame, impl Stream<Item = Bytes, Error = io::Error>) {
-    ( self.frame, stream::iter(self.remainder.into_iter().map(|b| Ok(b))).chain(self.stream) )
+    ( self.frame, stream::iter_ok(self.remainder).chain(self.stream) )
   }
 }
 
@@ -196,6 +253,6 @@ pub struct ByteFrame {
 
 impl ByteFrame {
   pub fn new(vec: Vec<Bytes>, length: usize) -> ByteFrame {
-    ByteFrame { vec: vec, length: length }
+    ByteFrame { vec, length }
   }
 }
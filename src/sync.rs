@@ -0,0 +1,356 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use bottle::{BottleType, FIELD_INTERLEAVED, FIELD_STREAM_COUNT, check_magic, encode_bottle_header};
+use bottle_header::{Header};
+use error::BottleError;
+use file_bottle::FileMetadata;
+use framed_stream::{truncated_error, unexpected_end_error};
+use indexed_bottle::{FOOTER_SIZE, decode_offsets};
+use zint;
+
+// largest frame written, and the most read from the source at a time.
+const FRAME_SIZE: usize = 64 * 1024;
+
+/// Blocking version of `make_bottle`: write the header, then copy each
+/// reader into the bottle as one child stream, until it hits EOF.
+pub fn write_bottle<W, I, R>(mut writer: W, btype: BottleType, header: &Header, streams: I) -> io::Result<()>
+  where
+    W: Write,
+    I: IntoIterator<Item = R>,
+    R: Read
+{
+  writer.write_all(&encode_bottle_header(btype, header)?)?;
+  write_streams(&mut writer, streams).map(|_| ())
+}
+
+/// Add more child streams to the end of an existing bottle, in place,
+/// returning the indices of the new children. The bottle's frames are
+/// skipped (not read) to find the end marker, which is overwritten by the
+/// new streams and then written again.
+///
+/// Only a bottle that's just a list of children can be added to: a folder,
+/// or a test bottle. Anything else (a file, or a hashed, encrypted, or
+/// compressed bottle) would no longer make sense, and so would a header
+/// that counts the children (`FIELD_STREAM_COUNT`), so they fail with
+/// `BottleError::NotAppendable`. Interleaved bottles fail in `read_bottle`.
+/// Fails with `BottleError::TrailingData` if anything follows the bottle
+/// (like the index of an indexed bottle), since it would be overwritten.
+/// If one of `streams` fails, the new streams are cut off again, leaving
+/// the bottle as it was.
+///
+/// This is blocking, like the rest of this module: the futures this crate
+/// uses have no async seek.
+pub fn append_to_bottle<F, I, R>(mut file: F, streams: I) -> io::Result<Range<usize>>
+  where
+    F: Read + Write + Seek + SetLen,
+    I: IntoIterator<Item = R>,
+    R: Read
+{
+  file.seek(SeekFrom::Start(0))?;
+  let ( btype, header, _ ) = read_bottle(&mut file)?;
+  if !appendable(btype, &header) { return Err(not_appendable_error(btype)) }
+  let mut count = 0;
+  loop {
+    match zint::decode_length(&mut file)? {
+      zint::END_OF_ALL_STREAMS => break,
+      zint::END_OF_STREAM => count += 1,
+      length => { file.seek(SeekFrom::Current(length as i64))?; }
+    }
+  }
+  let end = file.stream_position()?;
+  if file.seek(SeekFrom::End(0))? != end { return Err(trailing_data_error()) }
+
+  file.seek(SeekFrom::Start(end - 1))?;
+  match write_streams(&mut file, streams) {
+    Ok(added) => Ok(count .. count + added),
+    Err(e) => {
+      // the first error is the interesting one.
+      let _ = end_bottle_at(&mut file, end);
+      Err(e)
+    }
+  }
+}
+
+// cut the bottle off at `end`, which is just past its end marker.
+fn end_bottle_at<F: Write + Seek + SetLen>(file: &mut F, end: u64) -> io::Result<()> {
+  file.seek(SeekFrom::Start(end - 1))?;
+  zint::write_length(file, zint::END_OF_ALL_STREAMS)?;
+  file.set_len(end)?;
+  file.flush()
+}
+
+/// Something with a length that can be cut short, like a file, so
+/// `append_to_bottle` can undo a failed append.
+pub trait SetLen {
+  fn set_len(&mut self, size: u64) -> io::Result<()>;
+}
+
+impl SetLen for fs::File {
+  fn set_len(&mut self, size: u64) -> io::Result<()> {
+    fs::File::set_len(self, size)
+  }
+}
+
+impl SetLen for io::Cursor<Vec<u8>> {
+  fn set_len(&mut self, size: u64) -> io::Result<()> {
+    self.get_mut().truncate(size as usize);
+    Ok(())
+  }
+}
+
+impl<T: SetLen + ?Sized> SetLen for &mut T {
+  fn set_len(&mut self, size: u64) -> io::Result<()> {
+    (**self).set_len(size)
+  }
+}
+
+fn appendable(btype: BottleType, header: &Header) -> bool {
+  if header.get_number(FIELD_STREAM_COUNT).is_some() { return false }
+  match btype {
+    BottleType::File => FileMetadata::from_header(header).map(|metadata| metadata.folder).unwrap_or(false),
+    BottleType::Test | BottleType::Test2 => true,
+    _ => false
+  }
+}
+
+// copy each reader into its own child stream, then end the bottle.
+// returns how many there were.
+fn write_streams<W, I, R>(writer: &mut W, streams: I) -> io::Result<usize>
+  where
+    W: Write,
+    I: IntoIterator<Item = R>,
+    R: Read
+{
+  let mut buffer = vec![ 0u8; FRAME_SIZE ];
+  let mut count = 0;
+  for mut reader in streams {
+    loop {
+      let n = read_fully(&mut reader, &mut buffer)?;
+      if n == 0 { break }
+      zint::write_length(writer, n as u32)?;
+      writer.write_all(&buffer[0 .. n])?;
+    }
+    zint::write_length(writer, zint::END_OF_STREAM)?;
+    count += 1;
+  }
+  zint::write_length(writer, zint::END_OF_ALL_STREAMS)?;
+  writer.flush()?;
+  Ok(count)
+}
+
+/// Blocking version of `read_bottle`: read the header, and return a
+/// `BottleReader` for walking the child streams.
+///
+/// Interleaved child streams (from `make_interleaved_bottle`) aren't sorted
+/// out here: a bottle with `FIELD_INTERLEAVED` fails with
+/// `BottleError::Interleaved`.
+pub fn read_bottle<R: Read>(mut reader: R) -> io::Result<(BottleType, Header, BottleReader<R>)> {
+  let mut cap = [ 0u8; 8 ];
+  read_exact(&mut reader, &mut cap)?;
+  let ( btype, header_length ) = check_magic(&cap)?;
+  let mut buffer = vec![ 0u8; header_length ];
+  read_exact(&mut reader, &mut buffer)?;
+  let header = Header::decode(&buffer)?;
+  if header.get_number(FIELD_INTERLEAVED).is_some() { return Err(interleaved_error()) }
+  Ok(( btype, header, BottleReader { reader, remaining: 0, in_stream: false, done: false } ))
+}
+
+/// Child streams of a bottle, from `read_bottle`. Like the streaming
+/// reader, children must be read in order, and moving to the next one
+/// skips whatever's left of the current one.
+pub struct BottleReader<R: Read> {
+  reader: R,
+  // bytes left in the current frame
+  remaining: usize,
+  in_stream: bool,
+  done: bool
+}
+
+impl<R: Read> BottleReader<R> {
+  /// Move to the next child stream, or return `None` at the end of the
+  /// bottle.
+  pub fn next_stream(&mut self) -> io::Result<Option<ChildReader<'_, R>>> {
+    // skip the rest of the previous child.
+    while self.in_stream {
+      io::copy(&mut ChildReader { bottle: self }, &mut io::sink())?;
+    }
+    if self.done || !self.start_stream()? { return Ok(None) }
+    Ok(Some(ChildReader { bottle: self }))
+  }
+
+  /// The underlying reader, positioned wherever this bottle left off.
+  pub fn into_inner(self) -> R {
+    self.reader
+  }
+
+  // read the first length of a child stream, which may be the end of all
+  // streams instead.
+  fn start_stream(&mut self) -> io::Result<bool> {
+    match zint::decode_length(&mut self.reader)? {
+      zint::END_OF_ALL_STREAMS => {
+        self.done = true;
+        return Ok(false);
+      }
+      zint::END_OF_STREAM => (),
+      length => {
+        self.remaining = length as usize;
+        self.in_stream = true;
+      }
+    }
+    Ok(true)
+  }
+
+  fn read_child(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+    while self.in_stream {
+      if self.remaining > 0 {
+        let count = buffer.len().min(self.remaining);
+        let n = self.reader.read(&mut buffer[0 .. count])?;
+        if n == 0 && count > 0 { return Err(truncated_error()) }
+        self.remaining -= n;
+        return Ok(n);
+      }
+      match zint::decode_length(&mut self.reader)? {
+        zint::END_OF_STREAM => self.in_stream = false,
+        zint::END_OF_ALL_STREAMS => return Err(unexpected_end_error()),
+        length => self.remaining = length as usize
+      }
+    }
+    Ok(0)
+  }
+}
+
+/// One child stream of a bottle, with the framing removed.
+pub struct ChildReader<'a, R: Read + 'a> {
+  bottle: &'a mut BottleReader<R>
+}
+
+impl<'a, R: Read> Read for ChildReader<'a, R> {
+  fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+    self.bottle.read_child(buffer)
+  }
+}
+
+/// Reads child streams out of a bottle written by `make_indexed_bottle`,
+/// in any order, by seeking straight to each one.
+///
+/// This only works over a blocking `Read + Seek` (a file, or a
+/// `RangeReader`): the futures this crate uses have no async seek, so
+/// there's no async version. To use it from a future, run it on a thread,
+/// like a `futures_cpupool::CpuPool`.
+pub struct SeekableBottleReader<R: Read + Seek> {
+  reader: R,
+  btype: BottleType,
+  header: Header,
+  offsets: Vec<u64>
+}
+
+impl<R: Read + Seek> SeekableBottleReader<R> {
+  /// Read the bottle's header and its index. Fails with
+  /// `BottleError::NoIndex` if there's no index at the end.
+  pub fn open(mut reader: R) -> io::Result<SeekableBottleReader<R>> {
+    reader.seek(SeekFrom::Start(0))?;
+    let ( btype, header, _ ) = read_bottle(&mut reader)?;
+
+    let end = reader.seek(SeekFrom::End(0))?;
+    if end < FOOTER_SIZE as u64 { return Err(no_index_error()) }
+    reader.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+    let mut footer = [ 0u8; FOOTER_SIZE ];
+    read_exact(&mut reader, &mut footer)?;
+    let index_offset = u64::from_le_bytes(footer);
+    if index_offset >= end - FOOTER_SIZE as u64 { return Err(no_index_error()) }
+
+    reader.seek(SeekFrom::Start(index_offset))?;
+    let ( index_type, index_header, mut index ) = match read_bottle(&mut reader) {
+      Ok(bottle) => bottle,
+      Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => return Err(no_index_error()),
+      Err(e) => return Err(e)
+    };
+    if index_type != BottleType::Index { return Err(no_index_error()) }
+    let mut data = Vec::new();
+    if let Some(mut child) = index.next_stream()? { child.read_to_end(&mut data)?; }
+    let offsets = decode_offsets(&index_header, &data)?;
+    Ok(SeekableBottleReader { reader, btype, header, offsets })
+  }
+
+  pub fn bottle_type(&self) -> BottleType {
+    self.btype
+  }
+
+  pub fn header(&self) -> &Header {
+    &self.header
+  }
+
+  /// How many child streams the index lists.
+  pub fn stream_count(&self) -> usize {
+    self.offsets.len()
+  }
+
+  /// Seek to child stream `n`, and read it.
+  pub fn open_stream(&mut self, n: usize) -> io::Result<IndexedStream<'_, R>> {
+    let offset = *self.offsets.get(n).ok_or_else(|| no_such_entry_error(n))?;
+    self.reader.seek(SeekFrom::Start(offset))?;
+    let mut bottle = BottleReader { reader: &mut self.reader, remaining: 0, in_stream: false, done: false };
+    if !bottle.start_stream()? { return Err(bad_index_error()) }
+    Ok(IndexedStream { bottle })
+  }
+}
+
+/// One child stream, from `SeekableBottleReader::open_stream`.
+pub struct IndexedStream<'a, R: Read + 'a> {
+  bottle: BottleReader<&'a mut R>
+}
+
+impl<'a, R: Read> Read for IndexedStream<'a, R> {
+  fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+    self.bottle.read_child(buffer)
+  }
+}
+
+// like `read_exact`, but with the same "truncated" error as the streams.
+fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<()> {
+  if read_fully(reader, buffer)? < buffer.len() { return Err(truncated_error()) }
+  Ok(())
+}
+
+// fill as much of the buffer as possible, stopping early only at EOF.
+fn read_fully<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+  let mut total = 0;
+  while total < buffer.len() {
+    match reader.read(&mut buffer[total ..]) {
+      Ok(0) => break,
+      Ok(n) => total += n,
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+      Err(e) => return Err(e)
+    }
+  }
+  Ok(total)
+}
+
+
+// ----- errors
+
+fn no_index_error() -> io::Error {
+  BottleError::NoIndex.into()
+}
+
+fn bad_index_error() -> io::Error {
+  BottleError::BadIndex.into()
+}
+
+fn no_such_entry_error(n: usize) -> io::Error {
+  BottleError::NoSuchEntry(n).into()
+}
+
+fn trailing_data_error() -> io::Error {
+  BottleError::TrailingData.into()
+}
+
+fn not_appendable_error(btype: BottleType) -> io::Error {
+  BottleError::NotAppendable(btype).into()
+}
+
+fn interleaved_error() -> io::Error {
+  BottleError::Interleaved.into()
+}
@@ -6,6 +6,7 @@ pub trait ToHex {
 }
 
 pub trait FromHex {
+  #[allow(clippy::wrong_self_convention)]
   fn from_hex(&self) -> Vec<u8>;
 }
 
@@ -34,7 +35,7 @@ impl<T> ToHex for Vec<T> where T: ToHex {
   }
 }
 
-impl<'a> FromHex for &'a str {
+impl FromHex for &str {
   fn from_hex(&self) -> Vec<u8> {
     // rust still doesn't have step_by! :(
     (0 .. self.len() / 2).map(|i| {
@@ -0,0 +1,293 @@
+use bytes::Bytes;
+use futures::{Future, Stream, future};
+use futures::future::Loop;
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+use archive::{BottleStream, ByteStream};
+use bottle::{BottleType, DecodeLimits, peek_bottle_type};
+use compressed_bottle::{CompressOptions, CompressionType, compress_bottle_with_options, decompress_bottle_with_limits};
+use dedup_bottle::{dedup_bottle, reassemble_bottle_with_limits};
+use encrypted_bottle::{EncryptionInfo, EncryptionType, KeySource, decrypt_bottle_with_limits, encrypt_bottle_with_cipher};
+use error::BottleError;
+use hash_bottle::{hash_bottle, verify_hash_bottle_with_limits};
+use hashing::HashAlgorithm;
+use parity_bottle::{FIELD_DATA_SHARDS, FIELD_PARITY_SHARDS, repair_bottle_with_limits, with_parity};
+
+type KeyResolver = Rc<dyn Fn(&EncryptionInfo) -> io::Result<KeySource>>;
+
+// the layers that can be changed, from the inside out, in the order
+// `ArchiveWriter` builds them.
+const HASH: usize = 0;
+const DEDUP: usize = 1;
+const COMPRESSION: usize = 2;
+const ENCRYPTION: usize = 3;
+const PARITY: usize = 4;
+
+// what to do with one kind of layer.
+#[derive(Default)]
+enum Change<T> {
+  // leave it as it is (or isn't)
+  #[default]
+  Keep,
+  Remove,
+  // replace it, or add it
+  Set(T)
+}
+
+impl<T> Change<T> {
+  fn is_keep(&self) -> bool {
+    matches!(*self, Change::Keep)
+  }
+
+  // the layer to build, given the one that was there.
+  fn pick(self, found: Option<T>) -> Option<T> {
+    match self {
+      Change::Keep => found,
+      Change::Remove => None,
+      Change::Set(value) => Some(value)
+    }
+  }
+}
+
+/*
+ * How `transcode_bottle` changes an archive: each layer is kept as it is
+ * unless it's replaced, added, or stripped here.
+ */
+#[derive(Default)]
+pub struct TranscodeOptions {
+  key_resolver: Option<KeyResolver>,
+  limits: DecodeLimits,
+  hash: Change<HashAlgorithm>,
+  dedup: Change<()>,
+  compression: Change<CompressOptions>,
+  encryption: Change<( KeySource, Vec<String>, EncryptionType )>,
+  parity: Change<( usize, usize )>
+}
+
+impl TranscodeOptions {
+  pub fn new() -> TranscodeOptions {
+    TranscodeOptions::default()
+  }
+
+  /// Called to find the key, if an encrypted layer has to be opened. If
+  /// the encryption is kept, the same key is used to encrypt again.
+  pub fn with_key<F>(mut self, resolver: F) -> TranscodeOptions
+    where F: Fn(&EncryptionInfo) -> io::Result<KeySource> + 'static
+  {
+    self.key_resolver = Some(Rc::new(resolver));
+    self
+  }
+
+  /// Refuse archives that would make opening their layers allocate (or
+  /// nest) more than this.
+  pub fn with_limits(mut self, limits: DecodeLimits) -> TranscodeOptions {
+    self.limits = limits;
+    self
+  }
+
+  /// Hash the files with a new algorithm (checking the old hash, if any).
+  /// Signed hashes can't be replaced.
+  pub fn hash(mut self, algorithm: HashAlgorithm) -> TranscodeOptions {
+    self.hash = Change::Set(algorithm);
+    self
+  }
+
+  pub fn strip_hash(mut self) -> TranscodeOptions {
+    self.hash = Change::Remove;
+    self
+  }
+
+  pub fn dedup(mut self) -> TranscodeOptions {
+    self.dedup = Change::Set(());
+    self
+  }
+
+  pub fn strip_dedup(mut self) -> TranscodeOptions {
+    self.dedup = Change::Remove;
+    self
+  }
+
+  pub fn compress(self, compression_type: CompressionType) -> TranscodeOptions {
+    self.compress_with(CompressOptions::new(compression_type))
+  }
+
+  pub fn compress_with(mut self, options: CompressOptions) -> TranscodeOptions {
+    self.compression = Change::Set(options);
+    self
+  }
+
+  pub fn strip_compression(mut self) -> TranscodeOptions {
+    self.compression = Change::Remove;
+    self
+  }
+
+  pub fn encrypt(self, key: KeySource, recipients: Vec<String>) -> TranscodeOptions {
+    self.encrypt_with(key, recipients, EncryptionType::Aes256Gcm)
+  }
+
+  pub fn encrypt_with(mut self, key: KeySource, recipients: Vec<String>, encryption_type: EncryptionType) -> TranscodeOptions {
+    self.encryption = Change::Set(( key, recipients, encryption_type ));
+    self
+  }
+
+  pub fn strip_encryption(mut self) -> TranscodeOptions {
+    self.encryption = Change::Remove;
+    self
+  }
+
+  pub fn parity(mut self, data_shards: usize, parity_shards: usize) -> TranscodeOptions {
+    self.parity = Change::Set(( data_shards, parity_shards ));
+    self
+  }
+
+  pub fn strip_parity(mut self) -> TranscodeOptions {
+    self.parity = Change::Remove;
+    self
+  }
+
+  // everything outside the innermost changed layer has to be opened, and
+  // rebuilt.
+  fn innermost_change(&self) -> Option<usize> {
+    let keep = [ self.hash.is_keep(), self.dedup.is_keep(), self.compression.is_keep(), self.encryption.is_keep(), self.parity.is_keep() ];
+    keep.iter().position(|&keep| !keep)
+  }
+}
+
+// the layers that were opened, and how they were set up.
+#[derive(Default)]
+struct Found {
+  depth: usize,
+  dedup: Option<()>,
+  compression: Option<CompressOptions>,
+  encryption: Option<( KeySource, Vec<String>, EncryptionType )>,
+  parity: Option<( usize, usize )>
+}
+
+/// Rewrite an archive with some of its layers changed: recompress it with
+/// a different codec, add or strip encryption, add parity, and so on. It's
+/// all streamed, without extracting anything.
+///
+/// Only the layers outside the innermost change are opened, and they're
+/// rebuilt the way they were (the same codec, the same key, the same
+/// parity). Anything inside that is copied as it is, so changing only the
+/// outer layers never needs a key for the inner ones. A compression level
+/// is only kept if the bottle recorded it.
+pub fn transcode_bottle<S>(s: S, options: TranscodeOptions) -> impl Future<Item = BottleStream, Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error> + 'static
+{
+  let innermost = match options.innermost_change() {
+    Some(innermost) => innermost,
+    None => return future::Either::A(future::ok(Box::new(s.map(|b| vec![ b ])) as BottleStream))
+  };
+  let key_resolver = options.key_resolver.clone();
+  let limits = options.limits;
+  let opened = future::loop_fn(( Box::new(s) as ByteStream, Found::default() ), move |( s, found )| {
+    let key_resolver = key_resolver.clone();
+    peek_bottle_type(s).and_then(move |( btype, s )| open_layer(btype, Box::new(s), found, innermost, key_resolver, limits))
+  });
+  future::Either::B(opened.and_then(move |( s, found )| rebuild(s, found, innermost, options)))
+}
+
+type LayerFuture = Box<dyn Future<Item = Loop<( ByteStream, Found ), ( ByteStream, Found )>, Error = io::Error>>;
+
+// open the next layer if it's one that's changing (or is outside one that
+// is), remembering how it was made.
+fn open_layer(
+  btype: BottleType,
+  s: ByteStream,
+  mut found: Found,
+  innermost: usize,
+  key_resolver: Option<KeyResolver>,
+  limits: DecodeLimits
+) -> LayerFuture {
+  let layer = match btype {
+    BottleType::Hashed => HASH,
+    BottleType::Dedup => DEDUP,
+    BottleType::Compressed => COMPRESSION,
+    BottleType::Encrypted => ENCRYPTION,
+    BottleType::Parity => PARITY,
+    BottleType::File => return Box::new(future::ok(Loop::Break(( s, found )))),
+    _ => return Box::new(future::err(unexpected_bottle_error(btype)))
+  };
+  if layer < innermost { return Box::new(future::ok(Loop::Break(( s, found )))) }
+  found.depth += 1;
+  if let Err(e) = limits.check_depth(found.depth) { return Box::new(future::err(e)) }
+
+  match btype {
+    BottleType::Hashed => Box::new(verify_hash_bottle_with_limits(s, limits).map(move |( _, s )| Loop::Continue(( Box::new(s) as ByteStream, found )))),
+    BottleType::Dedup => Box::new(reassemble_bottle_with_limits(s, limits).map(move |( _, s )| {
+      found.dedup = Some(());
+      Loop::Continue(( Box::new(s) as ByteStream, found ))
+    })),
+    BottleType::Compressed => Box::new(decompress_bottle_with_limits(s, limits).and_then(move |( header, s )| {
+      found.compression = Some(CompressOptions::from_header(&header)?);
+      Ok(Loop::Continue(( Box::new(s) as ByteStream, found )))
+    })),
+    BottleType::Encrypted => {
+      let resolver = match key_resolver {
+        Some(resolver) => resolver,
+        None => return Box::new(future::err(no_key_error()))
+      };
+      // hang on to the key, to encrypt with again.
+      let key = Rc::new(RefCell::new(None));
+      let saved = key.clone();
+      Box::new(decrypt_bottle_with_limits(s, move |info| {
+        let key = resolver(info)?;
+        *saved.borrow_mut() = Some(( key.clone(), info.recipients.clone(), info.encryption_type ));
+        Ok(key)
+      }, limits).map(move |( _, s )| {
+        found.encryption = key.borrow_mut().take();
+        Loop::Continue(( Box::new(s) as ByteStream, found ))
+      }))
+    }
+    _ => Box::new(repair_bottle_with_limits(s, limits).map(move |( header, s )| {
+      let data_shards = header.get_number(FIELD_DATA_SHARDS).unwrap_or(0) as usize;
+      let parity_shards = header.get_number(FIELD_PARITY_SHARDS).unwrap_or(0) as usize;
+      found.parity = Some(( data_shards, parity_shards ));
+      Loop::Continue(( Box::new(s) as ByteStream, found ))
+    }))
+  }
+}
+
+fn rebuild(s: ByteStream, found: Found, innermost: usize, options: TranscodeOptions) -> io::Result<BottleStream> {
+  let mut s: BottleStream = Box::new(s.map(|b| vec![ b ]));
+  // a kept hash is never opened, so it's always inside.
+  if innermost == HASH {
+    if let Some(algorithm) = options.hash.pick(None) { s = Box::new(hash_bottle(s, algorithm)?) }
+  }
+  if innermost <= DEDUP && options.dedup.pick(found.dedup).is_some() {
+    s = Box::new(dedup_bottle(s));
+  }
+  if innermost <= COMPRESSION {
+    if let Some(compression) = options.compression.pick(found.compression) {
+      s = Box::new(compress_bottle_with_options(s, &compression)?);
+    }
+  }
+  if innermost <= ENCRYPTION {
+    if let Some(( key, recipients, encryption_type )) = options.encryption.pick(found.encryption) {
+      if let KeySource::PrivateKey(_) = key { return Err(key_not_reusable_error()) }
+      s = Box::new(encrypt_bottle_with_cipher(s, key, recipients, encryption_type)?);
+    }
+  }
+  if let Some(( data_shards, parity_shards )) = options.parity.pick(found.parity) {
+    s = Box::new(with_parity(s, data_shards, parity_shards)?);
+  }
+  Ok(s)
+}
+
+
+// ----- errors
+
+fn no_key_error() -> io::Error {
+  BottleError::NoKey.into()
+}
+
+fn key_not_reusable_error() -> io::Error {
+  BottleError::KeyNotReusable.into()
+}
+
+fn unexpected_bottle_error(btype: BottleType) -> io::Error {
+  BottleError::UnexpectedType(btype).into()
+}
@@ -0,0 +1,492 @@
+use bytes::Bytes;
+use futures::{Future, Stream};
+use std::io;
+use std::mem;
+
+use bottle::{BottleType, DecodeLimits, FIELD_INTERLEAVED, FIELD_STREAM_COUNT, parse_bottle_cap};
+use bottle_header::{Header};
+use compressed_bottle::{FIELD_COMPRESSION_TYPE, decode_compression_type};
+use encrypted_bottle::{FIELD_ENCRYPTION_TYPE, decode_encryption_type};
+use error::BottleError;
+use file_bottle::FileMetadata;
+use hash_bottle::hash_info;
+use hashing::{Hasher, crc32c};
+use indexed_bottle::{FOOTER_SIZE, decode_offsets};
+use zint;
+
+/// One thing wrong with a bottle.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct Problem {
+  /// where it is, in bytes from the start of the stream
+  pub offset: u64,
+  /// which bottle: the child stream index at each level of nesting, so the
+  /// outer bottle is `[]`, and its second child's bottle is `[1]`
+  pub path: Vec<usize>,
+  pub error: BottleError
+}
+
+/// What `validate_bottle` found.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct ValidationReport {
+  pub problems: Vec<Problem>,
+  /// how many bottles were checked, including nested ones
+  pub bottles: usize,
+  pub bytes: u64
+}
+
+impl ValidationReport {
+  pub fn is_ok(&self) -> bool {
+    self.problems.is_empty()
+  }
+
+  fn add(&mut self, offset: u64, path: &[usize], e: io::Error) -> io::Result<()> {
+    let error = BottleError::find(&e).cloned().ok_or(e)?;
+    self.problems.push(Problem { offset, path: path.to_vec(), error });
+    Ok(())
+  }
+}
+
+/// Check a whole bottle without extracting anything: the magic, version,
+/// and header of each bottle, the framing of each child stream, the end
+/// markers, the digest of each (unsigned) hashed bottle, and the CRC32C of
+/// each file that has one. Bottles inside folders and hashed bottles are
+/// checked too, but compressed and encrypted data isn't opened. An index
+/// and footer after the bottle (from `make_indexed_bottle`) are checked
+/// against it.
+///
+/// Interleaved child streams (from `make_interleaved_bottle`) can't be
+/// checked yet: a bottle with `FIELD_INTERLEAVED` is reported as
+/// `BottleError::Interleaved`, and nothing after its header is checked.
+///
+/// Problems go in the report; the future only fails if the stream does. A
+/// bottle whose framing is broken can't be read any further, so later
+/// problems inside it won't be found.
+pub fn validate_bottle<S>(s: S) -> impl Future<Item = ValidationReport, Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  validate_bottle_with_limits(s, DecodeLimits::default())
+}
+
+/// `validate_bottle`, reporting bottles nested deeper than
+/// `max_nesting_depth`, and digests or indexes bigger than
+/// `max_buffered_bytes`, instead of checking inside them.
+pub fn validate_bottle_with_limits<S>(s: S, limits: DecodeLimits) -> impl Future<Item = ValidationReport, Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  s.fold(Validation::new(limits), |mut v, b| v.feed(&b).map(|_| v)).and_then(|v| v.finish())
+}
+
+struct Validation {
+  limits: DecodeLimits,
+  report: ValidationReport,
+  bottle: Checker,
+  // an index bottle after the bottle, and its footer
+  index: Option<Checker>,
+  footer: Vec<u8>,
+  footer_offset: u64
+}
+
+impl Validation {
+  fn new(limits: DecodeLimits) -> Validation {
+    Validation {
+      limits,
+      report: ValidationReport::default(),
+      bottle: Checker::new(Vec::new(), false, limits),
+      index: None,
+      footer: Vec::new(),
+      footer_offset: 0
+    }
+  }
+
+  fn feed(&mut self, data: &[u8]) -> io::Result<()> {
+    let offset = self.report.bytes;
+    self.report.bytes += data.len() as u64;
+    let mut n = self.bottle.feed(data, offset, &mut self.report)?;
+    if n == data.len() || self.bottle.state != State::Done { return Ok(()) }
+
+    let limits = self.limits;
+    let index = self.index.get_or_insert_with(|| Checker::new(Vec::new(), true, limits));
+    n += index.feed(&data[n ..], offset + n as u64, &mut self.report)?;
+    if n == data.len() || index.state != State::Done { return Ok(()) }
+    if self.footer.is_empty() { self.footer_offset = offset + n as u64 }
+    // anything past the footer's size is already wrong.
+    let room = (FOOTER_SIZE + 1).saturating_sub(self.footer.len());
+    self.footer.extend_from_slice(&data[n ..][.. room.min(data.len() - n)]);
+    Ok(())
+  }
+
+  fn finish(mut self) -> io::Result<ValidationReport> {
+    let end = self.report.bytes;
+    self.bottle.finish(end, &mut self.report)?;
+    if let Some(mut index) = self.index.take() {
+      index.finish(end, &mut self.report)?;
+      if index.state == State::Done { self.check_index(&index)? }
+    }
+    Ok(self.report)
+  }
+
+  // the index has to point at the bottle's child streams, and the footer at
+  // the index.
+  fn check_index(&mut self, index: &Checker) -> io::Result<()> {
+    let offsets = decode_offsets(&index.header, &index.kept);
+    if offsets.ok().as_ref() != Some(&self.bottle.child_offsets) {
+      self.report.add(index.start, &[], bad_index_error())?;
+    }
+    let footer_ok = self.footer.len() == FOOTER_SIZE && {
+      let mut buffer = [ 0u8; FOOTER_SIZE ];
+      buffer.copy_from_slice(&self.footer);
+      u64::from_le_bytes(buffer) == index.start
+    };
+    if !footer_ok {
+      let offset = if self.footer.is_empty() { self.report.bytes } else { self.footer_offset };
+      self.report.add(offset, &[], bad_index_error())?;
+    }
+    Ok(())
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+  // reading the 8-byte cap (magic, version, type, header length)
+  Cap,
+  // reading a header of this length
+  Header(usize),
+  // waiting for a new child stream, or the end of all streams
+  BetweenStreams,
+  // inside a child stream, waiting for a frame length
+  InStream,
+  // inside a frame, with this many bytes left
+  InFrame(usize),
+  Done,
+  // the framing is broken, so nothing more can be read.
+  Failed
+}
+
+// what to do with a child stream's data.
+#[derive(Clone, Copy, PartialEq)]
+enum ChildKind {
+  Data,
+  // it's a bottle: check that too.
+  Bottle,
+  // a hashed bottle's inner bottle, which is also hashed
+  HashedBottle,
+  // small, and needed at the end (a digest or an index)
+  Keep
+}
+
+// checks one bottle as its bytes go by, handing the data of any child that
+// is a bottle to a nested checker.
+struct Checker {
+  path: Vec<usize>,
+  // an index after the main bottle, where anything else is trailing data
+  trailer: bool,
+  limits: DecodeLimits,
+  start: u64,
+  state: State,
+  // the cap, header, or frame length so far
+  buffer: Vec<u8>,
+  buffer_offset: u64,
+  btype: Option<BottleType>,
+  header: Header,
+  // a folder's children are bottles too.
+  folder: bool,
+  // a file with a CRC32C: how many children it covers, and the CRC so far
+  crc_children: Option<usize>,
+  crc: u32,
+  signed: bool,
+  // where each child stream starts (at its first frame length)
+  child_offsets: Vec<u64>,
+  nested: Option<Box<Checker>>,
+  hasher: Option<Hasher>,
+  digest: Option<Bytes>,
+  kept: Vec<u8>
+}
+
+impl Checker {
+  fn new(path: Vec<usize>, trailer: bool, limits: DecodeLimits) -> Checker {
+    Checker {
+      path,
+      trailer,
+      limits,
+      start: 0,
+      state: State::Cap,
+      buffer: Vec::new(),
+      buffer_offset: 0,
+      btype: None,
+      header: Header::new(),
+      folder: false,
+      crc_children: None,
+      crc: 0,
+      signed: false,
+      child_offsets: Vec::new(),
+      nested: None,
+      hasher: None,
+      digest: None,
+      kept: Vec::new()
+    }
+  }
+
+  // returns how much was used: after the end of the bottle, nothing is.
+  fn feed(&mut self, data: &[u8], offset: u64, report: &mut ValidationReport) -> io::Result<usize> {
+    let mut used = 0;
+    while used < data.len() || self.ready_without_data() {
+      let at = offset + used as u64;
+      match self.state {
+        State::Done | State::Failed => break,
+        State::Cap => {
+          if self.buffer.is_empty() { self.start = at }
+          used += self.gather(&data[used ..], 8);
+          if self.buffer.len() == 8 { self.end_cap(report)? }
+        }
+        State::Header(length) => {
+          used += self.gather(&data[used ..], length);
+          if self.buffer.len() == length { self.end_header(report)? }
+        }
+        State::BetweenStreams | State::InStream => {
+          if self.buffer.is_empty() { self.buffer_offset = at }
+          self.buffer.push(data[used]);
+          used += 1;
+          if self.buffer.len() == zint::length_of_length(self.buffer[0]) {
+            let length = zint::decode_length(&mut io::Cursor::new(mem::take(&mut self.buffer)))?;
+            self.end_length(length, at + 1, report)?;
+          }
+        }
+        State::InFrame(remaining) => {
+          let n = remaining.min(data.len() - used);
+          self.child_data(&data[used .. used + n], at, report)?;
+          used += n;
+          if self.state == State::Failed { break }
+          self.state = if n == remaining { State::InStream } else { State::InFrame(remaining - n) };
+        }
+      }
+    }
+    Ok(used)
+  }
+
+  // the end of the stream (or the child stream this bottle is in).
+  fn finish(&mut self, offset: u64, report: &mut ValidationReport) -> io::Result<()> {
+    if self.ready_without_data() { self.feed(&[], offset, report)?; }
+    match self.state {
+      State::Done | State::Failed => Ok(()),
+      State::Cap if self.trailer => self.fail(self.start, trailing_data_error(), report),
+      _ => self.fail(offset, truncated_error(), report)
+    }
+  }
+
+  // an empty header has nothing to wait for.
+  fn ready_without_data(&self) -> bool {
+    self.state == State::Header(0)
+  }
+
+  fn gather(&mut self, data: &[u8], size: usize) -> usize {
+    let n = (size - self.buffer.len()).min(data.len());
+    self.buffer.extend_from_slice(&data[.. n]);
+    n
+  }
+
+  fn end_cap(&mut self, report: &mut ValidationReport) -> io::Result<()> {
+    let mut cap = [ 0u8; 8 ];
+    cap.copy_from_slice(&self.buffer);
+    self.buffer.clear();
+    match parse_bottle_cap(&cap) {
+      Ok(( btype, _ )) if self.trailer && btype != BottleType::Index => self.fail(self.start, trailing_data_error(), report),
+      Ok(( btype, length )) => {
+        report.bottles += 1;
+        // the nested checkers recurse, so stop before the stack runs out.
+        if let Err(e) = self.limits.check_depth(self.path.len() + 1) { return self.fail(self.start, e, report) }
+        self.btype = Some(btype);
+        self.state = State::Header(length);
+        Ok(())
+      }
+      Err(BottleError::BadMagic) if self.trailer => self.fail(self.start, trailing_data_error(), report),
+      Err(e) => self.fail(self.start, e.into(), report)
+    }
+  }
+
+  // a bad header is reported, but the framing after it can still be checked.
+  fn end_header(&mut self, report: &mut ValidationReport) -> io::Result<()> {
+    let offset = self.start + 8;
+    let header = Header::decode(&mem::take(&mut self.buffer));
+    self.state = State::BetweenStreams;
+    self.header = match header {
+      Ok(header) => header,
+      Err(e) => return report.add(offset, &self.path, e)
+    };
+    if self.header.get_number(FIELD_INTERLEAVED).is_some() { return self.fail(offset, interleaved_error(), report) }
+    let header = &self.header;
+    let checked = match self.btype {
+      Some(BottleType::File) => FileMetadata::from_header(header).map(|metadata| {
+        self.folder = metadata.folder;
+        if metadata.crc32c && !metadata.folder { self.crc_children = Some(if metadata.sparse { 2 } else { 1 }) }
+      }),
+      Some(BottleType::Hashed) => hash_info(header, Bytes::new()).and_then(|info| {
+        self.hasher = Some(Hasher::new(info.algorithm)?);
+        self.signed = info.signed_by.is_some();
+        Ok(())
+      }),
+      Some(BottleType::Compressed) => decode_compression_type(header.get_number(FIELD_COMPRESSION_TYPE).unwrap_or(0)).map(|_| ()),
+      Some(BottleType::Encrypted) => decode_encryption_type(header.get_number(FIELD_ENCRYPTION_TYPE).unwrap_or(0)).map(|_| ()),
+      _ => Ok(())
+    };
+    if let Err(e) = checked { report.add(offset, &self.path, e)? }
+    Ok(())
+  }
+
+  fn end_length(&mut self, length: u32, next: u64, report: &mut ValidationReport) -> io::Result<()> {
+    let offset = self.buffer_offset;
+    match ( self.state, length ) {
+      ( State::BetweenStreams, zint::END_OF_ALL_STREAMS ) => {
+        self.state = State::Done;
+        self.end_bottle(offset, report)
+      }
+      ( State::BetweenStreams, zint::END_OF_STREAM ) => {
+        self.start_child(offset);
+        self.end_child(next, report)
+      }
+      ( State::BetweenStreams, length ) => {
+        self.start_child(offset);
+        self.state = State::InFrame(length as usize);
+        Ok(())
+      }
+      ( _, zint::END_OF_ALL_STREAMS ) => self.fail(offset, unexpected_end_error(), report),
+      ( _, zint::END_OF_STREAM ) => {
+        self.state = State::BetweenStreams;
+        self.end_child(next, report)
+      }
+      ( _, length ) => {
+        self.state = State::InFrame(length as usize);
+        Ok(())
+      }
+    }
+  }
+
+  fn child_kind(&self, index: usize) -> ChildKind {
+    match ( self.btype, index ) {
+      ( Some(BottleType::File), _ ) if self.folder => ChildKind::Bottle,
+      ( Some(BottleType::File), index ) if self.crc_children == Some(index) => ChildKind::Keep,
+      ( Some(BottleType::Hashed), 0 ) => ChildKind::HashedBottle,
+      ( Some(BottleType::Hashed), 1 ) | ( Some(BottleType::Index), 0 ) => ChildKind::Keep,
+      _ => ChildKind::Data
+    }
+  }
+
+  fn start_child(&mut self, offset: u64) {
+    let index = self.child_offsets.len();
+    self.child_offsets.push(offset);
+    let kind = self.child_kind(index);
+    if kind == ChildKind::Bottle || kind == ChildKind::HashedBottle {
+      let mut path = self.path.clone();
+      path.push(index);
+      self.nested = Some(Box::new(Checker::new(path, false, self.limits)));
+    }
+  }
+
+  fn child_data(&mut self, data: &[u8], offset: u64, report: &mut ValidationReport) -> io::Result<()> {
+    let index = self.child_offsets.len() - 1;
+    match self.child_kind(index) {
+      ChildKind::Keep => {
+        let size = self.kept.len() as u64 + data.len() as u64;
+        if let Err(e) = self.limits.check_buffered(size) { return self.fail(offset, e, report) }
+        self.kept.extend_from_slice(data)
+      }
+      ChildKind::Data if self.crc_children.is_some_and(|n| index < n) => self.crc = crc32c(self.crc, data),
+      ChildKind::HashedBottle => {
+        if let Some(ref mut hasher) = self.hasher { hasher.update(data) }
+      }
+      _ => ()
+    }
+    if let Some(ref mut nested) = self.nested {
+      let n = nested.feed(data, offset, report)?;
+      if n < data.len() && nested.state == State::Done {
+        nested.fail(offset + n as u64, trailing_data_error(), report)?;
+      }
+    }
+    Ok(())
+  }
+
+  fn end_child(&mut self, offset: u64, report: &mut ValidationReport) -> io::Result<()> {
+    if let Some(mut nested) = self.nested.take() { nested.finish(offset, report)? }
+    let index = self.child_offsets.len() - 1;
+    match self.child_kind(index) {
+      ChildKind::HashedBottle => self.digest = self.hasher.take().map(|h| h.finish()),
+      // a signed digest can't be checked without the verifier.
+      ChildKind::Keep if self.btype == Some(BottleType::Hashed) && !self.signed => {
+        let matches = self.digest.as_ref().map(|d| d[..] == self.kept[..]).unwrap_or(true);
+        if !matches { report.add(self.child_offsets[index], &self.path, hash_mismatch_error())? }
+      }
+      ChildKind::Keep if self.btype == Some(BottleType::File) => {
+        let offset = self.child_offsets[index];
+        if self.kept.len() != 4 { return report.add(offset, &self.path, missing_checksum_error()) }
+        let expected = u32::from_be_bytes([ self.kept[0], self.kept[1], self.kept[2], self.kept[3] ]);
+        if expected != self.crc { report.add(offset, &self.path, checksum_mismatch_error(expected, self.crc))? }
+      }
+      _ => ()
+    }
+    Ok(())
+  }
+
+  fn end_bottle(&mut self, offset: u64, report: &mut ValidationReport) -> io::Result<()> {
+    let count = self.child_offsets.len() as u64;
+    if let Some(expected) = self.header.get_number(FIELD_STREAM_COUNT) {
+      if expected != count { report.add(offset, &self.path, stream_count_error(expected, count))? }
+    }
+    if self.btype == Some(BottleType::Hashed) && count < 2 {
+      report.add(offset, &self.path, missing_hash_stream_error())?;
+    }
+    if self.crc_children.is_some_and(|n| count <= n as u64) {
+      report.add(offset, &self.path, missing_checksum_error())?;
+    }
+    Ok(())
+  }
+
+  fn fail(&mut self, offset: u64, e: io::Error, report: &mut ValidationReport) -> io::Result<()> {
+    self.state = State::Failed;
+    self.nested = None;
+    report.add(offset, &self.path, e)
+  }
+}
+
+
+// ----- errors
+
+fn truncated_error() -> io::Error {
+  BottleError::TruncatedStream.into()
+}
+
+fn unexpected_end_error() -> io::Error {
+  BottleError::UnexpectedEnd.into()
+}
+
+fn trailing_data_error() -> io::Error {
+  BottleError::TrailingData.into()
+}
+
+fn interleaved_error() -> io::Error {
+  BottleError::Interleaved.into()
+}
+
+fn bad_index_error() -> io::Error {
+  BottleError::BadIndex.into()
+}
+
+fn hash_mismatch_error() -> io::Error {
+  BottleError::HashMismatch.into()
+}
+
+fn missing_hash_stream_error() -> io::Error {
+  BottleError::MissingHashStream.into()
+}
+
+fn missing_checksum_error() -> io::Error {
+  BottleError::MissingChecksum.into()
+}
+
+fn checksum_mismatch_error(expected: u32, found: u32) -> io::Error {
+  BottleError::ChecksumMismatch { expected, found }.into()
+}
+
+fn stream_count_error(expected: u64, found: u64) -> io::Error {
+  BottleError::StreamCountMismatch { expected, found }.into()
+}
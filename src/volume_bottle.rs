@@ -0,0 +1,273 @@
+use aes_gcm::aead::OsRng;
+use aes_gcm::aead::rand_core::RngCore;
+use bytes::Bytes;
+use futures::{Async, Future, Poll, Stream, future, stream};
+use futures::stream::Fuse;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+
+use bottle::{BottleType, encode_bottle_header, read_bottle};
+use bottle_header::{Header};
+use error::BottleError;
+use zint;
+
+const FIELD_VOLUME_INDEX: u8 = 0;
+const FIELD_VOLUME_SET: u8 = 1;
+
+// room left at the end of each volume for the closing markers, and in the
+// last one, the total size: END_OF_STREAM, a frame with up to 8 bytes of
+// packed int, END_OF_STREAM, END_OF_ALL_STREAMS.
+const TRAILER_SIZE: usize = 12;
+const MAX_PREFIX_SIZE: usize = 4;
+// a volume has to hold its header and at least a little data.
+pub const MIN_VOLUME_SIZE: usize = 64;
+
+/*
+ * Split a bottle (or any byte stream) into volumes of at most
+ * `volume_size` bytes each. Each volume is a small bottle of its own: the
+ * header has its index and a random id shared by the whole set, and its
+ * one child stream is the next piece of data. The last volume has a second
+ * child stream with the total size, so `join_volumes` can tell that
+ * nothing's missing.
+ *
+ * Volumes share the source stream, so each has to be read to the end
+ * before asking for the next one.
+ */
+pub fn split_bottle<S>(s: S, volume_size: usize) -> io::Result<Volumes<S>>
+  where S: Stream<Item = Vec<Bytes>, Error = io::Error>
+{
+  if volume_size < MIN_VOLUME_SIZE { return Err(volume_too_small_error(volume_size)) }
+  let state = SplitState {
+    stream: s.fuse(),
+    leftover: VecDeque::new(),
+    set_id: OsRng.next_u64(),
+    volume_size,
+    volume: 0,
+    used: 0,
+    total: 0,
+    mode: SplitMode::Start
+  };
+  Ok(Volumes { state: Rc::new(RefCell::new(state)), issued: 0 })
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SplitMode {
+  // about to write a volume header
+  Start,
+  // writing data into the current volume
+  Body,
+  // wrote the last volume
+  Done
+}
+
+struct SplitState<S> where S: Stream<Item = Vec<Bytes>, Error = io::Error> {
+  stream: Fuse<S>,
+  // source buffers that haven't been written yet
+  leftover: VecDeque<Bytes>,
+  set_id: u64,
+  volume_size: usize,
+  // the volume being written, and how much of it is used
+  volume: usize,
+  used: usize,
+  total: u64,
+  mode: SplitMode
+}
+
+impl<S> SplitState<S> where S: Stream<Item = Vec<Bytes>, Error = io::Error> {
+  fn poll_volume(&mut self) -> Poll<Option<Vec<Bytes>>, io::Error> {
+    loop {
+      match self.mode {
+        SplitMode::Done => return Ok(Async::Ready(None)),
+        SplitMode::Start => {
+          let mut header = Header::new();
+          header.add_number(FIELD_VOLUME_INDEX, self.volume as u64);
+          header.add_number(FIELD_VOLUME_SET, self.set_id);
+          let buffer = encode_bottle_header(BottleType::Volume, &header)?;
+          self.used = buffer.len();
+          self.mode = SplitMode::Body;
+          return Ok(Async::Ready(Some(vec![ Bytes::from(buffer) ])));
+        }
+        SplitMode::Body => {
+          let room = cmp::min(
+            self.volume_size.saturating_sub(self.used + MAX_PREFIX_SIZE + TRAILER_SIZE),
+            zint::MAX_LENGTH as usize
+          );
+          if room == 0 {
+            // full: close this volume, and the next poll starts another.
+            self.volume += 1;
+            self.mode = SplitMode::Start;
+            return Ok(Async::Ready(Some(vec![ end_of_stream(), end_of_all_streams() ])));
+          }
+          if self.leftover.is_empty() {
+            match try_ready!(self.stream.poll()) {
+              Some(buffers) => {
+                self.leftover.extend(buffers.into_iter().filter(|b| !b.is_empty()));
+                continue;
+              }
+              None => {
+                self.mode = SplitMode::Done;
+                let total = zint::encode_packed_int_bytes(self.total);
+                return Ok(Async::Ready(Some(vec![
+                  end_of_stream(), zint::encode_length_bytes(total.len() as u32), total, end_of_stream(), end_of_all_streams()
+                ])));
+              }
+            }
+          }
+
+          let mut b = self.leftover.pop_front().unwrap();
+          if b.len() > room { self.leftover.push_front(b.split_off(room)) }
+          let prefix = zint::encode_length_bytes(b.len() as u32);
+          self.used += prefix.len() + b.len();
+          self.total += b.len() as u64;
+          return Ok(Async::Ready(Some(vec![ prefix, b ])));
+        }
+      }
+    }
+  }
+}
+
+/// Stream of volumes, from `split_bottle`.
+#[must_use = "streams do nothing unless polled"]
+pub struct Volumes<S> where S: Stream<Item = Vec<Bytes>, Error = io::Error> {
+  state: Rc<RefCell<SplitState<S>>>,
+  // how many volumes have been handed out
+  issued: usize
+}
+
+impl<S> Stream for Volumes<S> where S: Stream<Item = Vec<Bytes>, Error = io::Error> {
+  type Item = VolumeStream<S>;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    let state = self.state.borrow();
+    if state.mode == SplitMode::Done { return Ok(Async::Ready(None)) }
+    if self.issued > state.volume { return Err(unfinished_volume_error()) }
+    self.issued += 1;
+    Ok(Async::Ready(Some(VolumeStream { index: state.volume, state: self.state.clone() })))
+  }
+}
+
+/// One volume, from `split_bottle`.
+#[must_use = "streams do nothing unless polled"]
+pub struct VolumeStream<S> where S: Stream<Item = Vec<Bytes>, Error = io::Error> {
+  index: usize,
+  state: Rc<RefCell<SplitState<S>>>
+}
+
+impl<S> Stream for VolumeStream<S> where S: Stream<Item = Vec<Bytes>, Error = io::Error> {
+  type Item = Vec<Bytes>;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    let mut state = self.state.borrow_mut();
+    if state.volume != self.index { return Ok(Async::Ready(None)) }
+    state.poll_volume()
+  }
+}
+
+fn end_of_stream() -> Bytes {
+  zint::encode_length_bytes(zint::END_OF_STREAM)
+}
+
+fn end_of_all_streams() -> Bytes {
+  zint::encode_length_bytes(zint::END_OF_ALL_STREAMS)
+}
+
+
+// ----- joining
+
+struct JoinState {
+  set_id: Option<u64>,
+  total: u64,
+  finished: bool
+}
+
+/// Reassemble the volumes made by `split_bottle`, which must be given in
+/// order. Fails if a volume is missing, out of order, or from another set.
+pub fn join_volumes<I, S>(volumes: I) -> impl Stream<Item = Bytes, Error = io::Error>
+  where
+    I: IntoIterator<Item = S>,
+    S: Stream<Item = Bytes, Error = io::Error> + 'static
+{
+  let state = Rc::new(RefCell::new(JoinState { set_id: None, total: 0, finished: false }));
+  let final_state = state.clone();
+  let volumes: Vec<Box<dyn Stream<Item = Bytes, Error = io::Error>>> = volumes.into_iter().enumerate().map(|( index, s )| {
+    Box::new(read_volume(s, index, state.clone())) as Box<dyn Stream<Item = Bytes, Error = io::Error>>
+  }).collect();
+  let done = future::lazy(move || {
+    if final_state.borrow().finished { Ok(()) } else { Err(missing_volume_error()) }
+  });
+  stream::iter_ok::<_, io::Error>(volumes).flatten().chain(done.into_stream().filter_map(|()| None))
+}
+
+fn read_volume<S>(s: S, index: usize, state: Rc<RefCell<JoinState>>) -> impl Stream<Item = Bytes, Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  read_bottle(s).and_then(move |( btype, header, children )| {
+    if btype != BottleType::Volume { return Err(BottleError::WrongType { expected: BottleType::Volume, found: btype }.into()) }
+    let found = header.get_number(FIELD_VOLUME_INDEX).unwrap_or(0);
+    if found != index as u64 { return Err(out_of_order_error(index, found)) }
+    let set_id = header.get_number(FIELD_VOLUME_SET).unwrap_or(0);
+    {
+      let mut state = state.borrow_mut();
+      if state.finished { return Err(extra_volume_error()) }
+      if *state.set_id.get_or_insert(set_id) != set_id { return Err(wrong_set_error()) }
+    }
+
+    Ok(children.into_future().map_err(|( e, _ )| e).map(move |( data, children )| {
+      let counter = state.clone();
+      let data = stream::iter_ok::<_, io::Error>(data).flatten().map(move |b: Bytes| {
+        counter.borrow_mut().total += b.len() as u64;
+        b
+      });
+      // if there's a second child, this was the last volume.
+      let trailer = children.into_future().map_err(|( e, _ )| e).and_then(|( total, _ )| match total {
+        Some(total) => future::Either::A(total.concat2().map(Some)),
+        None => future::Either::B(future::ok(None))
+      }).and_then(move |total| {
+        if let Some(total) = total {
+          let expected = zint::decode_packed_int(&mut &total[..], total.len())?;
+          let mut state = state.borrow_mut();
+          if expected != state.total { return Err(size_mismatch_error(expected, state.total)) }
+          state.finished = true;
+        }
+        Ok(())
+      });
+      data.chain(trailer.into_stream().filter_map(|()| None))
+    }).flatten_stream())
+  }).flatten_stream()
+}
+
+
+// ----- errors
+
+fn volume_too_small_error(size: usize) -> io::Error {
+  BottleError::VolumeTooSmall(size).into()
+}
+
+fn unfinished_volume_error() -> io::Error {
+  BottleError::UnfinishedVolume.into()
+}
+
+fn out_of_order_error(expected: usize, found: u64) -> io::Error {
+  BottleError::VolumeOutOfOrder { expected: expected as u64, found }.into()
+}
+
+fn wrong_set_error() -> io::Error {
+  BottleError::WrongVolumeSet.into()
+}
+
+fn extra_volume_error() -> io::Error {
+  BottleError::ExtraVolume.into()
+}
+
+fn missing_volume_error() -> io::Error {
+  BottleError::MissingVolume.into()
+}
+
+fn size_mismatch_error(expected: u64, got: u64) -> io::Error {
+  BottleError::VolumeSizeMismatch { expected, got }.into()
+}
@@ -0,0 +1,180 @@
+use bytes::Bytes;
+use futures::{Future, Stream, future};
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+use archive::{ByteStream, SharedStream};
+use bottle::{BottleType, DecodeLimits, ReadOptions, peek_bottle_type, read_bottle_with_options};
+use bottle_header::{Header};
+use compressed_bottle::decompress_bottle_with_limits;
+use dedup_bottle::reassemble_bottle_with_limits;
+use encrypted_bottle::{EncryptionInfo, KeySource, decrypt_bottle_with_limits};
+use file_bottle::FIELD_FOLDER;
+use parity_bottle::repair_bottle_with_limits;
+
+type KeyResolver = Rc<dyn Fn(&EncryptionInfo) -> io::Result<KeySource>>;
+type WalkFuture = Box<dyn Future<Item = (), Error = io::Error>>;
+
+/// Callbacks from `walk_bottles`, in the order the bottles are found.
+/// `depth` is 1 for the outermost bottle, and one more for each bottle
+/// it's inside of. An error from any of them stops the walk.
+pub trait BottleVisitor {
+  /// A bottle begins. For a compressed, dedup, parity, or encrypted layer,
+  /// the next thing visited is the bottle inside it.
+  fn enter(&mut self, btype: BottleType, header: &Header, depth: usize) -> io::Result<()>;
+
+  /// Part of a child stream that isn't a nested bottle (a file's contents,
+  /// or a digest), with the framing removed. `index` is which child stream.
+  fn data(&mut self, _depth: usize, _index: usize, _data: Bytes) -> io::Result<()> {
+    Ok(())
+  }
+
+  /// A bottle is over, along with everything inside it.
+  fn leave(&mut self, btype: BottleType, header: &Header, depth: usize) -> io::Result<()>;
+}
+
+/*
+ * Walks a bottle and the bottles nested inside it, depth first: layers are
+ * decoded (decrypted only if there's a key), and the bottles inside folders
+ * and hashed bottles are walked in order. Hashes are reported as data, not
+ * checked.
+ */
+#[derive(Clone, Default)]
+pub struct BottleWalker {
+  key_resolver: Option<KeyResolver>,
+  limits: DecodeLimits,
+  max_depth: Option<usize>
+}
+
+impl BottleWalker {
+  pub fn new() -> BottleWalker {
+    BottleWalker::default()
+  }
+
+  /// Called for each encrypted layer, to find the key. Without one, an
+  /// encrypted bottle's children are visited as data.
+  pub fn with_key<F>(mut self, resolver: F) -> BottleWalker
+    where F: Fn(&EncryptionInfo) -> io::Result<KeySource> + 'static
+  {
+    self.key_resolver = Some(Rc::new(resolver));
+    self
+  }
+
+  /// Fail on bottles that nest (or allocate) more than this.
+  pub fn with_limits(mut self, limits: DecodeLimits) -> BottleWalker {
+    self.limits = limits;
+    self
+  }
+
+  /// Don't open anything inside bottles this deep: their children are
+  /// visited as data, even if they're bottles.
+  pub fn max_depth(mut self, depth: usize) -> BottleWalker {
+    self.max_depth = Some(depth);
+    self
+  }
+
+  /// Walk every bottle in `s`, and hand back the visitor when it's done.
+  pub fn walk<S, V>(self, s: S, visitor: V) -> impl Future<Item = V, Error = io::Error>
+    where
+      S: Stream<Item = Bytes, Error = io::Error> + 'static,
+      V: BottleVisitor + 'static
+  {
+    let visitor = Shared(Rc::new(RefCell::new(Some(visitor))));
+    walk_bottle(Rc::new(self), visitor.clone(), Box::new(s), 1).map(move |_| {
+      visitor.0.borrow_mut().take().unwrap()
+    })
+  }
+}
+
+/// Walk a bottle and everything nested inside it (see `BottleWalker`).
+pub fn walk_bottles<S, V>(s: S, visitor: V) -> impl Future<Item = V, Error = io::Error>
+  where
+    S: Stream<Item = Bytes, Error = io::Error> + 'static,
+    V: BottleVisitor + 'static
+{
+  BottleWalker::new().walk(s, visitor)
+}
+
+// the visitor, shared by every level of the walk until it's handed back.
+struct Shared<V>(Rc<RefCell<Option<V>>>);
+
+impl<V> Clone for Shared<V> {
+  fn clone(&self) -> Shared<V> {
+    Shared(self.0.clone())
+  }
+}
+
+impl<V: BottleVisitor> Shared<V> {
+  fn with<T, F: FnOnce(&mut V) -> T>(&self, f: F) -> T {
+    f(self.0.borrow_mut().as_mut().unwrap())
+  }
+}
+
+fn walk_bottle<V: BottleVisitor + 'static>(walker: Rc<BottleWalker>, visitor: Shared<V>, s: ByteStream, depth: usize) -> WalkFuture {
+  if let Err(e) = walker.limits.check_depth(depth) { return Box::new(future::err(e)) }
+  if walker.max_depth.is_some_and(|max| depth >= max) { return walk_children(walker, visitor, s, depth, false) }
+  let limits = walker.limits;
+  Box::new(peek_bottle_type(s).and_then(move |( btype, s )| -> WalkFuture {
+    match btype {
+      BottleType::Compressed => walk_layer(walker, visitor, btype, decompress_bottle_with_limits(s, limits), depth),
+      BottleType::Dedup => walk_layer(walker, visitor, btype, reassemble_bottle_with_limits(s, limits), depth),
+      BottleType::Parity => walk_layer(walker, visitor, btype, repair_bottle_with_limits(s, limits), depth),
+      BottleType::Encrypted if walker.key_resolver.is_some() => {
+        let resolver = walker.key_resolver.clone().unwrap();
+        let decrypted = decrypt_bottle_with_limits(s, move |info| resolver(info), limits);
+        walk_layer(walker, visitor, btype, decrypted, depth)
+      }
+      BottleType::Encrypted => walk_children(walker, visitor, Box::new(s), depth, false),
+      _ => walk_children(walker, visitor, Box::new(s), depth, true)
+    }
+  }))
+}
+
+// a layer holds one bottle. once that's over, the rest of the layer is
+// drained, so it can check itself (a digest, or the encryption tag).
+fn walk_layer<V, F, S>(walker: Rc<BottleWalker>, visitor: Shared<V>, btype: BottleType, f: F, depth: usize) -> WalkFuture
+  where
+    V: BottleVisitor + 'static,
+    F: Future<Item = ( Header, S ), Error = io::Error> + 'static,
+    S: Stream<Item = Bytes, Error = io::Error> + 'static
+{
+  Box::new(f.and_then(move |( header, inner )| {
+    visitor.with(|v| v.enter(btype, &header, depth))?;
+    let inner: Rc<RefCell<Option<ByteStream>>> = Rc::new(RefCell::new(Some(Box::new(inner))));
+    let rest = SharedStream(inner.clone());
+    Ok(walk_bottle(walker, visitor.clone(), Box::new(SharedStream(inner)), depth + 1).and_then(move |_| {
+      rest.for_each(|_| Ok(()))
+    }).and_then(move |_| visitor.with(|v| v.leave(btype, &header, depth))))
+  }).flatten())
+}
+
+// each child is walked if it's a nested bottle (and `open` is set), or
+// visited as data if not.
+fn walk_children<V: BottleVisitor + 'static>(walker: Rc<BottleWalker>, visitor: Shared<V>, s: ByteStream, depth: usize, open: bool) -> WalkFuture {
+  let options = ReadOptions { limits: walker.limits, ..ReadOptions::default() };
+  Box::new(read_bottle_with_options(s, options).and_then(move |( btype, header, children )| {
+    visitor.with(|v| v.enter(btype, &header, depth))?;
+    let nested = if open { nested_children(btype, &header) } else { 0 };
+    let child_visitor = visitor.clone();
+    let walked = children.fold(0, move |index, child| -> Box<dyn Future<Item = usize, Error = io::Error>> {
+      let visitor = child_visitor.clone();
+      let f: WalkFuture = if index < nested {
+        walk_bottle(walker.clone(), visitor, Box::new(child), depth + 1)
+      } else {
+        Box::new(child.for_each(move |data| visitor.with(|v| v.data(depth, index, data))))
+      };
+      Box::new(f.map(move |_| index + 1))
+    });
+    Ok(walked.and_then(move |_| visitor.with(|v| v.leave(btype, &header, depth))))
+  }).flatten())
+}
+
+// how many of a bottle's children (from the start) are bottles.
+fn nested_children(btype: BottleType, header: &Header) -> usize {
+  match btype {
+    BottleType::File if header.get_bool(FIELD_FOLDER) => usize::MAX,
+    BottleType::Hashed => 1,
+    _ => 0
+  }
+}
@@ -1,4 +1,11 @@
+use bytes::Bytes;
+use futures::{Future, Stream};
 use std::io;
+use std::ops::Deref;
+
+use error::BottleError;
+use stream_helpers::flatten_bytes;
+use stream_reader::StreamReader;
 
 /*
  * methods for encoding ints as:
@@ -29,52 +36,76 @@ pub fn encode_packed_int(number: u64) -> Vec<u8> {
   cursor.into_inner()
 }
 
+pub fn encode_packed_int_bytes(number: u64) -> Bytes {
+  Bytes::from(encode_packed_int(number))
+}
+
 pub fn read_packed_int<R: io::Read>(reader: &mut R) -> io::Result<u64> {
   let mut buffer: [u8; 1] = [ 0 ];
   let mut rv: u64 = 0;
   let mut shift: u8 = 0;
 
-  while reader.read(&mut buffer)? > 0 && shift < 64 {
-    rv += (buffer[0] as u64) << shift;
-    shift += 8;
+  // stop at 8 bytes without reading a 9th, and retry interrupted reads
+  // instead of treating them as the end.
+  while shift < 64 {
+    match reader.read(&mut buffer) {
+      Ok(0) => break,
+      Ok(_) => {
+        rv += (buffer[0] as u64) << shift;
+        shift += 8;
+      }
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+      Err(e) => return Err(e)
+    }
   }
   Ok(rv)
 }
 
-pub fn decode_packed_int(buffer: &[u8]) -> io::Result<u64> {
-  read_packed_int(&mut io::Cursor::new(buffer))
+/*
+ * Read a packed int whose length (in bytes) is known out-of-band, as it
+ * always is in the format. Reads exactly `len` bytes, so it can be used on
+ * a reader with more data after the int. More than 8 bytes can't fit in a
+ * u64, so that's an error instead of wrapping around.
+ */
+pub fn decode_packed_int<R: io::Read>(reader: &mut R, len: usize) -> io::Result<u64> {
+  if len > 8 {
+    return Err(BottleError::PackedIntTooLong(len).into());
+  }
+  let mut buffer: [u8; 8] = [ 0; 8 ];
+  reader.read_exact(&mut buffer[0..len])?;
+  Ok(buffer[0..len].iter().rev().fold(0, |rv, &b| (rv << 8) | (b as u64)))
 }
 
 
 /*
  * 00000000 - end of stream
  * 0xxxxxxx - 1 thru 2^7 = 128
- * 10xxxxxx - (+ 1 byte, LSB) = 2^14 = 8K
- * 110xxxxx - (+ 2 byte, LSB) = 2^21 = 2M
- * 1110xxxx - (+ 3 byte, LSB) = 2^28 = 128M
+ * 10xxxxxx - (+ 1 byte, LSB) = 2^13 = 8K (6 + 8 bits, top bit unused)
+ * 110xxxxx - (+ 2 byte, LSB) = 2^21 = 2M (5 + 16 bits)
+ * 1110xxxx - (+ 3 byte, LSB) = 2^28 = 128M (4 + 24 bits)
  * 1111xxxx - 2^(7+x) = any power-of-2 block size from 128 to 2^21 = 2M
  * 11111111 - end of all streams
  */
 pub fn write_length<W: io::Write>(writer: &mut W, number: u32) -> io::Result<()> {
   match number {
     END_OF_ALL_STREAMS => {
-      writer.write(&[ 0xff ])?;
+      writer.write_all(&[ 0xff ])?;
       Ok(())
     }
     n if n < 128 => {
-      writer.write(&[ n as u8 ])?;
+      writer.write_all(&[ n as u8 ])?;
       Ok(())
     }
-    n if n <= (1 << 22) && (n & (n - 1) == 0) => {
-      writer.write(&[ (0xf0 + log_base2(n) - 7) as u8 ])?;
+    n if n <= (1 << 21) && (n & (n - 1) == 0) => {
+      writer.write_all(&[ (0xf0 + log_base2(n) - 7) as u8 ])?;
       Ok(())
     }
     n if n < 8192 => {
-      writer.write(&[ 0x80 + (n & 0x3f) as u8, ((n >> 6) & 0xff) as u8 ])?;
+      writer.write_all(&[ 0x80 + (n & 0x3f) as u8, ((n >> 6) & 0xff) as u8 ])?;
       Ok(())
     }
     n if n < (1 << 21) => {
-      writer.write(&[
+      writer.write_all(&[
         0xc0 + (n & 0x1f) as u8,
         ((n >> 5) & 0xff) as u8,
         ((n >> 13) & 0xff) as u8
@@ -82,7 +113,7 @@ pub fn write_length<W: io::Write>(writer: &mut W, number: u32) -> io::Result<()>
       Ok(())
     }
     n if n < (1 << 28) => {
-      writer.write(&[
+      writer.write_all(&[
         0xe0 + (n & 0xf) as u8,
         ((n >> 4) & 0xff) as u8,
         ((n >> 12) & 0xff) as u8,
@@ -90,7 +121,7 @@ pub fn write_length<W: io::Write>(writer: &mut W, number: u32) -> io::Result<()>
       ])?;
       Ok(())
     }
-    _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "😝"))
+    n => Err(BottleError::FrameOverflow(n as u64).into())
   }
 }
 
@@ -101,6 +132,42 @@ pub fn encode_length(number: u32) -> Vec<u8> {
   cursor.into_inner()
 }
 
+// a length is never more than 4 bytes, so `Bytes` keeps it inline, and
+// framing a chunk doesn't cost a heap allocation. callers only frame
+// chunks they've already cut to `MAX_LENGTH`.
+pub(crate) fn encode_length_bytes(number: u32) -> Bytes {
+  debug_assert!(number <= MAX_LENGTH || number == END_OF_ALL_STREAMS, "length {} is too long for a frame", number);
+  Bytes::from(&encode_length_to_bytes(number as u64).unwrap()[..])
+}
+
+/// An encoded length, kept on the stack: at most 4 bytes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LengthBytes {
+  buffer: [u8; 4],
+  len: usize
+}
+
+impl Deref for LengthBytes {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    &self.buffer[0 .. self.len]
+  }
+}
+
+/// Encode a length without allocating. Fails with `FrameOverflow` if it's
+/// longer than `MAX_LENGTH` (and isn't `END_OF_ALL_STREAMS`).
+pub fn encode_length_to_bytes(number: u64) -> io::Result<LengthBytes> {
+  if number > u32::MAX as u64 { return Err(BottleError::FrameOverflow(number).into()) }
+  let mut buffer = [ 0u8; 4 ];
+  let len = {
+    let mut cursor = io::Cursor::new(&mut buffer[..]);
+    write_length(&mut cursor, number as u32)?;
+    cursor.position() as usize
+  };
+  Ok(LengthBytes { buffer, len })
+}
+
 /*
  * Determine how many bytes will be needed to get the full length.
  */
@@ -115,8 +182,7 @@ pub fn length_of_length(byte: u8) -> usize {
   }
 }
 
-pub const END_OF_STREAM: u32 = 0;
-pub const END_OF_ALL_STREAMS: u32 = 0xffffffff;
+pub use spec::{END_OF_ALL_STREAMS, END_OF_STREAM, MAX_LENGTH};
 
 /*
  * Returns the length, or one of the two constants above.
@@ -125,10 +191,10 @@ pub const END_OF_ALL_STREAMS: u32 = 0xffffffff;
  */
 pub fn decode_length<R: io::Read>(reader: &mut R) -> io::Result<u32> {
   let mut buffer: [u8; 4] = [ 0; 4 ];
-  reader.read_exact(&mut buffer[0..1])?;
+  read_length_bytes(reader, &mut buffer[0..1], 1)?;
   let total_len = length_of_length(buffer[0]);
   if total_len > 1 {
-    reader.read_exact(&mut buffer[1..total_len])?;
+    read_length_bytes(reader, &mut buffer[1..total_len], total_len)?;
   }
 
   if buffer[0] == 0xff {
@@ -151,32 +217,42 @@ pub fn decode_length<R: io::Read>(reader: &mut R) -> io::Result<u32> {
   }
 }
 
-// export function readLength(stream) {
-//   return stream.readPromise(1).then(prefix => {
-//     if (prefix == null || prefix[0] == 0) return null;
-//     if ((prefix[0] & 0x80) == 0) return prefix[0];
-//     if ((prefix[0] & 0xf0) == 0xf0) return Math.pow(2, 7 + (prefix[0] & 0xf));
-//     if ((prefix[0] & 0xc0) == 0x80) {
-//       return stream.readPromise(1).then(data => {
-//         if (data == null) return null;
-//         return (prefix[0] & 0x3f) + (data[0] << 6);
-//       });
-//     }
-//     if ((prefix[0] & 0xe0) == 0xc0) {
-//       return stream.readPromise(2).then(data => {
-//         if (data == null) return null;
-//         return (prefix[0] & 0x3f) + (data[0] << 5) + (data[1] << 13);
-//       });
-//     }
-//     if ((prefix[0] & 0xf0) == 0xe0) {
-//       return stream.readPromise(3).then(data => {
-//         if (data == null) return null;
-//         return (prefix[0] & 0xf) + (data[0] << 4) + (data[1] << 12) + (data[2] << 20);
-//       });
-//     }
-//     return null;
-//   });
-// }
+/*
+ * Read a length from the front of a byte stream, returning the length (or
+ * one of the two constants above) and the rest of the stream.
+ */
+pub fn read_length<S>(s: S) -> impl Future<Item = (u32, impl Stream<Item = Bytes, Error = io::Error>), Error = io::Error>
+  where S: Stream<Item = Bytes, Error = io::Error>
+{
+  StreamReader::read_at_most(s, 1).and_then(|( frame, s )| {
+    let first = flatten_bytes(frame.vec);
+    let needed = if first.is_empty() { 0 } else { length_of_length(first[0]) - 1 };
+    StreamReader::read_at_most(s, needed).and_then(move |( frame, s )| {
+      let mut buffer = first.to_vec();
+      buffer.extend_from_slice(&flatten_bytes(frame.vec));
+      // if we came up short, this will explain how short.
+      decode_length(&mut io::Cursor::new(buffer)).map(|length| ( length, s ))
+    })
+  })
+}
+
+// like `read_exact`, but if the stream ends early, say how much of the
+// length prefix we got, so a truncated stream is easy to diagnose.
+fn read_length_bytes<R: io::Read>(reader: &mut R, buffer: &mut [u8], total_len: usize) -> io::Result<()> {
+  let mut n = 0;
+  while n < buffer.len() {
+    match reader.read(&mut buffer[n..]) {
+      Ok(0) => {
+        let got = total_len - buffer.len() + n;
+        return Err(BottleError::TruncatedLength { expected: total_len, got }.into());
+      }
+      Ok(count) => n += count,
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+      Err(e) => return Err(e)
+    }
+  }
+  Ok(())
+}
 
 // hacker's delight! (only works on exact powers of 2)
 fn log_base2(number: u32) -> u32 {
@@ -191,7 +267,9 @@ fn log_base2(number: u32) -> u32 {
   x
 }
 
-pub fn bytes_needed(mut number: u64) -> usize {
+// how many bytes `write_packed_int` will use, so a header can be sized
+// before it's written.
+pub fn packed_int_bytes(mut number: u64) -> usize {
   let mut count = 1;//if (number & (number - 1)) == 0 { 0 } else { 1 };
   let mut found = if (number & 0xffffffff00000000) == 0 { 0 } else { 4 };
   count += found;
@@ -203,3 +281,8 @@ pub fn bytes_needed(mut number: u64) -> usize {
   count += found;
   count
 }
+
+// the original name for `packed_int_bytes`.
+pub fn bytes_needed(number: u64) -> usize {
+  packed_int_bytes(number)
+}
@@ -0,0 +1,22 @@
+// helpers shared by the integration tests. not every test uses all of them.
+#![allow(dead_code)]
+
+use bytes::Bytes;
+use futures::{Future, Stream};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+// an empty folder, unique to this test run.
+pub fn temp_dir(name: &str) -> PathBuf {
+  let path = env::temp_dir().join(format!("lib4bottle-{}-{}", name, ::std::process::id()));
+  let _ = fs::remove_dir_all(&path);
+  fs::create_dir_all(&path).unwrap();
+  path
+}
+
+// run a bottle stream to the end, and collect its bytes.
+pub fn drain<S: Stream<Item = Vec<Bytes>, Error = io::Error>>(s: S) -> Vec<u8> {
+  s.collect().wait().unwrap().into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect()
+}
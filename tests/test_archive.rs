@@ -0,0 +1,274 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use common::temp_dir;
+  use futures::{Future, Stream, future};
+  use lib4bottle::archive::{ArchiveReader, ArchiveWriter, list_bottle};
+  use lib4bottle::bottle::{BottleType, DecodeLimits, bottle_from_slice};
+  use lib4bottle::compressed_bottle::{CompressionType, decompress_bottle};
+  use lib4bottle::encrypted_bottle::{KeySource, decrypt_bottle};
+  use lib4bottle::error::{BottleError, DecodeLimit};
+  use lib4bottle::file_bottle::{Deterministic, ExtractOptions, FileMetadata, extract_bottle};
+  use lib4bottle::hash_bottle::{verify_hash_bottle_signed};
+  use lib4bottle::hashing::{HashAlgorithm};
+  use lib4bottle::stream_helpers::{make_stream};
+  use std::fs;
+  use std::io;
+  use std::path::PathBuf;
+
+  fn drain(writer: ArchiveWriter) -> Vec<u8> {
+    ::common::drain(writer.into_stream().unwrap())
+  }
+
+  #[test]
+  fn archive_with_every_layer() {
+    let source = temp_dir("archive-source");
+    fs::write(source.join("a.txt"), "hello sailor!".repeat(100)).unwrap();
+    let data = drain(
+      ArchiveWriter::new()
+        .add_path(source.join("a.txt"))
+        .compress(CompressionType::Snappy)
+        .encrypt(KeySource::Passphrase("hunter2".to_string()), vec![ "alice".to_string() ])
+        .sign("alice", future::ok)
+    );
+
+    let target = temp_dir("archive-target");
+    let s = make_stream(vec![ Bytes::from(data) ]);
+    let paths = decrypt_bottle(s, |_| Ok(KeySource::Passphrase("hunter2".to_string()))).and_then(|( _, s )| {
+      decompress_bottle(s)
+    }).and_then(|( _, s )| {
+      verify_hash_bottle_signed(s, |signed_by, blob| {
+        assert_eq!(signed_by, "alice");
+        future::ok::<_, io::Error>(blob)
+      })
+    }).and_then({
+      let target = target.clone();
+      move |( _, s )| extract_bottle(s, target, ExtractOptions::default())
+    }).wait().unwrap();
+    assert_eq!(paths, vec![ target.join("a.txt") ]);
+    assert_eq!(fs::read(target.join("a.txt")).unwrap(), "hello sailor!".repeat(100).into_bytes());
+    fs::remove_dir_all(&source).unwrap();
+    fs::remove_dir_all(&target).unwrap();
+  }
+
+  #[test]
+  fn archive_several_paths() {
+    let source = temp_dir("archive-several");
+    fs::write(source.join("a.txt"), "ay").unwrap();
+    fs::create_dir(source.join("b")).unwrap();
+    let data = drain(ArchiveWriter::new().add_path(source.join("a.txt")).add_path(source.join("b")).folder_name("stuff"));
+    fs::remove_dir_all(&source).unwrap();
+
+    let ( btype, header, streams ) = bottle_from_slice(&data).unwrap();
+    assert_eq!(btype, BottleType::File);
+    let metadata = FileMetadata::from_header(&header).unwrap();
+    assert_eq!(metadata.filename, "stuff");
+    assert!(metadata.folder);
+    assert_eq!(streams.len(), 2);
+  }
+
+  #[test]
+  fn archive_layer_order() {
+    let source = temp_dir("archive-layers");
+    fs::write(source.join("a.txt"), "ay").unwrap();
+    let data = drain(ArchiveWriter::new().add_path(source.join("a.txt")).hash(HashAlgorithm::Sha256).compress(CompressionType::Lzma2));
+    fs::remove_dir_all(&source).unwrap();
+    assert_eq!(bottle_from_slice(&data).unwrap().0, BottleType::Compressed);
+  }
+
+  #[test]
+  fn archive_nothing() {
+    assert_eq!(ArchiveWriter::new().into_stream().err().unwrap().to_string(), "Nothing to archive");
+    assert!(ArchiveWriter::new().add_path("/does/not/exist").into_stream().is_err());
+  }
+
+  fn read_entries(reader: ArchiveReader, data: Vec<u8>) -> io::Result<Vec<( String, bool, Vec<u8> )>> {
+    let s = make_stream(data.chunks(50).map(Bytes::from).collect());
+    reader.entries(s).and_then(|entry| {
+      let path = entry.path.to_string_lossy().to_string();
+      let folder = entry.metadata.folder;
+      entry.content.collect().map(move |chunks| ( path, folder, chunks.concat() ))
+    }).collect().wait()
+  }
+
+  fn source_tree(name: &str) -> PathBuf {
+    let source = temp_dir(name);
+    let root = source.join("stuff");
+    fs::create_dir_all(root.join("inner")).unwrap();
+    fs::write(root.join("a.txt"), "ay").unwrap();
+    fs::write(root.join("inner").join("c.txt"), "sea".repeat(1000)).unwrap();
+    source
+  }
+
+  #[test]
+  fn read_archive_with_every_layer() {
+    let source = source_tree("reader-layers");
+    let data = drain(
+      ArchiveWriter::new()
+        .add_path(source.join("stuff"))
+        .compress(CompressionType::Lzma2)
+        .encrypt(KeySource::Raw(vec![ 9; 32 ]), vec![])
+        .sign("alice", future::ok)
+    );
+    fs::remove_dir_all(&source).unwrap();
+
+    let reader = ArchiveReader::new().with_key(|_| Ok(KeySource::Raw(vec![ 9; 32 ]))).with_verifier(|_, blob| future::ok(blob));
+    assert_eq!(read_entries(reader, data).unwrap(), vec![
+      ( "stuff".to_string(), true, vec![] ),
+      ( "stuff/a.txt".to_string(), false, b"ay".to_vec() ),
+      ( "stuff/inner".to_string(), true, vec![] ),
+      ( "stuff/inner/c.txt".to_string(), false, "sea".repeat(1000).into_bytes() )
+    ]);
+  }
+
+  #[test]
+  fn read_deduped_archive() {
+    let source = source_tree("reader-dedup");
+    fs::write(source.join("stuff").join("inner").join("d.txt"), "sea".repeat(1000)).unwrap();
+    let data = drain(ArchiveWriter::new().add_path(source.join("stuff")).hash(HashAlgorithm::Sha256).dedup().compress(CompressionType::Snappy));
+    fs::remove_dir_all(&source).unwrap();
+    assert_eq!(read_entries(ArchiveReader::new(), data.clone()).unwrap().len(), 5);
+    let entries = list_bottle(make_stream(vec![ Bytes::from(data) ])).collect().wait().unwrap();
+    assert_eq!(entries[0].layers, vec![ BottleType::Compressed, BottleType::Dedup, BottleType::Hashed, BottleType::File ]);
+  }
+
+  #[test]
+  fn read_tampered_archive() {
+    let source = source_tree("reader-tampered");
+    let mut data = drain(ArchiveWriter::new().add_path(source.join("stuff")).hash(HashAlgorithm::Sha256));
+    fs::remove_dir_all(&source).unwrap();
+    // a byte of "seaseasea...".
+    let n = data.len() - 1000;
+    data[n] ^= 1;
+    let e = read_entries(ArchiveReader::new(), data).err().unwrap();
+    assert_eq!(e.to_string(), "Hash mismatch");
+  }
+
+  #[test]
+  fn read_archive_with_crc32c() {
+    let source = source_tree("reader-crc");
+    let mut data = drain(ArchiveWriter::new().add_path(source.join("stuff")).crc32c());
+    fs::remove_dir_all(&source).unwrap();
+    let entries = read_entries(ArchiveReader::new(), data.clone()).unwrap();
+    assert_eq!(entries[3], ( "stuff/inner/c.txt".to_string(), false, "sea".repeat(1000).into_bytes() ));
+
+    let n = data.len() - 1000;
+    data[n] ^= 1;
+    let e = read_entries(ArchiveReader::new(), data).err().unwrap();
+    assert!(e.to_string().starts_with("CRC32C mismatch"), "{}", e);
+  }
+
+  #[test]
+  fn deterministic_archive() {
+    let source = source_tree("deterministic");
+    let writer = || {
+      ArchiveWriter::new().add_path(source.join("stuff")).deterministic(Deterministic::default()).hash(HashAlgorithm::Sha256).compress(CompressionType::Zstd)
+    };
+    let data = drain(writer());
+    fs::write(source.join("stuff").join("a.txt"), "ay").unwrap();
+    assert_eq!(drain(writer()), data);
+    fs::remove_dir_all(&source).unwrap();
+    assert_eq!(read_entries(ArchiveReader::new(), data).unwrap().len(), 4);
+  }
+
+  #[test]
+  fn read_damaged_parity_archive() {
+    let source = source_tree("reader-parity");
+    let mut data = drain(ArchiveWriter::new().add_path(source.join("stuff")).hash(HashAlgorithm::Sha256).parity(4, 2));
+    fs::remove_dir_all(&source).unwrap();
+    let entries = list_bottle(make_stream(vec![ Bytes::from(data.clone()) ])).collect().wait().unwrap();
+    assert_eq!(entries[0].layers, vec![ BottleType::Parity, BottleType::Hashed, BottleType::File ]);
+    // somewhere in the first data shard.
+    data[100] ^= 1;
+    let entries = read_entries(ArchiveReader::new(), data).unwrap();
+    assert_eq!(entries[3], ( "stuff/inner/c.txt".to_string(), false, "sea".repeat(1000).into_bytes() ));
+  }
+
+  #[test]
+  fn read_archive_without_key() {
+    let source = source_tree("reader-no-key");
+    let data = drain(ArchiveWriter::new().add_path(source.join("stuff")).encrypt(KeySource::Raw(vec![ 9; 32 ]), vec![]));
+    fs::remove_dir_all(&source).unwrap();
+    let e = read_entries(ArchiveReader::new(), data).err().unwrap();
+    assert_eq!(e.to_string(), "Archive is encrypted, but no key was given");
+  }
+
+  #[test]
+  fn list_archive() {
+    let source = source_tree("list");
+    let data = drain(ArchiveWriter::new().add_path(source.join("stuff")).hash(HashAlgorithm::Sha256).compress(CompressionType::Snappy));
+    fs::remove_dir_all(&source).unwrap();
+
+    let s = make_stream(data.chunks(50).map(Bytes::from).collect());
+    let entries = list_bottle(s).collect().wait().unwrap();
+    let paths: Vec<String> = entries.iter().map(|e| e.path.to_string_lossy().to_string()).collect();
+    assert_eq!(paths, vec![ "stuff", "stuff/a.txt", "stuff/inner", "stuff/inner/c.txt" ]);
+    assert_eq!(entries[0].layers, vec![ BottleType::Compressed, BottleType::Hashed, BottleType::File ]);
+    assert_eq!(entries[0].hashes.len(), 1);
+    assert_eq!(entries[0].hashes[0].algorithm, HashAlgorithm::Sha256);
+    assert_eq!(entries[0].hashes[0].signed_by, None);
+    assert_eq!(entries[0].hashes[0].digest.len(), 32);
+    assert_eq!(entries[3].layers, vec![ BottleType::File ]);
+    assert_eq!(entries[3].metadata.size, Some(3000));
+    assert!(entries[3].hashes.is_empty());
+  }
+
+  #[test]
+  fn list_encrypted_archive() {
+    let source = source_tree("list-encrypted");
+    let data = drain(ArchiveWriter::new().add_path(source.join("stuff")).encrypt(KeySource::Raw(vec![ 9; 32 ]), vec![]).sign("alice", future::ok));
+    fs::remove_dir_all(&source).unwrap();
+
+    let e = list_bottle(make_stream(vec![ Bytes::from(data.clone()) ])).collect().wait().err().unwrap();
+    assert_eq!(e.to_string(), "Archive is encrypted, but no key was given");
+    let reader = ArchiveReader::new().with_key(|_| Ok(KeySource::Raw(vec![ 9; 32 ])));
+    let entries = reader.list(make_stream(vec![ Bytes::from(data) ])).collect().wait().unwrap();
+    assert_eq!(entries.len(), 4);
+    assert_eq!(entries[0].layers, vec![ BottleType::Encrypted, BottleType::Hashed, BottleType::File ]);
+    assert_eq!(entries[0].hashes[0].signed_by, Some("alice".to_string()));
+  }
+
+  #[test]
+  fn read_archive_with_limits() {
+    let source = source_tree("reader-limits");
+    let data = drain(ArchiveWriter::new().add_path(source.join("stuff")).hash(HashAlgorithm::Sha256).dedup());
+    fs::remove_dir_all(&source).unwrap();
+    let limit_error = |e: io::Error| BottleError::find(&e).cloned().unwrap();
+
+    // two layers, then stuff/inner/c.txt.
+    let limits = DecodeLimits { max_nesting_depth: 5, ..DecodeLimits::default() };
+    assert_eq!(read_entries(ArchiveReader::new().with_limits(limits), data.clone()).unwrap().len(), 4);
+    let limits = DecodeLimits { max_nesting_depth: 4, ..DecodeLimits::default() };
+    let expected = BottleError::DecodeLimitExceeded { limit: DecodeLimit::NestingDepth, size: 5, max: 4 };
+    assert_eq!(limit_error(read_entries(ArchiveReader::new().with_limits(limits), data.clone()).unwrap_err()), expected);
+    let s = make_stream(vec![ Bytes::from(data.clone()) ]);
+    assert_eq!(limit_error(ArchiveReader::new().with_limits(limits).list(s).collect().wait().unwrap_err()), expected);
+
+    // the dedup layer keeps every chunk: about 3K here.
+    let limits = DecodeLimits { max_buffered_bytes: 1000, ..DecodeLimits::default() };
+    let e = limit_error(read_entries(ArchiveReader::new().with_limits(limits), data.clone()).unwrap_err());
+    assert!(matches!(e, BottleError::DecodeLimitExceeded { limit: DecodeLimit::BufferedBytes, .. }));
+    let limits = DecodeLimits { max_buffered_bytes: 16, ..DecodeLimits::default() };
+    let s = make_stream(vec![ Bytes::from(data) ]);
+    let e = limit_error(ArchiveReader::new().with_limits(limits).list(s).collect().wait().unwrap_err());
+    assert!(matches!(e, BottleError::DecodeLimitExceeded { limit: DecodeLimit::BufferedBytes, .. }));
+  }
+
+  #[test]
+  fn large_reads_archive_the_same() {
+    let source = temp_dir("archive-large-reads");
+    fs::write(source.join("big"), (0 .. 3_000_000).map(|i| (i % 253) as u8).collect::<Vec<u8>>()).unwrap();
+    fs::write(source.join("small"), "hello sailor!").unwrap();
+    let make = || ArchiveWriter::new().add_path(&source).deterministic(Deterministic::default());
+    let ( large, small ) = ( drain(make().large_reads()), drain(make()) );
+    fs::remove_dir_all(&source).unwrap();
+    // only the framing differs.
+    assert_eq!(read_entries(ArchiveReader::new(), large).unwrap(), read_entries(ArchiveReader::new(), small).unwrap());
+  }
+}
@@ -0,0 +1,163 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+extern crate tokio_io;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use futures::{Async, Future, Sink, Stream};
+  use lib4bottle::async_io::{BottleEntry, BottleSink, bottle_from_async_read, write_bottle_to, write_vectored_to};
+  use lib4bottle::bottle::{BottleType, bottle_from_slice, make_bottle, read_bottle};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::stream_helpers::{make_stream_2, make_vec_stream_1};
+  use std::io::{self, IoSlice, Write};
+  use tokio_io::AsyncWrite;
+
+  #[test]
+  fn write_a_bottle_to_a_writer() {
+    let mut h = Header::new();
+    h.add_number(0, 150);
+    let b = make_bottle(BottleType::Test, &h, vec![ make_vec_stream_1(Bytes::from_static(b"hello")) ]);
+    let cursor = write_bottle_to(b, io::Cursor::new(Vec::new())).wait().unwrap();
+    let ( btype, header, streams ) = bottle_from_slice(cursor.get_ref()).unwrap();
+    assert_eq!(btype, BottleType::Test);
+    assert_eq!(header.get_number(0), Some(150));
+    assert_eq!(streams, vec![ b"hello".to_vec() ]);
+  }
+
+  #[test]
+  fn read_a_bottle_from_a_reader() {
+    let b = make_bottle(BottleType::Test, &Header::new(), vec![ make_vec_stream_1(Bytes::from_static(b"hello")) ]);
+    let data: Vec<u8> = b.collect().wait().unwrap().into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect();
+    let ( btype, _, children ) = read_bottle(bottle_from_async_read(io::Cursor::new(data))).wait().unwrap();
+    assert_eq!(btype, BottleType::Test);
+    let content: Vec<Vec<u8>> = children.map(|child| child.concat2()).buffered(1).map(|b| b.to_vec()).collect().wait().unwrap();
+    assert_eq!(content, vec![ b"hello".to_vec() ]);
+  }
+
+  #[test]
+  fn read_a_large_stream_in_chunks() {
+    let data = vec![ 7u8; 200 * 1024 ];
+    let chunks = bottle_from_async_read(&data[..]).collect().wait().unwrap();
+    assert!(chunks.len() > 1);
+    assert_eq!(chunks.iter().map(|b| b.len()).sum::<usize>(), data.len());
+  }
+
+  // takes at most `limit` bytes per write, and refuses every other call.
+  struct SlowWriter {
+    data: Vec<u8>,
+    limit: usize,
+    calls: usize,
+    blocked: bool
+  }
+
+  impl Write for SlowWriter {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+      self.write_vectored(&[ IoSlice::new(buffer) ])
+    }
+
+    fn write_vectored(&mut self, slices: &[IoSlice]) -> io::Result<usize> {
+      self.blocked = !self.blocked;
+      if self.blocked { return Err(io::Error::from(io::ErrorKind::WouldBlock)) }
+      self.calls += 1;
+      let mut n = 0;
+      for slice in slices {
+        let count = slice.len().min(self.limit - n);
+        self.data.extend_from_slice(&slice[0 .. count]);
+        n += count;
+      }
+      Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  impl AsyncWrite for SlowWriter {
+    fn shutdown(&mut self) -> futures::Poll<(), io::Error> {
+      Ok(Async::Ready(()))
+    }
+  }
+
+  fn small_bottle() -> Vec<u8> {
+    let b = make_bottle(BottleType::Test, &Header::new(), vec![ make_vec_stream_1(Bytes::from_static(b"hello")) ]);
+    b.collect().wait().unwrap().into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect()
+  }
+
+  #[test]
+  fn write_vectored() {
+    let b = make_bottle(BottleType::Test, &Header::new(), vec![ make_vec_stream_1(Bytes::from_static(b"hello")) ]);
+    let writer = SlowWriter { data: Vec::new(), limit: 1000, calls: 0, blocked: false };
+    let mut f = write_vectored_to(b, writer);
+    let writer = loop {
+      if let Async::Ready(writer) = f.poll().unwrap() { break writer }
+    };
+    assert_eq!(writer.data, small_bottle());
+    // the header, frame, and end markers all went out together.
+    assert_eq!(writer.calls, 1);
+  }
+
+  #[test]
+  fn write_vectored_in_pieces() {
+    let b = make_bottle(BottleType::Test, &Header::new(), vec![ make_vec_stream_1(Bytes::from_static(b"hello")) ]);
+    let writer = SlowWriter { data: Vec::new(), limit: 3, calls: 0, blocked: false };
+    let mut f = write_vectored_to(b, writer);
+    let writer = loop {
+      if let Async::Ready(writer) = f.poll().unwrap() { break writer }
+    };
+    let expected = small_bottle();
+    assert_eq!(writer.calls, expected.len().div_ceil(3));
+    assert_eq!(writer.data, expected);
+  }
+
+  #[test]
+  fn push_entries_into_a_sink() {
+    let mut h = Header::new();
+    h.add_number(0, 150);
+    let sink = BottleSink::new(io::Cursor::new(Vec::new()), BottleType::Test, &h).unwrap();
+    let entries = vec![
+      BottleEntry::data(make_stream_2(Bytes::from_static(b"hel"), Bytes::from_static(b"lo"))),
+      BottleEntry::data(make_vec_stream_1(Bytes::new())),
+      BottleEntry::bottle(BottleType::Test2, &Header::new(), vec![ make_vec_stream_1(Bytes::from_static(b"inner")) ])
+    ];
+    let sink = sink.send_all(futures::stream::iter_ok::<_, io::Error>(entries)).wait().unwrap().0;
+    let cursor = sink.into_inner();
+
+    let ( btype, header, streams ) = bottle_from_slice(cursor.get_ref()).unwrap();
+    assert_eq!(btype, BottleType::Test);
+    assert_eq!(header.get_number(0), Some(150));
+    assert_eq!(streams.len(), 3);
+    assert_eq!(streams[0], b"hello".to_vec());
+    assert_eq!(streams[1], b"".to_vec());
+    let ( btype, _, inner ) = bottle_from_slice(&streams[2]).unwrap();
+    assert_eq!(btype, BottleType::Test2);
+    assert_eq!(inner, vec![ b"inner".to_vec() ]);
+  }
+
+  #[test]
+  fn sink_matches_make_bottle() {
+    let writer = SlowWriter { data: Vec::new(), limit: 3, calls: 0, blocked: false };
+    let sink = BottleSink::new(writer, BottleType::Test, &Header::new()).unwrap();
+    let entry = BottleEntry::data(make_vec_stream_1(Bytes::from_static(b"hello")));
+    // the writer never wakes anyone up, so poll by hand.
+    let mut f = sink.send(entry);
+    let mut sink = loop {
+      if let Async::Ready(sink) = f.poll().unwrap() { break sink }
+    };
+    loop {
+      if let Async::Ready(()) = sink.close().unwrap() { break }
+    }
+    assert_eq!(sink.into_inner().data, small_bottle());
+  }
+
+  #[test]
+  fn send_after_close() {
+    let mut sink = BottleSink::new(io::Cursor::new(Vec::new()), BottleType::Test, &Header::new()).unwrap();
+    assert_eq!(sink.close().unwrap(), Async::Ready(()));
+    assert!(sink.start_send(BottleEntry::data(make_vec_stream_1(Bytes::new()))).is_err());
+    let ( _, _, streams ) = bottle_from_slice(sink.into_inner().get_ref()).unwrap();
+    assert!(streams.is_empty());
+  }
+}
@@ -6,14 +6,24 @@ extern crate lib4bottle;
 mod tests {
   // use std::io;
   use bytes::{Bytes};
-  use futures::{Future, Stream, stream};
-  use lib4bottle::bottle::{BottleType, framed_vec_stream, make_bottle};
+  use futures::{Future, Sink, Stream, stream};
+  use futures::sync::mpsc;
+  use lib4bottle::bottle::{
+    BottleOptions, BottleType, FIELD_INTERLEAVED, FIELD_STREAM_COUNT, MAX_INTERLEAVED, bottle_from_slice, bottle_to_vec, child_from_bytes, decode_bottle_type,
+    framed_vec_stream, make_bottle, make_bottle_with_options, make_counted_bottle, make_interleaved_bottle, parse_bottle_cap, peek_is_bottle, peek_is_bottle_stream, read_bottle,
+    read_bottle_with_options, DecodeLimits, ReadOptions
+  };
   use lib4bottle::bottle_header::{Header};
+  use lib4bottle::error::{BottleError, DecodeLimit, TruncationContext};
   use lib4bottle::buffered_stream::{buffer_stream};
-  use lib4bottle::stream_helpers::{drain_stream, make_stream_1, make_stream_4};
+  use lib4bottle::stream_helpers::{drain_stream, make_stream, make_vec_stream_1, make_stream_4};
   use lib4bottle::to_hex::{FromHex, ToHex};
+  use lib4bottle::zint;
+  use std::convert::TryFrom;
   use std::io;
   use std::iter;
+  use std::thread;
+  use std::time::Duration;
 
   pub fn bytes123() -> Bytes {
     Bytes::from(vec![ 1, 2, 3 ])
@@ -22,7 +32,7 @@ mod tests {
 
   #[test]
   fn write_a_small_frame() {
-    let s = framed_vec_stream(make_stream_1(bytes123()));
+    let s = framed_vec_stream(make_vec_stream_1(bytes123()));
     assert_eq!(
       s.collect().wait().unwrap().to_hex(),
       "0301020300"
@@ -43,10 +53,9 @@ mod tests {
 
   #[test]
   fn write_power_of_2_frame() {
-    for block_size in vec![ 128, 1024, 1 << 18, 1 << 21 ] {
-      let mut buffer: Vec<u8> = Vec::with_capacity(block_size);
-      buffer.resize(block_size, 0);
-      let b = framed_vec_stream(make_stream_1(Bytes::from(buffer)));
+    for block_size in [ 128, 1024, 1 << 18, 1 << 21 ] {
+      let buffer: Vec<u8> = vec![ 0; block_size ];
+      let b = framed_vec_stream(make_vec_stream_1(Bytes::from(buffer)));
       let out = drain_stream(b);
       assert_eq!(out.len(), block_size + 2);
       assert_eq!(out[0], (((block_size as f32).log(2.0) as u8) & 0x1f) + (0xf0 - 7));
@@ -57,10 +66,9 @@ mod tests {
   #[test]
   fn write_medium_frame() {
     // < 8K
-    for block_size in vec![ 129, 1234, 8191 ] {
-      let mut buffer: Vec<u8> = Vec::with_capacity(block_size);
-      buffer.resize(block_size, 0);
-      let b = framed_vec_stream(make_stream_1(Bytes::from(buffer)));
+    for block_size in [ 129, 1234, 8191 ] {
+      let buffer: Vec<u8> = vec![ 0; block_size ];
+      let b = framed_vec_stream(make_vec_stream_1(Bytes::from(buffer)));
       let out = drain_stream(b);
       assert_eq!(out.len(), block_size + 3);
       assert_eq!(out[0], (block_size & 0x3f) as u8 + 0x80);
@@ -72,10 +80,9 @@ mod tests {
   #[test]
   fn write_large_frame() {
     // < 2M
-    for block_size in vec![ 8193, 12345, 456123 ] {
-      let mut buffer: Vec<u8> = Vec::with_capacity(block_size);
-      buffer.resize(block_size, 0);
-      let b = framed_vec_stream(make_stream_1(Bytes::from(buffer)));
+    for block_size in [ 8193, 12345, 456123 ] {
+      let buffer: Vec<u8> = vec![ 0; block_size ];
+      let b = framed_vec_stream(make_vec_stream_1(Bytes::from(buffer)));
       let out = drain_stream(b);
       assert_eq!(out.len(), block_size + 4);
       assert_eq!(out[0], (block_size & 0x1f) as u8 + 0xc0);
@@ -88,10 +95,9 @@ mod tests {
   #[test]
   fn write_huge_frame() {
     // >= 2M
-    for block_size in vec![ (1 << 21) + 1, 3998778 ] {
-      let mut buffer: Vec<u8> = Vec::with_capacity(block_size);
-      buffer.resize(block_size, 0);
-      let b = framed_vec_stream(make_stream_1(Bytes::from(buffer)));
+    for block_size in [ (1 << 21) + 1, 3998778 ] {
+      let buffer: Vec<u8> = vec![ 0; block_size ];
+      let b = framed_vec_stream(make_vec_stream_1(Bytes::from(buffer)));
       let out = drain_stream(b);
       assert_eq!(out.len(), block_size + 5);
       assert_eq!(out[0], (block_size & 0xf) as u8 + 0xe0);
@@ -102,6 +108,18 @@ mod tests {
     }
   }
 
+  #[test]
+  fn convert_bottle_types() {
+    for &btype in [ BottleType::File, BottleType::Hashed, BottleType::Encrypted, BottleType::Compressed, BottleType::Index, BottleType::Volume, BottleType::Dedup, BottleType::Parity ].iter() {
+      assert_eq!(BottleType::try_from(btype as u8).unwrap(), btype);
+      assert_eq!(decode_bottle_type(btype as u8).unwrap(), btype);
+    }
+    for &n in [ 2, 9, 12, 15, 16, 255 ].iter() {
+      assert_eq!(BottleType::try_from(n).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+      assert!(decode_bottle_type(n).is_err());
+    }
+  }
+
   #[test]
   fn write_a_small_bottle() {
     let mut h = Header::new();
@@ -114,7 +132,7 @@ mod tests {
 
   #[test]
   fn write_a_small_data_bottle() {
-    let data = make_stream_1(Bytes::from("ff00ff00".from_hex()));
+    let data = make_vec_stream_1(Bytes::from("ff00ff00".from_hex()));
     let b = make_bottle(BottleType::Test, &Header::new(), vec![ data ]);
 
     let magic_hex = "f09f8dbc0000";
@@ -131,16 +149,625 @@ mod tests {
     assert_eq!(b2.collect().wait().unwrap().to_hex(), format!("{}b00009{}a000ff00ff", magic_hex, magic_hex));
   }
 
+  #[test]
+  fn write_a_bottle_with_oversized_header() {
+    // exactly 4095 bytes is fine.
+    let mut h = Header::new();
+    for i in 0 .. 3 { h.add_string(i, "x".repeat(1022)) }
+    h.add_string(3, "x".repeat(1021));
+    let data = make_vec_stream_1(Bytes::from("ff00ff00".from_hex()));
+    assert!(make_bottle(BottleType::Test, &h, vec![ data ]).collect().wait().is_ok());
+
+    h.add_bool(4);
+    let data = make_vec_stream_1(Bytes::from("ff00ff00".from_hex()));
+    let e = make_bottle(BottleType::Test, &h, vec![ data ]).collect().wait().err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(e.to_string(), "Header too large: 4097 bytes (limit 4095)");
+    assert!(bottle_to_vec(BottleType::Test, &h, vec![]).is_err());
+  }
+
   #[test]
   fn write_a_bottle_of_several_streams() {
-    let data1 = make_stream_1(Bytes::from("f0f0f0".from_hex()));
-    let data2 = make_stream_1(Bytes::from("e0e0e0".from_hex()));
-    let data3 = make_stream_1(Bytes::from("cccccc".from_hex()));
+    let data1 = make_vec_stream_1(Bytes::from("f0f0f0".from_hex()));
+    let data2 = make_vec_stream_1(Bytes::from("e0e0e0".from_hex()));
+    let data3 = make_vec_stream_1(Bytes::from("cccccc".from_hex()));
     let b = make_bottle(BottleType::Test, &Header::new(), vec![ data1, data2, data3 ]);
 
     let magic_hex = "f09f8dbc0000";
     assert_eq!(b.collect().wait().unwrap().to_hex(), format!("{}a00003f0f0f00003e0e0e00003cccccc00ff", magic_hex));
   }
+
+  #[test]
+  fn write_a_counted_bottle() {
+    let data1 = make_vec_stream_1(Bytes::from("f0f0f0".from_hex()));
+    let data2 = make_vec_stream_1(Bytes::from("e0e0e0".from_hex()));
+    let data3 = make_vec_stream_1(Bytes::from("cccccc".from_hex()));
+    let mut h = Header::new();
+    h.add_number(0, 150);
+    let b = drain_stream(make_counted_bottle(BottleType::Test, &h, vec![ data1, data2, data3 ]));
+    let ( _, header, streams ) = bottle_from_slice(&b).unwrap();
+    assert_eq!(header.get_number(0), Some(150));
+    assert_eq!(header.get_number(FIELD_STREAM_COUNT), Some(3));
+    assert_eq!(streams.len(), 3);
+    // the caller's header is left alone.
+    assert_eq!(h.get_number(FIELD_STREAM_COUNT), None);
+  }
+
+  // lengths of the frames in a bottle with no header, until END_OF_ALL_STREAMS.
+  fn frame_lengths(data: &[u8]) -> Vec<u32> {
+    let mut cursor = io::Cursor::new(&data[8 ..]);
+    let mut lengths = Vec::new();
+    loop {
+      match zint::decode_length(&mut cursor).unwrap() {
+        zint::END_OF_ALL_STREAMS => return lengths,
+        n => {
+          lengths.push(n);
+          cursor.set_position(cursor.position() + n as u64);
+        }
+      }
+    }
+  }
+
+  fn bottle_with_options(options: &BottleOptions) -> Vec<u8> {
+    let chunks: Vec<Vec<Bytes>> = (0 .. 10).map(|_| vec![ Bytes::from(vec![ 1u8; 100 ]) ]).collect();
+    let s = stream::iter_ok::<_, io::Error>(chunks);
+    drain_stream(make_bottle_with_options(BottleType::Test, &Header::new(), vec![ s ], options).unwrap())
+  }
+
+  #[test]
+  fn write_a_bottle_with_options() {
+    let options = BottleOptions { min_frame: 250, max_frame: 300, exact_frames: false };
+    assert_eq!(frame_lengths(&bottle_with_options(&options)), vec![ 300, 300, 300, 100, 0 ]);
+    let options = BottleOptions { min_frame: 150, max_frame: 150, exact_frames: false };
+    assert_eq!(frame_lengths(&bottle_with_options(&options)), vec![ 150, 50, 150, 50, 150, 50, 150, 50, 150, 50, 0 ]);
+    let options = BottleOptions { min_frame: 1, max_frame: 256, exact_frames: true };
+    let data = bottle_with_options(&options);
+    assert_eq!(frame_lengths(&data), vec![ 256, 256, 256, 232, 0 ]);
+    let ( _, _, streams ) = bottle_from_slice(&data).unwrap();
+    assert_eq!(streams, vec![ vec![ 1u8; 1000 ] ]);
+  }
+
+  #[test]
+  fn write_a_bottle_with_bad_options() {
+    for &( min_frame, max_frame ) in [ ( 0, 10 ), ( 20, 10 ), ( 1, 1 << 28 ) ].iter() {
+      let options = BottleOptions { min_frame, max_frame, exact_frames: false };
+      let e = make_bottle_with_options(BottleType::Test, &Header::new(), Vec::<stream::Empty<_, _>>::new(), &options).err().unwrap();
+      assert_eq!(BottleError::find(&e), Some(&BottleError::InvalidFrameSize { min: min_frame, max: max_frame }));
+    }
+  }
+
+  #[test]
+  fn write_a_bottle_to_a_vec() {
+    let mut h = Header::new();
+    h.add_number(0, 150);
+    let data1 = make_vec_stream_1(Bytes::from("f0f0f0".from_hex()));
+    let data2 = make_vec_stream_1(Bytes::from("e0e0e0".from_hex()));
+    let expected = drain_stream(make_bottle(BottleType::Test, &h, vec![ data1, data2 ]));
+    let b = bottle_to_vec(BottleType::Test, &h, vec![ "f0f0f0".from_hex(), "e0e0e0".from_hex() ]).unwrap();
+    assert_eq!(b, expected);
+  }
+
+  #[test]
+  fn write_an_empty_stream_to_a_vec() {
+    let b = bottle_to_vec(BottleType::Test, &Header::new(), vec![ vec![], bytes123().to_vec() ]).unwrap();
+    let magic_hex = "f09f8dbc0000";
+    assert_eq!(b.to_hex(), format!("{}a000000301020300ff", magic_hex));
+  }
+
+  #[test]
+  fn read_a_bottle_from_a_slice() {
+    let mut h = Header::new();
+    h.add_number(0, 150);
+    let b = bottle_to_vec(BottleType::Test, &h, vec![ "f0f0f0".from_hex(), vec![], bytes123().to_vec() ]).unwrap();
+    let ( btype, header, streams ) = bottle_from_slice(&b).unwrap();
+    assert_eq!(btype, BottleType::Test);
+    assert_eq!(format!("{:?}", header), "Header(N0=150)");
+    assert_eq!(streams, vec![ "f0f0f0".from_hex(), vec![], bytes123().to_vec() ]);
+
+    for i in 0 .. b.len() {
+      assert!(bottle_from_slice(&b[0 .. i]).is_err());
+    }
+  }
+
+  #[test]
+  fn read_an_empty_stream_followed_by_a_full_one() {
+    // an empty file: a child stream with no frames at all, just END_OF_STREAM.
+    let streams: Vec<Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error>>> = vec![
+      Box::new(stream::empty()),
+      Box::new(make_vec_stream_1(bytes123()))
+    ];
+    let b = drain_stream(make_bottle(BottleType::Test, &Header::new(), streams));
+    assert_eq!(b.to_hex(), "f09f8dbc0000a000000301020300ff");
+    let ( _, _, streams ) = bottle_from_slice(&b).unwrap();
+    assert_eq!(streams, vec![ vec![], bytes123().to_vec() ]);
+  }
+
+  #[test]
+  fn read_a_bottle_truncated_inside_a_frame_length() {
+    let b = bottle_to_vec(BottleType::Test, &Header::new(), vec![ vec![ 0; 1234 ] ]).unwrap();
+    // 8 bytes of header, then a 2-byte frame length.
+    assert_eq!(b[8..10].to_hex(), "9213");
+    let e = bottle_from_slice(&b[0 .. 9]).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(e.to_string(), "Truncated length: expected 2 bytes, got 1");
+  }
+
+  #[test]
+  fn peek_for_a_bottle() {
+    let b = drain_stream(make_bottle(BottleType::Test, &Header::new(), vec![ make_vec_stream_1(bytes123()) ]));
+    assert!(peek_is_bottle(&b));
+    assert!(!peek_is_bottle(&b[0..3]));
+    assert!(!peek_is_bottle(b"hello sailor"));
+    assert!(!peek_is_bottle(&[]));
+  }
+
+  #[test]
+  fn peek_for_a_bottle_in_a_stream() {
+    let b = drain_stream(make_bottle(BottleType::Test, &Header::new(), vec![ make_vec_stream_1(bytes123()) ]));
+    let s = make_stream(vec![ Bytes::from(&b[0..2]), Bytes::from(&b[2..]) ]);
+    let (is_bottle, s) = peek_is_bottle_stream(s).wait().unwrap();
+    assert!(is_bottle);
+    assert_eq!(s.collect().wait().unwrap().to_hex(), b.to_hex());
+
+    let (is_bottle, s) = peek_is_bottle_stream(make_stream(vec![ Bytes::from_static(b"hi") ])).wait().unwrap();
+    assert!(!is_bottle);
+    assert_eq!(s.collect().wait().unwrap().to_hex(), "6869");
+  }
+
+  // a nested bottle with a bit of everything in it.
+  fn representative_bottle() -> Vec<u8> {
+    let mut h1 = Header::new();
+    h1.add_string(0, String::from("file.txt"));
+    h1.add_number(1, 1000);
+    let inner = make_bottle(BottleType::Test, &h1, vec![ make_vec_stream_1(Bytes::from_static(b"hello")) ]);
+    let mut h2 = Header::new();
+    h2.add_bool(2);
+    let streams: Vec<Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error>>> = vec![
+      Box::new(inner),
+      Box::new(make_vec_stream_1(bytes123()))
+    ];
+    drain_stream(make_bottle(BottleType::Test2, &h2, streams))
+  }
+
+  // run some bytes through the whole read path, descending into the nested
+  // bottle: it may succeed or fail, but it must never panic.
+  fn read_everything(data: &[u8]) -> Result<(), io::Error> {
+    read_bottle(make_stream(vec![ Bytes::from(data) ])).and_then(|( _, _, streams )| {
+      streams.into_future().map_err(|( e, _ )| e).and_then(|( inner, streams )| {
+        read_bottle(inner.unwrap()).and_then(|( _, _, inner_streams )| {
+          inner_streams.and_then(|child| child.collect()).collect()
+        }).and_then(|_| streams.and_then(|child| child.collect()).collect())
+      })
+    }).wait().map(|_| ())
+  }
+
+  // read every child stream of a bottle into hex.
+  fn read_children(s: impl Stream<Item = Bytes, Error = io::Error>) -> Result<(BottleType, Header, Vec<String>), io::Error> {
+    read_bottle(s).and_then(|( btype, header, streams )| {
+      streams.and_then(|child| child.collect()).collect().map(move |children| {
+        ( btype, header, children.iter().map(|c| c.to_hex()).collect() )
+      })
+    }).wait()
+  }
+
+  fn hex_stream(hex: &str) -> impl Stream<Item = Bytes, Error = io::Error> {
+    make_stream(vec![ Bytes::from(hex.from_hex()) ])
+  }
+
+  // one byte at a time, to make sure nothing assumes a whole length prefix
+  // (or header) arrives in one chunk.
+  fn trickle_stream(hex: &str) -> impl Stream<Item = Bytes, Error = io::Error> {
+    make_stream(hex.from_hex().into_iter().map(|b| Bytes::from(vec![ b ])).collect())
+  }
+
+  #[test]
+  fn read_path_survives_truncation() {
+    let data = representative_bottle();
+    for i in 0 .. data.len() {
+      assert!(read_everything(&data[0 .. i]).is_err(), "truncated at {}", i);
+    }
+    assert!(read_everything(&data).is_ok());
+  }
+
+  #[test]
+  fn read_path_survives_corruption() {
+    let data = representative_bottle();
+    for i in 0 .. data.len() {
+      for &bits in [ 0x01, 0x80, 0xff ].iter() {
+        let mut corrupted = data.clone();
+        corrupted[i] ^= bits;
+        let rv = read_everything(&corrupted);
+        if i < 4 { assert!(rv.is_err(), "corrupted magic at {}", i) }
+      }
+    }
+  }
+
+  #[test]
+  fn read_validates_the_header() {
+    assert_eq!(read_children(hex_stream("00")).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    assert!(format!("{}", read_children(hex_stream("00ff00ff00ff00ff")).unwrap_err()).contains("magic"));
+    assert!(format!("{}", read_children(hex_stream("f09f8dbcff000000")).unwrap_err()).contains("version"));
+    assert!(format!("{}", read_children(hex_stream("f09f8dbc00ff0000")).unwrap_err()).contains("version"));
+  }
+
+  #[test]
+  fn read_the_header() {
+    let ( btype, header, children ) = read_children(hex_stream("f09f8dbc0000a000ff")).unwrap();
+    assert_eq!(btype, BottleType::Test);
+    assert_eq!(format!("{:?}", header), "Header()");
+    assert_eq!(children.len(), 0);
+
+    let ( btype, header, _ ) = read_children(hex_stream("f09f8dbc0000b003800196ff")).unwrap();
+    assert_eq!(btype, BottleType::Test2);
+    assert_eq!(format!("{:?}", header), "Header(N0=150)");
+  }
+
+  #[test]
+  fn read_a_data_block() {
+    let ( _, _, children ) = read_children(hex_stream("f09f8dbc0000a0000568656c6c6f00ff")).unwrap();
+    assert_eq!(children, vec![ "68656c6c6f" ]);
+  }
+
+  #[test]
+  fn read_a_continuing_data_block() {
+    let ( _, _, children ) = read_children(hex_stream("f09f8dbc0000a000026865016c026c6f00ff")).unwrap();
+    assert_eq!(children, vec![ "68656c6c6f" ]);
+  }
+
+  #[test]
+  fn read_several_streams() {
+    let hex = "f09f8dbc0000a00003f0f0f00003e0e0e00003cccccc00ff";
+    let ( _, _, children ) = read_children(hex_stream(hex)).unwrap();
+    assert_eq!(children, vec![ "f0f0f0", "e0e0e0", "cccccc" ]);
+    let ( _, _, children ) = read_children(trickle_stream(hex)).unwrap();
+    assert_eq!(children, vec![ "f0f0f0", "e0e0e0", "cccccc" ]);
+  }
+
+  #[test]
+  fn read_empty_streams() {
+    let ( _, _, children ) = read_children(hex_stream("f09f8dbc0000a0000003f0f0f00000ff")).unwrap();
+    assert_eq!(children, vec![ "", "f0f0f0", "" ]);
+  }
+
+  #[test]
+  fn read_large_frames() {
+    let data: Vec<u8> = (0 .. 10000).map(|i| (i % 251) as u8).collect();
+    let b = bottle_to_vec(BottleType::Test, &Header::new(), vec![ data.clone(), vec![ 1; 1 << 13 ] ]).unwrap();
+    let ( _, _, children ) = read_children(trickle_stream(&b.to_hex())).unwrap();
+    assert_eq!(children, vec![ data.to_hex(), vec![ 1u8; 1 << 13 ].to_hex() ]);
+  }
+
+  #[test]
+  fn read_a_nested_bottle() {
+    let data = representative_bottle();
+    let ( btype, header, streams ) = read_bottle(make_stream(vec![ Bytes::from(data) ])).wait().unwrap();
+    assert_eq!(btype, BottleType::Test2);
+    assert_eq!(format!("{:?}", header), "Header(B2)");
+    let ( inner, streams ) = streams.into_future().map_err(|( e, _ )| e).wait().unwrap();
+    let ( btype, header, children ) = read_children(inner.unwrap()).unwrap();
+    assert_eq!(btype, BottleType::Test);
+    assert_eq!(format!("{:?}", header), "Header(S0=\"file.txt\", N1=1000)");
+    assert_eq!(children, vec![ "68656c6c6f" ]);
+    let rest = streams.and_then(|child| child.collect()).collect().wait().unwrap();
+    assert_eq!(rest.iter().map(|c| c.to_hex()).collect::<Vec<String>>(), vec![ "010203" ]);
+  }
+
+  #[test]
+  fn read_skips_unfinished_streams() {
+    let hex = "f09f8dbc0000a000026865016c026c6f0003f0f0f000ff";
+    let ( _, _, streams ) = read_bottle(trickle_stream(hex)).wait().unwrap();
+    // ignore the first stream entirely, and only read the second.
+    let ( first, streams ) = streams.into_future().map_err(|( e, _ )| e).wait().unwrap();
+    let ( second, streams ) = streams.into_future().map_err(|( e, _ )| e).wait().unwrap();
+    assert_eq!(second.unwrap().collect().wait().unwrap().to_hex(), "f0f0f0");
+    // the skipped stream is just over.
+    assert_eq!(first.unwrap().collect().wait().unwrap().to_hex(), "");
+    assert_eq!(streams.collect().wait().unwrap().len(), 0);
+  }
+
+  // read as much as possible, with truncation tolerated, until it fails.
+  fn salvage(hex: &str) -> ( Vec<String>, BottleError ) {
+    let options = ReadOptions { tolerate_truncation: true, ..ReadOptions::default() };
+    let mut children = Vec::new();
+    let e = match read_bottle_with_options(hex_stream(hex), options).wait() {
+      Err(e) => e,
+      Ok(( _, _, streams )) => {
+        let mut failure = None;
+        for child in streams.wait() {
+          let child = match child {
+            Ok(child) => child,
+            Err(e) => { failure = Some(e); break }
+          };
+          let mut data = Vec::new();
+          for b in child.wait() {
+            match b {
+              Ok(b) => data.extend_from_slice(&b),
+              Err(e) => { failure = Some(e); break }
+            }
+          }
+          children.push(data.to_hex());
+          if failure.is_some() { break }
+        }
+        failure.unwrap()
+      }
+    };
+    ( children, BottleError::find(&e).cloned().unwrap() )
+  }
+
+  #[test]
+  fn read_truncated_bottle() {
+    let truncated = |offset, context| BottleError::TruncatedAt { offset, context };
+    assert_eq!(salvage("f09f8d"), ( vec![], truncated(3, TruncationContext::Header) ));
+    assert_eq!(salvage("f09f8dbc0000a00300"), ( vec![], truncated(9, TruncationContext::Header) ));
+    assert_eq!(salvage("f09f8dbc0000a00003f0f0"), ( vec![ "f0f0".to_string() ], truncated(11, TruncationContext::FrameData { child: 0 }) ));
+    assert_eq!(salvage("f09f8dbc0000a00003f0f0f081"), ( vec![ "f0f0f0".to_string() ], truncated(13, TruncationContext::FrameLength { child: 0 }) ));
+    assert_eq!(salvage("f09f8dbc0000a00003f0f0f00002ab"), (
+      vec![ "f0f0f0".to_string(), "ab".to_string() ],
+      truncated(15, TruncationContext::FrameData { child: 1 })
+    ));
+    assert_eq!(salvage("f09f8dbc0000a00003f0f0f000"), ( vec![ "f0f0f0".to_string() ], truncated(13, TruncationContext::BetweenStreams) ));
+
+    // the truncation is the last thing the reader says.
+    let options = ReadOptions { tolerate_truncation: true, ..ReadOptions::default() };
+    let ( _, _, streams ) = read_bottle_with_options(hex_stream("f09f8dbc0000a00003f0f0"), options).wait().unwrap();
+    let ( child, streams ) = streams.into_future().map_err(|( e, _ )| e).wait().unwrap();
+    assert!(child.unwrap().collect().wait().is_err());
+    assert_eq!(streams.collect().wait().unwrap().len(), 0);
+
+    // without tolerance, it's just truncated.
+    let e = read_children(hex_stream("f09f8dbc0000a00003f0f0")).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::TruncatedStream));
+    let e = read_children(hex_stream("f09f8d")).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::TruncatedStream));
+  }
+
+  fn read_with_limits(data: Vec<u8>, limits: DecodeLimits) -> Result<Vec<String>, BottleError> {
+    let options = ReadOptions { limits, ..ReadOptions::default() };
+    read_bottle_with_options(make_stream(vec![ Bytes::from(data) ]), options).and_then(|( _, _, streams )| {
+      streams.and_then(|child| child.collect()).collect()
+    }).wait().map(|children| children.iter().map(|c| c.to_hex()).collect()).map_err(|e| BottleError::find(&e).cloned().unwrap())
+  }
+
+  #[test]
+  fn read_with_decode_limits() {
+    let data = "f09f8dbc0000a00003f0f0f00002abab00ff".from_hex();
+    let limits = DecodeLimits { max_frame_size: 3, ..DecodeLimits::default() };
+    assert_eq!(read_with_limits(data.clone(), limits).unwrap(), vec![ "f0f0f0", "abab" ]);
+    let limits = DecodeLimits { max_frame_size: 2, ..DecodeLimits::default() };
+    assert_eq!(read_with_limits(data, limits).unwrap_err(), BottleError::DecodeLimitExceeded { limit: DecodeLimit::FrameSize, size: 3, max: 2 });
+
+    let mut header = Header::new();
+    header.add_string(0, "hello");
+    let data = bottle_to_vec(BottleType::Test, &header, vec![]).unwrap();
+    let limits = DecodeLimits { max_header_size: 7, ..DecodeLimits::default() };
+    assert_eq!(read_with_limits(data.clone(), limits).unwrap().len(), 0);
+    let limits = DecodeLimits { max_header_size: 6, ..DecodeLimits::default() };
+    let e = read_with_limits(data, limits).unwrap_err();
+    assert_eq!(e, BottleError::DecodeLimitExceeded { limit: DecodeLimit::HeaderSize, size: 7, max: 6 });
+    assert_eq!(e.to_string(), "Bottle is over the header size limit: 7 (limit 6)");
+  }
+
+  #[test]
+  fn read_rejects_bad_framing() {
+    // end of all streams in the middle of a stream
+    assert_eq!(
+      read_children(hex_stream("f09f8dbc0000a00003f0f0f0ff")).unwrap_err().kind(),
+      io::ErrorKind::InvalidData
+    );
+    // stream ends mid-frame
+    assert_eq!(
+      read_children(hex_stream("f09f8dbc0000a00005f0f0f0")).unwrap_err().kind(),
+      io::ErrorKind::UnexpectedEof
+    );
+    // stream ends without the end of all streams
+    assert_eq!(
+      read_children(hex_stream("f09f8dbc0000a00003f0f0f000")).unwrap_err().kind(),
+      io::ErrorKind::UnexpectedEof
+    );
+  }
+
+  #[test]
+  fn write_a_bottle_from_a_byte_source() {
+    // something like a subprocess's stdout: lots of small, uneven reads.
+    let chunks: Vec<Bytes> = (0 .. 500).map(|i| Bytes::from(vec![ (i % 256) as u8; (i % 7) + 1 ])).collect();
+    let expected: Vec<u8> = chunks.iter().fold(Vec::new(), |mut v, b| { v.extend(b.as_ref()); v });
+    let b = make_bottle(BottleType::Test, &Header::new(), vec![ child_from_bytes(make_stream(chunks)) ]);
+    let out = drain_stream(b);
+
+    // skip the 8-byte (empty) header, then unframe by hand.
+    let mut cursor = io::Cursor::new(&out[8..]);
+    let mut data: Vec<u8> = Vec::new();
+    let mut frames = 0;
+    loop {
+      let length = zint::decode_length(&mut cursor).unwrap() as usize;
+      if length == 0 { break }
+      let start = cursor.position() as usize;
+      data.extend(&cursor.get_ref()[start .. start + length]);
+      cursor.set_position((start + length) as u64);
+      frames += 1;
+    }
+    assert_eq!(data, expected);
+    // buffered into frames of at least 1KB.
+    assert!(frames < 3);
+    assert_eq!(zint::decode_length(&mut cursor).unwrap(), zint::END_OF_ALL_STREAMS);
+  }
+
+  #[test]
+  fn parse_a_cap() {
+    assert_eq!(parse_bottle_cap(&[ 0xf0, 0x9f, 0x8d, 0xbc, 0, 0, 0xa0, 0x03 ]), Ok(( BottleType::Test, 3 )));
+    assert_eq!(parse_bottle_cap(&[ 0xf0, 0x9f, 0x8d, 0xbc, 0, 0, 0x4f, 0xff ]), Ok(( BottleType::Compressed, 4095 )));
+    assert_eq!(parse_bottle_cap(&[ 0xf0, 0x9f, 0x8d, 0xbc, 0, 0, 0x01, 0x02 ]), Ok(( BottleType::File, 258 )));
+  }
+
+  #[test]
+  fn parse_a_bad_cap() {
+    assert_eq!(parse_bottle_cap(&[ 0xf0, 0x9f, 0x8d, 0xbd, 0, 0, 0xa0, 0x03 ]), Err(BottleError::BadMagic));
+    assert_eq!(parse_bottle_cap(&[ 0xf0, 0x9f, 0x8d, 0xbc, 1, 0, 0xa0, 0x03 ]), Err(BottleError::BadVersion { found: 1, extra: 0 }));
+    assert_eq!(parse_bottle_cap(&[ 0xf0, 0x9f, 0x8d, 0xbc, 0, 7, 0xa0, 0x03 ]), Err(BottleError::BadVersion { found: 0, extra: 7 }));
+    assert_eq!(parse_bottle_cap(&[ 0xf0, 0x9f, 0x8d, 0xbc, 0, 0, 0x20, 0x03 ]), Err(BottleError::UnknownType(2)));
+
+    let e: io::Error = BottleError::UnknownType(2).into();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(e.to_string(), "Unknown bottle type: 2");
+    assert_eq!(BottleError::find(&e), Some(&BottleError::UnknownType(2)));
+    let e = bottle_from_slice(&"f09f8dbc0100a000ff".from_hex()).unwrap_err();
+    assert_eq!(e.to_string(), "Incompatible version: 1, 0");
+  }
+
+  // xorshift, so the "random" input is the same every run.
+  fn random_bytes(seed: &mut u64, n: usize) -> Vec<u8> {
+    (0 .. n).map(|_| {
+      *seed ^= *seed << 13;
+      *seed ^= *seed >> 7;
+      *seed ^= *seed << 17;
+      (*seed >> 24) as u8
+    }).collect()
+  }
+
+  #[test]
+  fn parse_arbitrary_caps() {
+    let mut seed = 0x4b0771e;
+    for _ in 0 .. 100_000 {
+      let mut cap = [ 0u8; 8 ];
+      cap.copy_from_slice(&random_bytes(&mut seed, 8));
+      // a valid magic is too unlikely to show up by chance.
+      if seed % 2 == 0 { cap[0 .. 6].copy_from_slice(&[ 0xf0, 0x9f, 0x8d, 0xbc, 0, 0 ]) }
+      match parse_bottle_cap(&cap) {
+        Ok(( btype, length )) => {
+          assert_eq!(btype as u8, cap[6] >> 4);
+          assert!(length <= 4095);
+        }
+        Err(BottleError::BadMagic) => assert!(cap[0 .. 4] != [ 0xf0, 0x9f, 0x8d, 0xbc ]),
+        Err(BottleError::BadVersion { found: a, extra: b }) => assert!(a != 0 || b != 0),
+        Err(BottleError::UnknownType(n)) => assert!(decode_bottle_type(n).is_err()),
+        Err(e) => panic!("unexpected error: {}", e)
+      }
+    }
+  }
+
+  fn interleaved(children: Vec<Vec<u8>>, max_active: usize) -> Vec<u8> {
+    let streams = children.into_iter().map(|c| make_vec_stream_1(Bytes::from(c)));
+    drain_stream(make_interleaved_bottle(BottleType::Test, &Header::new(), streams, max_active))
+  }
+
+  #[test]
+  fn interleaved_round_trip() {
+    let children = vec![ vec![ 1; 5000 ], vec![], b"hello".to_vec(), (0 .. 3000).map(|i| (i % 251) as u8).collect() ];
+    for max_active in 1 .. 5 {
+      let data = interleaved(children.clone(), max_active);
+      let ( btype, header, streams ) = bottle_from_slice(&data).unwrap();
+      assert_eq!(btype, BottleType::Test);
+      assert_eq!(header.get_number(FIELD_INTERLEAVED), Some(max_active as u64));
+      assert_eq!(streams, children);
+
+      let ( _, _, read ) = read_children(trickle_stream(&data.to_hex())).unwrap();
+      assert_eq!(read, children.iter().map(|c| c.to_hex()).collect::<Vec<String>>());
+    }
+  }
+
+  #[test]
+  fn interleaved_slow_child() {
+    // the first child has nothing until the second is done.
+    let ( tx, rx ) = mpsc::channel::<Vec<Bytes>>(1);
+    let slow = rx.map_err(|_| io::Error::other("closed"));
+    let sender = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(20));
+      tx.send(vec![ Bytes::from_static(b"slow") ]).wait().unwrap();
+    });
+    let fast = make_vec_stream_1(Bytes::from_static(b"fast"));
+    let streams: Vec<Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error> + Send>> = vec![ Box::new(slow), Box::new(fast) ];
+    let data = drain_stream(make_interleaved_bottle(BottleType::Test, &Header::new(), streams, 2));
+    sender.join().unwrap();
+
+    // after the header: all of "fast" (stream 1), and then "slow" (stream 0).
+    let start = 8 + data[7] as usize;
+    assert_eq!(data[start ..].to_hex(), "01046661737401000004736c6f770000ff");
+    let ( _, _, read ) = read_children(hex_stream(&data.to_hex())).unwrap();
+    assert_eq!(read, vec![ "736c6f77", "66617374" ]);
+  }
+
+  #[test]
+  fn interleaved_bad_stream_ids() {
+    let mut header = Header::new();
+    header.add_number(FIELD_INTERLEAVED, 2);
+    let empty = bottle_to_vec(BottleType::Test, &header, vec![]).unwrap();
+    let cap = empty[0 .. empty.len() - 1].to_hex();
+    let read = |frames: &str| {
+      let data = format!("{}{}", cap, frames).as_str().from_hex();
+      read_with_limits(data, DecodeLimits::default())
+    };
+    assert_eq!(read("0001aa0101bb01000000ff").unwrap(), vec![ "aa", "bb" ]);
+    // three open at once
+    assert_eq!(read("0001aa0101bb0201cc").unwrap_err(), BottleError::BadStreamId(2));
+    // a frame after the end
+    assert_eq!(read("00000001aa").unwrap_err(), BottleError::BadStreamId(0));
+    // ending everything while a stream is open
+    assert_eq!(read("0001aaff").unwrap_err(), BottleError::UnexpectedEnd);
+
+    // frames for a stream that isn't being read yet are held, up to a limit.
+    let limits = DecodeLimits { max_buffered_bytes: 2, ..DecodeLimits::default() };
+    let data = format!("{}0101bb0102cccc010000010000ff", cap).as_str().from_hex();
+    let e = read_with_limits(data, limits).unwrap_err();
+    assert_eq!(e, BottleError::DecodeLimitExceeded { limit: DecodeLimit::BufferedBytes, size: 3, max: 2 });
+
+    // skipping ahead past the window, even by a lot.
+    let far = zint::encode_length_to_bytes(1 << 27).unwrap().to_hex();
+    for ( id, frames ) in [ ( 2, "0201aa".to_string() ), ( 1 << 27, format!("{}01aa", far) ) ] {
+      let data = format!("{}{}000000ff", cap, frames).as_str().from_hex();
+      assert_eq!(read(&format!("{}000000ff", frames)).unwrap_err(), BottleError::BadStreamId(id));
+      assert_eq!(BottleError::find(&bottle_from_slice(&data).unwrap_err()), Some(&BottleError::BadStreamId(id)));
+    }
+
+    // ended streams that haven't been read yet are still open.
+    let limits = DecodeLimits { max_open_streams: 2, ..DecodeLimits::default() };
+    let data = format!("{}0001aa010002000000ff", cap).as_str().from_hex();
+    let e = read_with_limits(data, limits).unwrap_err();
+    assert_eq!(e, BottleError::DecodeLimitExceeded { limit: DecodeLimit::OpenStreams, size: 3, max: 2 });
+  }
+
+  #[test]
+  fn interleaved_window_is_capped() {
+    for &window in &[ 0, MAX_INTERLEAVED as u64 + 1, 1 << 40 ] {
+      let mut header = Header::new();
+      header.add_number(FIELD_INTERLEAVED, window);
+      let data = bottle_to_vec(BottleType::Test, &header, vec![]).unwrap();
+      let expected = BottleError::DecodeLimitExceeded { limit: DecodeLimit::OpenStreams, size: window, max: MAX_INTERLEAVED as u64 };
+      assert_eq!(read_with_limits(data.clone(), DecodeLimits::default()).unwrap_err(), expected);
+      assert_eq!(BottleError::find(&bottle_from_slice(&data).unwrap_err()), Some(&expected));
+    }
+
+    // and never written.
+    let data = interleaved(vec![ b"hi".to_vec() ], MAX_INTERLEAVED * 4);
+    let ( _, header, streams ) = bottle_from_slice(&data).unwrap();
+    assert_eq!(header.get_number(FIELD_INTERLEAVED), Some(MAX_INTERLEAVED as u64));
+    assert_eq!(streams, vec![ b"hi".to_vec() ]);
+  }
+
+  #[test]
+  fn read_arbitrary_bottles() {
+    let mut seed = 0xb0771e5;
+    let valid = bottle_to_vec(BottleType::Test, &Header::new(), vec![ vec![ 1, 2, 3 ], vec![] ]).unwrap();
+    for i in 0 .. 20_000 {
+      // garbage after a valid cap, or a valid bottle with a byte changed.
+      let data = if i % 2 == 0 {
+        let mut data = valid[0 .. 8].to_vec();
+        data[7] = (i % 16) as u8;
+        data.extend(random_bytes(&mut seed, i % 64));
+        data
+      } else {
+        let mut data = valid.clone();
+        let r = random_bytes(&mut seed, 2);
+        let n = r[0] as usize % data.len();
+        data[n] = r[1];
+        data.truncate(data.len() - (i % 3));
+        data
+      };
+      let _ = bottle_from_slice(&data);
+      let _ = read_bottle(make_stream(vec![ Bytes::from(data) ])).and_then(|( _, _, children )| {
+        children.and_then(|child| child.collect()).collect()
+      }).wait();
+    }
+  }
 }
 
 
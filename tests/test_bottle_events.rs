@@ -0,0 +1,93 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use futures::{Future, Stream};
+  use lib4bottle::bottle::{BottleType, FIELD_INTERLEAVED, bottle_to_vec};
+  use lib4bottle::bottle_events::{BottleEvent, decode_events};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::error::BottleError;
+  use lib4bottle::stream_helpers::{make_stream};
+  use lib4bottle::to_hex::{ToHex};
+
+  fn describe(events: Vec<BottleEvent>) -> Vec<String> {
+    events.into_iter().map(|e| {
+      match e {
+        BottleEvent::Header(btype, header) => format!("header {:?} {:?}", btype, header.get_number(0)),
+        BottleEvent::StreamStart => "start".to_string(),
+        BottleEvent::Data(b) => format!("data {}", b.to_hex()),
+        BottleEvent::StreamEnd => "end".to_string(),
+        BottleEvent::BottleEnd => "bottle end".to_string()
+      }
+    }).collect()
+  }
+
+  fn sample() -> Vec<u8> {
+    let mut header = Header::new();
+    header.add_number(0, 150);
+    bottle_to_vec(BottleType::Test, &header, vec![ vec![ 1, 2, 3 ], vec![], vec![ 4, 5 ] ]).unwrap()
+  }
+
+  #[test]
+  fn decode_a_bottle() {
+    let events = decode_events(make_stream(vec![ Bytes::from(sample()) ])).collect().wait().unwrap();
+    assert_eq!(describe(events), vec![
+      "header Test Some(150)",
+      "start", "data 010203", "end",
+      "start", "end",
+      "start", "data 0405", "end",
+      "bottle end"
+    ]);
+  }
+
+  #[test]
+  fn decode_a_bottle_in_pieces() {
+    // one byte at a time: frames arrive in pieces too.
+    let data = sample();
+    let events = decode_events(make_stream(data.iter().map(|b| Bytes::from(vec![ *b ])).collect())).collect().wait().unwrap();
+    let events = describe(events);
+    assert_eq!(events[0], "header Test Some(150)");
+    assert_eq!(events.iter().filter(|e| e.starts_with("data")).cloned().collect::<Vec<_>>(), vec![
+      "data 01", "data 02", "data 03", "data 04", "data 05"
+    ]);
+    assert_eq!(events.last().unwrap(), "bottle end");
+  }
+
+  #[test]
+  fn leave_the_remainder() {
+    let mut data = sample();
+    let length = data.len() as u64;
+    data.extend_from_slice(&[ 9, 9, 9 ]);
+    let mut events = decode_events(make_stream(vec![ Bytes::from(data) ]));
+    assert_eq!((&mut events).collect().wait().unwrap().len(), 10);
+    assert_eq!(events.position(), length);
+    assert_eq!(events.into_remainder().concat2().wait().unwrap().to_vec(), vec![ 9, 9, 9 ]);
+  }
+
+  #[test]
+  fn truncated_bottle() {
+    let data = sample();
+    for &end in &[ 4, 10, data.len() - 3, data.len() - 1 ] {
+      let e = decode_events(make_stream(vec![ Bytes::from(data[.. end].to_vec()) ])).collect().wait().unwrap_err();
+      assert_eq!(BottleError::find(&e), Some(&BottleError::TruncatedStream), "end {}", end);
+    }
+  }
+
+  #[test]
+  fn not_a_bottle() {
+    let e = decode_events(make_stream(vec![ Bytes::from_static(b"hello sailor") ])).collect().wait().unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::BadMagic));
+  }
+
+  #[test]
+  fn interleaved_bottle() {
+    let mut header = Header::new();
+    header.add_number(FIELD_INTERLEAVED, 2);
+    let data = bottle_to_vec(BottleType::Test, &header, vec![]).unwrap();
+    let e = decode_events(make_stream(vec![ Bytes::from(data) ])).collect().wait().unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::Interleaved));
+  }
+}
@@ -2,10 +2,12 @@ extern crate lib4bottle;
 
 #[cfg(test)]
 mod tests {
-  // use std::io;
   // use std::io::Seek;
   use lib4bottle::to_hex::{FromHex, ToHex};
-  use lib4bottle::bottle_header::Header;
+  use lib4bottle::bottle_header::{Header, MAX_FIELDS, MAX_FIELD_LENGTH};
+  use lib4bottle::error::BottleError;
+  use lib4bottle::zint;
+  use std::io;
 
   #[test]
   fn pack() {
@@ -21,6 +23,47 @@ mod tests {
     assert_eq!(m.encode().to_hex(), "c400a802e8030c0469726f6e");
   }
 
+  #[test]
+  fn replace_fields() {
+    let mut m = Header::new();
+    m.add_bool(1);
+    m.add_number(10, 1000);
+    m.add_string(3, String::from("iron"));
+    m.set_number(10, 150);
+    assert_eq!(format!("{:?}", m), "Header(B1, N10=150, S3=\"iron\")");
+    assert_eq!(m.encode().to_hex(), "c400a801960c0469726f6e");
+    // same id, different kind: a new field.
+    m.set_string(10, String::from("x"));
+    assert_eq!(format!("{:?}", m), "Header(B1, N10=150, S3=\"iron\", S10=\"x\")");
+    m.set_bool(1);
+    assert_eq!(format!("{:?}", m), "Header(B1, N10=150, S3=\"iron\", S10=\"x\")");
+  }
+
+  #[test]
+  fn replace_repeated_fields() {
+    let mut m = Header::new();
+    m.add_string(0, String::from("alice"));
+    m.add_number(1, 1);
+    m.add_string(0, String::from("bob"));
+    m.set_string(0, String::from("carol"));
+    assert_eq!(format!("{:?}", m), "Header(S0=\"carol\", N1=1)");
+  }
+
+  #[test]
+  fn remove_fields() {
+    let mut m = Header::new();
+    m.add_bool(1);
+    m.add_number(10, 1000);
+    m.add_string(3, String::from("iron"));
+    m.add_string(3, String::from("copper"));
+    m.remove_string(3);
+    assert_eq!(format!("{:?}", m), "Header(B1, N10=1000)");
+    assert_eq!(m.encode().to_hex(), "c400a802e803");
+    m.remove_number(1);
+    m.remove_bool(1);
+    assert_eq!(m.encode().to_hex(), "a802e803");
+  }
+
   #[test]
   fn unpack() {
     assert_eq!(
@@ -37,25 +80,128 @@ mod tests {
     );
     assert_eq!(
       format!("{:?}", Header::decode("3c0d6f6e650074776f007468726565".from_hex().as_ref()).unwrap()),
-      "Header(S15=\"one\\u{0}two\\u{0}three\")"
+      "Header(S15=\"one\\0two\\0three\")"
     );
   }
 
   #[test]
-  #[should_panic(expected="Truncated header")]
+  fn scan_raw_fields() {
+    let mut m = Header::new();
+    m.add_bool(1);
+    m.add_number(3, 1000);
+    m.add_string(3, String::from("iron"));
+    m.add_string(4, String::from("copper"));
+    let buffer = m.encode();
+    assert_eq!(Header::get_raw_string(&buffer, 3), Some(&b"iron"[..]));
+    assert_eq!(Header::get_raw_string(&buffer, 4), Some(&b"copper"[..]));
+    assert_eq!({
+      let raw = Header::get_raw_number(&buffer, 3).unwrap();
+      zint::decode_packed_int(&mut &raw[..], raw.len()).unwrap()
+    }, 1000);
+    assert_eq!(Header::get_raw_string(&buffer, 1), None);
+    assert_eq!(Header::get_raw_number(&buffer, 4), None);
+    // matches the full decode.
+    assert_eq!(format!("{:?}", Header::decode(&buffer).unwrap()), "Header(B1, N3=1000, S3=\"iron\", S4=\"copper\")");
+    // truncated: not found.
+    assert_eq!(Header::get_raw_string(&buffer[0 .. buffer.len() - 1], 4), None);
+  }
+
+  #[test]
+  fn unpack_too_many_fields() {
+    let many = "c400".repeat(MAX_FIELDS + 1).as_str().from_hex();
+    assert_eq!(Header::decode(&many).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert!(Header::decode(&many[0 .. MAX_FIELDS * 2]).is_ok());
+    assert!(Header::decode_with_limit("c400c400c400".from_hex().as_ref(), 3).is_ok());
+    assert!(Header::decode_with_limit("c400c400c400".from_hex().as_ref(), 2).is_err());
+  }
+
+  #[test]
+  #[should_panic(expected="TruncatedHeader")]
   fn unpack_truncated_1() {
     Header::decode("c4".from_hex().as_ref()).unwrap();
   }
 
   #[test]
-  #[should_panic(expected="Truncated header")]
+  #[should_panic(expected="TruncatedHeader")]
   fn unpack_truncated_2() {
     Header::decode("c401".from_hex().as_ref()).unwrap();
   }
 
   #[test]
-  #[should_panic(expected="Truncated header")]
+  #[should_panic(expected="TruncatedHeader")]
   fn unpack_truncated_3() {
     Header::decode("c403ffff".from_hex().as_ref()).unwrap();
   }
+
+  #[test]
+  fn typed_accessors() {
+    let mut m = Header::new();
+    m.add_string(1, "alice");
+    m.add_string(1, String::from("bob"));
+    m.add_number(1, 23);
+    m.add_bool(2);
+    let m = Header::decode(&m.encode()).unwrap();
+    assert_eq!(m.get_string(1), Some("alice"));
+    assert_eq!(m.get_strings(1), vec![ "alice", "bob" ]);
+    assert_eq!(m.get_number(1), Some(23));
+    assert_eq!(m.get_string(2), None);
+    assert!(m.get_bool(2));
+    assert!(!m.get_bool(1));
+  }
+
+  #[test]
+  fn long_string_field() {
+    // lengths use the two low bits of the first byte.
+    for &n in &[ 255, 256, 600, MAX_FIELD_LENGTH ] {
+      let mut m = Header::new();
+      m.add_string(5, "x".repeat(n));
+      let encoded = m.encode();
+      assert_eq!(encoded.len(), n + 2);
+      assert_eq!(Header::decode(&encoded).unwrap().get_string(5).map(|s| s.len()), Some(n));
+    }
+  }
+
+  #[test]
+  #[should_panic]
+  fn too_long_string_field() {
+    Header::new().add_string(5, "x".repeat(MAX_FIELD_LENGTH + 1));
+  }
+
+  #[test]
+  fn unpack_bad_fields() {
+    let e = Header::decode("c40100".from_hex().as_ref()).err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(e.to_string(), "Boolean field has content");
+    let e = Header::decode("a809010203040506070809".from_hex().as_ref()).err().unwrap();
+    assert_eq!(e.to_string(), "Number field is longer than 8 bytes");
+  }
+
+  #[test]
+  fn header_budget() {
+    let mut m = Header::new();
+    assert_eq!(m.remaining(), 4095);
+    m.add_bool(1);
+    m.add_number(2, 500);
+    m.add_string(3, "hello");
+    assert_eq!(m.encoded_size(), m.encode().len());
+    assert_eq!(m.remaining(), 4095 - 13);
+
+    for _ in 0 .. 4 { m.try_add_string(4, "greeting", "x".repeat(1000)).unwrap() }
+    assert_eq!(m.remaining(), 74);
+    let e = m.try_add_string(5, "farewell", "y".repeat(1000)).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::HeaderFull { field: "farewell".to_string(), size: 1002, remaining: 74 }));
+    assert_eq!(e.to_string(), "No room in the header for farewell: it needs 1002 bytes, and 74 are left (limit 4095)");
+    // nothing was added.
+    assert_eq!(m.get_string(5), None);
+
+    m.try_add_number(6, "count", 1 << 40).unwrap();
+    m.try_add_bool(7, "flag").unwrap();
+    assert_eq!(m.remaining(), 74 - 8 - 2);
+  }
+
+  #[test]
+  fn try_too_long_string_field() {
+    let e = Header::new().try_add_string(5, "essay", "x".repeat(MAX_FIELD_LENGTH + 1)).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::HeaderFull { field: "essay".to_string(), size: MAX_FIELD_LENGTH + 3, remaining: 4095 }));
+  }
 }
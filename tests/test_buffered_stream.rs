@@ -5,8 +5,14 @@ extern crate lib4bottle;
 #[cfg(test)]
 mod tests {
   use bytes::Bytes;
-  use lib4bottle::buffered_stream::BufferedStream;
-  use lib4bottle::stream_helpers::{make_stream_2, make_stream_4, string_stream};
+  use futures::{Async, Future, Stream, stream};
+  use std::cell::Cell;
+  use std::io;
+  use std::rc::Rc;
+  use lib4bottle::bottle::{BottleType, make_bottle};
+  use lib4bottle::bottle_header::Header;
+  use lib4bottle::buffered_stream::{BufferedStream, batch_writes};
+  use lib4bottle::stream_helpers::{drain_stream, make_stream_2, make_stream_4, make_vec_stream_1, rate_limit_chunks, string_stream};
 
   #[test]
   fn combine_small_buffers() {
@@ -41,4 +47,134 @@ mod tests {
     let b = BufferedStream::new(s, 5, true);
     assert_eq!(string_stream(b), vec![ "hello", "kitty", "howar", "eyou!" ]);
   }
+
+  #[test]
+  fn slow_source() {
+    let s = make_stream_4(
+      Bytes::from_static(b"hell"),
+      Bytes::from_static(b"ok"),
+      Bytes::from_static(b"it"),
+      Bytes::from_static(b"ty!")
+    );
+    let b = BufferedStream::new(rate_limit_chunks(s, 2), 5, false);
+    assert_eq!(string_stream(b), vec![ "hellok", "itty!" ]);
+  }
+
+  #[test]
+  fn flush_when_idle() {
+    let s = make_stream_4(
+      Bytes::from_static(b"hell"),
+      Bytes::from_static(b"ok"),
+      Bytes::from_static(b"it"),
+      Bytes::from_static(b"ty!")
+    );
+    let b = BufferedStream::new(rate_limit_chunks(s, 1), 5, false).flush_when_idle(true);
+    assert_eq!(string_stream(b), vec![ "hell", "ok", "it", "ty!" ]);
+  }
+
+  #[test]
+  fn exact_ignores_idle() {
+    let s = make_stream_4(
+      Bytes::from_static(b"hell"),
+      Bytes::from_static(b"ok"),
+      Bytes::from_static(b"it"),
+      Bytes::from_static(b"ty!")
+    );
+    let b = BufferedStream::new(rate_limit_chunks(s, 1), 5, true).flush_when_idle(true);
+    assert_eq!(string_stream(b), vec![ "hello", "kitty", "!" ]);
+  }
+
+  #[test]
+  fn exact_frames() {
+    let data: Vec<u8> = (0 .. 1000).map(|i| i as u8).collect();
+    for &size in &[ 1, 3, 7, 64, 100, 999, 1000, 1001 ] {
+      let mut chunks = Vec::new();
+      let mut offset = 0;
+      let mut n = 1;
+      while offset < data.len() {
+        let end = (offset + n).min(data.len());
+        chunks.push(Ok(vec![ Bytes::from(&data[offset .. end]) ]));
+        offset = end;
+        n = n * 3 % 17 + 1;
+      }
+      let frames = BufferedStream::new(stream::iter_result(chunks), size, true).collect().wait().unwrap();
+      let lengths: Vec<usize> = frames.iter().map(|f| f.iter().map(|b| b.len()).sum()).collect();
+      let ( last, whole ) = lengths.split_last().unwrap();
+      assert!(whole.iter().all(|&n| n == size));
+      assert_eq!(*last, (data.len() - 1) % size + 1);
+      let joined: Vec<u8> = frames.iter().flat_map(|f| f.iter().flat_map(|b| b.to_vec())).collect();
+      assert_eq!(joined, data);
+    }
+  }
+
+  #[test]
+  fn flush_at_end() {
+    let s = make_stream_2(Bytes::from_static(b"hell"), Bytes::from_static(b"o"));
+    assert_eq!(string_stream(BufferedStream::new(s, 1024, true)), vec![ "hello" ]);
+  }
+
+  #[test]
+  fn empty_source() {
+    let s = stream::iter_ok::<_, io::Error>(Vec::<Vec<Bytes>>::new());
+    assert_eq!(string_stream(BufferedStream::new(s, 5, true)), Vec::<String>::new());
+  }
+
+  #[test]
+  fn data_before_error() {
+    let s = stream::iter_result(vec![
+      Ok(vec![ Bytes::from_static(b"hello kit") ]),
+      Err(io::Error::other("boom"))
+    ]);
+    let mut b = BufferedStream::new(s, 5, true).wait();
+    assert_eq!(b.next().unwrap().unwrap(), vec![ Bytes::from_static(b"hello") ]);
+    assert_eq!(b.next().unwrap().unwrap(), vec![ Bytes::from_static(b" kit") ]);
+    assert_eq!(b.next().unwrap().unwrap_err().to_string(), "boom");
+    assert!(b.next().is_none());
+  }
+
+  #[test]
+  fn reads_only_what_it_needs() {
+    let pulled = Rc::new(Cell::new(0));
+    let counter = pulled.clone();
+    let s = stream::iter_ok::<_, io::Error>(0 .. 100).map(move |_| {
+      counter.set(counter.get() + 1);
+      vec![ Bytes::from_static(b"abc") ]
+    });
+    let mut b = BufferedStream::new(s, 5, true);
+    match b.poll().unwrap() {
+      Async::Ready(Some(block)) => assert_eq!(block.iter().map(|b| b.len()).sum::<usize>(), 5),
+      _ => panic!("expected a block")
+    }
+    assert_eq!(pulled.get(), 2);
+    // one byte is left over, so two more items make the next block.
+    b.poll().unwrap();
+    assert_eq!(pulled.get(), 4);
+  }
+
+  #[test]
+  fn batch_small_writes() {
+    let s = make_stream_4(
+      Bytes::from_static(b"hell"),
+      Bytes::from_static(b"ok"),
+      Bytes::from_static(b"it"),
+      Bytes::from_static(b"ty!")
+    );
+    // items are merged whole, never split.
+    assert_eq!(string_stream(batch_writes(s, 5)), vec![ "hellok", "itty!" ]);
+  }
+
+  #[test]
+  fn batch_bottle_writes() {
+    let make = || {
+      let streams = (0 .. 10).map(|i| make_vec_stream_1(Bytes::from(vec![ i as u8; 100 ])));
+      make_bottle(BottleType::Test, &Header::new(), streams.collect::<Vec<_>>())
+    };
+    let plain_count = make().collect().wait().unwrap().len();
+    let batched = batch_writes(make(), 512).collect().wait().unwrap();
+    assert!(batched.len() < plain_count);
+    for batch in &batched[0 .. batched.len() - 1] {
+      assert!(batch.iter().fold(0, |sum, b| sum + b.len()) >= 512);
+    }
+    assert_eq!(drain_stream(batch_writes(make(), 512)), drain_stream(make()));
+  }
 }
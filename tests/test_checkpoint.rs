@@ -0,0 +1,116 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+  use common::temp_dir;
+  use futures::{Future, Stream};
+  use lib4bottle::archive::{ArchiveWriter};
+  use lib4bottle::checkpoint::{Checkpoint, CheckpointingWriter};
+  use lib4bottle::error::BottleError;
+  use lib4bottle::hashing::{HashAlgorithm, Hasher};
+  use std::fs;
+  use std::path::{Path, PathBuf};
+
+  fn source(name: &str) -> PathBuf {
+    let source = temp_dir(name);
+    fs::write(source.join("a.txt"), "ay").unwrap();
+    fs::create_dir(source.join("b")).unwrap();
+    fs::write(source.join("b/c.txt"), vec![ 7u8; 5000 ]).unwrap();
+    fs::write(source.join("d.txt"), "hello sailor!".repeat(100)).unwrap();
+    // read everything once, so access times don't change between writes.
+    writer(&source).write(Vec::new(), |_| ()).unwrap();
+    source
+  }
+
+  fn writer(source: &Path) -> CheckpointingWriter {
+    CheckpointingWriter::new().add_path(source.join("a.txt")).add_path(source.join("b")).add_path(source.join("d.txt")).folder_name("stuff")
+  }
+
+  fn sha256(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Hasher::new(HashAlgorithm::Sha256).unwrap();
+    hasher.update(data);
+    hasher.finish().to_vec()
+  }
+
+  #[test]
+  fn write_with_checkpoints() {
+    let source = source("checkpoint-write");
+    let mut data = Vec::new();
+    let mut checkpoints = Vec::new();
+    writer(&source).write(&mut data, |c| checkpoints.push(c.clone())).unwrap();
+
+    let expected = ArchiveWriter::new()
+      .add_path(source.join("a.txt")).add_path(source.join("b")).add_path(source.join("d.txt")).folder_name("stuff")
+      .into_stream().unwrap().collect().wait().unwrap();
+    let expected: Vec<u8> = expected.into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect();
+    assert_eq!(data, expected);
+
+    assert_eq!(checkpoints.iter().map(|c| c.entries).collect::<Vec<_>>(), vec![ 0, 1, 2, 3 ]);
+    for c in checkpoints.iter() {
+      assert_eq!(c.digest.to_vec(), sha256(&data[0 .. c.bytes_written as usize]));
+    }
+    assert_eq!(checkpoints[3].bytes_written, data.len() as u64 - 1);
+    fs::remove_dir_all(&source).unwrap();
+  }
+
+  #[test]
+  fn encode_checkpoint() {
+    let mut checkpoints = Vec::new();
+    let source = source("checkpoint-encode");
+    writer(&source).write(Vec::new(), |c| checkpoints.push(c.clone())).unwrap();
+    for c in checkpoints.iter() {
+      assert_eq!(Checkpoint::decode(&c.encode()).unwrap(), *c);
+    }
+    let e = Checkpoint::decode(&[]).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::BadCheckpoint));
+    fs::remove_dir_all(&source).unwrap();
+  }
+
+  #[test]
+  fn resume_after_interruption() {
+    let source = source("checkpoint-resume");
+    let mut data = Vec::new();
+    let mut checkpoints = Vec::new();
+    writer(&source).write(&mut data, |c| checkpoints.push(c.clone())).unwrap();
+
+    for c in checkpoints.iter() {
+      // the job died partway into the next entry.
+      let filename = source.join("out.4b");
+      fs::write(&filename, &data[0 .. (c.bytes_written as usize + 3).min(data.len())]).unwrap();
+      let mut file = fs::OpenOptions::new().read(true).write(true).open(&filename).unwrap();
+      let mut resumed = Vec::new();
+      writer(&source).resume(&mut file, c, |c| resumed.push(c.clone())).unwrap();
+      assert_eq!(fs::read(&filename).unwrap(), data);
+      assert_eq!(resumed, checkpoints[c.entries + 1 ..].to_vec());
+    }
+    fs::remove_dir_all(&source).unwrap();
+  }
+
+  #[test]
+  fn refuse_to_resume_changed_output() {
+    let source = source("checkpoint-mismatch");
+    let mut data = Vec::new();
+    let mut checkpoints = Vec::new();
+    writer(&source).write(&mut data, |c| checkpoints.push(c.clone())).unwrap();
+
+    let filename = source.join("out.4b");
+    let mut partial = data[0 .. checkpoints[2].bytes_written as usize].to_vec();
+    partial[20] ^= 1;
+    fs::write(&filename, &partial).unwrap();
+    let mut file = fs::OpenOptions::new().read(true).write(true).open(&filename).unwrap();
+    let e = writer(&source).resume(&mut file, &checkpoints[2], |_| ()).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::CheckpointMismatch));
+    assert_eq!(fs::read(&filename).unwrap(), partial);
+
+    // too short:
+    fs::write(&filename, &data[0 .. 10]).unwrap();
+    let mut file = fs::OpenOptions::new().read(true).write(true).open(&filename).unwrap();
+    let e = writer(&source).resume(&mut file, &checkpoints[2], |_| ()).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::CheckpointMismatch));
+    fs::remove_dir_all(&source).unwrap();
+  }
+}
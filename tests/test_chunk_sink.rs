@@ -0,0 +1,90 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use futures::{Future, Stream, stream};
+  use lib4bottle::bottle::{BottleType, bottle_from_slice, make_bottle};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::chunk_sink::{ChunkSink, upload_parts};
+  use lib4bottle::stream_helpers::{make_vec_stream_1};
+  use std::cell::Cell;
+  use std::io;
+  use std::rc::Rc;
+
+  // keeps every part, and can be told to fail one.
+  #[derive(Default)]
+  struct MemorySink {
+    parts: Vec<( usize, Vec<u8> )>,
+    fail_part: Option<usize>,
+    completed: bool,
+    aborted: Rc<Cell<bool>>
+  }
+
+  impl ChunkSink for MemorySink {
+    fn upload_part(&mut self, part_number: usize, data: Vec<Bytes>) -> io::Result<()> {
+      if self.fail_part == Some(part_number) { return Err(io::Error::other("nope")) }
+      self.parts.push(( part_number, data.iter().flat_map(|b| b.to_vec()).collect() ));
+      Ok(())
+    }
+
+    fn complete(&mut self) -> io::Result<()> {
+      self.completed = true;
+      Ok(())
+    }
+
+    fn abort(&mut self) {
+      self.aborted.set(true);
+    }
+  }
+
+  fn bottle(count: usize) -> impl Stream<Item = Vec<Bytes>, Error = io::Error> {
+    let streams = (0 .. count).map(|i| make_vec_stream_1(Bytes::from(vec![ i as u8; 1000 ])));
+    make_bottle(BottleType::Test, &Header::new(), streams.collect::<Vec<_>>())
+  }
+
+  #[test]
+  fn upload_in_parts() {
+    let ( sink, count ) = upload_parts(bottle(10), MemorySink::default(), 2500).wait().unwrap();
+    assert!(sink.completed);
+    assert!(!sink.aborted.get());
+    assert_eq!(count, sink.parts.len());
+    assert_eq!(sink.parts.iter().map(|&( n, _ )| n).collect::<Vec<_>>(), (1 ..= count).collect::<Vec<_>>());
+    assert!(count > 2);
+
+    // every part but the last is at least the part size.
+    let sizes: Vec<usize> = sink.parts.iter().map(|( _, data )| data.len()).collect();
+    assert!(sizes[.. count - 1].iter().all(|&size| size >= 2500));
+
+    let data: Vec<u8> = sink.parts.into_iter().flat_map(|( _, data )| data).collect();
+    let ( _, _, streams ) = bottle_from_slice(&data).unwrap();
+    assert_eq!(streams.len(), 10);
+    assert_eq!(streams[9], vec![ 9; 1000 ]);
+  }
+
+  #[test]
+  fn empty_stream_is_one_part() {
+    let ( sink, count ) = upload_parts(stream::empty(), MemorySink::default(), 100).wait().unwrap();
+    assert_eq!(count, 1);
+    assert_eq!(sink.parts, vec![ ( 1, vec![] ) ]);
+    assert!(sink.completed);
+  }
+
+  #[test]
+  fn abort_on_failure() {
+    let sink = MemorySink { fail_part: Some(2), ..MemorySink::default() };
+    let aborted = sink.aborted.clone();
+    let e = upload_parts(bottle(10), sink, 2500).wait().err().unwrap();
+    assert_eq!(e.to_string(), "nope");
+    assert!(aborted.get());
+
+    let sink = MemorySink::default();
+    let aborted = sink.aborted.clone();
+    let failing = stream::iter_result(vec![ Ok(vec![ Bytes::from("hello") ]), Err(io::Error::other("broken")) ]);
+    let e = upload_parts(failing, sink, 2).wait().err().unwrap();
+    assert_eq!(e.to_string(), "broken");
+    assert!(aborted.get());
+  }
+}
@@ -0,0 +1,102 @@
+#![cfg(feature = "cli")]
+
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+  use common::temp_dir;
+  use lib4bottle::bottle::{BottleType, bottle_to_vec};
+  use lib4bottle::file_bottle::FileMetadata;
+  use std::env;
+  use std::fs;
+  use std::path::PathBuf;
+  use std::process::{Command, Output};
+
+  fn run(command: &str, args: &[&str]) -> Output {
+    let output = Command::new(command).args(args).env_remove("BOTTLE_PASSWORD").output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    output
+  }
+
+  fn source_tree(name: &str) -> PathBuf {
+    let source = temp_dir(name);
+    let root = source.join("stuff");
+    fs::create_dir_all(root.join("inner")).unwrap();
+    fs::write(root.join("a.txt"), "ay").unwrap();
+    fs::write(root.join("inner").join("c.txt"), "sea".repeat(1000)).unwrap();
+    source
+  }
+
+  #[test]
+  fn pack_list_unpack() {
+    let source = source_tree("cli");
+    let archive = source.join("stuff.4b");
+    let target = source.join("out");
+    let archive = archive.to_str().unwrap();
+    let stuff = source.join("stuff");
+    run(env!("CARGO_BIN_EXE_4pack"), &[ "-H", "sha256", "-c", "zstd", "-e", "-p", "hunter2", "-P", "4:2", "-o", archive, stuff.to_str().unwrap() ]);
+
+    let output = run(env!("CARGO_BIN_EXE_4ls"), &[ "-p", "hunter2", archive ]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "stuff/\nstuff/a.txt\nstuff/inner/\nstuff/inner/c.txt\n");
+    let output = run(env!("CARGO_BIN_EXE_4ls"), &[ "-l", "-p", "hunter2", archive ]);
+    assert!(String::from_utf8(output.stdout).unwrap().starts_with("layers: Parity > Encrypted > Compressed > Hashed > File\nSha256: "));
+    let output = run(env!("CARGO_BIN_EXE_4ls"), &[ "--json", "-p", "hunter2", archive ]);
+    let lines: Vec<String> = String::from_utf8(output.stdout).unwrap().lines().map(|line| line.to_string()).collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[1].starts_with("{\"path\":\"stuff/a.txt\",\"metadata\":{\"filename\":\"a.txt\""), "{}", lines[1]);
+
+    run(env!("CARGO_BIN_EXE_4unpack"), &[ "-p", "hunter2", "-C", target.to_str().unwrap(), archive ]);
+    assert_eq!(fs::read(target.join("stuff").join("a.txt")).unwrap(), b"ay");
+    assert_eq!(fs::read(target.join("stuff").join("inner").join("c.txt")).unwrap(), "sea".repeat(1000).into_bytes());
+    fs::remove_dir_all(&source).unwrap();
+  }
+
+  #[test]
+  fn missing_password() {
+    let source = source_tree("cli-password");
+    let archive = source.join("stuff.4b");
+    let archive = archive.to_str().unwrap();
+    run(env!("CARGO_BIN_EXE_4pack"), &[ "-e", "-p", "hunter2", "-o", archive, source.join("stuff").to_str().unwrap() ]);
+    let output = Command::new(env!("CARGO_BIN_EXE_4ls")).arg(archive).env_remove("BOTTLE_PASSWORD").output().unwrap();
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "4ls: Archive is encrypted, but no key was given\n");
+    fs::remove_dir_all(&source).unwrap();
+  }
+
+  #[test]
+  fn unpack_stays_inside_target() {
+    let source = temp_dir("cli-escape");
+    let outside = source.join("slip-outside");
+    let target = source.join("out");
+    fs::create_dir_all(&target).unwrap();
+
+    // a symlink out of the target, and then a folder of the same name.
+    let entry = |metadata: FileMetadata, children: Vec<Vec<u8>>| bottle_to_vec(BottleType::File, &metadata.to_header(), children).unwrap();
+    let pwned = FileMetadata { filename: "pwned".to_string(), size: Some(5), ..FileMetadata::default() };
+    let link = FileMetadata {
+      filename: "evil".to_string(),
+      symlink: Some(outside.to_str().unwrap().to_string()),
+      ..FileMetadata::default()
+    };
+    let folder = |name: &str| FileMetadata { filename: name.to_string(), folder: true, ..FileMetadata::default() };
+    let evil = entry(folder("evil"), vec![ entry(pwned, vec![ b"pwned".to_vec() ]) ]);
+    let data = entry(folder("stuff"), vec![ entry(link, vec![]), evil ]);
+    let archive = source.join("evil.4b");
+    fs::write(&archive, data).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_4unpack"))
+      .args([ "-C", target.to_str().unwrap(), archive.to_str().unwrap() ])
+      .env_remove("BOTTLE_PASSWORD")
+      .output()
+      .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().starts_with("4unpack: "));
+    assert!(fs::symlink_metadata(&outside).is_err());
+    assert!(fs::symlink_metadata(target.join("stuff/evil")).is_err());
+    fs::remove_dir_all(&source).unwrap();
+  }
+}
@@ -0,0 +1,228 @@
+extern crate bytes;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate lib4bottle;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use futures::{Future, Stream};
+  use futures_cpupool::CpuPool;
+  use lib4bottle::bottle::{BottleType, DecodeLimits, bottle_from_slice, bottle_to_vec};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::compressed_bottle::{
+    ByteStream, Codec, CompressOptions, CompressionType, compress_bottle, compress_bottle_parallel, compress_bottle_with_options,
+    decompress_bottle, decompress_bottle_parallel, find_codec, register_codec
+  };
+  use lib4bottle::error::{BottleError, DecodeLimit};
+  use lib4bottle::stream_helpers::{make_stream, make_vec_stream_1};
+  use std::io;
+
+  fn compressed(data: &[u8], compression_type: CompressionType) -> Vec<u8> {
+    let s = compress_bottle(make_vec_stream_1(Bytes::from(data)), compression_type).unwrap();
+    s.collect().wait().unwrap().into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect()
+  }
+
+  fn decompressed(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let s = make_stream(data.chunks(100).map(Bytes::from).collect());
+    decompress_bottle(s).and_then(|( _, s )| s.collect()).map(|chunks| chunks.concat()).wait()
+  }
+  fn decompressed_parallel(data: &[u8], in_flight: usize, limits: DecodeLimits) -> io::Result<Vec<u8>> {
+    let s = make_stream(data.chunks(1000).map(Bytes::from).collect());
+    let pool = CpuPool::new(in_flight);
+    decompress_bottle_parallel(s, &pool, in_flight, limits).and_then(|( _, s )| s.collect()).map(|chunks| chunks.concat()).wait()
+  }
+
+
+  #[test]
+  fn write_compressed_bottle() {
+    let plaintext = vec![ b'x'; 10000 ];
+    for &compression_type in &[ CompressionType::Lzma2, CompressionType::Snappy, CompressionType::Zstd ] {
+      let data = compressed(&plaintext, compression_type);
+      let ( btype, header, streams ) = bottle_from_slice(&data).unwrap();
+      assert_eq!(btype, BottleType::Compressed);
+      assert_eq!(header.get_number(0), Some(compression_type.id() as u64));
+      assert_eq!(streams.len(), 1);
+      assert!(streams[0].len() < 1000);
+    }
+  }
+
+  #[test]
+  fn round_trip() {
+    for &compression_type in &[ CompressionType::Lzma2, CompressionType::Snappy, CompressionType::Zstd ] {
+      for &size in &[ 0, 13, 200000 ] {
+        let plaintext: Vec<u8> = (0 .. size).map(|i| ((i / 7) % 251) as u8).collect();
+        assert_eq!(decompressed(compressed(&plaintext, compression_type)).unwrap(), plaintext);
+      }
+    }
+  }
+
+  fn compressed_with(data: &[u8], options: &CompressOptions) -> io::Result<Vec<u8>> {
+    let s = compress_bottle_with_options(make_vec_stream_1(Bytes::from(data)), options)?;
+    Ok(s.collect().wait().unwrap().into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect())
+  }
+
+  #[test]
+  fn round_trip_with_options() {
+    let plaintext: Vec<u8> = (0 .. 200000).map(|i| ((i / 7) % 251) as u8).collect();
+    let options = vec![
+      CompressOptions::new(CompressionType::Lzma2).level(0),
+      CompressOptions::new(CompressionType::Lzma2).level(9),
+      CompressOptions::new(CompressionType::Zstd).level(1),
+      CompressOptions::new(CompressionType::Zstd).level(19),
+      CompressOptions::new(CompressionType::Zstd).level(3).long_distance(true)
+    ];
+    for options in options {
+      let data = compressed_with(&plaintext, &options).unwrap();
+      // recorded, for rebuilding the bottle the same way.
+      assert_eq!(CompressOptions::from_header(&bottle_from_slice(&data).unwrap().1).unwrap(), options);
+      assert_eq!(decompressed(data).unwrap(), plaintext, "{:?}", options);
+    }
+  }
+
+  #[test]
+  fn bad_compression_level() {
+    let options = vec![
+      ( CompressOptions::new(CompressionType::Lzma2).level(10), 10 ),
+      ( CompressOptions::new(CompressionType::Snappy).level(1), 1 ),
+      ( CompressOptions::new(CompressionType::Zstd).level(0), 0 ),
+      ( CompressOptions::new(CompressionType::Zstd).level(23), 23 )
+    ];
+    for ( options, level ) in options {
+      let e = compressed_with(b"hello", &options).err().unwrap();
+      assert_eq!(BottleError::find(&e), Some(&BottleError::BadCompressionLevel(level)));
+    }
+  }
+
+  #[test]
+  fn round_trip_parallel() {
+    // several blocks, with a partial one at the end.
+    let plaintext: Vec<u8> = (0 .. 3_500_000).map(|i| ((i / 7) % 251) as u8).collect();
+    let chunks: Vec<Vec<Bytes>> = plaintext.chunks(100_000).map(|c| vec![ Bytes::from(c) ]).collect();
+    for &compression_type in &[ CompressionType::Lzma2, CompressionType::Snappy, CompressionType::Zstd ] {
+      let options = CompressOptions::new(compression_type);
+      let s = compress_bottle_parallel(::futures::stream::iter_ok(chunks.clone()), &options, 4).unwrap();
+      let data: Vec<u8> = s.collect().wait().unwrap().into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect();
+      let ( _, header, streams ) = bottle_from_slice(&data).unwrap();
+      assert_eq!(header.get_number(1), Some(1024 * 1024));
+      assert_eq!(streams.len(), 4);
+
+      assert_eq!(decompressed(data.clone()).unwrap(), plaintext);
+      assert_eq!(decompressed_parallel(&data, 3, DecodeLimits::default()).unwrap(), plaintext);
+    }
+  }
+
+  #[test]
+  fn decompress_single_stream_in_parallel() {
+    let plaintext: Vec<u8> = (0 .. 200000).map(|i| ((i / 7) % 251) as u8).collect();
+    let data = compressed(&plaintext, CompressionType::Zstd);
+    assert_eq!(decompressed_parallel(&data, 3, DecodeLimits::default()).unwrap(), plaintext);
+  }
+
+  #[test]
+  fn parallel_block_limits() {
+    // one block that decompresses to more than the size in the header.
+    let ( btype, mut header, streams ) = bottle_from_slice(&compressed(b"hello sailor!", CompressionType::Zstd)).unwrap();
+    header.add_number(1, 10);
+    let data = bottle_to_vec(btype, &header, streams).unwrap();
+    let e = decompressed_parallel(&data, 2, DecodeLimits::default()).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::DecodeLimitExceeded { limit: DecodeLimit::BufferedBytes, size: 13, max: 10 }));
+
+    let limits = DecodeLimits { max_buffered_bytes: 5, ..DecodeLimits::default() };
+    let e = decompressed_parallel(&data, 2, limits).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::DecodeLimitExceeded { limit: DecodeLimit::BufferedBytes, size: 10, max: 5 }));
+  }
+
+  #[test]
+  fn parallel_checks_level() {
+    let options = CompressOptions::new(CompressionType::Zstd).level(30);
+    let e = compress_bottle_parallel(make_vec_stream_1(Bytes::from("hello")), &options, 2).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::BadCompressionLevel(30)));
+  }
+
+  #[test]
+  fn truncated_stream() {
+    let plaintext: Vec<u8> = (0 .. 200000).map(|i| ((i * 7) % 251) as u8).collect();
+    for &compression_type in &[ CompressionType::Lzma2, CompressionType::Snappy, CompressionType::Zstd ] {
+      let ( _, header, streams ) = bottle_from_slice(&compressed(&plaintext, compression_type)).unwrap();
+      let n = streams[0].len() / 2;
+      let short = bottle_to_vec(BottleType::Compressed, &header, vec![ streams[0][0 .. n].to_vec() ]).unwrap();
+      assert_eq!(decompressed(short).err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+    }
+  }
+
+  #[test]
+  fn unknown_compression_type() {
+    let mut header = Header::new();
+    header.add_number(0, 9);
+    let data = bottle_to_vec(BottleType::Compressed, &header, vec![ vec![ 1 ] ]).unwrap();
+    assert_eq!(decompressed(data).err().unwrap().to_string(), "Unknown compression type: 9");
+  }
+
+  // "compresses" by flipping every bit.
+  struct InvertCodec;
+
+  impl Codec for InvertCodec {
+    fn id(&self) -> u8 { 40 }
+    fn name(&self) -> &str { "invert" }
+
+    fn wrap_encode<'a>(&self, s: ByteStream<'a>, options: &CompressOptions) -> io::Result<ByteStream<'a>> {
+      if let Some(n) = options.level { return Err(BottleError::BadCompressionLevel(n).into()) }
+      self.wrap_decode(s)
+    }
+
+    fn wrap_decode<'a>(&self, s: ByteStream<'a>) -> io::Result<ByteStream<'a>> {
+      Ok(Box::new(s.map(|b| Bytes::from(b.iter().map(|n| !n).collect::<Vec<u8>>()))))
+    }
+  }
+
+  #[test]
+  fn custom_codec() {
+    let compression_type = register_codec(InvertCodec).unwrap();
+    assert_eq!(compression_type, CompressionType::Custom(40));
+    assert_eq!(find_codec("invert"), Some(compression_type));
+    assert_eq!(compression_type.codec().unwrap().name(), "invert");
+
+    let data = compressed(b"hello", compression_type);
+    let ( _, header, streams ) = bottle_from_slice(&data).unwrap();
+    assert_eq!(header.get_number(0), Some(40));
+    assert_eq!(streams[0], vec![ !b'h', !b'e', !b'l', !b'l', !b'o' ]);
+    assert_eq!(decompressed(data).unwrap(), b"hello");
+
+    let plaintext: Vec<u8> = (0 .. 3_000_000).map(|i| ((i * 7) % 251) as u8).collect();
+    let options = CompressOptions::new(compression_type);
+    let s = compress_bottle_parallel(make_vec_stream_1(Bytes::from(plaintext.clone())), &options, 2).unwrap();
+    let data: Vec<u8> = s.collect().wait().unwrap().into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect();
+    assert_eq!(decompressed_parallel(&data, 2, DecodeLimits::default()).unwrap(), plaintext);
+
+    let e = compress_bottle_with_options(make_vec_stream_1(Bytes::new()), &CompressOptions::new(compression_type).level(1)).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::BadCompressionLevel(1)));
+  }
+
+  #[test]
+  fn built_in_codecs() {
+    assert_eq!(find_codec("lzma2"), Some(CompressionType::Lzma2));
+    assert_eq!(find_codec("snappy"), Some(CompressionType::Snappy));
+    assert_eq!(find_codec("zstd"), Some(CompressionType::Zstd));
+    assert_eq!(find_codec("brotli"), None);
+    assert_eq!(CompressionType::Zstd.codec().unwrap().id(), 2);
+    assert!(CompressionType::Custom(99).codec().is_err());
+    let e = compress_bottle(make_vec_stream_1(Bytes::from_static(b"hi")), CompressionType::Custom(99)).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::UnknownCompressionType(99)));
+  }
+
+  #[test]
+  fn reserved_codec_id() {
+    struct Squatter;
+
+    impl Codec for Squatter {
+      fn id(&self) -> u8 { 2 }
+      fn name(&self) -> &str { "zstd" }
+      fn wrap_encode<'a>(&self, s: ByteStream<'a>, _: &CompressOptions) -> io::Result<ByteStream<'a>> { Ok(s) }
+      fn wrap_decode<'a>(&self, s: ByteStream<'a>) -> io::Result<ByteStream<'a>> { Ok(s) }
+    }
+
+    let e = register_codec(Squatter).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::ReservedCompressionType(2)));
+  }
+}
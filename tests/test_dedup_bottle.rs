@@ -0,0 +1,73 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use futures::{Future, Stream, stream};
+  use lib4bottle::bottle::{BottleType, bottle_from_slice, bottle_to_vec};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::dedup_bottle::{dedup_bottle, reassemble_bottle};
+  use lib4bottle::error::BottleError;
+  use std::io;
+
+  // not very random, but random enough that it doesn't dedup itself.
+  fn noise(size: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed;
+    (0 .. size).map(|_| {
+      state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+      (state >> 24) as u8
+    }).collect()
+  }
+
+  fn dedup(data: &[u8], chunk_size: usize) -> Vec<u8> {
+    let chunks: Vec<Vec<Bytes>> = data.chunks(chunk_size).map(|c| vec![ Bytes::from(c) ]).collect();
+    let buffers = dedup_bottle(stream::iter_ok::<_, io::Error>(chunks)).collect().wait().unwrap();
+    buffers.into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect()
+  }
+
+  fn reassemble(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let s = stream::iter_ok::<_, io::Error>(data.chunks(1000).map(Bytes::from).collect::<Vec<_>>());
+    reassemble_bottle(s).and_then(|( _, s )| s.concat2()).wait().map(|b| b.to_vec())
+  }
+
+  #[test]
+  fn round_trip() {
+    for &size in [ 0, 5, 3000, 100_000 ].iter() {
+      let data = noise(size, 1);
+      let deduped = dedup(&data, 777);
+      assert_eq!(bottle_from_slice(&deduped).unwrap().0, BottleType::Dedup);
+      assert_eq!(reassemble(deduped).unwrap(), data);
+    }
+  }
+
+  #[test]
+  fn store_repeats_once() {
+    let a = noise(200_000, 1);
+    let mut data = a.clone();
+    // a shifted copy still has mostly the same chunks.
+    data.extend(noise(1234, 2));
+    data.extend(&a);
+    data.extend(&a[5000..]);
+    let deduped = dedup(&data, 4096);
+    assert!(deduped.len() < 250_000, "{}", deduped.len());
+    assert_eq!(reassemble(deduped).unwrap(), data);
+  }
+
+  #[test]
+  fn chunks_dont_depend_on_buffers() {
+    let data = noise(100_000, 3);
+    assert_eq!(dedup(&data, 1), dedup(&data, 100_000));
+  }
+
+  #[test]
+  fn bad_records() {
+    let e = reassemble(bottle_to_vec(BottleType::Dedup, &Header::new(), vec![ vec![ 1, 3 ] ]).unwrap()).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::BadChunkReference(3)));
+    let e = reassemble(bottle_to_vec(BottleType::Dedup, &Header::new(), vec![ vec![ 9 ] ]).unwrap()).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::BadChunkRecord));
+    let e = reassemble(bottle_to_vec(BottleType::Test, &Header::new(), vec![]).unwrap()).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::WrongType { expected: BottleType::Dedup, found: BottleType::Test }));
+  }
+}
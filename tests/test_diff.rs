@@ -0,0 +1,91 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use futures::{Future, Stream};
+  use lib4bottle::archive::{ArchiveReader, ArchiveWriter};
+  use lib4bottle::compressed_bottle::{CompressionType};
+  use lib4bottle::diff::{Change, DiffEntry, diff_archives, diff_bottles};
+  use lib4bottle::encrypted_bottle::{KeySource};
+  use lib4bottle::stream_helpers::{make_stream};
+  use std::fs;
+  use std::io;
+  use std::path::{Path, PathBuf};
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let path = ::common::temp_dir(&format!("diff-{}", name));
+    fs::create_dir_all(path.join("stuff").join("inner")).unwrap();
+    path
+  }
+
+  fn archive(writer: ArchiveWriter, path: &Path) -> Bytes {
+    let s = writer.add_path(path).into_stream().unwrap();
+    Bytes::from(s.collect().wait().unwrap().into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect::<Vec<u8>>())
+  }
+
+  // folders' modified times change along with their contents, so they're
+  // left out.
+  fn changes<S: Stream<Item = DiffEntry, Error = io::Error>>(s: S) -> Vec<( String, Change )> {
+    s.collect().wait().unwrap().into_iter().filter(|d| !d.new.as_ref().or(d.old.as_ref()).unwrap().folder).map(|d| ( d.path.to_string_lossy().to_string(), d.change )).collect()
+  }
+
+  #[test]
+  fn diff_snapshots() {
+    let source = temp_dir("snapshots");
+    let stuff = source.join("stuff");
+    fs::write(stuff.join("a.txt"), "ay").unwrap();
+    fs::write(stuff.join("b.txt"), "bee").unwrap();
+    fs::write(stuff.join("inner").join("c.txt"), "sea").unwrap();
+    fs::write(stuff.join("same.txt"), "same").unwrap();
+    let before = archive(ArchiveWriter::new(), &stuff);
+
+    fs::write(stuff.join("a.txt"), "ayy").unwrap();
+    fs::remove_file(stuff.join("b.txt")).unwrap();
+    fs::write(stuff.join("d.txt"), "dee").unwrap();
+    let mut permissions = fs::metadata(stuff.join("inner").join("c.txt")).unwrap().permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(stuff.join("inner").join("c.txt"), permissions).unwrap();
+    let after = archive(ArchiveWriter::new().compress(CompressionType::Snappy), &stuff);
+
+    let diff = diff_bottles(make_stream(vec![ before.clone() ]), make_stream(vec![ after ]));
+    assert_eq!(changes(diff), vec![
+      ( "stuff/a.txt".to_string(), Change::Modified ),
+      ( "stuff/b.txt".to_string(), Change::Removed ),
+      ( "stuff/d.txt".to_string(), Change::Added ),
+      ( "stuff/inner/c.txt".to_string(), Change::MetadataOnly )
+    ]);
+
+    // an archive against itself has no changes.
+    let diff = diff_bottles(make_stream(vec![ before.clone() ]), make_stream(vec![ before ]));
+    assert_eq!(changes(diff), vec![]);
+    fs::remove_dir_all(&source).unwrap();
+  }
+
+  #[test]
+  fn diff_encrypted() {
+    let source = temp_dir("encrypted");
+    let stuff = source.join("stuff");
+    fs::write(stuff.join("a.txt"), "ay").unwrap();
+    let key = || KeySource::Passphrase("hunter2".to_string());
+    let before = archive(ArchiveWriter::new().encrypt(key(), vec![]), &stuff);
+    fs::create_dir_all(stuff.join("new")).unwrap();
+    let after = archive(ArchiveWriter::new(), &stuff);
+
+    let reader = ArchiveReader::new().with_key(move |_| Ok(key()));
+    let diff = diff_archives(( reader, make_stream(vec![ before.clone() ]) ), ( ArchiveReader::new(), make_stream(vec![ after.clone() ]) ));
+    let entries: Vec<DiffEntry> = diff.collect().wait().unwrap().into_iter().filter(|d| d.path != Path::new("stuff")).collect();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].path, PathBuf::from("stuff/new"));
+    assert_eq!(entries[0].change, Change::Added);
+    assert!(entries[0].old.is_none() && entries[0].new.as_ref().unwrap().folder);
+
+    // without a key, it's an error.
+    assert!(diff_bottles(make_stream(vec![ before ]), make_stream(vec![ after ])).collect().wait().is_err());
+    fs::remove_dir_all(&source).unwrap();
+  }
+}
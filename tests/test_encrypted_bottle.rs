@@ -0,0 +1,352 @@
+extern crate aes_gcm;
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+#[cfg(test)]
+mod tests {
+  use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+  use aes_gcm::aead::Aead;
+  use bytes::Bytes;
+  use futures::{Future, Stream};
+  use lib4bottle::bottle::{BottleType, bottle_from_slice, bottle_to_vec};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::error::BottleError;
+  use lib4bottle::encrypted_bottle::{
+    Cipher, CipherKey, EncryptionInfo, EncryptionType, KeySource, decrypt_bottle, encrypt_bottle, encrypt_bottle_with_cipher, find_cipher,
+    generate_key_pair, register_cipher
+  };
+  use lib4bottle::to_hex::{FromHex, ToHex};
+  use lib4bottle::stream_helpers::{make_stream, make_vec_stream_1};
+  use std::io;
+
+  fn key() -> KeySource {
+    KeySource::Raw((0 .. 32).collect())
+  }
+
+  fn encrypted(data: &[u8], key: KeySource) -> Vec<u8> {
+    let s = encrypt_bottle(make_vec_stream_1(Bytes::from(data)), key, vec![ "alice".to_string(), "bob".to_string() ]).unwrap();
+    s.collect().wait().unwrap().into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect()
+  }
+
+  fn decrypted(data: Vec<u8>, key: KeySource) -> io::Result<Vec<u8>> {
+    let s = make_stream(data.chunks(1000).map(Bytes::from).collect());
+    decrypt_bottle(s, move |_| Ok(key)).and_then(|( _, s )| s.collect()).map(|chunks| {
+      chunks.into_iter().flat_map(|b| b.to_vec()).collect()
+    }).wait()
+  }
+
+  #[test]
+  fn write_encrypted_bottle() {
+    let data = encrypted(b"hello sailor!", key());
+    let ( btype, header, streams ) = bottle_from_slice(&data).unwrap();
+    assert_eq!(btype, BottleType::Encrypted);
+    assert_eq!(header.get_number(0), Some(0));
+    assert_eq!(header.get_strings(0), vec![ "alice", "bob" ]);
+    assert_eq!(streams.len(), 1);
+    assert_eq!(streams[0].len(), 13 + 16);
+    assert!(!data.windows(6).any(|w| w == b"sailor"));
+  }
+
+  #[test]
+  fn round_trip() {
+    // empty, one partial segment, exactly one segment, and several.
+    for &size in &[ 0, 13, 65536, 200000 ] {
+      let plaintext: Vec<u8> = (0 .. size).map(|i| (i % 251) as u8).collect();
+      assert_eq!(decrypted(encrypted(&plaintext, key()), key()).unwrap(), plaintext);
+    }
+  }
+
+  #[test]
+  fn round_trip_with_passphrase() {
+    let data = encrypted(b"hello sailor!", KeySource::Passphrase("correct horse".to_string()));
+    let mut seen: Option<EncryptionInfo> = None;
+    let s = make_stream(vec![ Bytes::from(data.clone()) ]);
+    let plaintext = decrypt_bottle(s, |info| {
+      seen = Some(info.clone());
+      Ok(KeySource::Passphrase("correct horse".to_string()))
+    }).and_then(|( _, s )| s.collect()).wait().unwrap();
+    assert_eq!(plaintext.concat(), b"hello sailor!".to_vec());
+    assert_eq!(seen, Some(EncryptionInfo {
+      encryption_type: EncryptionType::Aes256Gcm,
+      recipients: vec![ "alice".to_string(), "bob".to_string() ],
+      passphrase: true,
+      wrapped_keys: 0
+    }));
+
+    let e = decrypted(data, KeySource::Passphrase("wrong".to_string())).err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn passphrase_uses_argon2id() {
+    let data = encrypted(b"hello sailor!", KeySource::Passphrase("correct horse".to_string()));
+    let ( _, header, _ ) = bottle_from_slice(&data).unwrap();
+    assert_eq!(header.get_number(3), Some(1));
+    assert_eq!(header.get_number(1), Some(2));
+    assert_eq!(header.get_number(4), Some(19 * 1024));
+    assert_eq!(header.get_number(5), Some(1));
+    assert_eq!(header.get_string(1).map(|s| s.len()), Some(32));
+  }
+
+  #[test]
+  fn refuse_bad_kdf_parameters() {
+    let data = encrypted(b"hello sailor!", KeySource::Passphrase("correct horse".to_string()));
+    let ( _, header, streams ) = bottle_from_slice(&data).unwrap();
+    let cases = vec![
+      ( 4, 1 << 40, BottleError::BadKdfParameters ),
+      ( 4, 1, BottleError::BadKdfParameters ),
+      ( 1, 1000, BottleError::BadKdfParameters ),
+      ( 1, 0, BottleError::BadKdfParameters ),
+      ( 5, 0, BottleError::BadKdfParameters ),
+      ( 3, 9, BottleError::UnknownKdf(9) )
+    ];
+    for ( field, value, expected ) in cases {
+      let mut h = header.clone();
+      h.set_number(field, value);
+      let bad = bottle_to_vec(BottleType::Encrypted, &h, streams.clone()).unwrap();
+      let e = decrypted(bad, KeySource::Passphrase("correct horse".to_string())).err().unwrap();
+      assert_eq!(BottleError::find(&e), Some(&expected), "field {} = {}", field, value);
+    }
+  }
+
+  #[test]
+  fn round_trip_with_public_keys() {
+    let ( alice, alice_public ) = generate_key_pair();
+    let ( bob, bob_public ) = generate_key_pair();
+    let ( eve, _ ) = generate_key_pair();
+    let plaintext: Vec<u8> = (0 .. 100_000).map(|i| (i % 251) as u8).collect();
+    let data = encrypted(&plaintext, KeySource::PublicKeys(vec![ alice_public, bob_public ]));
+    assert_eq!(decrypted(data.clone(), KeySource::PrivateKey(alice)).unwrap(), plaintext);
+    assert_eq!(decrypted(data.clone(), KeySource::PrivateKey(bob)).unwrap(), plaintext);
+
+    let e = decrypted(data.clone(), KeySource::PrivateKey(eve)).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::NoMatchingKey));
+    let e = decrypted(data.clone(), KeySource::Passphrase("correct horse".to_string())).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::NoPassphrase));
+
+    let mut seen: Option<EncryptionInfo> = None;
+    let s = make_stream(vec![ Bytes::from(data) ]);
+    decrypt_bottle(s, |info| {
+      seen = Some(info.clone());
+      Ok(KeySource::PrivateKey(bob))
+    }).and_then(|( _, s )| s.collect()).wait().unwrap();
+    assert_eq!(seen.map(|info| ( info.passphrase, info.wrapped_keys )), Some(( false, 2 )));
+  }
+
+  #[test]
+  fn public_keys_are_required() {
+    let s = make_vec_stream_1(Bytes::from("hello"));
+    let e = encrypt_bottle(s, KeySource::PublicKeys(vec![]), vec![]).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::NoRecipients));
+  }
+
+  #[test]
+  fn refuse_bad_wrapped_keys() {
+    let ( alice, alice_public ) = generate_key_pair();
+    let data = encrypted(b"hello sailor!", KeySource::PublicKeys(vec![ alice_public ]));
+    let ( _, header, streams ) = bottle_from_slice(&data).unwrap();
+    let mut h = header.clone();
+    h.add_string(2, "not hex");
+    let bad = bottle_to_vec(BottleType::Encrypted, &h, streams).unwrap();
+    let e = decrypted(bad, KeySource::PrivateKey(alice)).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::BadWrappedKey));
+  }
+
+  #[test]
+  fn wrong_key() {
+    let e = decrypted(encrypted(b"hello sailor!", key()), KeySource::Raw(vec![ 0; 32 ])).err().unwrap();
+    assert_eq!(e.to_string(), "Decryption failed (wrong key, or corrupted data)");
+    let e = decrypted(encrypted(b"hello sailor!", key()), KeySource::Raw(vec![ 0; 5 ])).err().unwrap();
+    assert_eq!(e.to_string(), "Key must be 32 bytes");
+  }
+
+  #[test]
+  fn truncated_at_segment_boundary() {
+    let plaintext = vec![ 7u8; 65536 * 2 ];
+    let data = encrypted(&plaintext, key());
+    let ( _, header, streams ) = bottle_from_slice(&data).unwrap();
+    // rebuild the bottle with only the first segment.
+    let short = bottle_to_vec(BottleType::Encrypted, &header, vec![ streams[0][0 .. 65536 + 16].to_vec() ]).unwrap();
+    assert!(decrypted(short, key()).is_err());
+  }
+
+  #[test]
+  fn header_is_authenticated() {
+    let data = encrypted(b"hello sailor!", key());
+    let ( _, header, streams ) = bottle_from_slice(&data).unwrap();
+    assert!(header.get_bool(0));
+    let tampers: Vec<fn(&mut Header)> = vec![
+      |h| h.set_string(0, "mallory"),
+      |h| h.add_string(0, "mallory"),
+      |h| h.remove_string(0),
+      |h| h.add_number(6, 1),
+      // pretending to be an old bottle, from before headers were bound.
+      |h| h.remove_bool(0)
+    ];
+    for tamper in tampers {
+      let mut h = header.clone();
+      tamper(&mut h);
+      let bad = bottle_to_vec(BottleType::Encrypted, &h, streams.clone()).unwrap();
+      let e = decrypted(bad, key()).err().unwrap();
+      assert_eq!(BottleError::find(&e), Some(&BottleError::DecryptionFailed));
+    }
+  }
+
+  #[test]
+  fn read_bottle_without_bound_header() {
+    // written before headers were bound: a single final segment, with no
+    // associated data.
+    let key_bytes: Vec<u8> = (0 .. 32).collect();
+    let mut nonce = [ 0u8; 12 ];
+    nonce[11] = 1;
+    let cipher = Aes256Gcm::new_from_slice(&key_bytes).unwrap();
+    let sealed = cipher.encrypt(Nonce::from_slice(&nonce), &b"hello sailor!"[..]).unwrap();
+    let mut header = Header::new();
+    header.add_number(0, 0);
+    header.add_number(2, 0);
+    let data = bottle_to_vec(BottleType::Encrypted, &header, vec![ sealed ]).unwrap();
+    assert_eq!(decrypted(data, key()).unwrap(), b"hello sailor!".to_vec());
+  }
+
+  fn encrypted_with(data: &[u8], key: KeySource, encryption_type: EncryptionType) -> Vec<u8> {
+    let s = encrypt_bottle_with_cipher(make_vec_stream_1(Bytes::from(data)), key, vec![], encryption_type).unwrap();
+    s.collect().wait().unwrap().into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect()
+  }
+
+  #[test]
+  fn round_trip_xchacha20_poly1305() {
+    let ( alice, alice_public ) = generate_key_pair();
+    for &size in &[ 0, 13, 65536, 200000 ] {
+      let plaintext: Vec<u8> = (0 .. size).map(|i| (i % 251) as u8).collect();
+      let data = encrypted_with(&plaintext, key(), EncryptionType::XChaCha20Poly1305);
+      let ( _, header, _ ) = bottle_from_slice(&data).unwrap();
+      assert_eq!(header.get_number(0), Some(1));
+      assert_eq!(decrypted(data, key()).unwrap(), plaintext);
+
+      let data = encrypted_with(&plaintext, KeySource::PublicKeys(vec![ alice_public ]), EncryptionType::XChaCha20Poly1305);
+      assert_eq!(decrypted(data, KeySource::PrivateKey(alice)).unwrap(), plaintext);
+    }
+
+    let data = encrypted_with(b"hello sailor!", key(), EncryptionType::XChaCha20Poly1305);
+    let e = decrypted(data, KeySource::Raw(vec![ 0; 32 ])).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::DecryptionFailed));
+  }
+
+  #[test]
+  fn read_xchacha20_poly1305_bottle() {
+    // sealed elsewhere: key 0 - 31, nonce prefix 0 - 6, one final segment,
+    // no associated data.
+    let mut header = Header::new();
+    header.add_number(0, 1);
+    header.add_number(2, 0x0006_0504_0302_0100);
+    let sealed = "b3e8ccf9c54fdbbac3dd1f02d5bea06504e0987caa0423a4833417be82".from_hex();
+    let data = bottle_to_vec(BottleType::Encrypted, &header, vec![ sealed ]).unwrap();
+    assert_eq!(decrypted(data, key()).unwrap(), b"hello sailor!".to_vec());
+  }
+
+  // draft-irtf-cfrg-xchacha-03, appendix A.3.1.
+  #[test]
+  fn xchacha20_poly1305_vector() {
+    let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+    let sealed = concat!(
+      "bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb",
+      "731c7f1b0b4aa6440bf3a82f4eda7e39ae64c6708c54c216cb96b72e1213b452",
+      "2f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff9",
+      "21f9664c97637da9768812f615c68b13b52e",
+      "c0875924c1c7987947deafd8780acf49"
+    );
+    let nonce = "404142434445464748494a4b4c4d4e4f5051525354555657".from_hex();
+    let aad = "50515253c0c1c2c3c4c5c6c7".from_hex();
+    let cipher = EncryptionType::XChaCha20Poly1305.cipher().unwrap();
+    let key = cipher.with_key(&"808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f".from_hex()).unwrap();
+    assert_eq!(key.seal(&nonce, &aad, plaintext).unwrap().to_hex(), sealed);
+    assert_eq!(key.open(&nonce, &aad, &sealed.from_hex()).unwrap(), plaintext.to_vec());
+    let mut altered = sealed.from_hex();
+    altered[0] ^= 0x80;
+    assert!(key.open(&nonce, &aad, &altered).is_err());
+    assert!(cipher.with_key(&[ 0; 16 ]).is_err());
+  }
+
+  // "seals" by xor with the key, and a tag of the nonce's last byte.
+  struct XorCipher;
+
+  struct XorKey(Vec<u8>);
+
+  impl Cipher for XorCipher {
+    fn id(&self) -> u8 { 30 }
+    fn name(&self) -> &str { "xor" }
+    fn nonce_size(&self) -> usize { 16 }
+    fn tag_size(&self) -> usize { 1 }
+
+    fn with_key(&self, key: &[u8]) -> io::Result<Box<dyn CipherKey>> {
+      Ok(Box::new(XorKey(key.to_vec())))
+    }
+  }
+
+  impl CipherKey for XorKey {
+    fn seal(&self, nonce: &[u8], _: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+      let mut sealed: Vec<u8> = data.iter().zip(self.0.iter().cycle()).map(|( a, b )| a ^ b).collect();
+      sealed.push(nonce[15]);
+      Ok(sealed)
+    }
+
+    fn open(&self, nonce: &[u8], _: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+      if data.last() != Some(&nonce[15]) { return Err(BottleError::DecryptionFailed.into()) }
+      Ok(data[.. data.len() - 1].iter().zip(self.0.iter().cycle()).map(|( a, b )| a ^ b).collect())
+    }
+  }
+
+  #[test]
+  fn custom_cipher() {
+    let encryption_type = register_cipher(XorCipher).unwrap();
+    assert_eq!(encryption_type, EncryptionType::Custom(30));
+    assert_eq!(find_cipher("xor"), Some(encryption_type));
+    assert_eq!(encryption_type.cipher().unwrap().nonce_size(), 16);
+
+    let data = encrypted_with(b"hello", KeySource::Raw(vec![ 1; 32 ]), encryption_type);
+    let ( _, header, streams ) = bottle_from_slice(&data).unwrap();
+    assert_eq!(header.get_number(0), Some(30));
+    // the last segment is marked in the nonce's last byte.
+    assert_eq!(streams[0], vec![ b'h' ^ 1, b'e' ^ 1, b'l' ^ 1, b'l' ^ 1, b'o' ^ 1, 1 ]);
+    assert_eq!(decrypted(data, KeySource::Raw(vec![ 1; 32 ])).unwrap(), b"hello".to_vec());
+
+    let mut seen = None;
+    let s = make_stream(vec![ Bytes::from(encrypted_with(b"hello", KeySource::Raw(vec![ 1; 32 ]), encryption_type)) ]);
+    decrypt_bottle(s, |info| {
+      seen = Some(info.encryption_type);
+      Ok(KeySource::Raw(vec![ 1; 32 ]))
+    }).and_then(|( _, s )| s.collect()).wait().unwrap();
+    assert_eq!(seen, Some(EncryptionType::Custom(30)));
+  }
+
+  #[test]
+  fn built_in_ciphers() {
+    assert_eq!(find_cipher("aes-256-gcm"), Some(EncryptionType::Aes256Gcm));
+    assert_eq!(find_cipher("xchacha20-poly1305"), Some(EncryptionType::XChaCha20Poly1305));
+    assert_eq!(find_cipher("rot13"), None);
+    assert_eq!(EncryptionType::XChaCha20Poly1305.cipher().unwrap().nonce_size(), 24);
+    assert!(EncryptionType::Custom(99).cipher().is_err());
+
+    let mut header = Header::new();
+    header.add_number(0, 99);
+    let data = bottle_to_vec(BottleType::Encrypted, &header, vec![ vec![ 1 ] ]).unwrap();
+    assert_eq!(decrypted(data, key()).err().unwrap().to_string(), "Unknown encryption type: 99");
+  }
+
+  #[test]
+  fn reserved_cipher_id() {
+    struct Squatter;
+
+    impl Cipher for Squatter {
+      fn id(&self) -> u8 { 1 }
+      fn name(&self) -> &str { "squatter" }
+      fn nonce_size(&self) -> usize { 12 }
+      fn tag_size(&self) -> usize { 0 }
+      fn with_key(&self, _: &[u8]) -> io::Result<Box<dyn CipherKey>> { Err(BottleError::EncryptionFailed.into()) }
+    }
+
+    let e = register_cipher(Squatter).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::ReservedEncryptionType(1)));
+  }
+}
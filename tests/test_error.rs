@@ -0,0 +1,40 @@
+extern crate lib4bottle;
+
+#[cfg(test)]
+mod tests {
+  use lib4bottle::bottle::{BottleType, bottle_from_slice};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::error::BottleError;
+  use lib4bottle::to_hex::FromHex;
+  use std::io;
+
+  #[test]
+  fn convert_to_io_error() {
+    let e: io::Error = BottleError::HashMismatch.into();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(e.to_string(), "Hash mismatch");
+    assert_eq!(BottleError::find(&e), Some(&BottleError::HashMismatch));
+
+    let e: io::Error = BottleError::WrongType { expected: BottleType::Encrypted, found: BottleType::File }.into();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(e.to_string(), "Not an encrypted bottle: File");
+  }
+
+  #[test]
+  fn find_only_bottle_errors() {
+    assert_eq!(BottleError::find(&io::Error::new(io::ErrorKind::InvalidData, "nope")), None);
+    assert_eq!(BottleError::find(&io::Error::from(io::ErrorKind::UnexpectedEof)), None);
+  }
+
+  #[test]
+  fn branch_on_reader_errors() {
+    let e = bottle_from_slice(&"f09f8dbc0000a0".from_hex()).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::TruncatedStream));
+    let e = bottle_from_slice(&"f09f8dbc0000a00300".from_hex()).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::TruncatedStream));
+    let e = bottle_from_slice(&"f09f8dbc00009000ff".from_hex()).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::UnknownType(9)));
+    let e = Header::decode(&"c401".from_hex()).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::TruncatedHeader));
+  }
+}
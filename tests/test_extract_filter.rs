@@ -0,0 +1,101 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use common::temp_dir;
+  use futures::{Future, Stream};
+  use lib4bottle::error::BottleError;
+  use lib4bottle::extract_filter::{Decision, ExtractFilter};
+  use lib4bottle::file_bottle::{ExtractOptions, archive_directory, extract_bottle};
+  use lib4bottle::stream_helpers::{make_stream};
+  use std::cell::{Cell, RefCell};
+  use std::fs;
+  use std::io;
+  use std::path::{Path};
+  use std::rc::Rc;
+
+  // stuff/{a.txt, b.md, inner/{c.txt, d.md}}
+  fn archive(name: &str) -> Vec<u8> {
+    let source = temp_dir(name);
+    let root = source.join("stuff");
+    fs::create_dir_all(root.join("inner")).unwrap();
+    fs::write(root.join("a.txt"), "ay").unwrap();
+    fs::write(root.join("b.md"), "bee").unwrap();
+    fs::write(root.join("inner/c.txt"), "sea".repeat(1000)).unwrap();
+    fs::write(root.join("inner/d.md"), "dee").unwrap();
+    let s = archive_directory(&root).unwrap();
+    let data = s.collect().wait().unwrap().into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect();
+    fs::remove_dir_all(&source).unwrap();
+    data
+  }
+
+  // extract, returning the written paths relative to the target, and how
+  // many bytes of the bottle were read.
+  fn extract(name: &str, filter: ExtractFilter) -> io::Result<( Vec<String>, usize )> {
+    let data = archive(name);
+    let target = temp_dir(&format!("{}-target", name));
+    let count = Rc::new(Cell::new(0));
+    let counter = count.clone();
+    let s = make_stream(data.chunks(10).map(Bytes::from).collect()).inspect(move |b| counter.set(counter.get() + b.len()));
+    let paths = extract_bottle(s, &target, ExtractOptions::default().with_filter(filter)).wait();
+    let paths = paths.map(|paths| paths.iter().map(|p| p.strip_prefix(&target).unwrap().to_string_lossy().to_string()).collect());
+    fs::remove_dir_all(&target).unwrap();
+    paths.map(|paths| ( paths, count.get() ))
+  }
+
+  #[test]
+  fn include_globs() {
+    let filter = ExtractFilter::new().include("**/*.txt").unwrap();
+    assert_eq!(extract("filter-include", filter).unwrap().0, vec![ "stuff/a.txt", "stuff/inner/c.txt" ]);
+    let filter = ExtractFilter::new().include("stuff/*.md").unwrap();
+    assert_eq!(extract("filter-include-2", filter).unwrap().0, vec![ "stuff/b.md" ]);
+  }
+
+  #[test]
+  fn prefixes() {
+    let filter = ExtractFilter::new().prefix("stuff/inner");
+    assert_eq!(extract("filter-prefix", filter).unwrap().0, vec![ "stuff/inner", "stuff/inner/c.txt", "stuff/inner/d.md" ]);
+  }
+
+  #[test]
+  fn exclude_globs() {
+    let filter = ExtractFilter::new().exclude("stuff/inner").unwrap().exclude("*/*.md").unwrap();
+    assert_eq!(extract("filter-exclude", filter).unwrap().0, vec![ "stuff", "stuff/a.txt" ]);
+  }
+
+  #[test]
+  fn callback() {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let saved = seen.clone();
+    let filter = ExtractFilter::new().callback(move |entry| {
+      saved.borrow_mut().push(entry.path.clone());
+      if entry.metadata.size == Some(3) { Decision::Skip } else { Decision::Extract }
+    });
+    assert_eq!(extract("filter-callback", filter).unwrap().0, vec![ "stuff", "stuff/a.txt", "stuff/inner", "stuff/inner/c.txt" ]);
+    assert_eq!(seen.borrow().len(), 6);
+    assert_eq!(seen.borrow()[5], Path::new("stuff/inner/d.md"));
+  }
+
+  #[test]
+  fn stop_early() {
+    let size = archive("filter-size").len();
+    let filter = ExtractFilter::new().callback(|entry| {
+      if entry.path == Path::new("stuff/b.md") { Decision::Stop } else { Decision::Extract }
+    });
+    let ( paths, read ) = extract("filter-stop", filter).unwrap();
+    assert_eq!(paths, vec![ "stuff", "stuff/a.txt" ]);
+    // c.txt is most of the bottle, and was never read.
+    assert!(read < size - 3000, "{} of {}", read, size);
+  }
+
+  #[test]
+  fn bad_pattern() {
+    let e = ExtractFilter::new().include("[oops").err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::BadPattern("[oops".to_string())));
+  }
+}
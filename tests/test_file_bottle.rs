@@ -0,0 +1,512 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use common::{drain, temp_dir};
+  use futures::{Future, Stream};
+  use lib4bottle::bottle::{BottleType, bottle_from_slice};
+  use lib4bottle::bottle::{DecodeLimits, bottle_to_vec, make_interleaved_bottle};
+  use lib4bottle::file_bottle::{Deterministic, ExistingFilePolicy, ExtractOptions, FileMetadata, archive_directory, archive_directory_deterministic};
+  use lib4bottle::file_bottle::{LARGE_READ_BLOCK_SIZE, extract_bottle, file_bottle, file_bottle_with_crc32c, file_stream, large_file_stream};
+  use lib4bottle::hashing::crc32c;
+  use lib4bottle::stream_helpers::{make_stream};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::error::{BottleError, DecodeLimit};
+  use std::env;
+  use std::fs;
+  use std::io::Write;
+  #[cfg(unix)]
+  use std::os::unix::fs::{self as unix_fs, MetadataExt, PermissionsExt};
+  use std::path::PathBuf;
+  use std::time::{Duration, UNIX_EPOCH};
+
+  fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+    let path = env::temp_dir().join(format!("lib4bottle-{}-{}", name, ::std::process::id()));
+    fs::File::create(&path).unwrap().write_all(data).unwrap();
+    path
+  }
+
+  #[test]
+  fn metadata_round_trip() {
+    let metadata = FileMetadata {
+      filename: "hello.txt".to_string(),
+      size: Some(23),
+      posix_mode: Some(0o644),
+      modified_nanos: Some(1_500_000_000_000_000_000),
+      username: Some("robey".to_string()),
+      symlink: Some("../there".to_string()),
+      hardlink: Some("inner/c.txt".to_string()),
+      xattrs: vec![ ( "user.a=b".to_string(), vec![ 0, 1, 255 ] ), ( "user.empty".to_string(), vec![] ) ],
+      sparse: true,
+      ..FileMetadata::default()
+    };
+    let header = Header::decode(&metadata.to_header().encode()).unwrap();
+    assert_eq!(FileMetadata::from_header(&header).unwrap(), metadata);
+  }
+
+  #[test]
+  fn metadata_requires_filename() {
+    let e = FileMetadata::from_header(&Header::new()).err().unwrap();
+    assert_eq!(e.to_string(), "File bottle has no filename");
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn bottle_a_file() {
+    let path = temp_file("bottle-a-file", b"hello sailor!");
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+    let data: Vec<u8> = file_bottle(&path).unwrap().collect().wait().unwrap().into_iter().flat_map(|v| {
+      v.into_iter().flat_map(|b: Bytes| b.to_vec())
+    }).collect();
+    fs::remove_file(&path).unwrap();
+
+    let ( btype, header, streams ) = bottle_from_slice(&data).unwrap();
+    assert_eq!(btype, BottleType::File);
+    let metadata = FileMetadata::from_header(&header).unwrap();
+    assert_eq!(metadata.filename, path.file_name().unwrap().to_string_lossy());
+    assert_eq!(metadata.size, Some(13));
+    assert_eq!(metadata.posix_mode, Some(0o640));
+    assert!(metadata.modified_nanos.is_some());
+    assert_eq!(streams, vec![ b"hello sailor!".to_vec() ]);
+  }
+
+  #[test]
+  fn archive_a_directory() {
+    let root = temp_dir("archive");
+    fs::create_dir_all(root.join("inner")).unwrap();
+    fs::File::create(root.join("b.txt")).unwrap().write_all(b"bee").unwrap();
+    fs::File::create(root.join("a.txt")).unwrap().write_all(b"ay").unwrap();
+    fs::File::create(root.join("inner").join("c.txt")).unwrap().write_all(b"sea").unwrap();
+    let data = drain(archive_directory(&root).unwrap());
+    fs::remove_dir_all(&root).unwrap();
+
+    let ( btype, header, streams ) = bottle_from_slice(&data).unwrap();
+    assert_eq!(btype, BottleType::File);
+    let metadata = FileMetadata::from_header(&header).unwrap();
+    assert!(metadata.folder);
+    assert_eq!(metadata.size, None);
+    assert_eq!(streams.len(), 3);
+
+    let children: Vec<( FileMetadata, Vec<Vec<u8>> )> = streams.iter().map(|s| {
+      let ( _, header, streams ) = bottle_from_slice(s).unwrap();
+      ( FileMetadata::from_header(&header).unwrap(), streams )
+    }).collect();
+    assert_eq!(children[0].0.filename, "a.txt");
+    assert_eq!(children[0].1, vec![ b"ay".to_vec() ]);
+    assert_eq!(children[1].0.filename, "b.txt");
+    assert_eq!(children[1].1, vec![ b"bee".to_vec() ]);
+    assert_eq!(children[2].0.filename, "inner");
+    assert!(children[2].0.folder);
+
+    let ( _, header, streams ) = bottle_from_slice(&children[2].1[0]).unwrap();
+    assert_eq!(FileMetadata::from_header(&header).unwrap().filename, "c.txt");
+    assert_eq!(streams, vec![ b"sea".to_vec() ]);
+  }
+
+  #[test]
+  fn archive_a_file_as_directory() {
+    let path = temp_file("not-a-folder", b"");
+    let e = archive_directory(&path).err().unwrap();
+    fs::remove_file(&path).unwrap();
+    assert!(e.to_string().starts_with("Not a folder"));
+  }
+
+  #[test]
+  fn bottle_a_missing_file() {
+    assert!(file_bottle(env::temp_dir().join("lib4bottle-does-not-exist")).is_err());
+  }
+
+  fn extract(data: Vec<u8>, target: &PathBuf, options: ExtractOptions) -> ::std::io::Result<Vec<PathBuf>> {
+    let s = make_stream(data.chunks(7).map(Bytes::from).collect());
+    extract_bottle(s, target, options).wait()
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn extract_a_directory() {
+    let source = temp_dir("extract-source");
+    let root = source.join("stuff");
+    fs::create_dir_all(root.join("inner")).unwrap();
+    fs::File::create(root.join("a.txt")).unwrap().write_all(b"ay").unwrap();
+    fs::File::create(root.join("inner").join("c.txt")).unwrap().write_all(b"sea").unwrap();
+    fs::set_permissions(root.join("a.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+    let mtime = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    fs::File::open(root.join("inner").join("c.txt")).unwrap().set_modified(mtime).unwrap();
+    let data = drain(archive_directory(&root).unwrap());
+
+    let target = temp_dir("extract-target");
+    let paths = extract(data, &target, ExtractOptions::default()).unwrap();
+    assert_eq!(paths, vec![
+      target.join("stuff"), target.join("stuff/a.txt"), target.join("stuff/inner"), target.join("stuff/inner/c.txt")
+    ]);
+    assert_eq!(fs::read(target.join("stuff/a.txt")).unwrap(), b"ay");
+    assert_eq!(fs::read(target.join("stuff/inner/c.txt")).unwrap(), b"sea");
+    assert_eq!(fs::metadata(target.join("stuff/a.txt")).unwrap().permissions().mode() & 0o777, 0o600);
+    assert_eq!(fs::metadata(target.join("stuff/inner/c.txt")).unwrap().modified().unwrap(), mtime);
+    fs::remove_dir_all(&source).unwrap();
+    fs::remove_dir_all(&target).unwrap();
+  }
+
+  #[test]
+  fn extract_interleaved_folder() {
+    let source = temp_dir("interleaved-source");
+    let names = [ "a.txt", "b.txt", "c.txt" ];
+    let files = names.iter().enumerate().map(|( i, name )| {
+      let path = source.join(name);
+      fs::write(&path, name.repeat(1000 * (i + 1))).unwrap();
+      file_bottle(path).unwrap()
+    }).collect::<Vec<_>>();
+    let folder = FileMetadata { filename: "stuff".to_string(), folder: true, ..FileMetadata::default() };
+    let data = drain(Box::new(make_interleaved_bottle(BottleType::File, &folder.to_header(), files, 3)));
+
+    let target = temp_dir("interleaved-target");
+    let paths = extract(data, &target, ExtractOptions::default()).unwrap();
+    assert_eq!(paths.len(), 4);
+    for ( i, name ) in names.iter().enumerate() {
+      assert_eq!(fs::read(target.join("stuff").join(name)).unwrap(), name.repeat(1000 * (i + 1)).into_bytes());
+    }
+    fs::remove_dir_all(&source).unwrap();
+    fs::remove_dir_all(&target).unwrap();
+  }
+
+  #[test]
+  fn extract_nested_too_deep() {
+    let source = temp_dir("extract-deep-source");
+    let root = source.join("stuff");
+    fs::create_dir_all(root.join("inner")).unwrap();
+    fs::write(root.join("inner").join("c.txt"), b"sea").unwrap();
+    let data = drain(archive_directory(&root).unwrap());
+    fs::remove_dir_all(&source).unwrap();
+
+    let target = temp_dir("extract-deep-target");
+    let options = ExtractOptions { limits: DecodeLimits { max_nesting_depth: 2, ..DecodeLimits::default() }, ..ExtractOptions::default() };
+    let e = extract(data.clone(), &target, options).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::DecodeLimitExceeded { limit: DecodeLimit::NestingDepth, size: 3, max: 2 }));
+    fs::remove_dir_all(&target).unwrap();
+    let target = temp_dir("extract-deep-target");
+    let options = ExtractOptions { limits: DecodeLimits { max_nesting_depth: 3, ..DecodeLimits::default() }, ..ExtractOptions::default() };
+    assert_eq!(extract(data, &target, options).unwrap().len(), 3);
+    fs::remove_dir_all(&target).unwrap();
+  }
+
+  #[test]
+  fn extract_with_crc32c() {
+    let path = temp_file("extract-crc", b"hello sailor!");
+    let mut data = drain(Box::new(file_bottle_with_crc32c(path.clone()).unwrap()));
+    fs::remove_file(&path).unwrap();
+    let ( _, header, streams ) = bottle_from_slice(&data).unwrap();
+    assert!(FileMetadata::from_header(&header).unwrap().crc32c);
+    let expected = crc32c(0, b"hello sailor!");
+    assert_eq!(streams, vec![ b"hello sailor!".to_vec(), expected.to_be_bytes().to_vec() ]);
+
+    let target = temp_dir("extract-crc-target");
+    assert_eq!(extract(data.clone(), &target, ExtractOptions::default()).unwrap().len(), 1);
+    fs::remove_dir_all(&target).unwrap();
+
+    let n = data.windows(6).position(|w| w == b"sailor").unwrap();
+    data[n] = b'S';
+    let target = temp_dir("extract-crc-target");
+    let e = extract(data, &target, ExtractOptions::default()).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::ChecksumMismatch { expected, found: crc32c(0, b"hello Sailor!") }));
+    fs::remove_dir_all(&target).unwrap();
+  }
+
+  #[test]
+  fn extract_over_existing_files() {
+    let path = temp_file("extract-existing", b"new");
+    let data = drain(Box::new(file_bottle(path.clone()).unwrap()));
+    let target = temp_dir("extract-existing-target");
+    let existing = target.join(path.file_name().unwrap());
+    fs::write(&existing, b"old").unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let e = extract(data.clone(), &target, ExtractOptions::default()).err().unwrap();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::AlreadyExists);
+    let options = ExtractOptions { existing: ExistingFilePolicy::Skip, ..ExtractOptions::default() };
+    assert_eq!(extract(data.clone(), &target, options).unwrap().len(), 0);
+    assert_eq!(fs::read(&existing).unwrap(), b"old");
+    let options = ExtractOptions { existing: ExistingFilePolicy::Overwrite, ..ExtractOptions::default() };
+    assert_eq!(extract(data, &target, options).unwrap(), vec![ existing.clone() ]);
+    assert_eq!(fs::read(&existing).unwrap(), b"new");
+    fs::remove_dir_all(&target).unwrap();
+  }
+
+  #[test]
+  fn extract_unsafe_filename() {
+    let target = temp_dir("extract-unsafe");
+    for filename in &[ "../evil", "/etc/evil", "a/b", "", "." ] {
+      let metadata = FileMetadata { filename: filename.to_string(), ..FileMetadata::default() };
+      let data = bottle_to_vec(BottleType::File, &metadata.to_header(), vec![ b"evil".to_vec() ]).unwrap();
+      let e = extract(data, &target, ExtractOptions::default()).err().unwrap();
+      assert!(e.to_string().starts_with("Unsafe filename"), "{}", filename);
+    }
+    assert_eq!(fs::read_dir(&target).unwrap().count(), 0);
+    fs::remove_dir_all(&target).unwrap();
+  }
+
+  #[cfg(unix)]
+  fn link_tree(name: &str) -> ( PathBuf, Vec<u8> ) {
+    let source = temp_dir(name);
+    let root = source.join("stuff");
+    fs::create_dir_all(root.join("inner")).unwrap();
+    fs::write(root.join("a.txt"), b"ay").unwrap();
+    fs::hard_link(root.join("a.txt"), root.join("hard")).unwrap();
+    fs::hard_link(root.join("a.txt"), root.join("inner/hard")).unwrap();
+    unix_fs::symlink("a.txt", root.join("link")).unwrap();
+    unix_fs::symlink("../a.txt", root.join("inner/up")).unwrap();
+    let data = drain(archive_directory(&root).unwrap());
+    ( source, data )
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn archive_links() {
+    let ( source, data ) = link_tree("archive-links");
+    fs::remove_dir_all(&source).unwrap();
+    let ( _, _, streams ) = bottle_from_slice(&data).unwrap();
+    let entries: Vec<FileMetadata> = streams.iter().map(|s| {
+      FileMetadata::from_header(&bottle_from_slice(s).unwrap().1).unwrap()
+    }).collect();
+    let names: Vec<&str> = entries.iter().map(|m| m.filename.as_str()).collect();
+    assert_eq!(names, vec![ "a.txt", "hard", "inner", "link" ]);
+    assert_eq!(entries[0].hardlink, None);
+    assert_eq!(entries[1].hardlink, Some("a.txt".to_string()));
+    assert_eq!(entries[3].symlink, Some("a.txt".to_string()));
+
+    let ( _, _, inner ) = bottle_from_slice(&streams[2]).unwrap();
+    let inner: Vec<FileMetadata> = inner.iter().map(|s| FileMetadata::from_header(&bottle_from_slice(s).unwrap().1).unwrap()).collect();
+    assert_eq!(inner[0].hardlink, Some("../a.txt".to_string()));
+    assert_eq!(inner[1].symlink, Some("../a.txt".to_string()));
+  }
+
+  // the same tree, made in a different order, with different times and modes.
+  #[cfg(unix)]
+  fn messy_tree(name: &str, reverse: bool) -> PathBuf {
+    let root = temp_dir(name).join("stuff");
+    fs::create_dir_all(root.join("inner")).unwrap();
+    let mut files = [ "a.txt", "b.txt", "inner/c.txt" ];
+    if reverse { files.reverse() }
+    for ( i, file ) in files.iter().enumerate() {
+      fs::write(root.join(file), file.as_bytes()).unwrap();
+      let mode = if reverse { 0o600 } else { 0o664 };
+      fs::set_permissions(root.join(file), fs::Permissions::from_mode(mode)).unwrap();
+      let when = UNIX_EPOCH + Duration::from_secs(1_000_000 + if reverse { i as u64 * 7 } else { i as u64 });
+      fs::File::options().write(true).open(root.join(file)).unwrap().set_modified(when).unwrap();
+    }
+    fs::set_permissions(root.join("b.txt"), fs::Permissions::from_mode(if reverse { 0o700 } else { 0o775 })).unwrap();
+    unix_fs::symlink("a.txt", root.join("link")).unwrap();
+    root
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn archive_deterministic() {
+    let one = messy_tree("deterministic-one", false);
+    let two = messy_tree("deterministic-two", true);
+    let data = drain(archive_directory_deterministic(&one, Deterministic::default()).unwrap());
+    assert_eq!(data, drain(archive_directory_deterministic(&two, Deterministic::default()).unwrap()));
+    assert_ne!(drain(archive_directory(&one).unwrap()), drain(archive_directory(&two).unwrap()));
+
+    let ( _, header, streams ) = bottle_from_slice(&data).unwrap();
+    let folder = FileMetadata::from_header(&header).unwrap();
+    assert_eq!(( folder.posix_mode, folder.modified_nanos, folder.username ), ( Some(0o755), Some(0), None ));
+    let entries: Vec<FileMetadata> = streams.iter().map(|s| FileMetadata::from_header(&bottle_from_slice(s).unwrap().1).unwrap()).collect();
+    let names: Vec<&str> = entries.iter().map(|m| m.filename.as_str()).collect();
+    assert_eq!(names, vec![ "a.txt", "b.txt", "inner", "link" ]);
+    assert_eq!(entries[0].posix_mode, Some(0o644));
+    assert_eq!(entries[1].posix_mode, Some(0o755));
+    assert!(entries.iter().all(|m| m.created_nanos.is_none() && m.accessed_nanos.is_none() && m.group.is_none()));
+
+    // clamped, not zeroed: earlier times are kept.
+    let clamp = Deterministic { max_modified_nanos: 1_000_001_000_000_000, username: Some("builder".to_string()), ..Deterministic::default() };
+    let ( _, _, streams ) = bottle_from_slice(&drain(archive_directory_deterministic(&one, clamp).unwrap())).unwrap();
+    let entries: Vec<FileMetadata> = streams.iter().map(|s| FileMetadata::from_header(&bottle_from_slice(s).unwrap().1).unwrap()).collect();
+    assert_eq!(entries[0].modified_nanos, Some(1_000_000_000_000_000));
+    assert_eq!(entries[2].modified_nanos, Some(1_000_001_000_000_000));
+    assert_eq!(entries[0].username, Some("builder".to_string()));
+
+    fs::remove_dir_all(one.parent().unwrap()).unwrap();
+    fs::remove_dir_all(two.parent().unwrap()).unwrap();
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn extract_links() {
+    let ( source, data ) = link_tree("extract-links");
+    fs::remove_dir_all(&source).unwrap();
+
+    let target = temp_dir("extract-links-target");
+    extract(data.clone(), &target, ExtractOptions::default()).unwrap();
+    let root = target.join("stuff");
+    let inode = fs::metadata(root.join("a.txt")).unwrap().ino();
+    assert_eq!(fs::metadata(root.join("hard")).unwrap().ino(), inode);
+    assert_eq!(fs::metadata(root.join("inner/hard")).unwrap().ino(), inode);
+    assert_eq!(fs::read_link(root.join("link")).unwrap(), PathBuf::from("a.txt"));
+    assert_eq!(fs::read(root.join("inner/up")).unwrap(), b"ay");
+    fs::remove_dir_all(&target).unwrap();
+
+    let target = temp_dir("extract-links-deref");
+    let options = ExtractOptions { dereference_links: true, ..ExtractOptions::default() };
+    extract(data, &target, options).unwrap();
+    let root = target.join("stuff");
+    for name in &[ "hard", "inner/hard", "link", "inner/up" ] {
+      let stat = fs::symlink_metadata(root.join(name)).unwrap();
+      assert!(stat.is_file(), "{}", name);
+      assert_ne!(stat.ino(), fs::metadata(root.join("a.txt")).unwrap().ino());
+      assert_eq!(fs::read(root.join(name)).unwrap(), b"ay");
+    }
+    fs::remove_dir_all(&target).unwrap();
+  }
+
+  #[test]
+  fn extract_unsafe_links() {
+    let target = temp_dir("extract-unsafe-links");
+    let folder = FileMetadata { filename: "stuff".to_string(), folder: true, ..FileMetadata::default() };
+    for link in &[ "../../evil", "/etc/passwd", "inner/../../..", "" ] {
+      for &hard in &[ false, true ] {
+        let metadata = FileMetadata {
+          filename: "link".to_string(),
+          symlink: if hard { None } else { Some(link.to_string()) },
+          hardlink: if hard { Some(link.to_string()) } else { None },
+          ..FileMetadata::default()
+        };
+        let child = bottle_to_vec(BottleType::File, &metadata.to_header(), vec![]).unwrap();
+        let data = bottle_to_vec(BottleType::File, &folder.to_header(), vec![ child ]).unwrap();
+        let e = extract(data, &target, ExtractOptions::default()).err().unwrap();
+        assert_eq!(BottleError::find(&e), Some(&BottleError::UnsafeLink(link.to_string())));
+        assert!(fs::symlink_metadata(target.join("stuff/link")).is_err());
+      }
+    }
+
+    // inside the target, but not extracted (yet):
+    let metadata = FileMetadata { filename: "link".to_string(), hardlink: Some("../nope".to_string()), ..FileMetadata::default() };
+    let child = bottle_to_vec(BottleType::File, &metadata.to_header(), vec![]).unwrap();
+    let data = bottle_to_vec(BottleType::File, &folder.to_header(), vec![ child ]).unwrap();
+    let e = extract(data, &target, ExtractOptions::default()).err().unwrap();
+    assert_eq!(e.kind(), ::std::io::ErrorKind::NotFound);
+    fs::remove_dir_all(&target).unwrap();
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn extract_chained_links() {
+    // each link stays inside on its own, but "z" goes through "up", which
+    // lands on the target folder, so "z" reaches the folder above it.
+    let source = temp_dir("extract-chained-links");
+    let root = source.join("srcdir");
+    fs::create_dir_all(root.join("p/q/r")).unwrap();
+    unix_fs::symlink("../../../..", root.join("p/q/r/up")).unwrap();
+    unix_fs::symlink("p/q/r/up/../secret", root.join("z")).unwrap();
+    let data = drain(archive_directory(&root).unwrap());
+    fs::remove_dir_all(&root).unwrap();
+    fs::write(source.join("secret"), b"shh").unwrap();
+
+    let target = source.join("t");
+    fs::create_dir(&target).unwrap();
+    let e = extract(data, &target, ExtractOptions::default()).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::UnsafeLink("p/q/r/up/../secret".to_string())));
+    assert!(fs::symlink_metadata(target.join("srcdir/z")).is_err());
+
+    // a hard link would read through it too.
+    let folder = |name: &str, children: Vec<Vec<u8>>| {
+      let metadata = FileMetadata { filename: name.to_string(), folder: true, ..FileMetadata::default() };
+      bottle_to_vec(BottleType::File, &metadata.to_header(), children).unwrap()
+    };
+    let link = |name: &str, symlink: Option<&str>, hardlink: Option<&str>| {
+      let metadata = FileMetadata {
+        filename: name.to_string(),
+        symlink: symlink.map(|s| s.to_string()),
+        hardlink: hardlink.map(|s| s.to_string()),
+        ..FileMetadata::default()
+      };
+      bottle_to_vec(BottleType::File, &metadata.to_header(), vec![]).unwrap()
+    };
+    let up = link("up", Some("../../../.."), None);
+    let p = folder("p", vec![ folder("q", vec![ folder("r", vec![ up ]) ]) ]);
+    let data = folder("srcdir", vec![ p, link("z", None, Some("p/q/r/up/../secret")) ]);
+    fs::remove_dir_all(&target).unwrap();
+    fs::create_dir(&target).unwrap();
+    let e = extract(data, &target, ExtractOptions::default()).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::UnsafeLink("p/q/r/up/../secret".to_string())));
+    assert!(fs::symlink_metadata(target.join("srcdir/z")).is_err());
+    fs::remove_dir_all(&source).unwrap();
+  }
+
+  #[test]
+  fn bad_xattrs() {
+    for field in &[ "user.x", "=00", "user.x=0", "user.x=zz" ] {
+      let mut header = FileMetadata { filename: "a".to_string(), ..FileMetadata::default() }.to_header();
+      header.add_string(6, field.to_string());
+      let e = FileMetadata::from_header(&header).err().unwrap();
+      assert_eq!(BottleError::find(&e), Some(&BottleError::BadXattr(field.to_string())));
+    }
+  }
+
+  #[cfg(feature = "xattr")]
+  #[test]
+  fn extract_xattrs() {
+    extern crate xattr;
+    let path = temp_file("extract-xattrs", b"hello");
+    xattr::set(&path, "user.color", b"blue").unwrap();
+    xattr::set(&path, "user.binary", &[ 0, 255 ]).unwrap();
+    let data = drain(Box::new(file_bottle(path.clone()).unwrap()));
+    let filename = path.file_name().unwrap().to_owned();
+    fs::remove_file(&path).unwrap();
+    let metadata = FileMetadata::from_header(&bottle_from_slice(&data).unwrap().1).unwrap();
+    assert_eq!(metadata.xattrs, vec![
+      ( "user.binary".to_string(), vec![ 0, 255 ] ), ( "user.color".to_string(), b"blue".to_vec() )
+    ]);
+
+    let target = temp_dir("extract-xattrs-target");
+    extract(data.clone(), &target, ExtractOptions::default()).unwrap();
+    assert_eq!(xattr::get(target.join(&filename), "user.color").unwrap(), None);
+    fs::remove_file(target.join(&filename)).unwrap();
+    let options = ExtractOptions { restore_xattrs: true, ..ExtractOptions::default() };
+    extract(data, &target, options).unwrap();
+    assert_eq!(xattr::get(target.join(&filename), "user.color").unwrap(), Some(b"blue".to_vec()));
+    assert_eq!(xattr::get(target.join(&filename), "user.binary").unwrap(), Some(vec![ 0, 255 ]));
+    fs::remove_dir_all(&target).unwrap();
+  }
+
+  #[test]
+  fn metadata_too_big_for_a_header() {
+    let metadata = FileMetadata { filename: "link".to_string(), symlink: Some("x/".repeat(1000)), ..FileMetadata::default() };
+    let e = metadata.try_to_header().unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::HeaderFull { field: "symlink".to_string(), size: 2002, remaining: 4089 }));
+
+    // each xattr fits, but not all of them.
+    let xattrs = (0 .. 6).map(|i| ( format!("user.note{}", i), vec![ 7u8; 400 ] )).collect();
+    let metadata = FileMetadata { filename: "noted".to_string(), xattrs, ..FileMetadata::default() };
+    let e = metadata.try_to_header().unwrap_err();
+    match BottleError::find(&e) {
+      Some(BottleError::HeaderFull { field, .. }) => assert_eq!(field, "xattr user.note5"),
+      other => panic!("unexpected error {:?}", other)
+    }
+
+    let metadata = FileMetadata { filename: "small".to_string(), size: Some(10), ..FileMetadata::default() };
+    assert_eq!(metadata.try_to_header().unwrap().encode(), metadata.to_header().encode());
+  }
+
+  #[test]
+  fn large_file_stream_reads_everything() {
+    let data: Vec<u8> = (0 .. LARGE_READ_BLOCK_SIZE * 3 + 1234).map(|i| (i % 251) as u8).collect();
+    let path = temp_file("large-reads", &data);
+    let blocks = large_file_stream(fs::File::open(&path).unwrap()).collect().wait().unwrap();
+    assert_eq!(blocks.iter().map(|b| b.len()).collect::<Vec<usize>>(), vec![ LARGE_READ_BLOCK_SIZE, LARGE_READ_BLOCK_SIZE, LARGE_READ_BLOCK_SIZE, 1234 ]);
+    let read = file_stream(fs::File::open(&path).unwrap()).collect().wait().unwrap();
+    assert_eq!(blocks.concat(), read.concat());
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn large_file_stream_of_an_empty_file() {
+    let path = temp_file("large-reads-empty", b"");
+    assert_eq!(large_file_stream(fs::File::open(&path).unwrap()).collect().wait().unwrap().len(), 0);
+    fs::remove_file(&path).unwrap();
+  }
+}
@@ -0,0 +1,77 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use futures::{Future, Stream};
+  use lib4bottle::error::BottleError;
+  use lib4bottle::framed_stream::{framed_vec_stream, framed_vec_stream_with_limit, unframed_stream};
+  use lib4bottle::stream_helpers::{make_stream, make_stream_2};
+  use lib4bottle::to_hex::{FromHex, ToHex};
+  use std::io;
+
+  // one byte at a time, to split every length prefix.
+  fn trickle(hex: &str) -> impl Stream<Item = Bytes, Error = io::Error> {
+    make_stream(hex.from_hex().into_iter().map(|b| Bytes::from(vec![ b ])).collect())
+  }
+
+  #[test]
+  fn unframe_a_stream() {
+    let s = unframed_stream(make_stream(vec![ Bytes::from("0301020300".from_hex()) ]));
+    assert_eq!(s.collect().wait().unwrap().to_hex(), "010203");
+  }
+
+  #[test]
+  fn round_trip() {
+    let data: Vec<u8> = (0 .. 5000).map(|i| (i % 256) as u8).collect();
+    let framed = framed_vec_stream(make_stream_2(Bytes::from(&data[0 .. 200]), Bytes::from(&data[200 ..])));
+    let framed: Vec<Bytes> = framed.collect().wait().unwrap().into_iter().flatten().collect();
+    let s = unframed_stream(make_stream(framed));
+    assert_eq!(s.collect().wait().unwrap().concat(), data);
+  }
+
+  #[test]
+  fn split_oversized_chunks() {
+    // never touched, so the zeroed pages are never really allocated.
+    let data = Bytes::from(vec![ 0u8; (1 << 28) + 10 ]);
+    let frames = framed_vec_stream(make_stream_2(Bytes::from_static(b"abc"), data)).collect().wait().unwrap();
+    let lengths: Vec<String> = frames.iter().map(|f| f[0].to_hex()).collect();
+    assert_eq!(lengths, vec![ "03", "efffffff", "0b", "00" ]);
+    let sizes: Vec<usize> = frames.iter().map(|f| f[1 ..].iter().map(|b| b.len()).sum()).collect();
+    assert_eq!(sizes, vec![ 3, (1 << 28) - 1, 11, 0 ]);
+  }
+
+  #[test]
+  fn bad_frame_limits() {
+    for &max_frame in &[ 0, (1 << 28) ] {
+      let s = framed_vec_stream_with_limit(make_stream(vec![ Bytes::from_static(b"abc") ]).map(|b| vec![ b ]), max_frame);
+      let e = s.collect().wait().unwrap_err();
+      assert_eq!(BottleError::find(&e), Some(&BottleError::InvalidFrameSize { min: 0, max: max_frame }));
+    }
+  }
+
+  #[test]
+  fn leave_the_remainder() {
+    let mut s = unframed_stream(trickle("02010200030304050000ff"));
+    assert_eq!(s.by_ref().collect().wait().unwrap().to_hex(), "0102");
+    let mut s = unframed_stream(s.into_remainder());
+    assert_eq!(s.by_ref().collect().wait().unwrap().to_hex(), "030405");
+    let mut s = unframed_stream(s.into_remainder());
+    assert_eq!(s.by_ref().collect().wait().unwrap().len(), 0);
+    assert_eq!(s.into_remainder().collect().wait().unwrap().to_hex(), "ff");
+  }
+
+  #[test]
+  fn unframe_a_truncated_stream() {
+    let e = unframed_stream(trickle("030102")).collect().wait().err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    let e = unframed_stream(trickle("0201020381")).collect().wait().err().unwrap();
+    assert_eq!(e.to_string(), "Truncated bottle");
+    let e = unframed_stream(trickle("81")).collect().wait().err().unwrap();
+    assert_eq!(e.to_string(), "Truncated length: expected 2 bytes, got 1");
+    let e = unframed_stream(trickle("020102ff")).collect().wait().err().unwrap();
+    assert_eq!(e.to_string(), "End of all streams inside a stream");
+  }
+}
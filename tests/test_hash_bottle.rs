@@ -0,0 +1,195 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use futures::{Future, Stream, future};
+  use lib4bottle::bottle::{BottleType, bottle_from_slice, bottle_to_vec};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::hash_bottle::{hash_bottle, hash_bottle_signed, verify_hash_bottle, verify_hash_bottle_signed};
+  use lib4bottle::error::BottleError;
+  use lib4bottle::hashing::{CustomHasher, HashAlgorithm, Hasher, register_hash_algorithm};
+  use lib4bottle::stream_helpers::{make_stream, make_vec_stream_1};
+  use lib4bottle::to_hex::{ToHex};
+  use std::io;
+
+  fn inner_bottle() -> Vec<u8> {
+    bottle_to_vec(BottleType::Test, &Header::new(), vec![ b"hello sailor!".to_vec() ]).unwrap()
+  }
+
+  fn hashed(data: Vec<u8>, algorithm: HashAlgorithm) -> Vec<u8> {
+    let s = hash_bottle(make_vec_stream_1(Bytes::from(data)), algorithm).unwrap();
+    s.collect().wait().unwrap().into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect()
+  }
+
+  // feed it back a few bytes at a time.
+  fn chunked(data: Vec<u8>) -> impl Stream<Item = Bytes, Error = ::std::io::Error> {
+    make_stream(data.chunks(5).map(Bytes::from).collect())
+  }
+
+  #[test]
+  fn write_hashed_bottle() {
+    let data = hashed(inner_bottle(), HashAlgorithm::Sha256);
+    let ( btype, header, streams ) = bottle_from_slice(&data).unwrap();
+    assert_eq!(btype, BottleType::Hashed);
+    assert_eq!(format!("{:?}", header), "Header(N0=1)");
+    assert_eq!(streams.len(), 2);
+    assert_eq!(streams[0], inner_bottle());
+    let mut hasher = Hasher::new(HashAlgorithm::Sha256).unwrap();
+    hasher.update(&inner_bottle());
+    assert_eq!(streams[1].to_hex(), hasher.finish().to_hex());
+  }
+
+  #[test]
+  fn verify_hashed_bottle() {
+    for &algorithm in &[ HashAlgorithm::Sha256, HashAlgorithm::Sha512, HashAlgorithm::Blake3 ] {
+      let ( _, s ) = verify_hash_bottle(chunked(hashed(inner_bottle(), algorithm))).wait().unwrap();
+      let inner: Vec<u8> = s.collect().wait().unwrap().into_iter().flat_map(|b| b.to_vec()).collect();
+      assert_eq!(inner, inner_bottle());
+    }
+  }
+
+  // adds up the bytes: not much of a hash, but it's ours.
+  #[derive(Clone)]
+  struct Sum(u32);
+
+  impl CustomHasher for Sum {
+    fn update(&mut self, data: &[u8]) {
+      for &b in data { self.0 = self.0.wrapping_add(b as u32) }
+    }
+
+    fn finish(self: Box<Self>) -> Bytes {
+      Bytes::from(self.0.to_be_bytes().to_vec())
+    }
+
+    fn box_clone(&self) -> Box<dyn CustomHasher> {
+      Box::new(self.clone())
+    }
+  }
+
+  #[test]
+  fn verify_custom_algorithm() {
+    let algorithm = register_hash_algorithm(99, 4, || Box::new(Sum(0))).unwrap();
+    let data = hashed(inner_bottle(), algorithm);
+    let ( _, header, streams ) = bottle_from_slice(&data).unwrap();
+    assert_eq!(format!("{:?}", header), "Header(N0=99)");
+    let sum: u32 = inner_bottle().iter().map(|&b| b as u32).sum();
+    assert_eq!(streams[1], sum.to_be_bytes().to_vec());
+
+    let ( _, s ) = verify_hash_bottle(chunked(data)).wait().unwrap();
+    let inner: Vec<u8> = s.collect().wait().unwrap().into_iter().flat_map(|b| b.to_vec()).collect();
+    assert_eq!(inner, inner_bottle());
+  }
+
+  #[test]
+  fn unusable_custom_algorithms() {
+    for &( id, ref error ) in &[ ( 5, BottleError::ReservedHashAlgorithm(5) ), ( 97, BottleError::UnknownHashAlgorithm(97) ) ] {
+      let e = hash_bottle(make_vec_stream_1(Bytes::from(inner_bottle())), HashAlgorithm::Custom(id)).err().unwrap();
+      assert_eq!(BottleError::find(&e), Some(error));
+      assert_eq!(BottleError::find(&Hasher::new(HashAlgorithm::Custom(id)).err().unwrap()), Some(error));
+    }
+  }
+
+  #[test]
+  fn verify_mismatched_algorithm() {
+    // a SHA-256 digest, with a header that says SHA-512.
+    let ( _, _, streams ) = bottle_from_slice(&hashed(inner_bottle(), HashAlgorithm::Sha256)).unwrap();
+    let mut header = Header::new();
+    header.add_number(0, HashAlgorithm::Sha512.id());
+    let data = bottle_to_vec(BottleType::Hashed, &header, streams).unwrap();
+    let ( _, s ) = verify_hash_bottle(chunked(data)).wait().unwrap();
+    let e = s.collect().wait().err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::WrongDigestSize { algorithm: HashAlgorithm::Sha512, expected: 64, found: 32 }));
+    assert_eq!(e.to_string(), "Digest is 32 bytes, but Sha512 digests are 64 (wrong hash algorithm?)");
+
+    let mut header = Header::new();
+    header.add_number(0, 98);
+    let data = bottle_to_vec(BottleType::Hashed, &header, vec![ inner_bottle(), vec![] ]).unwrap();
+    let e = verify_hash_bottle(chunked(data)).wait().err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::UnknownHashAlgorithm(98)));
+  }
+
+  #[test]
+  fn verify_corrupted_bottle() {
+    let mut data = hashed(inner_bottle(), HashAlgorithm::Sha256);
+    // inside the inner bottle's child stream.
+    let n = data.len() - 40;
+    data[n] ^= 1;
+    let ( _, s ) = verify_hash_bottle(chunked(data)).wait().unwrap();
+    assert_eq!(s.collect().wait().err().unwrap().to_string(), "Hash mismatch");
+  }
+
+  #[test]
+  fn verify_wrong_bottle_type() {
+    let e = verify_hash_bottle(chunked(inner_bottle())).wait().err().unwrap();
+    assert_eq!(e.to_string(), "Not a hashed bottle: Test");
+  }
+
+  // a pretend signature: "signed:" and the digest.
+  fn signed(data: Vec<u8>) -> Vec<u8> {
+    let s = hash_bottle_signed(make_vec_stream_1(Bytes::from(data)), HashAlgorithm::Sha256, "alice".to_string(), |digest| {
+      let mut blob = b"signed:".to_vec();
+      blob.extend_from_slice(&digest);
+      future::ok(Bytes::from(blob))
+    }).unwrap();
+    s.collect().wait().unwrap().into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect()
+  }
+
+  fn check_signature(signed_by: &str, blob: Bytes) -> future::FutureResult<Bytes, io::Error> {
+    if signed_by != "alice" || !blob.starts_with(b"signed:") {
+      return future::err(io::Error::new(io::ErrorKind::InvalidData, "Bad signature"));
+    }
+    future::ok(blob.slice_from(7))
+  }
+
+  #[test]
+  fn write_signed_bottle() {
+    let ( btype, header, streams ) = bottle_from_slice(&signed(inner_bottle())).unwrap();
+    assert_eq!(btype, BottleType::Hashed);
+    assert_eq!(format!("{:?}", header), "Header(N0=1, S0=\"alice\")");
+    let mut hasher = Hasher::new(HashAlgorithm::Sha256).unwrap();
+    hasher.update(&inner_bottle());
+    assert_eq!(streams[1].to_hex(), format!("{}{}", b"signed:".to_hex(), hasher.finish().to_hex()));
+  }
+
+  #[test]
+  fn signed_by_too_long() {
+    let stream = make_vec_stream_1(Bytes::from(inner_bottle()));
+    let e = hash_bottle_signed(stream, HashAlgorithm::Sha256, "x".repeat(5000), |_| future::ok(Bytes::new())).err().unwrap();
+    match BottleError::find(&e) {
+      Some(BottleError::HeaderFull { field, .. }) => assert_eq!(field, "signed by"),
+      other => panic!("unexpected error {:?}", other)
+    }
+  }
+
+  #[test]
+  fn verify_signed_bottle() {
+    let ( _, s ) = verify_hash_bottle_signed(chunked(signed(inner_bottle())), check_signature).wait().unwrap();
+    assert_eq!(s.collect().wait().unwrap().concat(), inner_bottle());
+
+    let mut data = signed(inner_bottle());
+    let n = data.len() - 50;
+    data[n] ^= 1;
+    let ( _, s ) = verify_hash_bottle_signed(chunked(data), check_signature).wait().unwrap();
+    assert_eq!(s.collect().wait().err().unwrap().to_string(), "Hash mismatch");
+  }
+
+  #[test]
+  fn verify_bad_signature() {
+    let ( _, s ) = verify_hash_bottle_signed(chunked(signed(inner_bottle())), |_, blob| {
+      check_signature("mallory", blob)
+    }).wait().unwrap();
+    assert_eq!(s.collect().wait().err().unwrap().to_string(), "Bad signature");
+  }
+
+  #[test]
+  fn verify_signed_mixup() {
+    let e = verify_hash_bottle(chunked(signed(inner_bottle()))).wait().err().unwrap();
+    assert_eq!(e.to_string(), "Hashed bottle is signed (use a verifier)");
+    let data = hashed(inner_bottle(), HashAlgorithm::Sha256);
+    let e = verify_hash_bottle_signed(chunked(data), check_signature).wait().err().unwrap();
+    assert_eq!(e.to_string(), "Hashed bottle is not signed");
+  }
+}
@@ -0,0 +1,92 @@
+extern crate blake3;
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+extern crate sha2;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use futures::{Future, Stream};
+  use lib4bottle::bottle::{BottleType, make_bottle};
+  use lib4bottle::bottle_header::Header;
+  use lib4bottle::error::BottleError;
+  use lib4bottle::hashing::{CustomHasher, HashAlgorithm, crc32c, decode_hash_algorithm, hash_bottle_bytes, register_hash_algorithm};
+  use lib4bottle::stream_helpers::{drain_stream, make_vec_stream_1};
+  use lib4bottle::to_hex::ToHex;
+  use sha2::{Digest, Sha256, Sha512};
+  use std::io;
+
+  fn bottle() -> impl Stream<Item = Vec<Bytes>, Error = io::Error> {
+    let mut h = Header::new();
+    h.add_string(0, String::from("hello.txt"));
+    make_bottle(BottleType::Test, &h, vec![ make_vec_stream_1(Bytes::from_static(b"hello")) ])
+  }
+
+  #[test]
+  fn hash_bottle_bytes_sha256() {
+    let digest = hash_bottle_bytes(bottle(), HashAlgorithm::Sha256).wait().unwrap();
+    assert_eq!(digest.to_hex(), Sha256::digest(drain_stream(bottle())).to_vec().to_hex());
+  }
+
+  #[test]
+  fn hash_bottle_bytes_sha512() {
+    let digest = hash_bottle_bytes(bottle(), HashAlgorithm::Sha512).wait().unwrap();
+    assert_eq!(digest.len(), 64);
+    assert_eq!(digest.to_hex(), Sha512::digest(drain_stream(bottle())).to_vec().to_hex());
+  }
+
+  #[test]
+  fn hash_algorithm_ids() {
+    for &algorithm in &[ HashAlgorithm::Sha512, HashAlgorithm::Sha256, HashAlgorithm::Blake3 ] {
+      assert_eq!(decode_hash_algorithm(algorithm.id()).unwrap(), algorithm);
+    }
+    assert_eq!(HashAlgorithm::Blake3.id(), 2);
+    assert!(decode_hash_algorithm(9).is_err());
+  }
+
+  #[test]
+  fn hash_bottle_bytes_blake3() {
+    let digest = hash_bottle_bytes(bottle(), HashAlgorithm::Blake3).wait().unwrap();
+    assert_eq!(digest.len(), HashAlgorithm::Blake3.digest_size());
+    assert_eq!(digest.to_hex(), blake3::hash(&drain_stream(bottle())).as_bytes().to_hex());
+  }
+
+  #[derive(Clone)]
+  struct Length(usize);
+
+  impl CustomHasher for Length {
+    fn update(&mut self, data: &[u8]) {
+      self.0 += data.len();
+    }
+
+    fn finish(self: Box<Self>) -> Bytes {
+      Bytes::from((self.0 as u64).to_be_bytes().to_vec())
+    }
+
+    fn box_clone(&self) -> Box<dyn CustomHasher> {
+      Box::new(self.clone())
+    }
+  }
+
+  #[test]
+  fn custom_algorithm_ids() {
+    let e = register_hash_algorithm(2, 8, || Box::new(Length(0))).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::ReservedHashAlgorithm(2)));
+    assert_eq!(BottleError::find(&decode_hash_algorithm(40).unwrap_err()), Some(&BottleError::UnknownHashAlgorithm(40)));
+
+    let algorithm = register_hash_algorithm(40, 8, || Box::new(Length(0))).unwrap();
+    assert_eq!(algorithm, HashAlgorithm::Custom(40));
+    assert_eq!(decode_hash_algorithm(40).unwrap(), algorithm);
+    assert_eq!(algorithm.digest_size(), 8);
+    let digest = hash_bottle_bytes(bottle(), algorithm).wait().unwrap();
+    assert_eq!(digest.to_vec(), (drain_stream(bottle()).len() as u64).to_be_bytes().to_vec());
+  }
+
+  #[test]
+  fn crc32c_check_values() {
+    assert_eq!(crc32c(0, b""), 0);
+    assert_eq!(crc32c(0, b"123456789"), 0xe306_9283);
+    assert_eq!(crc32c(crc32c(0, b"1234"), b"56789"), 0xe306_9283);
+  }
+}
@@ -0,0 +1,137 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use futures::{Future, Stream};
+  use lib4bottle::archive::{ArchiveReader, ArchiveWriter};
+  use lib4bottle::compressed_bottle::{CompressionType};
+  use lib4bottle::error::{BottleError};
+  use lib4bottle::file_bottle::{ExtractOptions, archive_directory};
+  use lib4bottle::incremental::{Manifest, ManifestEntry, archive_directory_incremental, extract_incremental};
+  use lib4bottle::stream_helpers::{make_stream};
+  use std::fs;
+  use std::io;
+  use std::path::{Path, PathBuf};
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let path = ::common::temp_dir(&format!("incremental-{}", name));
+    fs::create_dir_all(path.join("stuff").join("inner")).unwrap();
+    path
+  }
+
+  fn drain<S: Stream<Item = Vec<Bytes>, Error = io::Error>>(s: S) -> Bytes {
+    Bytes::from(::common::drain(s))
+  }
+
+  fn manifest(data: &Bytes) -> Manifest {
+    Manifest::from_archive(ArchiveReader::new(), make_stream(vec![ data.clone() ])).wait().unwrap()
+  }
+
+  // each file's path, and whether it's a reference.
+  fn references(data: &Bytes) -> Vec<( String, bool )> {
+    ArchiveReader::new().entries(make_stream(vec![ data.clone() ])).filter(|entry| !entry.metadata.folder).map(|entry| {
+      ( entry.path.to_string_lossy().to_string(), entry.metadata.reference.is_some() )
+    }).collect().wait().unwrap()
+  }
+
+  // a full backup of a tree, and then an incremental one after a few changes.
+  fn snapshots(source: &Path) -> ( Bytes, Bytes ) {
+    let stuff = source.join("stuff");
+    fs::write(stuff.join("a.txt"), "ay").unwrap();
+    fs::write(stuff.join("b.txt"), "bee".repeat(100)).unwrap();
+    fs::write(stuff.join("inner").join("c.txt"), "sea".repeat(1000)).unwrap();
+    let full = drain(archive_directory(&stuff).unwrap());
+
+    fs::write(stuff.join("a.txt"), "ayy").unwrap();
+    fs::rename(stuff.join("b.txt"), stuff.join("inner").join("b2.txt")).unwrap();
+    fs::write(stuff.join("d.txt"), "dee").unwrap();
+    let incremental = drain(archive_directory_incremental(&stuff, manifest(&full)).unwrap());
+    ( full, incremental )
+  }
+
+  #[test]
+  fn manifest_round_trip() {
+    let mut manifest = Manifest::new();
+    manifest.add(ManifestEntry { path: PathBuf::from("stuff/a.txt"), size: Some(2), modified_nanos: Some(1000), digest: Bytes::from(vec![ 1; 32 ]) });
+    manifest.add(ManifestEntry { path: PathBuf::from("stuff/b.txt"), size: None, modified_nanos: None, digest: Bytes::from(vec![ 2; 32 ]) });
+    let decoded = Manifest::decode(&manifest.encode()).unwrap();
+    assert_eq!(decoded.entries(), manifest.entries());
+    assert_eq!(decoded.get("stuff/b.txt").unwrap().digest, Bytes::from(vec![ 2; 32 ]));
+    assert!(decoded.contains_digest(&[ 1; 32 ]));
+    assert!(!decoded.contains_digest(&[ 3; 32 ]));
+
+    let mut data = manifest.encode();
+    data.truncate(data.len() - 1);
+    assert_eq!(BottleError::find(&Manifest::decode(&data).err().unwrap()), Some(&BottleError::BadManifest));
+  }
+
+  #[test]
+  fn incremental_backup() {
+    let source = temp_dir("backup");
+    let ( full, incremental ) = snapshots(&source);
+    assert_eq!(references(&incremental), vec![
+      ( "stuff/a.txt".to_string(), false ),
+      ( "stuff/d.txt".to_string(), false ),
+      ( "stuff/inner/b2.txt".to_string(), true ),
+      ( "stuff/inner/c.txt".to_string(), true )
+    ]);
+    assert!(incremental.len() < full.len());
+
+    // the incremental archive is a parent for the next one, just like a full archive of the same tree.
+    let stuff = source.join("stuff");
+    let next = manifest(&incremental);
+    let everything = manifest(&drain(archive_directory(&stuff).unwrap()));
+    assert_eq!(next.entries(), everything.entries());
+
+    let target = source.join("out");
+    let parent = ( ArchiveReader::new(), make_stream(vec![ full ]) );
+    let paths = extract_incremental(( ArchiveReader::new(), make_stream(vec![ incremental ]) ), parent, &target, ExtractOptions::default());
+    assert_eq!(paths.wait().unwrap().len(), 6);
+    assert_eq!(fs::read(target.join("stuff").join("a.txt")).unwrap(), b"ayy");
+    assert_eq!(fs::read(target.join("stuff").join("d.txt")).unwrap(), b"dee");
+    assert_eq!(fs::read(target.join("stuff").join("inner").join("b2.txt")).unwrap(), "bee".repeat(100).into_bytes());
+    assert_eq!(fs::read(target.join("stuff").join("inner").join("c.txt")).unwrap(), "sea".repeat(1000).into_bytes());
+    assert_eq!(
+      fs::metadata(target.join("stuff").join("inner").join("c.txt")).unwrap().modified().unwrap(),
+      fs::metadata(stuff.join("inner").join("c.txt")).unwrap().modified().unwrap()
+    );
+    assert_eq!(fs::read_dir(&target).unwrap().count(), 1);
+    fs::remove_dir_all(&source).unwrap();
+  }
+
+  #[test]
+  fn incremental_with_layers() {
+    let source = temp_dir("layers");
+    let ( full, _ ) = snapshots(&source);
+    let writer = ArchiveWriter::new().add_path(source.join("stuff")).incremental(manifest(&full)).compress(CompressionType::Zstd);
+    let incremental = drain(writer.into_stream().unwrap());
+
+    let target = source.join("out");
+    let parent = ( ArchiveReader::new(), make_stream(vec![ full ]) );
+    extract_incremental(( ArchiveReader::new(), make_stream(vec![ incremental ]) ), parent, &target, ExtractOptions::default()).wait().unwrap();
+    assert_eq!(fs::read(target.join("stuff").join("inner").join("b2.txt")).unwrap(), "bee".repeat(100).into_bytes());
+    fs::remove_dir_all(&source).unwrap();
+  }
+
+  #[test]
+  fn missing_parent_entry() {
+    let source = temp_dir("missing");
+    let ( _, incremental ) = snapshots(&source);
+    // the wrong parent: it doesn't have the files that didn't change.
+    fs::create_dir_all(source.join("other")).unwrap();
+    fs::write(source.join("other").join("a.txt"), "ay").unwrap();
+    let other = drain(archive_directory(source.join("other")).unwrap());
+
+    let target = source.join("out");
+    let parent = ( ArchiveReader::new(), make_stream(vec![ other ]) );
+    let e = extract_incremental(( ArchiveReader::new(), make_stream(vec![ incremental ]) ), parent, &target, ExtractOptions::default()).wait().err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::MissingParentEntry(target.join("stuff").join("inner").join("b2.txt"))));
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    fs::remove_dir_all(&source).unwrap();
+  }
+}
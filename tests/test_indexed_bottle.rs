@@ -0,0 +1,87 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use common::drain;
+  use lib4bottle::bottle::{BottleType, bottle_from_slice, bottle_to_vec, make_bottle};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::error::BottleError;
+  use lib4bottle::indexed_bottle::{make_indexed_bottle};
+  use lib4bottle::stream_helpers::{make_vec_stream_1};
+  use lib4bottle::sync::{SeekableBottleReader, read_bottle};
+  use std::io::{self, Read};
+
+  fn indexed(streams: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut h = Header::new();
+    h.add_string(0, "hello");
+    drain(make_indexed_bottle(BottleType::Test, &h, streams.into_iter().map(|v| make_vec_stream_1(Bytes::from(v)))))
+  }
+
+  fn read_stream<R: Read + io::Seek>(reader: &mut SeekableBottleReader<R>, n: usize) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    reader.open_stream(n)?.read_to_end(&mut buffer)?;
+    Ok(buffer)
+  }
+
+  #[test]
+  fn read_streams_in_any_order() {
+    let big: Vec<u8> = (0 .. 100_000).map(|i| (i % 253) as u8).collect();
+    let data = indexed(vec![ b"one".to_vec(), vec![], big.clone(), b"four".to_vec() ]);
+    let mut reader = SeekableBottleReader::open(io::Cursor::new(data)).unwrap();
+    assert_eq!(reader.bottle_type(), BottleType::Test);
+    assert_eq!(reader.header().get_string(0), Some("hello"));
+    assert_eq!(reader.stream_count(), 4);
+    assert_eq!(read_stream(&mut reader, 3).unwrap(), b"four".to_vec());
+    assert_eq!(read_stream(&mut reader, 2).unwrap(), big);
+    assert_eq!(read_stream(&mut reader, 1).unwrap(), vec![]);
+    assert_eq!(read_stream(&mut reader, 0).unwrap(), b"one".to_vec());
+    let e = read_stream(&mut reader, 4).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::NoSuchEntry(4)));
+  }
+
+  #[test]
+  fn still_a_normal_bottle() {
+    let data = indexed(vec![ b"one".to_vec(), b"two".to_vec() ]);
+    let ( btype, header, streams ) = bottle_from_slice(&data).unwrap();
+    assert_eq!(btype, BottleType::Test);
+    assert_eq!(header.get_string(0), Some("hello"));
+    assert_eq!(streams, vec![ b"one".to_vec(), b"two".to_vec() ]);
+  }
+
+  #[test]
+  fn open_nested_bottles() {
+    let inner = |name: &str| {
+      let mut h = Header::new();
+      h.add_string(0, name);
+      make_bottle(BottleType::Test2, &h, vec![ make_vec_stream_1(Bytes::from(name.as_bytes().to_vec())) ])
+    };
+    let data = drain(make_indexed_bottle(BottleType::Test, &Header::new(), vec![ inner("a"), inner("b") ]));
+    let mut reader = SeekableBottleReader::open(io::Cursor::new(data)).unwrap();
+    let ( btype, header, mut children ) = read_bottle(reader.open_stream(1).unwrap()).unwrap();
+    assert_eq!(btype, BottleType::Test2);
+    assert_eq!(header.get_string(0), Some("b"));
+    let mut buffer = Vec::new();
+    children.next_stream().unwrap().unwrap().read_to_end(&mut buffer).unwrap();
+    assert_eq!(buffer, b"b".to_vec());
+  }
+
+  #[test]
+  fn no_index() {
+    let data = bottle_to_vec(BottleType::Test, &Header::new(), vec![ b"one".to_vec() ]).unwrap();
+    let e = SeekableBottleReader::open(io::Cursor::new(data)).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::NoIndex));
+
+    // an index with the wrong count.
+    let mut data = indexed(vec![ b"one".to_vec() ]);
+    let n = data.len() - 8 - 1 - 1 - 8 - 1 - 1;
+    assert_eq!(data[n], 1);
+    data[n] = 2;
+    let e = SeekableBottleReader::open(io::Cursor::new(data)).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::BadIndex));
+  }
+}
@@ -0,0 +1,95 @@
+#![cfg(unix)]
+
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+#[cfg(feature = "serde")]
+extern crate serde;
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use common::drain;
+  use futures::{Future, Stream};
+  use lib4bottle::archive::{ArchiveWriter};
+  use lib4bottle::file_bottle::{Deterministic};
+  use lib4bottle::hashing::{HashAlgorithm};
+  use lib4bottle::json_manifest::{JsonLine, export_manifest};
+  use lib4bottle::stream_helpers::{make_stream};
+  use std::fs;
+  use std::os::unix::fs as unix_fs;
+  use std::path::PathBuf;
+
+  fn temp_dir(name: &str) -> PathBuf {
+    let path = ::common::temp_dir(&format!("json-{}", name));
+    fs::create_dir_all(path.join("stuff")).unwrap();
+    path
+  }
+
+  fn manifest(writer: ArchiveWriter) -> Vec<String> {
+    let data = drain(writer.into_stream().unwrap());
+    export_manifest(make_stream(vec![ Bytes::from(data) ])).map(JsonLine::into_string).collect().wait().unwrap()
+  }
+
+  #[test]
+  fn export_a_manifest() {
+    let source = temp_dir("export");
+    let stuff = source.join("stuff");
+    fs::write(stuff.join("say \"hi\"\n.txt"), "hi").unwrap();
+    unix_fs::symlink("elsewhere", stuff.join("link")).unwrap();
+    let lines = manifest(ArchiveWriter::new().add_path(&stuff).deterministic(Deterministic::default()));
+    fs::remove_dir_all(&source).unwrap();
+
+    assert_eq!(lines, vec![
+      concat!(
+        "{\"path\":\"stuff\",\"metadata\":{\"filename\":\"stuff\",\"mime_type\":null,\"size\":null,\"posix_mode\":493,",
+        "\"created_nanos\":null,\"modified_nanos\":0,\"accessed_nanos\":null,\"username\":null,\"group\":null,\"folder\":true,",
+        "\"symlink\":null,\"hardlink\":null,\"xattrs\":[],\"sparse\":false,\"crc32c\":false,\"reference\":null},",
+        "\"layers\":[\"File\"],\"hashes\":[]}"
+      ).to_string(),
+      concat!(
+        "{\"path\":\"stuff/link\",\"metadata\":{\"filename\":\"link\",\"mime_type\":null,\"size\":null,\"posix_mode\":null,",
+        "\"created_nanos\":null,\"modified_nanos\":0,\"accessed_nanos\":null,\"username\":null,\"group\":null,\"folder\":false,",
+        "\"symlink\":\"elsewhere\",\"hardlink\":null,\"xattrs\":[],\"sparse\":false,\"crc32c\":false,\"reference\":null},",
+        "\"layers\":[\"File\"],\"hashes\":[]}"
+      ).to_string(),
+      concat!(
+        "{\"path\":\"stuff/say \\\"hi\\\"\\n.txt\",\"metadata\":{\"filename\":\"say \\\"hi\\\"\\n.txt\",\"mime_type\":null,\"size\":2,",
+        "\"posix_mode\":420,\"created_nanos\":null,\"modified_nanos\":0,\"accessed_nanos\":null,\"username\":null,\"group\":null,",
+        "\"folder\":false,\"symlink\":null,\"hardlink\":null,\"xattrs\":[],\"sparse\":false,\"crc32c\":false,\"reference\":null},",
+        "\"layers\":[\"File\"],\"hashes\":[]}"
+      ).to_string()
+    ]);
+  }
+
+  #[test]
+  fn export_hashes() {
+    let source = temp_dir("hashes");
+    fs::write(source.join("stuff").join("a.txt"), "ay").unwrap();
+    let lines = manifest(ArchiveWriter::new().add_path(source.join("stuff").join("a.txt")).hash(HashAlgorithm::Sha256));
+    fs::remove_dir_all(&source).unwrap();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].contains(",\"layers\":[\"Hashed\",\"File\"],\"hashes\":[{\"algorithm\":\"Sha256\",\"signed_by\":null,\"digest\":\""));
+    assert!(lines[0].ends_with("\"}]}"));
+  }
+
+  #[cfg(feature = "serde")]
+  #[test]
+  fn serde_types() {
+    use lib4bottle::archive::EntryInfo;
+    use lib4bottle::bottle_header::Header;
+    use lib4bottle::error::BottleError;
+    use lib4bottle::validate::{Problem, ValidationReport};
+    use serde::{Serialize};
+    use serde::de::DeserializeOwned;
+
+    fn check<T: Serialize + DeserializeOwned>() {}
+    check::<Header>();
+    check::<EntryInfo>();
+    check::<BottleError>();
+    check::<Problem>();
+    check::<ValidationReport>();
+  }
+}
@@ -0,0 +1,118 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use futures::{Future, Stream};
+  use lib4bottle::archive::ArchiveReader;
+  use lib4bottle::bottle::{BottleType, DecodeLimits, bottle_from_slice, bottle_to_vec};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::error::{BottleError, DecodeLimit};
+  use lib4bottle::parity_bottle::{repair_bottle, with_parity};
+  use lib4bottle::stream_helpers::{make_stream, make_vec_stream_1};
+  use std::io;
+
+  const RECORD_SIZE: usize = 8 + 4096;
+
+  fn data(size: usize) -> Vec<u8> {
+    (0 .. size).map(|i| ((i * 7) % 251) as u8).collect()
+  }
+
+  fn protect(data: &[u8], data_shards: usize, parity_shards: usize) -> Vec<u8> {
+    let s = with_parity(make_vec_stream_1(Bytes::from(data.to_vec())), data_shards, parity_shards).unwrap();
+    s.collect().wait().unwrap().into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect()
+  }
+
+  fn repair(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let s = make_stream(data.chunks(1000).map(Bytes::from).collect());
+    repair_bottle(s).and_then(|( _, s )| s.concat2()).map(|b| b.to_vec()).wait()
+  }
+
+  // damage some of the shards in stripe `stripe`.
+  fn damage(bottle: &[u8], stripe: usize, shards: &[usize]) -> Vec<u8> {
+    let ( btype, header, mut stripes ) = bottle_from_slice(bottle).unwrap();
+    for &i in shards { stripes[stripe][i * RECORD_SIZE + 100] ^= 0x55 }
+    bottle_to_vec(btype, &header, stripes).unwrap()
+  }
+
+  #[test]
+  fn round_trip() {
+    for &size in &[ 0, 1, 100, 4 * 4096 - 4, 4 * 4096, 100000 ] {
+      let original = data(size);
+      let bottle = protect(&original, 4, 2);
+      assert_eq!(bottle_from_slice(&bottle).unwrap().0, BottleType::Parity);
+      assert_eq!(repair(bottle).unwrap(), original, "size {}", size);
+    }
+  }
+
+  #[test]
+  fn repair_damaged_shards() {
+    let original = data(100000);
+    let bottle = protect(&original, 4, 2);
+    let damaged = [ vec![ 0 ], vec![ 5 ], vec![ 1, 3 ], vec![ 0, 4 ], vec![ 4, 5 ] ];
+    for shards in damaged.iter() {
+      assert_eq!(repair(damage(&bottle, 1, shards)).unwrap(), original, "shards {:?}", shards);
+    }
+    // one in each stripe:
+    let stripes = bottle_from_slice(&bottle).unwrap().2.len();
+    let mut bottle = bottle.clone();
+    for stripe in 0 .. stripes { bottle = damage(&bottle, stripe, &[ stripe % 6 ]) }
+    assert_eq!(repair(bottle).unwrap(), original);
+  }
+
+  #[test]
+  fn too_much_damage() {
+    let bottle = protect(&data(100000), 4, 2);
+    let e = repair(damage(&bottle, 2, &[ 0, 2, 5 ])).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::ParityExhausted(2)));
+
+    let ( btype, header, mut stripes ) = bottle_from_slice(&bottle).unwrap();
+    stripes[1].pop();
+    let e = repair(bottle_to_vec(btype, &header, stripes).unwrap()).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::BadParityStripe(1)));
+  }
+
+  #[test]
+  fn shard_counts() {
+    let empty = || make_vec_stream_1(Bytes::new());
+    for &( data_shards, parity_shards ) in &[ ( 0, 2 ), ( 4, 0 ), ( 200, 57 ) ] {
+      let e = with_parity(empty(), data_shards, parity_shards).err().unwrap();
+      assert_eq!(BottleError::find(&e), Some(&BottleError::BadShardCount { data_shards, parity_shards }));
+    }
+    let original = data(300000);
+    let bottle = protect(&original, 200, 56);
+    assert_eq!(repair(damage(&bottle, 0, &[ 0, 50, 199, 255 ])).unwrap(), original);
+  }
+
+  #[test]
+  fn hostile_shard_size() {
+    // the shard size is from the header, and mustn't overflow a stripe.
+    let bottle = |data_shards: u64, parity_shards: u64, shard_size: u64| {
+      let mut header = Header::new();
+      header.add_number(0, data_shards);
+      header.add_number(1, parity_shards);
+      header.add_number(2, shard_size);
+      bottle_to_vec(BottleType::Parity, &header, vec![ vec![ 0; 100 ] ]).unwrap()
+    };
+    for &size in &[ 0, 1, 1 << 40, u64::MAX ] {
+      let e = repair(bottle(1, 1, size)).unwrap_err();
+      assert_eq!(BottleError::find(&e), Some(&BottleError::BadShardSize(size)));
+    }
+    let e = repair(bottle(u64::MAX, 2, 4096)).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::BadShardCount { data_shards: usize::MAX, parity_shards: 2 }));
+
+    let limits = DecodeLimits { max_buffered_bytes: 10000, ..DecodeLimits::default() };
+    let s = make_stream(vec![ Bytes::from(bottle(4, 2, 4096)) ]);
+    let e = ArchiveReader::new().with_limits(limits).list(s).collect().wait().unwrap_err();
+    let limit = BottleError::DecodeLimitExceeded { limit: DecodeLimit::BufferedBytes, size: 6 * RECORD_SIZE as u64, max: 10000 };
+    assert_eq!(BottleError::find(&e), Some(&limit));
+  }
+
+  #[test]
+  fn not_a_parity_bottle() {
+    let e = repair(bottle_to_vec(BottleType::Test, &Header::new(), vec![]).unwrap()).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::WrongType { expected: BottleType::Parity, found: BottleType::Test }));
+  }
+}
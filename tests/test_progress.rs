@@ -0,0 +1,77 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use common::{drain, temp_dir};
+  use futures::{Future};
+  use lib4bottle::bottle::{BottleType, make_bottle_with_progress};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::file_bottle::{ExtractOptions, archive_directory_with_progress, extract_bottle};
+  use lib4bottle::stream_helpers::{make_stream, make_vec_stream_1};
+  use std::cell::RefCell;
+  use std::fs;
+  use std::path::{Path, PathBuf};
+  use std::rc::Rc;
+
+  type Updates = Rc<RefCell<Vec<(u64, u64, Option<PathBuf>)>>>;
+
+  fn recorder() -> ( Updates, impl Fn(u64, u64, Option<&Path>) ) {
+    let updates: Updates = Rc::new(RefCell::new(Vec::new()));
+    let saved = updates.clone();
+    ( updates, move |bytes_in, bytes_out, entry: Option<&Path>| {
+      saved.borrow_mut().push(( bytes_in, bytes_out, entry.map(|p| p.to_path_buf()) ));
+    } )
+  }
+
+  #[test]
+  fn make_bottle_progress() {
+    let ( updates, progress ) = recorder();
+    let streams = vec![ make_vec_stream_1(Bytes::from("hello")), make_vec_stream_1(Bytes::from("sailor!")) ];
+    let data = drain(make_bottle_with_progress(BottleType::Test, &Header::new(), streams, progress));
+    let updates = updates.borrow();
+    let &( bytes_in, bytes_out, ref entry ) = updates.last().unwrap();
+    assert_eq!(bytes_in, 12);
+    assert_eq!(bytes_out, data.len() as u64);
+    assert_eq!(*entry, None);
+    // totals only go up.
+    assert!(updates.windows(2).all(|w| w[0].0 <= w[1].0 && w[0].1 <= w[1].1));
+  }
+
+  #[test]
+  fn archive_and_extract_progress() {
+    let source = temp_dir("progress-source");
+    let root = source.join("stuff");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("a.txt"), b"ay").unwrap();
+    fs::write(root.join("b.txt"), vec![ 7u8; 100_000 ]).unwrap();
+
+    let ( updates, progress ) = recorder();
+    let data = drain(archive_directory_with_progress(&root, progress).unwrap());
+    {
+      let updates = updates.borrow();
+      let entries: Vec<PathBuf> = updates.iter().filter_map(|u| u.2.clone()).fold(Vec::new(), |mut v, p| {
+        if v.last() != Some(&p) { v.push(p) }
+        v
+      });
+      assert_eq!(entries, vec![ root.clone(), root.join("a.txt"), root.join("b.txt") ]);
+      let last = updates.last().unwrap();
+      assert_eq!(( last.0, last.1 ), ( 100_002, data.len() as u64 ));
+    }
+
+    let target = temp_dir("progress-target");
+    let ( updates, progress ) = recorder();
+    let s = make_stream(data.chunks(1000).map(Bytes::from).collect());
+    extract_bottle(s, &target, ExtractOptions::default().with_progress(progress)).wait().unwrap();
+    let updates = updates.borrow();
+    let last = updates.last().unwrap();
+    assert_eq!(( last.0, last.1 ), ( data.len() as u64, 100_002 ));
+    assert_eq!(last.2, Some(target.join("stuff/b.txt")));
+    fs::remove_dir_all(&source).unwrap();
+    fs::remove_dir_all(&target).unwrap();
+  }
+}
@@ -0,0 +1,245 @@
+#![cfg(feature = "http")]
+
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use futures::{Future, Stream};
+  use lib4bottle::bottle::{BottleType};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::error::BottleError;
+  use lib4bottle::indexed_bottle::{make_indexed_bottle};
+  use lib4bottle::range_reader::{HttpSource, RangeReader, RangeSource};
+  use lib4bottle::stream_helpers::{make_vec_stream_1};
+  use lib4bottle::sync::{SeekableBottleReader};
+  use std::cell::Cell;
+  use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+  use std::net::TcpListener;
+  use std::rc::Rc;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+
+  // a file in memory, counting how many bytes were fetched.
+  struct MemorySource {
+    data: Vec<u8>,
+    fetched: Rc<Cell<usize>>
+  }
+
+  impl RangeSource for MemorySource {
+    fn size(&mut self) -> io::Result<u64> {
+      Ok(self.data.len() as u64)
+    }
+
+    fn fetch(&mut self, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+      self.fetched.set(self.fetched.get() + length);
+      Ok(self.data[offset as usize .. offset as usize + length].to_vec())
+    }
+  }
+
+  fn memory(data: Vec<u8>) -> MemorySource {
+    MemorySource { data, fetched: Rc::new(Cell::new(0)) }
+  }
+
+  fn indexed(streams: Vec<Vec<u8>>) -> Vec<u8> {
+    let s = make_indexed_bottle(BottleType::Test, &Header::new(), streams.into_iter().map(|v| make_vec_stream_1(Bytes::from(v))));
+    s.collect().wait().unwrap().into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect()
+  }
+
+  // answer `count` connections on a local port with whatever `respond`
+  // says, given the range that was asked for.
+  fn serve_with<F>(count: usize, respond: F) -> String where F: Fn(Option<( usize, usize )>) -> Vec<u8> + Send + 'static {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/archive.4b", listener.local_addr().unwrap());
+    thread::spawn(move || {
+      for stream in listener.incoming().take(count) {
+        let mut stream = stream.unwrap();
+        let mut range = None;
+        for line in BufReader::new(&stream).lines() {
+          let line = line.unwrap();
+          if line.is_empty() { break }
+          if let Some(value) = line.strip_prefix("Range: bytes=") {
+            let mut parts = value.split('-').map(|n| n.parse::<usize>().unwrap());
+            range = Some(( parts.next().unwrap(), parts.next().unwrap() ));
+          }
+        }
+        let _ = stream.write_all(&respond(range));
+      }
+    });
+    url
+  }
+
+  fn partial(data: &[u8], first: usize, last: usize) -> Vec<u8> {
+    let mut response = format!("HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n", first, last, data.len()).into_bytes();
+    response.extend(format!("Content-Length: {}\r\n\r\n", last - first + 1).into_bytes());
+    response.extend_from_slice(&data[first ..= last]);
+    response
+  }
+
+  // answer range requests for `data`, for `count` connections. with
+  // `ranges` false, it ignores the range and sends everything.
+  fn serve(data: Vec<u8>, count: usize, ranges: bool) -> String {
+    let data = Arc::new(data);
+    serve_with(count, move |range| match range {
+      Some(( first, last )) if ranges => partial(&data, first, last.min(data.len() - 1)),
+      _ => {
+        let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", data.len()).into_bytes();
+        response.extend_from_slice(&data);
+        response
+      }
+    })
+  }
+
+  #[test]
+  fn read_and_seek() {
+    let data: Vec<u8> = (0 .. 1000).map(|i| (i % 251) as u8).collect();
+    let mut reader = RangeReader::new(memory(data.clone())).unwrap().read_ahead(100);
+    assert_eq!(reader.size(), 1000);
+
+    let mut buffer = [ 0u8; 10 ];
+    reader.read_exact(&mut buffer).unwrap();
+    assert_eq!(&buffer[..], &data[0 .. 10]);
+    reader.read_exact(&mut buffer).unwrap();
+    assert_eq!(&buffer[..], &data[10 .. 20]);
+    assert_eq!(reader.requests(), 1);
+
+    assert_eq!(reader.seek(SeekFrom::End(-5)).unwrap(), 995);
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, data[995 ..].to_vec());
+    assert_eq!(reader.requests(), 2);
+
+    assert_eq!(reader.seek(SeekFrom::Current(-3)).unwrap(), 997);
+    assert_eq!(reader.seek(SeekFrom::Start(2000)).unwrap(), 2000);
+    assert_eq!(reader.read(&mut buffer).unwrap(), 0);
+    assert_eq!(reader.seek(SeekFrom::Current(-3000)).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+  }
+
+  #[test]
+  fn read_one_stream_without_the_rest() {
+    let big: Vec<u8> = (0 .. 500_000).map(|i| (i % 251) as u8).collect();
+    let data = indexed(vec![ big.clone(), b"small".to_vec(), big.clone() ]);
+    let source = memory(data.clone());
+    let fetched = source.fetched.clone();
+    let mut reader = SeekableBottleReader::open(RangeReader::new(source).unwrap().read_ahead(1024)).unwrap();
+    assert_eq!(reader.stream_count(), 3);
+    let mut buffer = Vec::new();
+    reader.open_stream(1).unwrap().read_to_end(&mut buffer).unwrap();
+    assert_eq!(buffer, b"small".to_vec());
+    assert!(fetched.get() < 10 * 1024);
+
+    buffer.clear();
+    reader.open_stream(0).unwrap().read_to_end(&mut buffer).unwrap();
+    assert_eq!(buffer, big);
+  }
+
+  #[test]
+  fn fetch_over_http() {
+    let data = indexed(vec![ b"one".to_vec(), b"two".to_vec() ]);
+    let url = serve(data.clone(), 10, true);
+    let mut reader = SeekableBottleReader::open(RangeReader::new(HttpSource::new(&url).unwrap()).unwrap()).unwrap();
+    let mut buffer = Vec::new();
+    reader.open_stream(1).unwrap().read_to_end(&mut buffer).unwrap();
+    assert_eq!(buffer, b"two".to_vec());
+  }
+
+  #[test]
+  fn server_without_ranges() {
+    let url = serve(vec![ 1, 2, 3 ], 1, false);
+    let e = RangeReader::new(HttpSource::new(&url).unwrap()).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::RangesNotSupported));
+    assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+  }
+
+  #[test]
+  fn server_sends_short_ranges() {
+    // at most 7 bytes per request, which is allowed: the reader asks again.
+    let data = indexed(vec![ b"one".to_vec(), b"two".to_vec() ]);
+    let shared = Arc::new(data.clone());
+    let url = serve_with(100, move |range| {
+      let ( first, last ) = range.unwrap();
+      partial(&shared, first, last.min(first + 6).min(shared.len() - 1))
+    });
+    let mut reader = RangeReader::new(HttpSource::new(&url).unwrap()).unwrap().read_ahead(0);
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer).unwrap();
+    assert_eq!(buffer, data);
+    assert!(reader.requests() >= data.len() / 7);
+  }
+
+  // read 10 bytes at 10 from a server that answers with `respond`, after
+  // getting the size right.
+  fn read_wrong_range<F>(respond: F) -> Option<BottleError> where F: Fn(&[u8], usize, usize) -> Vec<u8> + Send + 'static {
+    let data: Vec<u8> = (0 .. 100).collect();
+    let url = serve_with(2, move |range| {
+      let ( first, last ) = range.unwrap();
+      if first == 0 && last == 0 { partial(&data, 0, 0) } else { respond(&data, first, last) }
+    });
+    let mut reader = RangeReader::new(HttpSource::new(&url).unwrap()).unwrap().read_ahead(0);
+    reader.seek(SeekFrom::Start(10)).unwrap();
+    let mut buffer = [ 0u8; 10 ];
+    let e = reader.read(&mut buffer).unwrap_err();
+    BottleError::find(&e).cloned()
+  }
+
+  #[test]
+  fn server_sends_the_wrong_range() {
+    // starting somewhere else, or running past what was asked for.
+    assert_eq!(read_wrong_range(|data, first, last| partial(data, first + 1, last)), Some(BottleError::BadHttpResponse));
+    assert_eq!(read_wrong_range(|data, first, last| partial(data, first, last + 1)), Some(BottleError::BadHttpResponse));
+    // a length that doesn't match the range.
+    assert_eq!(read_wrong_range(|data, first, last| {
+      let mut response = format!("HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n", first, last, data.len()).into_bytes();
+      response.extend(b"Content-Length: 3\r\n\r\nabc".to_vec());
+      response
+    }), Some(BottleError::BadHttpResponse));
+    // less body than the headers promised.
+    assert_eq!(read_wrong_range(|data, first, last| {
+      let mut response = partial(data, first, last);
+      response.truncate(response.len() - 4);
+      response
+    }), Some(BottleError::TruncatedStream));
+    // an error status.
+    assert_eq!(read_wrong_range(|_, _, _| b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n".to_vec()), Some(BottleError::HttpStatus(403)));
+    // a header line that goes on and on is refused by the client, as an
+    // i/o error.
+    assert_eq!(read_wrong_range(|data, first, last| {
+      let mut response = b"HTTP/1.1 206 Partial Content\r\nX-Junk: ".to_vec();
+      response.extend(vec![ b'x'; 200_000 ]);
+      let rest = partial(data, first, last);
+      let n = rest.iter().position(|&b| b == b'\n').unwrap();
+      response.extend_from_slice(&rest[n - 1 ..]);
+      response
+    }), None);
+  }
+
+  #[test]
+  fn follow_redirects() {
+    // every other request is sent somewhere else on the same server.
+    let data = indexed(vec![ b"one".to_vec(), b"two".to_vec() ]);
+    let shared = Arc::new(data.clone());
+    let count = AtomicUsize::new(0);
+    let url = serve_with(4, move |range| {
+      if count.fetch_add(1, Ordering::SeqCst).is_multiple_of(2) { return b"HTTP/1.1 302 Found\r\nLocation: /moved.4b\r\nContent-Length: 0\r\n\r\n".to_vec() }
+      let ( first, last ) = range.unwrap();
+      partial(&shared, first, last.min(shared.len() - 1))
+    });
+    let mut reader = RangeReader::new(HttpSource::new(&url).unwrap()).unwrap();
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer).unwrap();
+    assert_eq!(buffer, data);
+  }
+
+  #[test]
+  fn bad_urls() {
+    for url in &[ "ftp://example.com/a", "http://", "http://host:port/a", "archive.4b" ] {
+      let e = HttpSource::new(url).err().unwrap();
+      assert_eq!(BottleError::find(&e), Some(&BottleError::BadUrl(url.to_string())));
+    }
+    assert!(HttpSource::new("http://example.com").is_ok());
+    assert!(HttpSource::new("https://example.com/bucket/archive.4b").is_ok());
+  }
+}
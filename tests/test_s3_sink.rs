@@ -0,0 +1,148 @@
+#![cfg(feature = "s3")]
+
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use futures::{Future, Stream};
+  use lib4bottle::bottle::{BottleType, bottle_from_slice, make_bottle};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::chunk_sink::{ChunkSink, upload_parts};
+  use lib4bottle::error::BottleError;
+  use lib4bottle::s3_sink::{MAX_PARTS, S3Credentials, S3Sink};
+  use lib4bottle::stream_helpers::{make_vec_stream_1};
+  use std::io::{self, BufRead, BufReader, Read, Write};
+  use std::net::TcpListener;
+  use std::sync::{Arc, Mutex};
+  use std::thread;
+
+  #[derive(Clone, Debug)]
+  struct Request {
+    method: String,
+    target: String,
+    authorization: String,
+    body: Vec<u8>
+  }
+
+  // a pretend S3 that logs every request, fails the PUT of `fail_part`, and
+  // pads the reply that starts an upload with `padding` bytes.
+  fn serve(fail_part: Option<usize>, padding: usize) -> ( String, Arc<Mutex<Vec<Request>>> ) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let server_log = log.clone();
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let words: Vec<String> = line.split_whitespace().map(|w| w.to_string()).collect();
+        let ( mut length, mut authorization ) = ( 0, String::new() );
+        loop {
+          let mut line = String::new();
+          reader.read_line(&mut line).unwrap();
+          let line = line.trim_end();
+          if line.is_empty() { break }
+          if let Some(n) = line.strip_prefix("Content-Length: ") { length = n.parse().unwrap() }
+          if let Some(a) = line.strip_prefix("Authorization: ") { authorization = a.to_string() }
+        }
+        let mut body = vec![ 0u8; length ];
+        reader.read_exact(&mut body).unwrap();
+        let request = Request { method: words[0].clone(), target: words[1].clone(), authorization, body };
+        server_log.lock().unwrap().push(request.clone());
+
+        let part = request.target.split("partNumber=").nth(1).and_then(|s| s.split('&').next()).map(|n| n.parse::<usize>().unwrap());
+        let ( status, headers, reply ) = match request.method.as_str() {
+          "PUT" if part.is_some() && part == fail_part => ( "500 Oops", "".to_string(), "<Error><Code>InternalError</Code></Error>".to_string() ),
+          "PUT" => ( "200 OK", format!("ETag: \"etag{}\"\r\n", part.unwrap()), "".to_string() ),
+          "POST" if request.target.ends_with("?uploads=") => {
+            let reply = format!("<InitiateMultipartUploadResult>{}<UploadId>up/1+2</UploadId></InitiateMultipartUploadResult>", " ".repeat(padding));
+            ( "200 OK", "".to_string(), reply )
+          },
+          "POST" => ( "200 OK", "".to_string(), "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>".to_string() ),
+          _ => ( "204 No Content", "".to_string(), "".to_string() )
+        };
+        write!(stream, "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}", status, headers, reply.len(), reply).unwrap();
+      }
+    });
+    ( url, log )
+  }
+
+  fn credentials() -> S3Credentials {
+    S3Credentials { access_key_id: "AKID".to_string(), secret_access_key: "secret".to_string(), session_token: None }
+  }
+
+  fn bottle() -> impl Stream<Item = Vec<Bytes>, Error = io::Error> {
+    let streams = (0 .. 10).map(|i| make_vec_stream_1(Bytes::from(vec![ i as u8; 1000 ])));
+    make_bottle(BottleType::Test, &Header::new(), streams.collect::<Vec<_>>())
+  }
+
+  #[test]
+  fn multipart_upload() {
+    let ( url, log ) = serve(None, 0);
+    let sink = S3Sink::new(&url, "us-east-1", "bucket", "backups/my archive.4b", credentials()).unwrap();
+    let ( sink, count ) = upload_parts(bottle(), sink, 4000).wait().unwrap();
+    assert_eq!(count, 3);
+    assert_eq!(sink.upload_id(), None);
+
+    let log = log.lock().unwrap();
+    let methods: Vec<&str> = log.iter().map(|r| r.method.as_str()).collect();
+    assert_eq!(methods, vec![ "POST", "PUT", "PUT", "PUT", "POST" ]);
+    assert_eq!(log[0].target, "/bucket/backups/my%20archive.4b?uploads=");
+    assert_eq!(log[1].target, "/bucket/backups/my%20archive.4b?partNumber=1&uploadId=up%2F1%2B2");
+    assert_eq!(log[4].target, "/bucket/backups/my%20archive.4b?uploadId=up%2F1%2B2");
+    assert!(log.iter().all(|r| r.authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKID/")));
+    assert!(log[0].authorization.contains("/us-east-1/s3/aws4_request, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature="));
+
+    let data: Vec<u8> = log[1 .. 4].iter().flat_map(|r| r.body.clone()).collect();
+    assert_eq!(bottle_from_slice(&data).unwrap().2.len(), 10);
+    let complete = String::from_utf8(log[4].body.clone()).unwrap();
+    assert!(complete.contains("<Part><PartNumber>3</PartNumber><ETag>&quot;etag3&quot;</ETag></Part>"));
+  }
+
+  #[test]
+  fn abort_after_failed_part() {
+    let ( url, log ) = serve(Some(2), 0);
+    let sink = S3Sink::new(&url, "us-east-1", "bucket", "archive.4b", credentials()).unwrap();
+    let e = upload_parts(bottle(), sink, 4000).wait().err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::UploadFailed("InternalError".to_string())));
+
+    let log = log.lock().unwrap();
+    let methods: Vec<&str> = log.iter().map(|r| r.method.as_str()).collect();
+    assert_eq!(methods, vec![ "POST", "PUT", "PUT", "DELETE" ]);
+    assert_eq!(log[3].target, "/bucket/archive.4b?uploadId=up%2F1%2B2");
+  }
+
+  #[test]
+  fn too_many_parts() {
+    let mut sink = S3Sink::new("http://localhost:1", "us-east-1", "bucket", "archive.4b", credentials()).unwrap();
+    let e = sink.upload_part(MAX_PARTS + 1, vec![]).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::TooManyParts(MAX_PARTS)));
+  }
+
+  #[test]
+  fn huge_reply() {
+    let ( url, log ) = serve(None, 100_000);
+    let sink = S3Sink::new(&url, "us-east-1", "bucket", "archive.4b", credentials()).unwrap();
+    let e = upload_parts(bottle(), sink, 4000).wait().err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::BadHttpResponse));
+    assert_eq!(log.lock().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn https_only() {
+    let s3 = |url: &str| S3Sink::new(url, "us-east-1", "bucket", "archive.4b", credentials()).map(|_| ());
+    assert!(s3("https://s3.us-east-1.amazonaws.com").is_ok());
+    assert!(s3("https://minio.example.com:9000/prefix").is_ok());
+    assert!(s3("http://localhost:9000").is_ok());
+    assert!(s3("http://127.0.0.1:9000").is_ok());
+    assert!(s3("http://[::1]:9000").is_ok());
+    let e = s3("http://s3.us-east-1.amazonaws.com").err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::BadUrl("http://s3.us-east-1.amazonaws.com".to_string())));
+    assert!(s3("ftp://localhost").is_err());
+  }
+}
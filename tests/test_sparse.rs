@@ -0,0 +1,166 @@
+#![cfg(unix)]
+
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use common::{drain, temp_dir};
+  use futures::{Future, Stream};
+  use lib4bottle::archive::ArchiveReader;
+  use lib4bottle::bottle::{BottleType, DecodeLimits, bottle_from_slice, child_from_bytes, make_bottle};
+  use lib4bottle::error::{BottleError, DecodeLimit};
+  use lib4bottle::file_bottle::{ExtractOptions, FileMetadata, extract_bottle, file_bottle};
+  use lib4bottle::sparse::data_extents;
+  use lib4bottle::stream_helpers::make_stream;
+  use std::fs;
+  use std::io::{self, Seek, SeekFrom, Write};
+  use std::os::unix::fs::MetadataExt;
+  use std::path::{Path, PathBuf};
+
+  const MB: u64 = 1024 * 1024;
+
+  // 10MB, with 64KB of data at 1MB and 5MB, and holes everywhere else.
+  fn sparse_file(path: &Path) -> Vec<u8> {
+    let mut file = fs::File::create(path).unwrap();
+    file.set_len(10 * MB).unwrap();
+    for &( offset, fill ) in &[ ( MB, 1u8 ), ( 5 * MB, 5u8 ) ] {
+      file.seek(SeekFrom::Start(offset)).unwrap();
+      file.write_all(&vec![ fill; 65536 ]).unwrap();
+    }
+    drop(file);
+    fs::read(path).unwrap()
+  }
+
+  fn is_sparse(path: &Path) -> bool {
+    let stat = fs::metadata(path).unwrap();
+    stat.blocks() * 512 < stat.len()
+  }
+
+  fn extract(data: Vec<u8>, target: &Path) -> io::Result<Vec<PathBuf>> {
+    let s = make_stream(data.chunks(1000).map(Bytes::from).collect());
+    extract_bottle(s, target, ExtractOptions::default()).wait()
+  }
+
+  #[test]
+  fn find_extents() {
+    let dir = temp_dir("sparse-extents");
+    let path = dir.join("holes");
+    sparse_file(&path);
+    let file = fs::File::open(&path).unwrap();
+    // the filesystem may round up to its own block size.
+    let extents = data_extents(&file, 10 * MB).unwrap();
+    assert!(extents.iter().any(|&( offset, length )| offset <= MB && offset + length >= MB + 65536));
+    assert!(extents.iter().any(|&( offset, length )| offset <= 5 * MB && offset + length >= 5 * MB + 65536));
+    assert!(extents.iter().map(|e| e.1).sum::<u64>() < 10 * MB);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn archive_and_extract_sparse_file() {
+    let dir = temp_dir("sparse-round-trip");
+    let path = dir.join("holes");
+    let original = sparse_file(&path);
+    if !is_sparse(&path) { return fs::remove_dir_all(&dir).unwrap() }
+
+    let data = drain(file_bottle(&path).unwrap());
+    assert!(data.len() < MB as usize);
+    let metadata = FileMetadata::from_header(&bottle_from_slice(&data).unwrap().1).unwrap();
+    assert!(metadata.sparse);
+    assert_eq!(metadata.size, Some(10 * MB));
+
+    let target = dir.join("out");
+    fs::create_dir(&target).unwrap();
+    assert_eq!(extract(data.clone(), &target).unwrap(), vec![ target.join("holes") ]);
+    assert!(fs::read(target.join("holes")).unwrap() == original);
+    assert!(is_sparse(&target.join("holes")));
+
+    // an archive reader fills the holes back in.
+    let s = make_stream(data.chunks(1000).map(Bytes::from).collect());
+    let entries = ArchiveReader::new().entries(s).and_then(|entry| entry.content.concat2()).collect().wait().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert!(entries[0].to_vec() == original);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn dense_file_is_not_sparse() {
+    let dir = temp_dir("sparse-dense");
+    let path = dir.join("dense");
+    fs::write(&path, vec![ 3u8; 100000 ]).unwrap();
+    let data = drain(file_bottle(&path).unwrap());
+    let metadata = FileMetadata::from_header(&bottle_from_slice(&data).unwrap().1).unwrap();
+    assert!(!metadata.sparse);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  fn sparse_bottle(size: u64, extents: &[( u64, u64 )], contents: Vec<u8>) -> Vec<u8> {
+    let metadata = FileMetadata { filename: "holes".to_string(), size: Some(size), sparse: true, ..FileMetadata::default() };
+    let mut map = Vec::new();
+    for &( offset, length ) in extents {
+      map.extend_from_slice(&offset.to_le_bytes());
+      map.extend_from_slice(&length.to_le_bytes());
+    }
+    let children = vec![ make_stream(vec![ Bytes::from(map) ]), make_stream(vec![ Bytes::from(contents) ]) ];
+    drain(make_bottle(BottleType::File, &metadata.to_header(), children.into_iter().map(child_from_bytes)))
+  }
+
+  #[test]
+  fn extract_from_map() {
+    let dir = temp_dir("sparse-map");
+    let data = sparse_bottle(100, &[ ( 10, 3 ), ( 50, 2 ) ], b"abcde".to_vec());
+    extract(data, &dir).unwrap();
+    let mut expected = vec![ 0u8; 100 ];
+    expected[10 .. 13].copy_from_slice(b"abc");
+    expected[50 .. 52].copy_from_slice(b"de");
+    assert_eq!(fs::read(dir.join("holes")).unwrap(), expected);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn reject_bad_maps() {
+    let dir = temp_dir("sparse-bad-map");
+    let bad = vec![
+      sparse_bottle(100, &[ ( 10, 3 ), ( 11, 2 ) ], b"abcde".to_vec()),
+      sparse_bottle(100, &[ ( 10, 0 ) ], Vec::new()),
+      sparse_bottle(100, &[ ( 99, 2 ) ], b"ab".to_vec()),
+      sparse_bottle(100, &[ ( 10, 3 ) ], b"ab".to_vec()),
+      sparse_bottle(100, &[ ( 10, 3 ) ], b"abcd".to_vec())
+    ];
+    for data in bad {
+      let _ = fs::remove_file(dir.join("holes"));
+      let e = extract(data, &dir).err().unwrap();
+      assert_eq!(BottleError::find(&e), Some(&BottleError::BadSparseMap));
+    }
+
+    let mut header = FileMetadata { filename: "holes".to_string(), sparse: true, ..FileMetadata::default() }.to_header();
+    assert!(FileMetadata::from_header(&header).is_err());
+    header.add_number(0, 10);
+    assert!(FileMetadata::from_header(&header).unwrap().sparse);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn limit_map_size() {
+    let dir = temp_dir("sparse-map-limit");
+    let extents: Vec<( u64, u64 )> = (0 .. 100).map(|i| ( i * 10, 1 )).collect();
+    let data = sparse_bottle(1000, &extents, vec![ b'x'; 100 ]);
+    let limits = DecodeLimits { max_buffered_bytes: 1000, ..DecodeLimits::default() };
+    let expected = BottleError::DecodeLimitExceeded { limit: DecodeLimit::BufferedBytes, size: 1600, max: 1000 };
+
+    let s = make_stream(vec![ Bytes::from(data.clone()) ]);
+    let e = extract_bottle(s, &dir, ExtractOptions { limits, ..ExtractOptions::default() }).wait().unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&expected));
+    let s = make_stream(vec![ Bytes::from(data.clone()) ]);
+    let e = ArchiveReader::new().with_limits(limits).entries(s).and_then(|entry| entry.content.concat2()).collect().wait().err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&expected));
+
+    let _ = fs::remove_file(dir.join("holes"));
+    assert_eq!(extract(data, &dir).unwrap().len(), 1);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,102 @@
+extern crate bytes;
+extern crate futures;
+extern crate futures_core;
+extern crate lib4bottle;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use futures::{Sink, Stream};
+  use futures::sync::mpsc;
+  use futures_core::Stream as StdStream;
+  use lib4bottle::bottle::{BottleType, bottle_to_vec};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::std_future::{compat_stream, read_bottle_std, read_header_std};
+  use lib4bottle::stream_helpers::{make_stream};
+  use std::future::{Future, poll_fn};
+  use std::io;
+  use std::pin::Pin;
+  use std::sync::Arc;
+  use std::task::{Context, Poll, Wake, Waker};
+  use std::thread;
+  use std::time::Duration;
+
+  struct ThreadWaker(thread::Thread);
+
+  impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+      self.0.unpark();
+    }
+  }
+
+  // the least executor that parks until it's woken.
+  fn block_on<F: Future>(f: F) -> F::Output {
+    let mut f = Box::pin(f);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+      if let Poll::Ready(output) = f.as_mut().poll(&mut cx) { return output }
+      thread::park();
+    }
+  }
+
+  fn next<S: StdStream + Unpin>(s: &mut S) -> Option<S::Item> {
+    block_on(poll_fn(|cx| Pin::new(&mut *s).poll_next(cx)))
+  }
+
+  fn sample() -> Vec<u8> {
+    let mut header = Header::new();
+    header.add_string(1, "hello");
+    bottle_to_vec(BottleType::Test, &header, vec![ b"ay".to_vec(), b"bee".to_vec() ]).unwrap()
+  }
+
+  #[test]
+  fn read_bottle_awaited() {
+    let s = make_stream(vec![ Bytes::from(sample()) ]);
+    let ( btype, header, mut children ) = block_on(read_bottle_std(s)).unwrap();
+    assert_eq!(btype, BottleType::Test);
+    assert_eq!(header.get_string(1), Some("hello"));
+
+    let mut contents = Vec::new();
+    while let Some(child) = next(&mut children) {
+      let mut child = compat_stream(child.unwrap());
+      let mut data = Vec::new();
+      while let Some(b) = next(&mut child) { data.extend_from_slice(&b.unwrap()) }
+      contents.push(data);
+    }
+    assert_eq!(contents, vec![ b"ay".to_vec(), b"bee".to_vec() ]);
+  }
+
+  #[test]
+  fn wakes_when_data_arrives() {
+    let ( tx, rx ) = mpsc::channel::<Bytes>(1);
+    let data = sample();
+    let sender = thread::spawn(move || {
+      let mut tx = tx.wait();
+      for b in data.chunks(3) {
+        thread::sleep(Duration::from_millis(5));
+        tx.send(Bytes::from(b)).unwrap();
+      }
+    });
+    let rx = rx.map_err(|_| io::Error::other("closed"));
+    let ( btype, _, mut rest ) = block_on(read_header_std(rx)).unwrap();
+    assert_eq!(btype, BottleType::Test);
+    let mut n = 0;
+    while let Some(b) = next(&mut rest) { n += b.unwrap().len() }
+    assert!(n > 5);
+    sender.join().unwrap();
+  }
+
+  #[test]
+  fn poll_next_reports_errors() {
+    let mut s = compat_stream(make_stream(vec![ Bytes::from("nope") ]).and_then(|_| -> io::Result<Bytes> {
+      Err(io::Error::other("nope"))
+    }));
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    match Pin::new(&mut s).poll_next(&mut cx) {
+      Poll::Ready(Some(Err(e))) => assert_eq!(e.to_string(), "nope"),
+      _ => panic!("expected an error")
+    }
+  }
+}
@@ -0,0 +1,125 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use futures::{Async, Future, Stream, future, stream};
+  use lib4bottle::error::BottleError;
+  use lib4bottle::hashing::{HashAlgorithm, hash_bottle_bytes};
+  use lib4bottle::stream_helpers::{limit_bytes, make_stream, make_stream_2, rate_limit_chunks, tee_digest, throttle_stream};
+  use lib4bottle::to_hex::ToHex;
+  use std::io;
+  use std::time::{Duration, Instant};
+
+  #[test]
+  fn limit_bytes_passes_small_streams() {
+    let s = make_stream(vec![ Bytes::from_static(b"hell"), Bytes::from_static(b"o") ]);
+    assert_eq!(limit_bytes(s, 5).collect().wait().unwrap().to_hex(), "68656c6c6f");
+  }
+
+  #[test]
+  fn limit_bytes_stops_large_streams() {
+    let s = make_stream(vec![ Bytes::from_static(b"hell"), Bytes::from_static(b"o"), Bytes::from_static(b"!") ]);
+    let mut limited = limit_bytes(s, 5);
+    assert_eq!(limited.poll().unwrap(), Async::Ready(Some(Bytes::from_static(b"hell"))));
+    assert_eq!(limited.poll().unwrap(), Async::Ready(Some(Bytes::from_static(b"o"))));
+    assert_eq!(limited.poll().unwrap_err().kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn rate_limit_chunks_delays_each_item() {
+    let s = make_stream(vec![ Bytes::from_static(b"hell"), Bytes::from_static(b"o") ]);
+    let mut slow = rate_limit_chunks(s, 2);
+    future::lazy(|| {
+      assert_eq!(slow.poll().unwrap(), Async::NotReady);
+      assert_eq!(slow.poll().unwrap(), Async::NotReady);
+      assert_eq!(slow.poll().unwrap(), Async::Ready(Some(Bytes::from_static(b"hell"))));
+      assert_eq!(slow.poll().unwrap(), Async::NotReady);
+      assert_eq!(slow.poll().unwrap(), Async::NotReady);
+      assert_eq!(slow.poll().unwrap(), Async::Ready(Some(Bytes::from_static(b"o"))));
+      Ok::<_, io::Error>(())
+    }).wait().unwrap();
+    assert_eq!(slow.collect().wait().unwrap().len(), 0);
+  }
+
+  #[test]
+  fn rate_limit_chunks_preserves_data() {
+    let s = make_stream(vec![ Bytes::from_static(b"hell"), Bytes::from_static(b"o") ]);
+    assert_eq!(rate_limit_chunks(s, 3).collect().wait().unwrap().to_hex(), "68656c6c6f");
+  }
+
+  #[test]
+  fn tee_digest_hashes_everything() {
+    let ( s, digest ) = tee_digest(make_stream_2(Bytes::from_static(b"hell"), Bytes::from_static(b"o")), HashAlgorithm::Sha256).unwrap();
+    let buffers = s.collect().wait().unwrap();
+    assert_eq!(buffers.to_hex(), "68656c6c6f");
+    let expected = hash_bottle_bytes(make_stream_2(Bytes::from_static(b"hell"), Bytes::from_static(b"o")), HashAlgorithm::Sha256);
+    assert_eq!(digest.wait().unwrap(), expected.wait().unwrap());
+  }
+
+  #[test]
+  fn tee_digest_fails_with_the_stream() {
+    let s = stream::iter_result(vec![ Ok(vec![ Bytes::from_static(b"hell") ]), Err(io::Error::new(io::ErrorKind::InvalidData, "oops")) ]);
+    let ( s, digest ) = tee_digest(s, HashAlgorithm::Sha512).unwrap();
+    assert!(s.collect().wait().is_err());
+    let e = digest.wait().unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::DigestCanceled));
+
+    let ( s, digest ) = tee_digest(make_stream_2(Bytes::from_static(b"hell"), Bytes::from_static(b"o")), HashAlgorithm::Sha512).unwrap();
+    drop(s);
+    assert!(digest.wait().is_err());
+  }
+
+  #[test]
+  fn throttle_stream_bursts_then_waits() {
+    let s = make_stream((0 .. 10).map(|_| Bytes::from(vec![ 7u8; 20_000 ])).collect());
+    let mut throttled = throttle_stream(s, 100_000);
+    future::lazy(|| {
+      // a second's worth goes right away, and a bit more on credit.
+      for _ in 0 .. 6 { assert_eq!(throttled.poll().unwrap().map(|b| b.map(|b| b.len())), Async::Ready(Some(20_000))) }
+      assert_eq!(throttled.poll().unwrap(), Async::NotReady);
+      Ok::<_, io::Error>(())
+    }).wait().unwrap();
+
+    let started = Instant::now();
+    let rest = throttled.collect().wait().unwrap();
+    assert_eq!(rest.len(), 4);
+    // paying off 20K, then 3 more buffers (the last one is never paid for).
+    assert!(started.elapsed() >= Duration::from_millis(750));
+  }
+
+  #[test]
+  fn throttle_stream_counts_bottle_buffers() {
+    let s = make_stream_2(Bytes::from(vec![ 1u8; 600 ]), Bytes::from(vec![ 2u8; 600 ]));
+    let mut throttled = throttle_stream(s, 1000);
+    future::lazy(|| {
+      assert_eq!(throttled.poll().unwrap().map(|v| v.map(|v| v.len())), Async::Ready(Some(1)));
+      assert_eq!(throttled.poll().unwrap().map(|v| v.map(|v| v.len())), Async::Ready(Some(1)));
+      assert_eq!(throttled.poll().unwrap(), Async::Ready(None));
+      Ok::<_, io::Error>(())
+    }).wait().unwrap();
+  }
+
+  #[test]
+  fn throttle_stream_preserves_data() {
+    let s = make_stream(vec![ Bytes::from_static(b"hell"), Bytes::from_static(b"o") ]);
+    assert_eq!(throttle_stream(s, 5).collect().wait().unwrap().to_hex(), "68656c6c6f");
+  }
+
+  #[test]
+  fn throttle_stream_stops_its_timer_when_dropped() {
+    let s = make_stream(vec![ Bytes::from(vec![ 0u8; 100 ]), Bytes::from(vec![ 0u8; 100 ]) ]);
+    let mut throttled = throttle_stream(s, 1);
+    let started = Instant::now();
+    future::lazy(move || {
+      assert!(throttled.poll().unwrap().is_ready());
+      // the next buffer would wait about 99 seconds.
+      assert_eq!(throttled.poll().unwrap(), Async::NotReady);
+      drop(throttled);
+      Ok::<_, io::Error>(())
+    }).wait().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+  }
+}
@@ -94,4 +94,58 @@ mod tests {
     assert_eq!(rv4.remainder, None);
     assert_eq!(rv4.stream.collect().wait().unwrap().to_hex(), "");
   }
+
+  #[test]
+  fn stream_peek_leaves_the_stream_alone() {
+    let s = make_stream(vec![
+      Bytes::from_static(b"pr"),
+      Bytes::from_static(b"ogres"),
+      Bytes::from_static(b"sive")
+    ]);
+    let (data1, s1) = StreamReader::peek(s, 4).wait().unwrap();
+    assert_eq!(data1.vec.to_hex(), "70726f67");
+    assert_eq!(data1.length, 4);
+    let (data2, s2) = StreamReader::peek(s1, 3).wait().unwrap();
+    assert_eq!(data2.vec.to_hex(), "70726f");
+    assert_eq!(s2.collect().wait().unwrap().to_hex(), "70726f6772657373697665");
+  }
+
+  #[test]
+  fn stream_peek_past_the_end() {
+    let s = make_stream_1(Bytes::from_static(b"pro"));
+    let (data1, s1) = StreamReader::peek(s, 10).wait().unwrap();
+    assert_eq!(data1.vec.to_hex(), "70726f");
+    assert_eq!(s1.collect().wait().unwrap().to_hex(), "70726f");
+  }
+
+  #[test]
+  fn stream_skip_exact_works() {
+    let s = make_stream(vec![
+      Bytes::from_static(b"pr"),
+      Bytes::from_static(b"ogres"),
+      Bytes::from_static(b"s"),
+      Bytes::from_static(b"ive")
+    ]);
+    let s1 = StreamReader::skip_exact(s, 3).wait().unwrap();
+    let (data1, s2) = StreamReader::read_exact(s1, 2).wait().unwrap();
+    assert_eq!(data1.vec.to_hex(), "6772");
+    let s3 = StreamReader::skip_exact(s2, 4).wait().unwrap();
+    assert_eq!(s3.collect().wait().unwrap().to_hex(), "7665");
+  }
+
+  #[test]
+  fn stream_skip_exact_on_a_boundary() {
+    let s = make_stream(vec![ Bytes::from_static(b"pr"), Bytes::from_static(b"ogressive") ]);
+    let s1 = StreamReader::skip_exact(s, 0).wait().unwrap();
+    let s2 = StreamReader::skip_exact(s1, 2).wait().unwrap();
+    assert_eq!(s2.collect().wait().unwrap().to_hex(), "6f6772657373697665");
+  }
+
+  #[test]
+  fn stream_skip_exact_refuses_to_truncate() {
+    let s = make_stream_1(Bytes::from_static(b"progressive"));
+    assert!(StreamReader::skip_exact(s, 12).wait().is_err());
+    let s = make_stream_1(Bytes::from_static(b"progressive"));
+    assert_eq!(StreamReader::skip_exact(s, 11).wait().unwrap().collect().wait().unwrap().len(), 0);
+  }
 }
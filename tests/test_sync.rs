@@ -0,0 +1,182 @@
+extern crate lib4bottle;
+
+#[cfg(test)]
+mod tests {
+  use lib4bottle::bottle::{BottleType, FIELD_INTERLEAVED, FIELD_STREAM_COUNT, bottle_from_slice, bottle_to_vec};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::error::BottleError;
+  use lib4bottle::file_bottle::FileMetadata;
+  use lib4bottle::sync::{append_to_bottle, read_bottle, write_bottle};
+  use lib4bottle::ToHex;
+  use std::io::{self, Read};
+
+  fn read_all(data: &[u8]) -> io::Result<(BottleType, Header, Vec<Vec<u8>>)> {
+    let ( btype, header, mut bottle ) = read_bottle(data)?;
+    let mut streams = Vec::new();
+    while let Some(mut child) = bottle.next_stream()? {
+      let mut buffer = Vec::new();
+      child.read_to_end(&mut buffer)?;
+      streams.push(buffer);
+    }
+    Ok(( btype, header, streams ))
+  }
+
+  #[test]
+  fn write_the_same_bytes_as_make_bottle() {
+    let mut h = Header::new();
+    h.add_number(0, 150);
+    let mut out = Vec::new();
+    write_bottle(&mut out, BottleType::Test, &h, vec![ &b"hello"[..], &b""[..], &b"goodbye"[..] ]).unwrap();
+    let expected = bottle_to_vec(BottleType::Test, &h, vec![ b"hello".to_vec(), vec![], b"goodbye".to_vec() ]).unwrap();
+    assert_eq!(out.to_hex(), expected.to_hex());
+  }
+
+  #[test]
+  fn round_trip_a_large_stream() {
+    let data: Vec<u8> = (0 .. 200_000).map(|i| (i % 251) as u8).collect();
+    let mut out = Vec::new();
+    write_bottle(&mut out, BottleType::Test2, &Header::new(), vec![ &data[..] ]).unwrap();
+    let ( btype, _, streams ) = bottle_from_slice(&out).unwrap();
+    assert_eq!(btype, BottleType::Test2);
+    assert_eq!(streams, vec![ data.clone() ]);
+
+    let ( _, _, streams ) = read_all(&out).unwrap();
+    assert_eq!(streams, vec![ data ]);
+  }
+
+  #[test]
+  fn read_a_bottle() {
+    let mut h = Header::new();
+    h.add_string(1, "name");
+    let data = bottle_to_vec(BottleType::Test, &h, vec![ b"one".to_vec(), vec![], b"three".to_vec() ]).unwrap();
+    let ( btype, header, streams ) = read_all(&data).unwrap();
+    assert_eq!(btype, BottleType::Test);
+    assert_eq!(header.get_string(1), Some("name"));
+    assert_eq!(streams, vec![ b"one".to_vec(), vec![], b"three".to_vec() ]);
+  }
+
+  #[test]
+  fn skip_unread_streams() {
+    let data = bottle_to_vec(BottleType::Test, &Header::new(), vec![ b"one".to_vec(), b"two".to_vec() ]).unwrap();
+    let ( _, _, mut bottle ) = read_bottle(&data[..]).unwrap();
+    bottle.next_stream().unwrap().unwrap();
+    let mut buffer = Vec::new();
+    bottle.next_stream().unwrap().unwrap().read_to_end(&mut buffer).unwrap();
+    assert_eq!(buffer, b"two".to_vec());
+    assert!(bottle.next_stream().unwrap().is_none());
+  }
+
+  #[test]
+  fn fail_on_truncated_bottles() {
+    let data = bottle_to_vec(BottleType::Test, &Header::new(), vec![ b"hello".to_vec() ]).unwrap();
+    for n in 0 .. data.len() - 1 {
+      assert!(read_all(&data[0 .. n]).is_err(), "truncated at {}", n);
+    }
+    assert!(read_all(b"not a bottle at all").is_err());
+  }
+
+  #[test]
+  fn refuse_interleaved_bottles() {
+    let mut h = Header::new();
+    h.add_number(FIELD_INTERLEAVED, 2);
+    let data = bottle_to_vec(BottleType::Test, &h, vec![ b"one".to_vec() ]).unwrap();
+    let e = read_all(&data).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::Interleaved));
+    assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+  }
+
+  #[test]
+  fn append_streams() {
+    let mut h = Header::new();
+    h.add_number(0, 150);
+    // 0xff inside the data shouldn't be mistaken for the end.
+    let big = vec![ 0xffu8; 70_000 ];
+    let data = bottle_to_vec(BottleType::Test, &h, vec![ b"one".to_vec(), big.clone() ]).unwrap();
+    let mut file = io::Cursor::new(data);
+    assert_eq!(append_to_bottle(&mut file, vec![ &b"three"[..], &b""[..] ]).unwrap(), 2 .. 4);
+    assert_eq!(append_to_bottle(&mut file, vec![ &b"five"[..] ]).unwrap(), 4 .. 5);
+    assert_eq!(append_to_bottle(&mut file, Vec::<&[u8]>::new()).unwrap(), 5 .. 5);
+
+    let expected = vec![ b"one".to_vec(), big, b"three".to_vec(), vec![], b"five".to_vec() ];
+    let ( btype, header, streams ) = read_all(file.get_ref()).unwrap();
+    assert_eq!(btype, BottleType::Test);
+    assert_eq!(header.get_number(0), Some(150));
+    assert_eq!(streams, expected.clone());
+    assert_eq!(file.into_inner(), bottle_to_vec(BottleType::Test, &h, expected).unwrap());
+  }
+
+  // reads some data, then fails.
+  struct Failing(usize);
+
+  impl Read for Failing {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+      if self.0 == 0 { return Err(io::Error::other("disk on fire")) }
+      let n = self.0.min(buffer.len());
+      buffer[.. n].fill(7);
+      self.0 -= n;
+      Ok(n)
+    }
+  }
+
+  #[test]
+  fn append_undoes_a_failed_stream() {
+    let data = bottle_to_vec(BottleType::Test, &Header::new(), vec![ b"one".to_vec() ]).unwrap();
+    let mut file = io::Cursor::new(data.clone());
+    let streams: Vec<Box<dyn Read>> = vec![ Box::new(&b"two"[..]), Box::new(Failing(100_000)) ];
+    let e = append_to_bottle(&mut file, streams).unwrap_err();
+    assert_eq!(e.to_string(), "disk on fire");
+    assert_eq!(file.get_ref(), &data);
+
+    // and it can still be added to.
+    assert_eq!(append_to_bottle(&mut file, vec![ &b"two"[..] ]).unwrap(), 1 .. 2);
+    assert_eq!(read_all(file.get_ref()).unwrap().2, vec![ b"one".to_vec(), b"two".to_vec() ]);
+  }
+
+  #[test]
+  fn append_refuses_trailing_data() {
+    let mut data = bottle_to_vec(BottleType::Test, &Header::new(), vec![ b"one".to_vec() ]).unwrap();
+    data.extend_from_slice(b"index");
+    let e = append_to_bottle(io::Cursor::new(data), vec![ &b"two"[..] ]).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::TrailingData));
+
+    let data = bottle_to_vec(BottleType::Test, &Header::new(), vec![ b"one".to_vec() ]).unwrap();
+    let truncated = data[0 .. data.len() - 1].to_vec();
+    assert!(append_to_bottle(io::Cursor::new(truncated), vec![ &b"two"[..] ]).is_err());
+  }
+
+  #[test]
+  fn append_to_a_folder() {
+    let folder = FileMetadata { filename: "stuff".to_string(), folder: true, ..FileMetadata::default() };
+    let a = FileMetadata { filename: "a".to_string(), ..FileMetadata::default() };
+    let a = bottle_to_vec(BottleType::File, &a.to_header(), vec![ b"ay".to_vec() ]).unwrap();
+    let mut file = io::Cursor::new(bottle_to_vec(BottleType::File, &folder.to_header(), vec![ a.clone() ]).unwrap());
+    assert_eq!(append_to_bottle(&mut file, vec![ &a[..] ]).unwrap(), 1 .. 2);
+    let ( _, _, streams ) = read_all(file.get_ref()).unwrap();
+    assert_eq!(streams, vec![ a.clone(), a ]);
+  }
+
+  #[test]
+  fn append_refuses_other_bottles() {
+    let file = FileMetadata { filename: "a".to_string(), ..FileMetadata::default() };
+    let mut counted = Header::new();
+    counted.add_number(FIELD_STREAM_COUNT, 1);
+    let mut interleaved = Header::new();
+    interleaved.add_number(FIELD_INTERLEAVED, 2);
+    let cases = vec![
+      ( BottleType::File, file.to_header(), BottleError::NotAppendable(BottleType::File) ),
+      ( BottleType::Hashed, Header::new(), BottleError::NotAppendable(BottleType::Hashed) ),
+      ( BottleType::Encrypted, Header::new(), BottleError::NotAppendable(BottleType::Encrypted) ),
+      ( BottleType::Compressed, Header::new(), BottleError::NotAppendable(BottleType::Compressed) ),
+      ( BottleType::Index, Header::new(), BottleError::NotAppendable(BottleType::Index) ),
+      ( BottleType::Test, counted, BottleError::NotAppendable(BottleType::Test) ),
+      ( BottleType::Test, interleaved, BottleError::Interleaved )
+    ];
+    for ( btype, header, error ) in cases {
+      let data = bottle_to_vec(btype, &header, vec![ b"one".to_vec() ]).unwrap();
+      let mut file = io::Cursor::new(data.clone());
+      let e = append_to_bottle(&mut file, vec![ &b"two"[..] ]).unwrap_err();
+      assert_eq!(BottleError::find(&e), Some(&error));
+      assert_eq!(file.into_inner(), data);
+    }
+  }
+}
@@ -0,0 +1,165 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use common::{drain, temp_dir};
+  use futures::{Future, Stream};
+  use lib4bottle::archive::{ArchiveReader, ArchiveWriter, list_bottle};
+  use lib4bottle::bottle::{BottleType, DecodeLimits, bottle_from_slice};
+  use lib4bottle::compressed_bottle::{CompressOptions, CompressionType};
+  use lib4bottle::encrypted_bottle::{EncryptionType, KeySource, generate_key_pair};
+  use lib4bottle::error::{BottleError, DecodeLimit};
+  use lib4bottle::hashing::{HashAlgorithm};
+  use lib4bottle::stream_helpers::{make_stream};
+  use lib4bottle::transcode::{TranscodeOptions, transcode_bottle};
+  use std::fs;
+  use std::io;
+
+  fn archive(name: &str, writer: ArchiveWriter) -> Vec<u8> {
+    let source = temp_dir(&format!("transcode-{}", name));
+    fs::create_dir_all(source.join("stuff")).unwrap();
+    fs::write(source.join("stuff").join("a.txt"), "ay").unwrap();
+    fs::write(source.join("stuff").join("c.txt"), "sea".repeat(1000)).unwrap();
+    let data = drain(writer.add_path(source.join("stuff")).into_stream().unwrap());
+    fs::remove_dir_all(&source).unwrap();
+    data
+  }
+
+  fn transcode(data: Vec<u8>, options: TranscodeOptions) -> io::Result<Vec<u8>> {
+    let s = make_stream(data.chunks(100).map(Bytes::from).collect());
+    transcode_bottle(s, options).and_then(|s| s.collect()).map(|v| {
+      v.into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect()
+    }).wait()
+  }
+
+  fn layers(reader: ArchiveReader, data: Vec<u8>) -> Vec<BottleType> {
+    reader.list(make_stream(vec![ Bytes::from(data) ])).collect().wait().unwrap()[0].layers.clone()
+  }
+
+  fn contents(reader: ArchiveReader, data: Vec<u8>) -> Vec<( String, Vec<u8> )> {
+    reader.entries(make_stream(vec![ Bytes::from(data) ])).and_then(|entry| {
+      let path = entry.path.to_string_lossy().to_string();
+      entry.content.concat2().map(move |b| ( path, b.to_vec() ))
+    }).collect().wait().unwrap()
+  }
+
+  fn expected() -> Vec<( String, Vec<u8> )> {
+    vec![
+      ( "stuff".to_string(), vec![] ),
+      ( "stuff/a.txt".to_string(), b"ay".to_vec() ),
+      ( "stuff/c.txt".to_string(), "sea".repeat(1000).into_bytes() )
+    ]
+  }
+
+  fn key() -> KeySource {
+    KeySource::Raw(vec![ 9; 32 ])
+  }
+
+  #[test]
+  fn change_nothing() {
+    let data = archive("nothing", ArchiveWriter::new().compress(CompressionType::Snappy));
+    assert_eq!(transcode(data.clone(), TranscodeOptions::new()).unwrap(), data);
+  }
+
+  #[test]
+  fn recompress() {
+    let data = archive("recompress", ArchiveWriter::new().hash(HashAlgorithm::Sha256).compress(CompressionType::Snappy));
+    let data = transcode(data, TranscodeOptions::new().compress(CompressionType::Zstd)).unwrap();
+    let entries = list_bottle(make_stream(vec![ Bytes::from(data.clone()) ])).collect().wait().unwrap();
+    assert_eq!(entries[0].layers, vec![ BottleType::Compressed, BottleType::Hashed, BottleType::File ]);
+    assert_eq!(contents(ArchiveReader::new(), data), expected());
+  }
+
+  #[test]
+  fn add_encryption_outside_compression() {
+    let data = archive("encrypt", ArchiveWriter::new().compress(CompressionType::Snappy));
+    let data = transcode(data, TranscodeOptions::new().encrypt(key(), vec![ "alice".to_string() ]).parity(4, 2)).unwrap();
+    let reader = || ArchiveReader::new().with_key(|info| {
+      assert_eq!(info.recipients, vec![ "alice".to_string() ]);
+      Ok(key())
+    });
+    assert_eq!(layers(reader(), data.clone()), vec![ BottleType::Parity, BottleType::Encrypted, BottleType::Compressed, BottleType::File ]);
+    assert_eq!(contents(reader(), data), expected());
+  }
+
+  #[test]
+  fn recompress_inside_encryption() {
+    // the encryption is kept, so it's redone with the same key.
+    let data = archive("reencrypt", ArchiveWriter::new().compress(CompressionType::Snappy).encrypt(key(), vec![]));
+    let e = transcode(data.clone(), TranscodeOptions::new().compress(CompressionType::Lzma2)).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::NoKey));
+
+    let data = transcode(data, TranscodeOptions::new().with_key(|_| Ok(key())).compress(CompressionType::Lzma2)).unwrap();
+    let reader = || ArchiveReader::new().with_key(|_| Ok(key()));
+    assert_eq!(layers(reader(), data.clone()), vec![ BottleType::Encrypted, BottleType::Compressed, BottleType::File ]);
+    assert_eq!(contents(reader(), data), expected());
+  }
+
+  #[test]
+  fn reencrypt_with_the_same_cipher() {
+    let writer = ArchiveWriter::new().compress(CompressionType::Snappy).encrypt_with(key(), vec![], EncryptionType::XChaCha20Poly1305);
+    let data = archive("recipher", writer);
+    let data = transcode(data, TranscodeOptions::new().with_key(|_| Ok(key())).compress(CompressionType::Zstd)).unwrap();
+    // the kept layer is sealed the way it was.
+    let reader = ArchiveReader::new().with_key(move |info| {
+      assert_eq!(info.encryption_type, EncryptionType::XChaCha20Poly1305);
+      Ok(key())
+    });
+    assert_eq!(contents(reader, data), expected());
+  }
+
+  #[test]
+  fn strip_layers() {
+    let data = archive("strip", ArchiveWriter::new().hash(HashAlgorithm::Sha256).dedup().compress(CompressionType::Snappy).encrypt(key(), vec![]));
+    let options = TranscodeOptions::new().with_key(|_| Ok(key())).strip_hash().strip_dedup().strip_encryption();
+    let data = transcode(data, options).unwrap();
+    assert_eq!(layers(ArchiveReader::new(), data.clone()), vec![ BottleType::Compressed, BottleType::File ]);
+    assert_eq!(contents(ArchiveReader::new(), data), expected());
+  }
+
+  #[test]
+  fn replace_the_hash() {
+    let data = archive("rehash", ArchiveWriter::new().hash(HashAlgorithm::Sha256).compress(CompressionType::Snappy));
+    let data = transcode(data, TranscodeOptions::new().hash(HashAlgorithm::Sha512).dedup()).unwrap();
+    let entries = list_bottle(make_stream(vec![ Bytes::from(data.clone()) ])).collect().wait().unwrap();
+    assert_eq!(entries[0].layers, vec![ BottleType::Compressed, BottleType::Dedup, BottleType::Hashed, BottleType::File ]);
+    assert_eq!(entries[0].hashes[0].algorithm, HashAlgorithm::Sha512);
+    assert_eq!(contents(ArchiveReader::new(), data), expected());
+  }
+
+  #[test]
+  fn private_keys_cant_encrypt() {
+    let ( secret, public ) = generate_key_pair();
+    let data = archive("private", ArchiveWriter::new().compress(CompressionType::Snappy).encrypt(KeySource::PublicKeys(vec![ public ]), vec![]));
+    // it opens the bottle, but can't seal a new one.
+    let options = TranscodeOptions::new().with_key(move |_| Ok(KeySource::PrivateKey(secret))).strip_compression();
+    let e = transcode(data, options).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::KeyNotReusable));
+  }
+
+  #[test]
+  fn keep_the_compression_level() {
+    let writer = ArchiveWriter::new().hash(HashAlgorithm::Sha256).compress_with(CompressOptions::new(CompressionType::Zstd).level(19));
+    let data = transcode(archive("level", writer), TranscodeOptions::new().strip_hash()).unwrap();
+    let ( _, header, _ ) = bottle_from_slice(&data).unwrap();
+    assert_eq!(CompressOptions::from_header(&header).unwrap(), CompressOptions::new(CompressionType::Zstd).level(19));
+    assert_eq!(contents(ArchiveReader::new(), data), expected());
+  }
+
+  #[test]
+  fn transcode_with_limits() {
+    let data = archive("limits", ArchiveWriter::new().hash(HashAlgorithm::Sha256).dedup().compress(CompressionType::Snappy));
+    let limits = DecodeLimits { max_buffered_bytes: 100, ..DecodeLimits::default() };
+    let e = transcode(data.clone(), TranscodeOptions::new().with_limits(limits).strip_hash()).unwrap_err();
+    assert!(matches!(BottleError::find(&e), Some(&BottleError::DecodeLimitExceeded { limit: DecodeLimit::BufferedBytes, .. })));
+
+    let limits = DecodeLimits { max_nesting_depth: 2, ..DecodeLimits::default() };
+    let e = transcode(data, TranscodeOptions::new().with_limits(limits).strip_hash()).unwrap_err();
+    assert!(matches!(BottleError::find(&e), Some(&BottleError::DecodeLimitExceeded { limit: DecodeLimit::NestingDepth, .. })));
+  }
+}
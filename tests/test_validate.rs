@@ -0,0 +1,205 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use common::drain;
+  use futures::{Future, Stream};
+  use lib4bottle::bottle::{BottleType, DecodeLimits, FIELD_STREAM_COUNT, bottle_to_vec, make_bottle, make_interleaved_bottle};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::error::{BottleError, DecodeLimit};
+  use lib4bottle::file_bottle::FileMetadata;
+  use lib4bottle::hash_bottle::hash_bottle;
+  use lib4bottle::hashing::{HashAlgorithm, crc32c};
+  use lib4bottle::indexed_bottle::make_indexed_bottle;
+  use lib4bottle::stream_helpers::{make_stream, make_vec_stream_1};
+  use lib4bottle::validate::{Problem, ValidationReport, validate_bottle, validate_bottle_with_limits};
+  use lib4bottle::zint;
+  use std::io;
+
+  fn validate(data: &[u8]) -> ValidationReport {
+    validate_bottle(make_stream(data.chunks(5).map(Bytes::from).collect())).wait().unwrap()
+  }
+
+  fn file(name: &str, contents: &str) -> Box<dyn Stream<Item = Vec<Bytes>, Error = io::Error>> {
+    let metadata = FileMetadata { filename: name.to_string(), size: Some(contents.len() as u64), ..FileMetadata::default() };
+    Box::new(make_bottle(BottleType::File, &metadata.to_header(), vec![ make_vec_stream_1(Bytes::from(contents.to_string())) ]))
+  }
+
+  // a hashed folder of two files.
+  fn archive() -> Vec<u8> {
+    let folder = FileMetadata { filename: "stuff".to_string(), folder: true, ..FileMetadata::default() };
+    let files = vec![ file("a.txt", "hello sailor!"), file("b.txt", "goodbye sailor!") ];
+    drain(hash_bottle(make_bottle(BottleType::File, &folder.to_header(), files), HashAlgorithm::Sha256).unwrap())
+  }
+
+  fn find(data: &[u8], s: &str) -> usize {
+    data.windows(s.len()).position(|w| w == s.as_bytes()).unwrap()
+  }
+
+  fn problems(report: &ValidationReport) -> Vec<( u64, Vec<usize>, BottleError )> {
+    report.problems.iter().map(|&Problem { offset, ref path, ref error }| ( offset, path.clone(), error.clone() )).collect()
+  }
+
+  #[test]
+  fn valid_archive() {
+    let data = archive();
+    let report = validate(&data);
+    assert!(report.is_ok(), "{:?}", report);
+    assert_eq!(report.bottles, 4);
+    assert_eq!(report.bytes, data.len() as u64);
+  }
+
+  #[test]
+  fn crc32c_mismatch() {
+    let metadata = FileMetadata { filename: "a.txt".to_string(), crc32c: true, ..FileMetadata::default() };
+    let crc = crc32c(0, b"hello sailor!").to_be_bytes().to_vec();
+    let streams = vec![ make_vec_stream_1(Bytes::from("hello sailor!")), make_vec_stream_1(Bytes::from(crc)) ];
+    let mut data = drain(make_bottle(BottleType::File, &metadata.to_header(), streams));
+    assert!(validate(&data).is_ok());
+
+    let n = find(&data, "sailor");
+    data[n] = b'S';
+    let report = validate(&data);
+    // the crc child starts after "Sailor!" and the end of the first child.
+    let offset = (find(&data, "Sailor") + 8) as u64;
+    let found = crc32c(0, b"hello Sailor!");
+    let expected = crc32c(0, b"hello sailor!");
+    assert_eq!(problems(&report), vec![ ( offset, vec![], BottleError::ChecksumMismatch { expected, found } ) ]);
+
+    let streams = vec![ make_vec_stream_1(Bytes::from("hello sailor!")) ];
+    let data = drain(make_bottle(BottleType::File, &metadata.to_header(), streams));
+    assert_eq!(problems(&validate(&data)).pop().map(|( _, _, e )| e), Some(BottleError::MissingChecksum));
+  }
+
+  #[test]
+  fn bad_magic() {
+    let mut data = archive();
+    data[0] = 0;
+    assert_eq!(problems(&validate(&data)), vec![ ( 0, vec![], BottleError::BadMagic ) ]);
+  }
+
+  #[test]
+  fn truncated() {
+    let data = archive();
+    for &size in &[ 3, 20, data.len() / 2, data.len() - 1 ] {
+      assert_eq!(problems(&validate(&data[0 .. size])), vec![ ( size as u64, vec![], BottleError::TruncatedStream ) ]);
+    }
+  }
+
+  #[test]
+  fn digest_mismatch() {
+    let mut data = archive();
+    let offset = find(&data, "goodbye");
+    data[offset] = b'G';
+    let report = validate(&data);
+    assert_eq!(report.problems.len(), 1);
+    assert_eq!(report.problems[0].path, Vec::<usize>::new());
+    assert_eq!(report.problems[0].error, BottleError::HashMismatch);
+    assert!(report.problems[0].offset > offset as u64);
+  }
+
+  #[test]
+  fn nested_problems() {
+    let mut data = archive();
+    // the second file's header has a filename; remove it by renaming its
+    // field to an unused id.
+    let offset = find(&data, "b.txt");
+    data[offset - 2] = (data[offset - 2] & 0xc3) | (9 << 2);
+    let report = validate(&data);
+    let errors: Vec<_> = report.problems.iter().map(|p| ( p.path.clone(), p.error.clone() )).collect();
+    assert_eq!(errors, vec![ ( vec![ 0, 1 ], BottleError::MissingFilename ), ( vec![], BottleError::HashMismatch ) ]);
+    assert_eq!(report.bottles, 4);
+  }
+
+  #[test]
+  fn trailing_data() {
+    let mut data = archive();
+    let size = data.len() as u64;
+    data.extend_from_slice(b"extra");
+    assert_eq!(problems(&validate(&data)), vec![ ( size, vec![], BottleError::TrailingData ) ]);
+  }
+
+  #[test]
+  fn unexpected_end() {
+    let mut data = drain(make_bottle(BottleType::Test, &Header::new(), vec![ make_vec_stream_1(Bytes::from("hi")) ]));
+    // replace the end of the child stream with the end of all streams.
+    let size = data.len();
+    data[size - 2] = 0xff;
+    data.pop();
+    assert_eq!(problems(&validate(&data)), vec![ ( size as u64 - 2, vec![], BottleError::UnexpectedEnd ) ]);
+  }
+
+  #[test]
+  fn stream_count() {
+    let mut header = Header::new();
+    header.add_number(FIELD_STREAM_COUNT, 3);
+    let data = drain(make_bottle(BottleType::Test, &header, vec![ make_vec_stream_1(Bytes::from("hi")) ]));
+    let report = validate(&data);
+    assert_eq!(problems(&report), vec![
+      ( data.len() as u64 - 1, vec![], BottleError::StreamCountMismatch { expected: 3, found: 1 } )
+    ]);
+  }
+
+  #[test]
+  fn interleaved_bottle() {
+    // reported, not misread as plain frames, even inside a folder.
+    let inner = drain(make_interleaved_bottle(BottleType::Test, &Header::new(), vec![ make_vec_stream_1(Bytes::from("hi")) ], 2));
+    let folder = FileMetadata { filename: "stuff".to_string(), folder: true, ..FileMetadata::default() };
+    let data = drain(make_bottle(BottleType::File, &folder.to_header(), vec![ make_vec_stream_1(Bytes::from(inner)) ]));
+    let report = validate(&data);
+    let errors: Vec<_> = report.problems.iter().map(|p| ( p.path.clone(), p.error.clone() )).collect();
+    assert_eq!(errors, vec![ ( vec![ 0 ], BottleError::Interleaved ) ]);
+    assert_eq!(report.bottles, 2);
+  }
+
+  #[test]
+  fn indexed_bottle() {
+    let streams = vec![ "one", "two", "three" ].into_iter().map(|s| make_vec_stream_1(Bytes::from(s)));
+    let data = drain(make_indexed_bottle(BottleType::Test, &Header::new(), streams));
+    assert!(validate(&data).is_ok());
+
+    let mut bad = data.clone();
+    let size = bad.len();
+    bad[size - 8] ^= 1;
+    assert_eq!(problems(&validate(&bad)), vec![ ( size as u64 - 8, vec![], BottleError::BadIndex ) ]);
+    let report = validate(&data[0 .. size - 3]);
+    assert_eq!(report.problems[0].error, BottleError::BadIndex);
+  }
+
+  #[test]
+  fn deeply_nested() {
+    // each folder's only child starts with the next folder, and the stream
+    // stops before any of them end.
+    let folder = FileMetadata { filename: "d".to_string(), folder: true, ..FileMetadata::default() };
+    let mut level = bottle_to_vec(BottleType::File, &folder.to_header(), vec![]).unwrap();
+    level.pop();
+    level.extend(zint::encode_length(zint::MAX_LENGTH));
+    let data: Vec<u8> = level.iter().cloned().cycle().take(level.len() * 100_000).collect();
+
+    let report = validate_bottle(make_stream(vec![ Bytes::from(data) ])).wait().unwrap();
+    assert_eq!(report.bottles, 257);
+    assert_eq!(report.problems[0].offset, (level.len() * 256) as u64);
+    assert_eq!(report.problems[0].path.len(), 256);
+    assert_eq!(report.problems[0].error, BottleError::DecodeLimitExceeded { limit: DecodeLimit::NestingDepth, size: 257, max: 256 });
+    assert!(report.problems[1 ..].iter().all(|p| p.error == BottleError::TruncatedStream));
+
+    let limits = DecodeLimits { max_nesting_depth: 3, ..DecodeLimits::default() };
+    let report = validate_bottle_with_limits(make_stream(vec![ Bytes::from(level.repeat(10)) ]), limits).wait().unwrap();
+    assert_eq!(report.problems[0].error, BottleError::DecodeLimitExceeded { limit: DecodeLimit::NestingDepth, size: 4, max: 3 });
+  }
+
+  #[test]
+  fn buffered_digest() {
+    let data = archive();
+    let limits = DecodeLimits { max_buffered_bytes: 16, ..DecodeLimits::default() };
+    let report = validate_bottle_with_limits(make_stream(vec![ Bytes::from(data) ]), limits).wait().unwrap();
+    assert_eq!(problems(&report).into_iter().map(|( _, path, e )| ( path, e )).collect::<Vec<_>>(), vec![
+      ( vec![], BottleError::DecodeLimitExceeded { limit: DecodeLimit::BufferedBytes, size: 32, max: 16 } )
+    ]);
+  }
+}
@@ -0,0 +1,93 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use futures::{Future, Stream, stream};
+  use lib4bottle::bottle::{BottleType, bottle_from_slice};
+  use lib4bottle::error::BottleError;
+  use lib4bottle::volume_bottle::{join_volumes, split_bottle};
+  use std::io;
+
+  fn split(data: &[u8], chunk_size: usize, volume_size: usize) -> Vec<Vec<u8>> {
+    let chunks: Vec<Vec<Bytes>> = data.chunks(chunk_size).map(|c| vec![ Bytes::from(c) ]).collect();
+    let volumes = split_bottle(stream::iter_ok::<_, io::Error>(chunks), volume_size).unwrap();
+    volumes.wait().map(|volume| {
+      let buffers = volume.unwrap().collect().wait().unwrap();
+      buffers.into_iter().flat_map(|v| v.into_iter().flat_map(|b| b.to_vec())).collect()
+    }).collect()
+  }
+
+  fn join(volumes: Vec<Vec<u8>>) -> io::Result<Vec<u8>> {
+    let streams = volumes.into_iter().map(|v| stream::iter_ok::<_, io::Error>(vec![ Bytes::from(v) ]));
+    join_volumes(streams).concat2().wait().map(|b| b.to_vec())
+  }
+
+  fn sample(size: usize) -> Vec<u8> {
+    (0 .. size).map(|i| (i % 251) as u8).collect()
+  }
+
+  #[test]
+  fn round_trip() {
+    let data = sample(10_000);
+    let volumes = split(&data, 700, 1000);
+    assert!(volumes.len() > 10);
+    for v in volumes.iter() {
+      assert!(v.len() <= 1000);
+      let ( btype, _, _ ) = bottle_from_slice(v).unwrap();
+      assert_eq!(btype, BottleType::Volume);
+    }
+    assert_eq!(join(volumes).unwrap(), data);
+  }
+
+  #[test]
+  fn round_trip_small() {
+    assert_eq!(join(split(b"hello", 5, 64)).unwrap(), b"hello".to_vec());
+    let volumes = split(b"", 5, 64);
+    assert_eq!(volumes.len(), 1);
+    assert_eq!(join(volumes).unwrap(), vec![]);
+  }
+
+  #[test]
+  fn volume_too_small() {
+    let e = split_bottle(stream::empty::<Vec<Bytes>, io::Error>(), 10).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::VolumeTooSmall(10)));
+  }
+
+  #[test]
+  fn out_of_order() {
+    let mut volumes = split(&sample(1000), 100, 200);
+    volumes.swap(0, 1);
+    let e = join(volumes).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::VolumeOutOfOrder { expected: 0, found: 1 }));
+  }
+
+  #[test]
+  fn missing_last_volume() {
+    let mut volumes = split(&sample(1000), 100, 200);
+    volumes.pop();
+    let e = join(volumes).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::MissingVolume));
+  }
+
+  #[test]
+  fn extra_volume() {
+    let mut volumes = split(&sample(100), 100, 200);
+    let first = volumes[0].clone();
+    volumes.push(first);
+    let e = join(volumes).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::VolumeOutOfOrder { expected: 1, found: 0 }));
+  }
+
+  #[test]
+  fn wrong_set() {
+    let data = sample(1000);
+    let mut volumes = split(&data, 100, 200);
+    let other = split(&data, 100, 200);
+    volumes[1] = other[1].clone();
+    let e = join(volumes).unwrap_err();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::WrongVolumeSet));
+  }
+}
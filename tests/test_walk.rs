@@ -0,0 +1,116 @@
+extern crate bytes;
+extern crate futures;
+extern crate lib4bottle;
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+  use bytes::Bytes;
+  use common::{drain, temp_dir};
+  use futures::{Future};
+  use lib4bottle::archive::{ArchiveWriter};
+  use lib4bottle::bottle::{BottleType, DecodeLimits};
+  use lib4bottle::bottle_header::{Header};
+  use lib4bottle::compressed_bottle::{CompressionType};
+  use lib4bottle::encrypted_bottle::{KeySource};
+  use lib4bottle::error::{BottleError, DecodeLimit};
+  use lib4bottle::file_bottle::{FileMetadata};
+  use lib4bottle::hashing::{HashAlgorithm};
+  use lib4bottle::stream_helpers::{make_stream};
+  use lib4bottle::walk::{BottleVisitor, BottleWalker, walk_bottles};
+  use std::fs;
+  use std::io;
+
+  fn archive(name: &str, writer: ArchiveWriter) -> Vec<u8> {
+    let source = temp_dir(&format!("walk-{}", name));
+    fs::create_dir_all(source.join("stuff").join("inner")).unwrap();
+    fs::write(source.join("stuff").join("a.txt"), "ay").unwrap();
+    fs::write(source.join("stuff").join("inner").join("c.txt"), "sea".repeat(1000)).unwrap();
+    let s = writer.add_path(source.join("stuff")).into_stream().unwrap();
+    let data = drain(s);
+    fs::remove_dir_all(&source).unwrap();
+    data
+  }
+
+  // writes each callback as a line, indented by depth.
+  #[derive(Default)]
+  struct Trace {
+    lines: Vec<String>,
+    bytes: usize
+  }
+
+  impl BottleVisitor for Trace {
+    fn enter(&mut self, btype: BottleType, header: &Header, depth: usize) -> io::Result<()> {
+      let name = if btype == BottleType::File { FileMetadata::from_header(header)?.filename } else { String::new() };
+      self.lines.push(format!("{}{:?} {}", " ".repeat(depth - 1), btype, name).trim_end().to_string());
+      Ok(())
+    }
+
+    fn data(&mut self, _depth: usize, _index: usize, data: Bytes) -> io::Result<()> {
+      self.bytes += data.len();
+      Ok(())
+    }
+
+    fn leave(&mut self, btype: BottleType, _header: &Header, depth: usize) -> io::Result<()> {
+      self.lines.push(format!("{}/{:?}", " ".repeat(depth - 1), btype));
+      Ok(())
+    }
+  }
+
+  fn walk(walker: BottleWalker, data: &[u8]) -> io::Result<Trace> {
+    let s = make_stream(data.chunks(100).map(Bytes::from).collect());
+    walker.walk(s, Trace::default()).wait()
+  }
+
+  #[test]
+  fn walk_folders() {
+    let data = archive("folders", ArchiveWriter::new());
+    let s = make_stream(vec![ Bytes::from(data) ]);
+    let trace = walk_bottles(s, Trace::default()).wait().unwrap();
+    assert_eq!(trace.lines, vec![
+      "File stuff",
+      " File a.txt",
+      " /File",
+      " File inner",
+      "  File c.txt",
+      "  /File",
+      " /File",
+      "/File"
+    ]);
+    assert_eq!(trace.bytes, 3002);
+  }
+
+  #[test]
+  fn walk_layers() {
+    let writer = ArchiveWriter::new()
+      .hash(HashAlgorithm::Sha256)
+      .compress(CompressionType::Zstd)
+      .encrypt(KeySource::Passphrase("hunter2".to_string()), vec![]);
+    let data = archive("layers", writer);
+    let trace = walk(BottleWalker::new().with_key(|_| Ok(KeySource::Passphrase("hunter2".to_string()))), &data).unwrap();
+    assert_eq!(&trace.lines[0 .. 4], &[ "Encrypted", " Compressed", "  Hashed", "   File stuff" ]);
+    assert_eq!(&trace.lines[trace.lines.len() - 4 ..], &[ "   /File", "  /Hashed", " /Compressed", "/Encrypted" ]);
+    // the files and the digest
+    assert_eq!(trace.bytes, 3002 + 32);
+
+    // without a key, the encrypted bottle isn't opened.
+    let trace = walk(BottleWalker::new(), &data).unwrap();
+    assert_eq!(trace.lines, vec![ "Encrypted", "/Encrypted" ]);
+
+    let e = walk(BottleWalker::new().with_key(|_| Ok(KeySource::Passphrase("wrong".to_string()))), &data).err().unwrap();
+    assert!(BottleError::find(&e).is_some());
+  }
+
+  #[test]
+  fn walk_to_max_depth() {
+    let data = archive("max-depth", ArchiveWriter::new().compress(CompressionType::Snappy));
+    let trace = walk(BottleWalker::new().max_depth(2), &data).unwrap();
+    assert_eq!(trace.lines, vec![ "Compressed", " File stuff", " /File", "/Compressed" ]);
+    assert!(trace.bytes > 0);
+
+    let limits = DecodeLimits { max_nesting_depth: 3, ..DecodeLimits::default() };
+    let e = walk(BottleWalker::new().with_limits(limits), &data).err().unwrap();
+    assert_eq!(BottleError::find(&e), Some(&BottleError::DecodeLimitExceeded { limit: DecodeLimit::NestingDepth, size: 4, max: 3 }));
+  }
+}
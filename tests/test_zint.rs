@@ -1,8 +1,13 @@
+extern crate bytes;
+extern crate futures;
 extern crate lib4bottle;
 
 #[cfg(test)]
 mod tests {
+  use bytes::Bytes;
+  use futures::{Future, Stream};
   use std::io;
+  use lib4bottle::stream_helpers::make_stream;
   use lib4bottle::to_hex::{FromHex, ToHex};
   use lib4bottle::zint;
 
@@ -22,6 +27,15 @@ mod tests {
     assert_eq!(zint::bytes_needed(0xff00000010000000), 8);
   }
 
+  #[test]
+  fn packed_int_bytes() {
+    for shift in 0 .. 64 {
+      for &n in [ 0u64, 1 << shift, (1 << shift) - 1, u64::MAX >> shift ].iter() {
+        assert_eq!(zint::packed_int_bytes(n), zint::encode_packed_int(n).len(), "{}", n);
+      }
+    }
+  }
+
   #[test]
   fn encode_packed_int() {
     assert_eq!(zint::encode_packed_int(0).to_hex(), "00");
@@ -32,16 +46,77 @@ mod tests {
     assert_eq!(zint::encode_packed_int(987654321).to_hex(), "b168de3a");
   }
 
+  #[test]
+  fn encode_packed_int_bytes() {
+    for &n in [ 0, 100, 129, 127, 256, 987654321, 0xffffffffffffffff ].iter() {
+      assert_eq!(zint::encode_packed_int_bytes(n).to_vec(), zint::encode_packed_int(n));
+    }
+    assert_eq!(zint::encode_packed_int_bytes(987654321).to_hex(), "b168de3a");
+  }
+
+  // hands out one byte at a time, with an `Interrupted` error before each.
+  struct AwkwardReader {
+    data: Vec<u8>,
+    index: usize,
+    interrupt: bool
+  }
+
+  impl io::Read for AwkwardReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      self.interrupt = !self.interrupt;
+      if self.interrupt { return Err(io::Error::new(io::ErrorKind::Interrupted, "try again")) }
+      if self.index >= self.data.len() || buf.is_empty() { return Ok(0) }
+      buf[0] = self.data[self.index];
+      self.index += 1;
+      Ok(1)
+    }
+  }
+
+  #[test]
+  fn read_packed_int_from_awkward_reader() {
+    let mut reader = AwkwardReader { data: "b168de3a".from_hex(), index: 0, interrupt: false };
+    assert_eq!(zint::read_packed_int(&mut reader).unwrap(), 987654321);
+    let mut reader = AwkwardReader { data: "0001".from_hex(), index: 0, interrupt: false };
+    assert_eq!(zint::decode_packed_int(&mut reader, 2).unwrap(), 256);
+  }
+
+  #[test]
+  fn read_packed_int_stops_at_8_bytes() {
+    let mut cursor = io::Cursor::new("ffffffffffffffff01".from_hex());
+    assert_eq!(zint::read_packed_int(&mut cursor).unwrap(), 0xffffffffffffffff);
+    assert_eq!(cursor.position(), 8);
+  }
+
   #[test]
   fn decode_packed_int() {
-    assert_eq!(zint::decode_packed_int("00".from_hex().as_ref()).unwrap(), 0);
-    assert_eq!(zint::decode_packed_int("0a".from_hex().as_ref()).unwrap(), 10);
-    assert_eq!(zint::decode_packed_int("ff".from_hex().as_ref()).unwrap(), 255);
-    assert_eq!(zint::decode_packed_int("64".from_hex().as_ref()).unwrap(), 100);
-    assert_eq!(zint::decode_packed_int("81".from_hex().as_ref()).unwrap(), 129);
-    assert_eq!(zint::decode_packed_int("7f".from_hex().as_ref()).unwrap(), 127);
-    assert_eq!(zint::decode_packed_int("0001".from_hex().as_ref()).unwrap(), 256);
-    assert_eq!(zint::decode_packed_int("b168de3a".from_hex().as_ref()).unwrap(), 987654321);
+    let decode = |hex: &str, n: usize| zint::decode_packed_int(&mut io::Cursor::new(hex.from_hex()), n).unwrap();
+    assert_eq!(decode("00", 1), 0);
+    assert_eq!(decode("0a", 1), 10);
+    assert_eq!(decode("ff", 1), 255);
+    assert_eq!(decode("81", 1), 129);
+    assert_eq!(decode("0001", 2), 256);
+    assert_eq!(decode("b168de3a", 4), 987654321);
+    assert_eq!(decode("ffffffffffffffff", 8), 0xffffffffffffffff);
+    // only the first n bytes are consumed.
+    assert_eq!(decode("0a0b0c", 1), 10);
+    assert_eq!(decode("0a0b0c", 2), 0x0b0a);
+
+    let mut cursor = io::Cursor::new("b168de3a64".from_hex());
+    assert_eq!(zint::decode_packed_int(&mut cursor, 4).unwrap(), 987654321);
+    assert_eq!(zint::decode_packed_int(&mut cursor, 1).unwrap(), 100);
+  }
+
+  #[test]
+  fn decode_packed_int_errors() {
+    let long = "000000000000000001".from_hex();
+    assert_eq!(
+      zint::decode_packed_int(&mut io::Cursor::new(long), 9).unwrap_err().kind(),
+      io::ErrorKind::InvalidData
+    );
+    assert_eq!(
+      zint::decode_packed_int(&mut io::Cursor::new("0001".from_hex()), 3).unwrap_err().kind(),
+      io::ErrorKind::UnexpectedEof
+    );
   }
 
   #[test]
@@ -58,6 +133,80 @@ mod tests {
     assert_eq!(zint::encode_length(1 << 21).to_hex(), "fe");
   }
 
+  #[test]
+  fn encode_length_boundaries() {
+    // top of the 2-byte range, and just past it (2^13 itself is a power of 2)
+    assert_eq!(zint::encode_length(8191).to_hex(), "bf7f");
+    assert_eq!(zint::encode_length(1 << 13).to_hex(), "f6");
+    assert_eq!(zint::encode_length((1 << 13) + 1).to_hex(), "c10001");
+    // top of the 3-byte range, and just past it
+    assert_eq!(zint::encode_length((1 << 21) - 1).to_hex(), "dfffff");
+    assert_eq!(zint::encode_length((1 << 21) + 1).to_hex(), "e1000002");
+    // top of the 4-byte range
+    assert_eq!(zint::encode_length((1 << 28) - 1).to_hex(), "efffffff");
+    assert!(zint::write_length(&mut io::Cursor::new(Vec::new()), 1 << 28).is_err());
+  }
+
+  #[test]
+  fn length_round_trips() {
+    // each edge of each encoding, every power of two (which switch to the
+    // one-byte form up to 2^21, and back to the 4-byte form after), every
+    // small length, and a spread of big ones.
+    let mut lengths: Vec<u64> = vec![ 1, 127, 128, 129, 8191, 8192, 8193, (1 << 21) - 1, 1 << 21, (1 << 21) + 1, (1 << 28) - 1 ];
+    for i in 7 .. 28 { lengths.extend_from_slice(&[ (1 << i) - 1, 1 << i, (1 << i) + 1 ]) }
+    lengths.extend(1 .. (1 << 16));
+    lengths.extend((0 .. 10000).map(|i: u32| ((i.wrapping_mul(2654435761) >> 4) | 1) as u64));
+    for n in lengths {
+      let encoded = zint::encode_length_to_bytes(n).unwrap();
+      assert_eq!(encoded.to_vec(), zint::encode_length(n as u32), "encoding of {}", n);
+      assert_eq!(zint::length_of_length(encoded[0]), encoded.len(), "length of length of {}", n);
+      assert_eq!(zint::decode_length(&mut io::Cursor::new(encoded.to_vec())).unwrap() as u64, n);
+      let is_power = n.is_power_of_two() && (128 ..= (1 << 21)).contains(&n);
+      assert_eq!(encoded.len() == 1 && encoded[0] & 0xf0 == 0xf0, is_power, "power-of-two form of {}", n);
+    }
+  }
+
+  #[test]
+  fn encode_length_to_bytes() {
+    assert_eq!(zint::encode_length_to_bytes(12345).unwrap().to_hex(), "d98101");
+    assert_eq!(zint::encode_length_to_bytes(zint::END_OF_ALL_STREAMS as u64).unwrap().to_hex(), "ff");
+    for &n in [ 1 << 28, 1 << 32, u64::MAX ].iter() {
+      let e = zint::encode_length_to_bytes(n).unwrap_err();
+      assert_eq!(e.to_string(), format!("Frame too long: {} bytes", n));
+    }
+  }
+
+  #[test]
+  fn encode_large_power_of_2_length() {
+    // 2^22 would be "ff", which is the end-of-all-streams marker.
+    assert_eq!(zint::encode_length(1 << 22).to_hex(), "e0000004");
+    assert_eq!(zint::encode_length(1 << 27).to_hex(), "e0000080");
+  }
+
+  #[test]
+  fn read_length_from_stream() {
+    let s = make_stream(vec![ Bytes::from("d9".from_hex()), Bytes::from("81".from_hex()), Bytes::from("0164".from_hex()) ]);
+    let (length, s) = zint::read_length(s).wait().unwrap();
+    assert_eq!(length, 12345);
+    let (length, s) = zint::read_length(s).wait().unwrap();
+    assert_eq!(length, 100);
+    assert_eq!(s.collect().wait().unwrap().len(), 0);
+
+    let s = make_stream(vec![ Bytes::from("ff00".from_hex()) ]);
+    let (length, s) = zint::read_length(s).wait().unwrap();
+    assert_eq!(length, zint::END_OF_ALL_STREAMS);
+    assert_eq!(s.collect().wait().unwrap().to_hex(), "00");
+  }
+
+  #[test]
+  fn read_length_from_truncated_stream() {
+    let e = zint::read_length(make_stream(vec![ Bytes::from("ea43".from_hex()) ])).wait().err().unwrap();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(e.to_string(), "Truncated length: expected 4 bytes, got 2");
+    let e = zint::read_length(make_stream(vec![])).wait().err().unwrap();
+    assert_eq!(e.to_string(), "Truncated length: expected 1 bytes, got 0");
+  }
+
   #[test]
   fn encode_special_length() {
     assert_eq!(zint::encode_length(zint::END_OF_STREAM).to_hex(), "00");
@@ -102,6 +251,15 @@ mod tests {
   fn decode_length_not_enough_bytes() {
     zint::decode_length(&mut io::Cursor::new("81".from_hex())).unwrap();
   }
+
+  #[test]
+  fn decode_length_truncated_errors() {
+    let e = zint::decode_length(&mut io::Cursor::new("ea43".from_hex())).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(e.to_string(), "Truncated length: expected 4 bytes, got 2");
+    let e = zint::decode_length(&mut io::Cursor::new(Vec::new())).unwrap_err();
+    assert_eq!(e.to_string(), "Truncated length: expected 1 bytes, got 0");
+  }
 }
 
 